@@ -3,7 +3,13 @@ use env_logger;
 use lazy_static::{__lazy_static_create, __lazy_static_internal, lazy_static};
 #[allow(unused_imports)]
 use log::{debug, error, info, log, trace, warn};
-use std::env;
+use serde_json::json;
+use std::{
+    env,
+    io::Write,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
 use transportation::EncryptionPerspective;
 
 lazy_static! {
@@ -17,13 +23,32 @@ crate fn create_app() -> App<'static, 'static> {
         .takes_value(true)
         .multiple(true)
         .number_of_values(1)
-        .help("A command to run after the connection is established. The same commands from the F10 prompt.");
+        .validator(validate_metacommand)
+        .help(
+            "A command to run after the connection is established. The same commands from the F10 prompt, quoted the same way. \
+             Several commands may be given on separate lines.",
+        );
     let identity = Arg::with_name("identity")
         .short("i")
         .long("identity")
         .takes_value(true)
-        .help("Use [identity] as authentication information for connecting to the remote server.")
+        .help(
+            "Use [identity] as authentication information for connecting to the remote server. - reads it from stdin, and @path \
+             from a file, which may be encrypted with keygen --encrypt-identity.",
+        )
         .env("OXY_IDENTITY");
+    let identity_passphrase_env = Arg::with_name("identity passphrase env")
+        .long("identity-passphrase-env")
+        .takes_value(true)
+        .value_name("VAR")
+        .help(
+            "Read the passphrase for an encrypted --identity @path file from this environment variable, instead of asking on \
+             the terminal",
+        );
+    let identity_from_stdin = Arg::with_name("identity from stdin")
+        .long("identity-from-stdin")
+        .conflicts_with("identity")
+        .help("Read the identity from the first line of stdin instead of the command line or config, so it never touches the disk.");
     let command = Arg::with_name("command").index(2);
     let l_portfwd = Arg::with_name("local port forward")
         .multiple(true)
@@ -43,10 +68,26 @@ crate fn create_app() -> App<'static, 'static> {
         .multiple(true)
         .short("D")
         .long("socks")
-        .help("Bind a local port as a SOCKS5 proxy")
+        .help("Bind a local port as a SOCKS5 proxy. Append ,auth=user:pass to require a login for this one.")
         .number_of_values(1)
         .takes_value(true)
+        .validator(validate_socks_spec)
         .display_order(104);
+    let socks_auth = Arg::with_name("socks auth")
+        .long("socks-auth")
+        .takes_value(true)
+        .value_name("user:pass")
+        .requires("socks")
+        .validator(validate_socks_auth)
+        .help(
+            "Require SOCKS clients to log in with this username and password on every -D proxy that doesn't give its own \
+             ,auth=. Without it, anyone who can reach the port can use the proxy.",
+        );
+    let bind_address = Arg::with_name("bind-address")
+        .index(1)
+        .default_value(DEFAULT_BIND_ADDRESS)
+        .validator(validate_bind_address)
+        .help("The address to listen on: an IP address, optionally with a port, which otherwise comes from --port. IPv6 may be bracketed.");
     let port = Arg::with_name("port")
         .short("p")
         .long("port")
@@ -62,98 +103,1387 @@ crate fn create_app() -> App<'static, 'static> {
         .takes_value(true)
         .multiple(true)
         .number_of_values(1)
-        .help("Connect to a different oxy server first, then proxy traffic through the intermediary server.");
+        .help(
+            "Connect to a different oxy server first, then proxy traffic through the intermediary server. Append \
+             ,proxy-command=<command> to reach that hop through a command instead, as with --proxy-command.",
+        );
+    let jump_timeout = Arg::with_name("jump timeout")
+        .long("jump-timeout")
+        .takes_value(true)
+        .validator(validate_duration)
+        .help(
+            "Give up if a --via hop takes longer than this (e.g. 10s) to connect and authenticate, and say which hop it was. Each \
+             hop's time starts once the hop before it is up.",
+        );
+    let proxy_command = Arg::with_name("proxy command")
+        .long("proxy-command")
+        .takes_value(true)
+        .value_name("command")
+        .help(
+            "Reach the server through this command's stdin and stdout instead of TCP, like ssh's ProxyCommand. %h and %p are \
+             replaced with the server's host and port. With --via, the command runs on the server it is reached through.",
+        );
     let verbose = Arg::with_name("verbose")
         .long("verbose")
         .multiple(true)
         .short("v")
         .help("Increase debugging output");
+    let log_format = Arg::with_name("log format")
+        .long("log-format")
+        .takes_value(true)
+        .possible_values(&["text", "json"])
+        .default_value("text")
+        .help("How log lines are written. json: one object per line with timestamp, level, target and message, for log collectors.");
+    let log_filter = Arg::with_name("log filter")
+        .long("log-filter")
+        .takes_value(true)
+        .help(
+            "A RUST_LOG style logging directive, such as 'oxy::core=trace,oxy=info'. Overrides both -v and the RUST_LOG environment \
+             variable.",
+        );
     let xforward = Arg::with_name("X Forwarding").short("X").long("x-forwarding").help("Enable X forwarding");
     let trusted_xforward = Arg::with_name("Trusted X Forwarding")
         .short("Y")
         .long("trusted-x-forwarding")
         .help("Enable trusted X forwarding");
+    let x_cookie_timeout = Arg::with_name("x cookie timeout")
+        .long("x-cookie-timeout")
+        .takes_value(true)
+        .validator(validate_duration)
+        .default_value("1h")
+        .help("How long the xauth cookie generated for X forwarding stays valid unused");
+    let x_display_offset = Arg::with_name("x display offset")
+        .long("x-display-offset")
+        .takes_value(true)
+        .validator(validate_count)
+        .default_value("10")
+        .help("The first display number to give sessions that forward X. Each session gets a display of its own.");
+    let config_dir = Arg::with_name("config dir")
+        .long("config-dir")
+        .takes_value(true)
+        .value_name("path")
+        .default_value("~/.config/oxy")
+        .help("The directory server.conf, client.conf, known_hosts and lastlog are found in, unless their own options say otherwise");
     let server_config = Arg::with_name("server config")
         .long("server-config")
-        .help("Path to server.conf")
-        .default_value("~/.config/oxy/server.conf");
+        .takes_value(true)
+        .help("Path to server.conf, or none to not read one. Defaults to server.conf in --config-dir");
     let client_config = Arg::with_name("client config")
         .long("client-config")
-        .help("Path to client.conf")
-        .default_value("~/.config/oxy/client.conf");
+        .takes_value(true)
+        .help("Path to client.conf, or none to not read one. Defaults to client.conf in --config-dir");
+    let client_config_url = Arg::with_name("client config url")
+        .long("config-url")
+        .takes_value(true)
+        .value_name("https-url")
+        .validator(validate_config_url)
+        .help(
+            "Fetch client.conf from this HTTPS URL at startup instead of reading the file. Whoever controls the endpoint decides \
+             which servers are trusted. Needs curl.",
+        );
+    let server_config_url = Arg::with_name("server config url")
+        .long("config-url")
+        .takes_value(true)
+        .value_name("https-url")
+        .validator(validate_config_url)
+        .help(
+            "Fetch server.conf from this HTTPS URL at startup instead of reading the file. Whoever controls the endpoint decides \
+             which clients may log in. Needs curl.",
+        );
+    let config_cache = Arg::with_name("config cache")
+        .long("config-cache")
+        .takes_value(true)
+        .value_name("path")
+        .help("Keep a copy of each config fetched by --config-url in this file");
+    let allow_stale_config = Arg::with_name("allow stale config")
+        .long("allow-stale-config")
+        .requires("config cache")
+        .help("If --config-url can't be fetched, use the copy in --config-cache instead of failing");
     let forced_command = Arg::with_name("forced command")
         .long("forced-command")
         .help("Restrict command execution to the specified command")
         .takes_value(true);
+    let authorized_identities = Arg::with_name("authorized identities")
+        .long("authorized-identities")
+        .takes_value(true)
+        .value_name("path")
+        .help(
+            "Also accept the client keys listed in this file, one per line, like OpenSSH's authorized_keys. A key may be preceded \
+             by options that restrict its sessions: command=\"...\", permitopen=\"host:port\", from=\"pattern,...\" and no-pty.",
+        );
+    let su_mode = Arg::with_name("su mode")
+        .long("su-mode")
+        .alias("remote-user-switch")
+        .help(
+            "Let clients choose who to log in as with --user, instead of always becoming their setuser. A client may only pick its \
+             setuser or a user listed in its allowed_users. Requires running as root and --auth key.",
+        );
     let unsafe_reexec = Arg::with_name("unsafe reexec")
         .long("unsafe-reexec")
         .help("Bypass safety restrictions intended to avoid privilege elevation");
     let compression = Arg::with_name("compression")
         .short("C")
         .long("compress")
-        .help("Enable ZLIB format compression of all transmitted data");
+        .takes_value(true)
+        .min_values(0)
+        .max_values(1)
+        .require_equals(true)
+        .possible_values(&["zlib", "zstd"])
+        .help(
+            "Compress all transmitted data, with zlib unless --compress=zstd is given. zstd usually does better over slow links. \
+             Servers that don't support zstd use zlib instead.",
+        );
+    let compress_level = Arg::with_name("compress level")
+        .long("compress-level")
+        .takes_value(true)
+        .requires("compression")
+        .validator(validate_compress_level)
+        .help("The zstd compression level, from 1 (fastest) to 22 (smallest). Defaults to 3. zlib always uses its default level.");
+    let channel_window = Arg::with_name("channel window")
+        .long("channel-window")
+        .takes_value(true)
+        .validator(validate_size)
+        .help(
+            "Bytes buffered per forwarded stream or peer-to-peer copy before reading pauses. Defaults to 1M. Memory use is roughly this \
+             value times the number of open channels, so larger windows help on fast, high-latency links and smaller windows help on \
+             constrained servers.",
+        );
+    let channel_max_packet = Arg::with_name("channel max packet")
+        .long("channel-max-packet")
+        .takes_value(true)
+        .validator(validate_size)
+        .help("Largest data payload carried by a single message. Defaults to 16K, or 4K with --priority interactive and 64K with --priority bulk.");
+    let tun = Arg::with_name("tun")
+        .long("tun")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .help("Bridge a local tun device to a remote one, as <local:remote>. The server must be run with --permit-tunnel.");
+    let tap = Arg::with_name("tap")
+        .long("tap")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .help("Bridge a local tap device to a remote one, as <local:remote>. The server must be run with --permit-tunnel.");
+    let tunnel_mtu = Arg::with_name("tunnel mtu")
+        .long("tunnel-mtu")
+        .alias("tun-mtu")
+        .takes_value(true)
+        .validator(validate_size)
+        .help(
+            "Set the MTU of tun/tap devices bridged on this end of the connection. A client asks the server to use the same MTU \
+             for its end, unless the server has --tunnel-mtu of its own.",
+        );
+    let remote_shell = Arg::with_name("remote shell")
+        .long("remote-shell")
+        .takes_value(true)
+        .value_name("path")
+        .help(
+            "Run this shell (e.g. /usr/bin/fish) as a login shell when no command is given, instead of the one configured for your \
+             account. The server must allow it with --allow-shell-override.",
+        );
+    let working_directory = Arg::with_name("working directory")
+        .long("working-directory")
+        .alias("cwd")
+        .takes_value(true)
+        .value_name("path")
+        .help("Start the command or shell in this directory on the server instead of your home directory. Fails if it can't be entered.");
+    let strict_config = Arg::with_name("strict config")
+        .long("strict-config")
+        .alias("fail-on-unknown-config-key")
+        .help("Fail on any key the config file doesn't know, with its file and line, instead of ignoring it. Catches typos.");
+    let allow_shell_override = Arg::with_name("allow shell override")
+        .long("allow-shell-override")
+        .help("Let clients choose the shell run for their interactive sessions with --remote-shell. It must be an executable file.");
+    let permit_tunnel = Arg::with_name("permit tunnel")
+        .long("permit-tunnel")
+        .help("Allow clients to bridge tun/tap devices to this server");
+    let interactive_allow = Arg::with_name("interactive allow")
+        .long("interactive-allow")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("cidr")
+        .help(
+            "Only open ptys for clients connecting from these addresses, given as CIDR networks or globs. Commands, copies and \
+             forwards are still allowed from anywhere. May be repeated.",
+        );
+    let keep_fds = Arg::with_name("keep fds")
+        .long("keep-fds")
+        .takes_value(true)
+        .value_name("fd,fd,...")
+        .validator(validate_keep_fds)
+        .help(
+            "Leave these inherited file descriptors open in the sessions' ptys, which otherwise get only stdin, stdout and \
+             stderr. Each must be open when the server starts.",
+        );
+    let capabilities = Arg::with_name("capabilities")
+        .long("capabilities")
+        .takes_value(true)
+        .value_name("list")
+        .validator(validate_capabilities)
+        .help(
+            "Only honor these kinds of request, as a comma-separated list of shell, exec, copy, forward, x11 and tunnel. \
+             Everything else is refused with a reason. Forward covers SOCKS too. All are offered by default.",
+        );
+    let no_x_forwarding = Arg::with_name("no x forwarding")
+        .long("no-x-forwarding")
+        .help("Refuse X forwarding, whatever clients ask for");
+    let no_port_forwarding = Arg::with_name("no port forwarding")
+        .long("no-port-forwarding")
+        .help("Refuse local and remote port forwards, including SOCKS, knock forwards and unix socket forwards");
+    let no_pty = Arg::with_name("no pty")
+        .long("no-pty")
+        .help("Refuse pty requests, so clients can only run commands without a terminal");
+    let gateway_ports = Arg::with_name("gateway ports")
+        .long("gateway-ports")
+        .alias("remote-forward-bind-all")
+        .takes_value(true)
+        .possible_values(&["no", "yes", "clientspecified"])
+        .default_value("no")
+        .help(
+            "Which interfaces clients' remote forwards (-R) may bind to. no: only loopback, and other bind addresses are refused. \
+             yes: all interfaces. clientspecified: whatever address the client gives, or loopback if it gives none.",
+        );
+    let stdin_eof_action = Arg::with_name("stdin eof action")
+        .long("stdin-eof-action")
+        .takes_value(true)
+        .possible_values(&["close", "keep"])
+        .default_value("close")
+        .help(
+            "What to do when stdin runs out while running a command. close: close the command's stdin, and end the session once \
+             the command exits and its output is written. keep: leave the command's stdin open, and if there are port forwards, \
+             keep them going after the command exits, until interrupted.",
+        );
+    let on_hup = Arg::with_name("on hup")
+        .long("on-hup")
+        .takes_value(true)
+        .possible_values(&["disconnect", "nohup", "signal"])
+        .default_value("disconnect")
+        .help(
+            "What a SIGHUP to the client, as when its terminal window closes, does to the remote command. disconnect: the client \
+             exits and the command ends with the session. nohup: the command keeps running on the server, with its output \
+             discarded. signal: the command gets SIGHUP before the client exits.",
+        );
+    let no_stdin = Arg::with_name("no stdin")
+        .short("n")
+        .long("no-stdin")
+        .alias("stdin-null")
+        .help(
+            "Don't read stdin: give the remote command an empty stdin, as if from /dev/null, and don't open a pty even on a \
+             terminal. Like ssh -n, for commands run in the background.",
+        );
+    let keepalive_unanswered_action = Arg::with_name("keepalive unanswered action")
+        .long("keepalive-unanswered-action")
+        .takes_value(true)
+        .possible_values(&["disconnect", "log"])
+        .default_value("disconnect")
+        .help(
+            "What to do when the peer hasn't answered --keepalive-count keepalives in a row. disconnect: give up on the \
+             connection. log: warn, and keep waiting for it to come back.",
+        );
+    let keepalive_interval = Arg::with_name("keepalive interval")
+        .long("keepalive-interval")
+        .takes_value(true)
+        .value_name("duration")
+        .default_value("60")
+        .validator(validate_duration)
+        .help(
+            "How often to send the peer a keepalive, in seconds or with a suffix like 5m, to keep NAT gateways from dropping an idle \
+             connection and to notice one that's gone. 0 sends none. Each end uses its own setting.",
+        );
+    let keepalive_count = Arg::with_name("keepalive count")
+        .long("keepalive-count")
+        .takes_value(true)
+        .default_value("3")
+        .validator(validate_count)
+        .help("How many keepalives in a row may go unanswered before --keepalive-unanswered-action applies");
+    let protocol_trace = Arg::with_name("protocol trace")
+        .long("protocol-trace")
+        .takes_value(true)
+        .value_name("file")
+        .help(
+            "Append a hex dump of every protocol message sent and received, after decryption, to this file, for debugging. It \
+             holds everything sent over the connection, passwords included.",
+        );
+    let secure_delete = Arg::with_name("secure delete")
+        .long("secure-delete")
+        .help("Overwrite temporary files, such as the xauth cookie file and the session env file, before removing them");
+    let version_string = Arg::with_name("version string")
+        .long("version-string")
+        .alias("client-version-string")
+        .takes_value(true)
+        .validator(validate_version_string)
+        .help("Identify to the peer with this string instead of oxy and its version. Up to 255 printable ASCII characters.");
+    let output_prefix = Arg::with_name("output prefix")
+        .long("output-prefix")
+        .takes_value(true)
+        .value_name("template")
+        .help(
+            "Start each line of a command's output with this, after replacing %h with the host and %c with the channel the \
+             command runs on. Partial lines are held back until they're complete.",
+        );
+    let no_terminal_reset = Arg::with_name("no terminal reset")
+        .long("no-terminal-reset")
+        .help("Don't restore terminal attributes or send a terminal reset sequence when the client exits");
+    let no_command = Arg::with_name("no command")
+        .short("N")
+        .long("no-command")
+        .alias("tunnel-only")
+        .conflicts_with_all(&["command", "watch", "stdio"])
+        .help("Don't run a shell or command on the server. Only set up the forwards, then stay connected until interrupted.");
+    let roam = Arg::with_name("roam")
+        .long("roam")
+        .alias("kex-rekey-on-address-change")
+        .requires("no command")
+        .conflicts_with_all(&["via", "proxy command", "detach after auth"])
+        .help(
+            "With -N, reconnect as soon as the local address used to reach the server changes, as when switching networks, and \
+             whenever the session ends. Connections open through forwards are lost, but the forwards come back.",
+        );
+    let detach_after_auth = Arg::with_name("detach after auth")
+        .short("f")
+        .long("detach-after-auth")
+        .alias("background")
+        .conflicts_with_all(&["command", "watch", "stdio", "hosts"])
+        .help(
+            "Like --no-command, but once authenticated and the forwards are set up, go to the background and give the shell \
+             back. Errors before then are reported in the foreground.",
+        );
+    let no_shell = Arg::with_name("no shell")
+        .long("no-shell")
+        .alias("no-remote-command-shell")
+        .help(
+            "Have the server run the command directly, split into arguments by oxy, instead of through /bin/sh -c. Quotes and \
+             backslashes work as in the shell, but nothing is expanded.",
+        );
+    let clear_env = Arg::with_name("clear env")
+        .long("clear-env")
+        .alias("command-environment-clear")
+        .help(
+            "Have the server run the command with an empty environment, apart from PATH, HOME and TERM, variables this client \
+             sends such as the locale, and oxy's own session variables.",
+        );
+    let login_shell = Arg::with_name("login shell")
+        .long("login-shell")
+        .alias("remote-exec-as-login-shell")
+        .conflicts_with("no shell")
+        .help(
+            "Have the server run the command through /bin/sh -lc instead of /bin/sh -c, so the login shell reads your profile \
+             first and the command sees the same PATH and environment as an interactive session.",
+        );
+    let pty_size = Arg::with_name("pty size")
+        .long("pty-size")
+        .takes_value(true)
+        .value_name("colsxrows")
+        .validator(validate_pty_size)
+        .help("The size to ask for a pty of, like 120x40, when there's no terminal to take one from. Without it, 80x24 is used.");
+    let pty_output_limit = Arg::with_name("pty output limit")
+        .long("pty-output-limit")
+        .takes_value(true)
+        .value_name("rate")
+        .validator(validate_size)
+        .help(
+            "Write remote terminal output at most this many bytes a second (e.g. 64K). Output beyond that is buffered, and once a \
+             megabyte is waiting the server stops reading from the remote program until it drains.",
+        );
+    let pty_flood_threshold = Arg::with_name("pty flood threshold")
+        .long("pty-flood-threshold")
+        .takes_value(true)
+        .value_name("size")
+        .validator(validate_size)
+        .help(
+            "Pause remote terminal output when more than this much (e.g. 10M) arrives within a second, and wait for Ctrl-Q to \
+             resume it or Ctrl-O to drop what was held. Keys typed meanwhile still reach the remote program.",
+        );
+    let print_connection_string = Arg::with_name("print connection string")
+        .long("print-connection-string")
+        .help(
+            "Print an oxy client command line that reproduces this invocation, and the destination's client config entry if it has \
+             one, with secrets redacted. Then exit without connecting.",
+        );
+    let handshake_timeout = Arg::with_name("handshake timeout")
+        .long("handshake-timeout")
+        .alias("server-greeting-timeout")
+        .takes_value(true)
+        .validator(validate_duration)
+        .help(
+            "Give up if the server hasn't completed the handshake this long (e.g. 5s) after connecting, which usually means the \
+             port isn't an oxy server",
+        );
+    let login_grace_time = Arg::with_name("login grace time")
+        .long("login-grace-time")
+        .takes_value(true)
+        .validator(validate_duration)
+        .default_value("2m")
+        .help("Close connections that haven't authenticated this long after connecting. 0 waits forever.");
+    let suspend_timeout = Arg::with_name("suspend timeout")
+        .long("suspend-timeout")
+        .takes_value(true)
+        .validator(validate_duration)
+        .default_value("12h")
+        .help("End sessions whose client suspended them and hasn't resumed within this long. 0 waits forever.");
+    let tcp_user_timeout = Arg::with_name("tcp user timeout")
+        .long("tcp-user-timeout")
+        .takes_value(true)
+        .validator(validate_duration)
+        .help(
+            "Have the kernel drop the connection when sent data goes unacknowledged this long (e.g. 30s), via TCP_USER_TIMEOUT. \
+             Linux only.",
+        );
+    let priority = Arg::with_name("priority")
+        .long("priority")
+        .takes_value(true)
+        .possible_values(&["interactive", "fair", "bulk"])
+        .default_value("fair")
+        .help(
+            "What to favor when a session's pty shares the connection with transfers or forwards. interactive keeps little \
+             data queued in the kernel and uses small packets, so typing stays responsive while the link is saturated. bulk \
+             uses large packets for throughput. Both also pick a --dscp mark to match, unless one is given.",
+        );
+    let dscp = Arg::with_name("dscp")
+        .long("dscp")
+        .alias("ip-qos")
+        .takes_value(true)
+        .validator(validate_tos)
+        .help(
+            "Mark the connection's packets for the network's QoS: a DSCP name like ef, af21 or cs1, lowdelay, throughput, a \
+             type-of-service byte, or none. Defaults to lowdelay for client sessions and throughput for copy.",
+        );
+    let forward_locale = Arg::with_name("forward locale")
+        .long("forward-locale")
+        .alias("remote-env-passthrough")
+        .takes_value(true)
+        .possible_values(&["auto", "yes", "no"])
+        .default_value("auto")
+        .help("Whether to send LANG and LC_* to the server, so remote programs use our locale. auto only sends them for a pty session.");
+    let command_timeout = Arg::with_name("command timeout")
+        .long("command-timeout")
+        .takes_value(true)
+        .validator(validate_duration)
+        .help(
+            "Terminate a non-interactive remote command that runs longer than this (e.g. 30s, 5m, 2h), then exit with status 124. The \
+             command gets SIGTERM, then SIGKILL if it is still running 5 seconds later.",
+        );
+    let test_connection = Arg::with_name("test connection")
+        .long("test-connection")
+        .conflicts_with_all(&["command", "stdio", "watch"])
+        .help(
+            "Connect and authenticate, then exit without starting a shell, command, or forwards. Exits with 0 on success, 2 if the \
+             server couldn't be reached, or 3 if authentication or the host key check failed.",
+        );
+    let watch = Arg::with_name("watch")
+        .long("watch")
+        .takes_value(true)
+        .value_name("interval")
+        .validator(validate_duration)
+        .requires("command")
+        .conflicts_with("stdio")
+        .help(
+            "Run the command, then run it again this long (e.g. 2s, 1m) after each time it exits, like watch(1). Every run reuses the \
+             same session. The command gets no stdin and no pty.",
+        );
+    let watch_clear = Arg::with_name("watch clear")
+        .long("watch-clear")
+        .requires("watch")
+        .help("Clear the screen before each run of the --watch command");
+    let record = Arg::with_name("record")
+        .long("record")
+        .takes_value(true)
+        .value_name("file")
+        .help("Record the session's terminal output to this file in asciicast v2 format, for replay with 'oxy playback'.");
+    let events_fd = Arg::with_name("events fd")
+        .long("events-fd")
+        .takes_value(true)
+        .value_name("fd")
+        .validator(validate_count)
+        .help("Write JSON lines describing the session's events (connected, authenticated, forwards, transfers, disconnected) to this descriptor.");
+    let events_file = Arg::with_name("events file")
+        .long("events-file")
+        .takes_value(true)
+        .value_name("path")
+        .conflicts_with("events fd")
+        .help("Like --events-fd, but append the events to this file.");
+    let output_file = Arg::with_name("output file")
+        .long("output-file")
+        .takes_value(true)
+        .value_name("path")
+        .help("Also save the remote command's output, or the pty's, to this local file. Stderr goes here too, unless --output-file-stderr is given.");
+    let output_file_stderr = Arg::with_name("output file stderr")
+        .long("output-file-stderr")
+        .takes_value(true)
+        .value_name("path")
+        .help("Also save the remote command's stderr to this local file.");
+    let output_file_append = Arg::with_name("output file append")
+        .long("append")
+        .help("Append to --output-file and --output-file-stderr instead of overwriting them.");
+    let bind_interface = Arg::with_name("bind interface")
+        .long("bind-interface")
+        .takes_value(true)
+        .value_name("name")
+        .help(
+            "Bind sockets to this network interface with SO_BINDTODEVICE, so that traffic is routed through it. Applies to outbound \
+             connections for the client and listeners for the server. Linux only, and usually requires CAP_NET_RAW.",
+        );
+    let knock_ports = Arg::with_name("knock ports")
+        .long("knock-ports")
+        .takes_value(true)
+        .value_name("port,port,...")
+        .validator(validate_knock_ports)
+        .help(
+            "Knock on these UDP ports, in this order, instead of the single port derived from the identity. The client and server \
+             must agree.",
+        );
+    let knock_sequence = Arg::with_name("knock sequence")
+        .long("knock-sequence")
+        .takes_value(true)
+        .value_name("ports|secret")
+        .conflicts_with("knock ports")
+        .validator(validate_knock_sequence)
+        .help(
+            "Knock on a sequence of UDP ports of your own, instead of the single port derived from the identity: either a list \
+             like 7000,8000,9000, as for --knock-ports, or a secret that a sequence of four ports is derived from. The client and \
+             server must agree.",
+        );
+    let no_knock = Arg::with_name("no knock")
+        .long("no-knock")
+        .alias("disable-knock")
+        .conflicts_with_all(&["knock ports", "knock sequence", "knock secret"])
+        .help(
+            "Skip port knocking, for networks that interfere with it. The server then accepts TCP connections from anyone, and \
+             the client connects without knocking. Both ends must agree: a client that skips the knock can't connect to a server \
+             that expects one.",
+        );
+    let hold = Arg::with_name("hold").long("hold").alias("reconnect");
+    let max_reconnects = Arg::with_name("max reconnects")
+        .long("max-reconnects")
+        .takes_value(true)
+        .value_name("count")
+        .validator(validate_count)
+        .requires("hold")
+        .help("With --hold, stop after this many reconnects. Failed attempts count too.");
+    let knock_secret = Arg::with_name("knock secret")
+        .long("knock-secret")
+        .takes_value(true)
+        .value_name("secret")
+        .help(
+            "Add a timestamped HMAC under this shared secret to each knock. The server then ignores knocks without a fresh proof and \
+             knocks it has already seen. The client and server must agree. This is a cheap gate in front of the TCP port, not a \
+             substitute for authentication.",
+        );
+    let obfuscate = Arg::with_name("obfuscate")
+        .long("obfuscate")
+        .takes_value(true)
+        .value_name("method")
+        .possible_values(&["xor"])
+        .requires("obfuscate secret")
+        .help(
+            "Disguise the connection, for networks that block oxy by its fingerprint. xor: XOR everything with a keystream under \
+             --obfuscate-secret, so even the handshake looks like random bytes. Both ends must use the same method and secret. \
+             This hides what the traffic is, not what it says, which oxy's own encryption already protects.",
+        );
+    let obfuscate_secret = Arg::with_name("obfuscate secret")
+        .long("obfuscate-secret")
+        .takes_value(true)
+        .value_name("secret")
+        .requires("obfuscate")
+        .env("OXY_OBFUSCATE_SECRET")
+        .help("The shared secret --obfuscate keys the connection's disguise with");
+    let server_name = Arg::with_name("server name")
+        .long("server-name")
+        .alias("server-name-indication")
+        .takes_value(true)
+        .value_name("name")
+        .help(
+            "Ask the server for this --virtual-server before authenticating, for servers sharing one port. Servers without \
+             virtual servers reject the connection.",
+        );
+    let virtual_server = Arg::with_name("virtual server")
+        .long("virtual-server")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("name:config")
+        .validator(validate_virtual_server)
+        .help(
+            "Serve clients that ask for this --server-name with the server config at this path instead of the usual one. May be \
+             repeated. Clients that don't send a name get the usual config.",
+        );
+    let knock_window = Arg::with_name("knock window")
+        .long("knock-window")
+        .takes_value(true)
+        .value_name("duration")
+        .validator(validate_duration)
+        .help("How long a client has to complete the --knock-ports sequence, from its first knock. Defaults to 10s.");
+    let knock_timeout = Arg::with_name("knock timeout")
+        .long("knock-timeout")
+        .takes_value(true)
+        .value_name("duration")
+        .validator(validate_duration)
+        .help("How long a completed knock lets its source address connect. Defaults to 50s.");
+    let first_hop = Arg::with_name("first hop")
+        .long("first-hop")
+        .takes_value(true)
+        .value_name("addr[:port]")
+        .help(
+            "Dial this address for the first connection (the last --via hop, or the destination without --via), while still \
+             looking up its keys and config by name. The port defaults to the hop's usual port.",
+        );
+    let resolve = Arg::with_name("resolve")
+        .long("resolve")
+        .takes_value(true)
+        .value_name("host:port:addr")
+        .multiple(true)
+        .number_of_values(1)
+        .validator(validate_resolve)
+        .help(
+            "Connect to addr (or a comma-separated list of addresses) instead of resolving host when connecting to port on it, \
+             like curl's --resolve. May be repeated.",
+        );
+    let no_resolve = Arg::with_name("no resolve")
+        .long("no-resolve")
+        .help("Never look up server names. Destinations must be IP addresses or have a --resolve override.");
+    let happy_eyeballs = Arg::with_name("happy eyeballs")
+        .long("happy-eyeballs")
+        .help(
+            "When the server has both IPv6 and IPv4 addresses, race connections to them as described in RFC 8305 and use whichever \
+             connects first, instead of trying them one at a time.",
+        );
+    let hostkey_algorithms = Arg::with_name("hostkey algorithms")
+        .long("hostkey-algorithms")
+        .takes_value(true)
+        .value_name("algorithm,algorithm,...")
+        .validator(validate_hostkey_algorithms)
+        .help(
+            "Only accept server keys of these types, checked before the key itself. Defaults to every type oxy supports, which is \
+             currently just ed25519.",
+        );
+    let security_level = Arg::with_name("security level")
+        .long("security-level")
+        .takes_value(true)
+        .possible_values(&["high", "medium", "legacy"])
+        .default_value("legacy")
+        .help(
+            "Refuse a session that doesn't meet this level once the handshake is done. high requires every algorithm to be rated \
+             high and the session key to include the static key, which a server falling back to interactive auth leaves out. \
+             medium allows that fallback. legacy checks nothing.",
+        );
+    let address_family = Arg::with_name("address family")
+        .long("address-family")
+        .takes_value(true)
+        .possible_values(&["auto", "inet", "inet6"])
+        .default_value("auto")
+        .help("Only connect to the server over IPv4 (inet) or IPv6 (inet6)");
+    let known_hosts = Arg::with_name("known hosts")
+        .long("known-hosts")
+        .takes_value(true)
+        .value_name("path")
+        .help(
+            "Where to remember the host keys of servers that have no pubkey in the client config. Defaults to known_hosts in \
+             --config-dir. none reads and writes no file, so with --strict-host-key-checking=no a key is trusted for this \
+             connection only.",
+        );
+    let write_known_hosts_on = Arg::with_name("write known hosts on")
+        .long("write-known-hosts-on")
+        .takes_value(true)
+        .possible_values(&["first", "verify", "never"])
+        .help(
+            "When to add a server's key to known_hosts: on first contact (first), after confirming it at a prompt (verify), or \
+             never (the default), treating known_hosts as read-only. A key that contradicts known_hosts is refused, unless \
+             replaced at the verify prompt.",
+        );
+    let strict_host_key_checking = Arg::with_name("strict host key checking")
+        .long("strict-host-key-checking")
+        .takes_value(true)
+        .possible_values(&["yes", "no", "ask"])
+        .conflicts_with("write known hosts on")
+        .help(
+            "The ssh-style spelling of --write-known-hosts-on: yes is never, ask is verify, and no is first, trusting and \
+             recording unknown keys. A key that contradicts known_hosts is refused either way.",
+        );
+    let prefer_ipv4 = Arg::with_name("prefer ipv4")
+        .long("prefer-ipv4")
+        .conflicts_with("prefer ipv6")
+        .help("Try the server's IPv4 addresses before its IPv6 ones, still falling back to IPv6");
+    let prefer_ipv6 = Arg::with_name("prefer ipv6")
+        .long("prefer-ipv6")
+        .help("Try the server's IPv6 addresses before its IPv4 ones, still falling back to IPv4");
+    let interactive_auth = Arg::with_name("interactive auth")
+        .long("interactive-auth")
+        .takes_value(true)
+        .possible_values(&["yes", "no", "auto"])
+        .default_value("auto")
+        .help(
+            "Whether to answer the server's login prompts, such as for a one-time code or a PAM password. auto only answers when run \
+             from a terminal.",
+        );
+    let interactive_auth_command = Arg::with_name("interactive auth command")
+        .long("interactive-auth-command")
+        .takes_value(true)
+        .help(
+            "Instead of rejecting clients with unknown keys, prompt them and run this command to check their answer. The answer is \
+             given on stdin and the client's key in OXY_CLIENT_KEY, and exiting 0 accepts it. Clients get 3 tries.",
+        );
+    let interactive_auth_prompt = Arg::with_name("interactive auth prompt")
+        .long("interactive-auth-prompt")
+        .takes_value(true)
+        .requires("interactive auth command")
+        .help("The prompt shown to clients for --interactive-auth-command. Defaults to \"Verification code: \".");
+    let auth = Arg::with_name("auth")
+        .long("auth")
+        .takes_value(true)
+        .possible_values(&["key", "pam", "key+pam"])
+        .default_value("key")
+        .validator(validate_auth)
+        .help(
+            "How clients log in. key: by their key alone. pam: clients with unknown keys are prompted to log in through PAM as the \
+             user the session runs as. key+pam: clients need a known key and must also pass PAM. PAM needs oxy built with the pam \
+             feature.",
+        );
+    let pam_service = Arg::with_name("pam service")
+        .long("pam-service")
+        .takes_value(true)
+        .default_value("oxy")
+        .help("The PAM service used by --auth, which picks the /etc/pam.d file that configures it");
+    let umask = Arg::with_name("umask")
+        .long("umask")
+        .takes_value(true)
+        .value_name("octal")
+        .validator(validate_umask)
+        .help("Set the umask of each session, for uploaded files and everything it runs, e.g. 022 or 077");
+    let limit_mem = Arg::with_name("limit mem")
+        .long("limit-mem")
+        .takes_value(true)
+        .value_name("size")
+        .validator(validate_size)
+        .help("Limit each session's processes to this much address space (RLIMIT_AS), e.g. 512M or 2G");
+    let limit_cpu = Arg::with_name("limit cpu")
+        .long("limit-cpu")
+        .takes_value(true)
+        .value_name("duration")
+        .validator(validate_duration)
+        .help("Limit each of a session's processes to this much CPU time (RLIMIT_CPU), rounded down to whole seconds");
+    let limit_files = Arg::with_name("limit files")
+        .long("limit-files")
+        .takes_value(true)
+        .value_name("count")
+        .validator(validate_count)
+        .help("Limit each of a session's processes to this many open files (RLIMIT_NOFILE)");
+    let limit_procs = Arg::with_name("limit procs")
+        .long("limit-procs")
+        .takes_value(true)
+        .value_name("count")
+        .validator(validate_count)
+        .help("Limit the number of processes the session's user may have (RLIMIT_NPROC)");
+    let forward_idle_timeout = Arg::with_name("forward idle timeout")
+        .long("forward-idle-timeout")
+        .takes_value(true)
+        .value_name("duration")
+        .validator(validate_duration)
+        .help(
+            "Close a forwarded connection, on both ends, when no data has passed through it in either direction for this long. Off \
+             by default.",
+        );
+    let proxy_protocol = Arg::with_name("proxy protocol")
+        .long("proxy-protocol")
+        .takes_value(true)
+        .possible_values(&["v1", "v2"])
+        .help(
+            "Start each connection through a -L or -R forward with a PROXY protocol header of this version, carrying the address \
+             it came from, for backends behind HAProxy or nginx. A forward's own proxy-protocol option overrides this.",
+        );
+    let connection_attempts = Arg::with_name("connection attempts")
+        .long("connection-attempts")
+        .takes_value(true)
+        .value_name("count")
+        .validator(validate_count)
+        .help("Try connecting to the server this many times, a second apart, before giving up. Knocks are sent again for each attempt.");
+    let max_connections_per_forward = Arg::with_name("max connections per forward")
+        .long("max-connections-per-forward")
+        .takes_value(true)
+        .value_name("count")
+        .validator(validate_count)
+        .help(
+            "Refuse new connections through any one -L, -R or -D forward while this many are open through it. A forward's own \
+             --max-connections metacommand option overrides this. Unlimited by default.",
+        );
+    let max_auth_tries = Arg::with_name("max auth tries")
+        .long("max-auth-tries")
+        .takes_value(true)
+        .validator(validate_count)
+        .help(
+            "Failed authentications allowed from one source address before its knocks are ignored for 10 minutes. Each connection \
+             gets one attempt, and the wait between attempts doubles after every failure. Defaults to 6.",
+        );
+    let max_startups = Arg::with_name("max startups")
+        .long("max-startups")
+        .alias("max-startup-backlog")
+        .takes_value(true)
+        .value_name("start:rate:full")
+        .validator(validate_max_startups)
+        .help(
+            "Limit connections that haven't finished authenticating yet, like sshd's MaxStartups. Once start are pending, new \
+             connections are dropped with probability rate%, rising linearly to 100% at full. A single number drops every \
+             connection past it.",
+        );
+    let session_env_file = Arg::with_name("session env file")
+        .long("session-env-file")
+        .takes_value(true)
+        .value_name("path template")
+        .help(
+            "Write a file describing each session, in shell syntax: its ID, the client address, key and name, and remote forwards. %s \
+             in the path is replaced by the session ID. Commands run in the session find it through OXY_SESSION_ENV_FILE. Removed \
+             when the session ends.",
+        );
+    let audit_log = Arg::with_name("audit log")
+        .long("audit-log")
+        .takes_value(true)
+        .help(
+            "Append one JSON record per connection to this file, covering the source address, authenticated key, commands, forwards, \
+             transfers, times, and bytes transferred",
+        );
+    let motd_command = Arg::with_name("motd command")
+        .long("motd-command")
+        .takes_value(true)
+        .help("Run this command when an interactive session starts, and show its output to the client before the shell");
+    let last_login = Arg::with_name("last login")
+        .long("last-login")
+        .help("Tell interactive clients when their key last logged in. Tracked in lastlog in --config-dir");
+    let stdio = Arg::with_name("stdio")
+        .long("stdio")
+        .takes_value(true)
+        .conflicts_with("command")
+        .help(
+            "Open a connection to <host:port> from the server and bridge it to stdin and stdout, doing nothing else. Useful as a \
+             ProxyCommand for other tools.",
+        );
+    let permit_open = Arg::with_name("permit open")
+        .long("permit-open")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .help("Only allow clients to open forwarded connections to destinations matching this host:port pattern. May be repeated.");
+    let accept_env = Arg::with_name("accept env")
+        .long("accept-env")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .help("Accept environment variables from clients whose names match this pattern, in addition to TERM, LANG and LC_*. May be repeated.");
+    let no_host_check_for_localhost = Arg::with_name("no host check for localhost")
+        .long("no-host-check-for-localhost")
+        .help("Skip verifying the server's key when the destination resolves only to loopback addresses, such as a forwarded port");
+    let ephemeral_identity = Arg::with_name("ephemeral identity")
+        .long("ephemeral-identity")
+        .help("Generate a throwaway identity at startup and print it, ignoring the server config. Nothing is written to disk.");
     let client_args = vec![
         metacommand.clone(),
+        protocol_trace.clone(),
+        secure_delete.clone(),
+        keepalive_unanswered_action.clone(),
+        keepalive_interval.clone(),
+        keepalive_count.clone(),
+        output_prefix,
+        version_string.clone(),
         identity.clone(),
+        identity_from_stdin.clone(),
+        identity_passphrase_env.clone(),
         l_portfwd,
         r_portfwd,
         d_portfwd,
+        socks_auth,
         port.clone(),
         xforward,
         trusted_xforward,
+        x_cookie_timeout,
+        config_dir.clone(),
         server_config.clone(),
         client_config.clone(),
+        client_config_url.clone(),
+        config_cache.clone(),
+        allow_stale_config.clone(),
+        strict_config.clone(),
         user,
         via,
+        jump_timeout,
+        handshake_timeout,
+        forward_locale,
+        tcp_user_timeout.clone(),
+        priority.clone(),
+        dscp.clone(),
+        proxy_command,
         compression.clone(),
+        compress_level.clone(),
+        channel_window.clone(),
+        channel_max_packet.clone(),
+        tun,
+        tap,
+        tunnel_mtu.clone(),
+        no_terminal_reset,
+        command_timeout,
+        forward_idle_timeout.clone(),
+        max_connections_per_forward,
+        connection_attempts,
+        proxy_protocol,
+        no_command,
+        detach_after_auth,
+        roam,
+        no_shell,
+        login_shell,
+        remote_shell,
+        working_directory,
+        clear_env,
+        stdin_eof_action,
+        on_hup,
+        no_stdin,
+        pty_size,
+        pty_output_limit,
+        pty_flood_threshold,
+        print_connection_string,
+        test_connection,
+        watch,
+        watch_clear,
+        stdio,
+        no_host_check_for_localhost,
+        record,
+        output_file,
+        output_file_stderr,
+        output_file_append,
+        events_fd,
+        events_file,
+        log_filter.clone(),
+        bind_interface.clone(),
+        first_hop,
+        knock_ports.clone(),
+        knock_sequence.clone(),
+        knock_secret.clone(),
+        no_knock.clone(),
+        // The relay threads don't survive going to the background.
+        obfuscate.clone().conflicts_with("detach after auth"),
+        obfuscate_secret.clone(),
+        server_name,
+        resolve,
+        no_resolve,
+        happy_eyeballs,
+        address_family,
+        prefer_ipv4,
+        prefer_ipv6,
+        known_hosts,
+        write_known_hosts_on,
+        strict_host_key_checking,
+        hostkey_algorithms,
+        security_level,
+        interactive_auth,
         verbose.clone(),
+        log_format.clone(),
         command,
     ];
     let server_args = vec![
+        config_dir.clone(),
         server_config.clone(),
         client_config.clone(),
+        server_config_url,
+        config_cache.clone(),
+        allow_stale_config.clone(),
+        strict_config.clone(),
         forced_command,
         identity.clone(),
+        identity_from_stdin.clone(),
+        identity_passphrase_env.clone(),
         port.clone(),
+        channel_window.clone(),
+        channel_max_packet.clone(),
+        tunnel_mtu,
+        permit_tunnel,
+        allow_shell_override,
+        no_x_forwarding,
+        no_port_forwarding,
+        no_pty,
+        capabilities,
+        interactive_allow,
+        keep_fds,
+        gateway_ports,
+        protocol_trace,
+        secure_delete,
+        version_string,
+        keepalive_unanswered_action,
+        keepalive_interval,
+        keepalive_count,
+        audit_log,
+        knock_ports,
+        knock_sequence,
+        knock_secret,
+        knock_window,
+        knock_timeout,
+        obfuscate,
+        obfuscate_secret,
+        no_knock,
+        virtual_server,
+        session_env_file,
+        max_auth_tries,
+        max_startups,
+        umask,
+        limit_mem,
+        limit_cpu,
+        limit_files,
+        limit_procs,
+        interactive_auth_command,
+        interactive_auth_prompt,
+        auth,
+        pam_service,
+        su_mode,
+        authorized_identities,
+        motd_command,
+        last_login,
+        permit_open,
+        forward_idle_timeout,
+        login_grace_time,
+        suspend_timeout,
+        x_display_offset,
+        tcp_user_timeout.clone(),
+        priority.clone(),
+        dscp.clone(),
+        accept_env,
+        log_filter.clone(),
+        ephemeral_identity,
+        bind_interface,
         verbose.clone(),
+        log_format.clone(),
     ];
 
     let subcommands = vec![
         SubCommand::with_name("client")
             .about("Connect to an Oxy server.")
             .args(&client_args)
-            .arg(Arg::with_name("destination").index(1).required(true)),
+            .arg(Arg::with_name("destination").index(1).required_unless("hosts"))
+            .arg(
+                Arg::with_name("hosts")
+                    .long("hosts")
+                    .takes_value(true)
+                    .value_name("list")
+                    .conflicts_with_all(&["via", "stdio", "print connection string"])
+                    .help(
+                        "Run the command on each of these comma-separated hosts, or the hosts listed one per line in @file, \
+                         instead of connecting to a destination. Output lines are prefixed with their host unless --output-prefix says \
+                         otherwise.",
+                    ),
+            )
+            .arg(
+                Arg::with_name("parallel")
+                    .long("parallel")
+                    .takes_value(true)
+                    .value_name("count")
+                    .default_value("10")
+                    .validator(validate_count)
+                    .help("With --hosts, how many hosts to run the command on at once"),
+            )
+            .arg(
+                Arg::with_name("scan key")
+                    .long("scan-key")
+                    .conflicts_with_all(&["hosts", "via"])
+                    .help("Print the server's public key as a known_hosts line once it arrives, and exit without going further. See keyscan."),
+            ),
         SubCommand::with_name("reexec")
             .about("Service a single oxy connection. Not intended to be run directly, run by oxy server")
             .setting(AppSettings::Hidden)
             .arg(Arg::with_name("fd").long("fd").takes_value(true).required(true))
+            .arg(Arg::with_name("auth notify fd").long("auth-notify-fd").takes_value(true))
             .args(&server_args),
         SubCommand::with_name("server")
             .about("Listen for port knocks, accept TCP connections, then reexec for each one.")
             .args(&server_args)
-            .arg(unsafe_reexec),
+            .arg(unsafe_reexec)
+            .arg(Arg::with_name("reexec path").long("reexec-path").takes_value(true).value_name("path").help(
+                "The oxy binary to run for each connection, instead of this one. A symlink is followed afresh for each \
+                 connection, so swapping it upgrades new connections only. It must pass the same location check as this \
+                 binary, unless --unsafe-reexec is given.",
+            )),
         SubCommand::with_name("serve-one")
             .about("Accept a single TCP connection, then service it in the same process.")
+            .after_help(
+                "EXIT STATUS:\n    0    The session completed cleanly\n    1    Internal failure\n    2    Connection error or keepalive \
+                 timeout\n    3    Authentication rejected",
+            )
             .args(&server_args)
-            .arg(Arg::with_name("bind-address").index(1).default_value("::0")),
+            .arg(
+                Arg::with_name("inherit fd")
+                    .long("inherit-fd")
+                    .takes_value(true)
+                    .value_name("fd")
+                    .validator(validate_fd)
+                    .help("Service an already-connected socket passed as this file descriptor, instead of binding. For inetd-style launchers."),
+            )
+            .arg(
+                Arg::with_name("socket activation")
+                    .long("socket-activation")
+                    .conflicts_with("inherit fd")
+                    .help("Service the connected socket passed by systemd socket activation (Accept=yes), instead of binding."),
+            )
+            .arg(bind_address.clone()),
         SubCommand::with_name("reverse-server")
             .about("Connect out to a listening client. Then, be a server.")
             .args(&server_args)
+            .arg(hold.clone().help("When the session ends, dial the client again, backing off up to --retry-max-delay while it can't be reached."))
+            .arg(max_reconnects.clone())
+            .arg(
+                Arg::with_name("connect retries")
+                    .long("connect-retries")
+                    .takes_value(true)
+                    .value_name("count")
+                    .validator(validate_count)
+                    .help("If the client can't be reached, try again this many times, backing off between attempts."),
+            )
+            .arg(
+                Arg::with_name("retry delay")
+                    .long("retry-delay")
+                    .takes_value(true)
+                    .value_name("duration")
+                    .validator(validate_duration)
+                    .default_value("1s")
+                    .help("How long to wait before the first retry. Each retry after waits twice as long."),
+            )
+            .arg(
+                Arg::with_name("retry max delay")
+                    .long("retry-max-delay")
+                    .takes_value(true)
+                    .value_name("duration")
+                    .validator(validate_duration)
+                    .default_value("1m")
+                    .help("The longest to wait between retries."),
+            )
             .arg(Arg::with_name("destination").index(1).required(true)),
         SubCommand::with_name("reverse-client")
             .about("Bind a port and wait for a server to connect. Then, be a client.")
             .args(&client_args)
-            .arg(Arg::with_name("bind-address").index(1).default_value("::0")),
+            .arg(hold.help("When the session ends, keep the port bound and wait for the server to connect again."))
+            .arg(max_reconnects)
+            .arg(bind_address.clone()),
+        SubCommand::with_name("bench")
+            .about("Diagnostic: measure round-trip latency and throughput to a server.")
+            .arg(config_dir.clone())
+            .arg(client_config.clone())
+            .arg(server_config.clone())
+            .arg(compression.clone())
+            .arg(compress_level.clone())
+            .arg(channel_window.clone())
+            .arg(channel_max_packet.clone())
+            .arg(identity.clone())
+            .arg(identity_from_stdin.clone())
+            .arg(identity_passphrase_env.clone())
+            .arg(log_filter.clone())
+            .arg(verbose.clone())
+            .arg(log_format.clone())
+            .arg(Arg::with_name("destination").index(1).required(true))
+            .arg(
+                Arg::with_name("rounds")
+                    .long("rounds")
+                    .takes_value(true)
+                    .default_value("20")
+                    .validator(validate_count)
+                    .help("How many round trips to time"),
+            )
+            .arg(
+                Arg::with_name("size")
+                    .long("size")
+                    .takes_value(true)
+                    .default_value("64M")
+                    .validator(validate_size)
+                    .help("How much data to upload, and then download, to measure throughput"),
+            ),
         SubCommand::with_name("copy")
             .about("Copy files from any number of sources to one destination.")
-            .arg(client_config)
+            .arg(config_dir.clone())
+            .arg(client_config.clone())
             .arg(server_config)
+            .arg(client_config_url)
+            .arg(config_cache)
+            .arg(allow_stale_config)
+            .arg(strict_config)
             .arg(compression)
-            .arg(Arg::with_name("location").index(1).multiple(true).number_of_values(1))
+            .arg(compress_level)
+            .arg(channel_window)
+            .arg(channel_max_packet)
+            .arg(tcp_user_timeout)
+            .arg(dscp)
+            .arg(
+                Arg::with_name("location")
+                    .index(1)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Sources, then the destination. Each is a local path or host:path, or - for stdin or stdout."),
+            )
+            .arg(
+                Arg::with_name("skip existing")
+                    .long("skip-existing")
+                    .conflicts_with_all(&["update", "checksum", "archive"])
+                    .help("Don't copy files that already exist at the destination"),
+            )
+            .arg(Arg::with_name("update").long("update").conflicts_with_all(&["checksum", "archive"]).help(
+                "Don't copy files whose destination is at least as new as the source. When an older destination exists, compare it \
+                 with the source in 64K blocks and only transfer the blocks that differ.",
+            ))
+            .arg(
+                Arg::with_name("checksum")
+                    .long("checksum")
+                    .conflicts_with("archive")
+                    .help("Don't copy files whose destination has the same size and SHA-256 digest as the source"),
+            )
+            .arg(
+                Arg::with_name("follow")
+                    .long("follow")
+                    .conflicts_with_all(&["update", "checksum", "skip existing", "archive"])
+                    .help(
+                        "Like tail -f: write data appended to one remote file to stdout as it's written, until interrupted. Keeps \
+                         following across log rotation.",
+                    ),
+            )
+            .arg(
+                Arg::with_name("streams")
+                    .long("streams")
+                    .takes_value(true)
+                    .value_name("count")
+                    .validator(validate_count)
+                    .conflicts_with_all(&["follow", "archive"])
+                    .help(
+                        "Download each large remote file as this many ranges at once, to get more out of links where one \
+                         transfer at a time can't fill the pipe. Each range is at least a megabyte.",
+                    ),
+            )
+            .arg(
+                Arg::with_name("recursive")
+                    .short("r")
+                    .long("recursive")
+                    .conflicts_with_all(&["follow", "archive"])
+                    .help(
+                        "Copy directories: walk each source that's a directory and recreate it at the destination. Symlinks are \
+                         copied as symlinks.",
+                    ),
+            )
+            .arg(
+                Arg::with_name("dereference")
+                    .long("dereference")
+                    .requires("recursive")
+                    .help(
+                        "With -r, copy what symlinks point to instead of the links themselves. A link back to a directory that's \
+                         already being copied is skipped.",
+                    ),
+            )
+            .arg(Arg::with_name("archive").long("archive").help(
+                "Stream each source as one tar archive and unpack it into the destination directory, instead of copying files one \
+                 at a time. Requires tar on both ends.",
+            ))
+            .arg(
+                Arg::with_name("verify")
+                    .long("verify")
+                    .takes_value(true)
+                    .min_values(0)
+                    .max_values(1)
+                    .require_equals(true)
+                    .possible_values(&["sha1", "sha256", "sha512"])
+                    .conflicts_with_all(&["follow", "archive"])
+                    .help(
+                        "After copying each file, compare its digest with the source's, sha256 unless another algorithm is given. A \
+                         file that doesn't match is copied once more, and copy exits with an error if it still doesn't match.",
+                    ),
+            )
+            .arg(
+                Arg::with_name("remote to remote direct")
+                    .long("remote-to-remote-direct")
+                    .alias("direct")
+                    .conflicts_with_all(&["follow", "archive"])
+                    .help(
+                        "When copying from one server to another, have the source server run oxy copy to send the files to the \
+                         destination itself, instead of relaying them through this machine. The source server needs oxy in its PATH \
+                         and a client config that reaches the destination by the name given here. The other copy options are \
+                         passed on to it. Sources it fails to copy are relayed instead.",
+                    ),
+            )
+            .arg(Arg::with_name("json").long("json").conflicts_with_all(&["follow", "archive", "remote to remote direct"]).help(
+                "When done, write a JSON summary of every file to stdout: its source, destination, status (transferred, skipped or \
+                 failed), bytes, seconds and --verify checksum, with totals. Progress goes to stderr instead.",
+            ))
+            .arg(
+                Arg::with_name("no compress ext")
+                    .long("no-compress-ext")
+                    .alias("disable-compression-for")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .requires("compression")
+                    .help(
+                        "Comma-separated file extensions to send uncompressed with --compress, on top of the built-in list of \
+                         compressed formats. Files that don't look compressible are always sent uncompressed.",
+                    ),
+            )
             .arg(identity.clone())
-            .arg(verbose.clone()),
-        SubCommand::with_name("guide").about("Print information to help a new user get the most out of Oxy."),
-        SubCommand::with_name("keygen").about("Generate keys"),
+            .arg(identity_from_stdin)
+            .arg(identity_passphrase_env.clone())
+            .arg(log_filter)
+            .arg(verbose.clone())
+            .arg(log_format.clone()),
+        SubCommand::with_name("guide")
+            .about("Print information to help a new user get the most out of Oxy.")
+            .arg(Arg::with_name("setup").long("setup").help(
+                "Instead, interactively generate keys, write a matching server.conf and client.conf to --config-dir, and test the \
+                 connection",
+            ))
+            .arg(config_dir.clone()),
+        SubCommand::with_name("keygen")
+            .about("Generate keys")
+            .arg(
+                Arg::with_name("import")
+                    .long("import")
+                    .takes_value(true)
+                    .value_name("openssh key")
+                    .help(
+                        "Instead, convert an OpenSSH ed25519 private key (unencrypted) or public key to oxy config values, and print \
+                         its fingerprint",
+                    ),
+            )
+            .arg(Arg::with_name("encrypt identity").long("encrypt-identity").conflicts_with("import").help(
+                "Instead, read an identity from stdin and print it encrypted under a passphrase, to save to a file for --identity @path",
+            ))
+            .arg(identity_passphrase_env),
+        SubCommand::with_name("fingerprint")
+            .about("Print the fingerprint of a key. Accepts keygen output, a config file, or a bare pubkey or identity.")
+            .arg(Arg::with_name("file").index(1).required(true).help("The key file, or - for stdin"))
+            .arg(Arg::with_name("randomart").long("randomart").help("Also draw the fingerprint as OpenSSH-style randomart"))
+            .arg(verbose.clone())
+            .arg(log_format.clone()),
+        SubCommand::with_name("keyscan")
+            .about("Print the public keys of servers as known_hosts lines, like ssh-keyscan.")
+            .long_about(
+                "Print the public keys of servers as known_hosts lines, like ssh-keyscan. \
+                 A server only shows its key to a client it accepts, so the identity used must be authorized there.",
+            )
+            .arg(Arg::with_name("hosts").index(1).multiple(true).required(true))
+            .arg(
+                Arg::with_name("timeout")
+                    .long("timeout")
+                    .takes_value(true)
+                    .validator(validate_duration)
+                    .default_value("5s")
+                    .help("Give up on a host that hasn't shown its key this long after starting"),
+            )
+            .arg(identity.clone())
+            .arg(identity_passphrase_env.clone())
+            .arg(port.clone())
+            .arg(config_dir)
+            .arg(client_config)
+            .arg(verbose.clone())
+            .arg(log_format.clone()),
+        SubCommand::with_name("playback")
+            .about("Replay a session recorded with --record, with its original timing.")
+            .arg(Arg::with_name("file").index(1).required(true))
+            .arg(
+                Arg::with_name("speed")
+                    .long("speed")
+                    .takes_value(true)
+                    .default_value("1")
+                    .validator(validate_speed)
+                    .help("Playback speed multiplier. 2 plays twice as fast."),
+            )
+            .arg(verbose.clone())
+            .arg(log_format.clone()),
     ];
     let subcommands: Vec<_> = subcommands.into_iter().map(|x| x.setting(AppSettings::UnifiedHelpMessage)).collect();
     App::new("oxy")
@@ -192,18 +1522,40 @@ crate fn batched_metacommands() -> Vec<String> {
 
 crate fn process() {
     ::lazy_static::initialize(&MATCHES);
+    // Options taking secrets from the environment have read them by now, so
+    // they're removed to keep them from the commands and shells oxy runs.
+    for var in SECRET_VARS {
+        env::remove_var(var);
+    }
     let level = match matches().occurrences_of("verbose") {
         0 => "info",
         1 => "debug",
         2 => "trace",
         _ => "trace",
     };
-    if env::var("RUST_LOG").is_err() {
+    if let Some(filter) = matches().value_of("log filter") {
+        env::set_var("RUST_LOG", filter);
+    } else if env::var("RUST_LOG").is_err() {
         env::set_var("RUST_LOG", format!("oxy={}", level));
     }
-    env_logger::try_init().ok();
+    let mut builder = env_logger::Builder::from_default_env();
+    if matches().value_of("log format") == Some("json") {
+        builder.format(|buf, record| {
+            let line = json!({
+                "timestamp": buf.timestamp().to_string(),
+                "level":     record.level().to_string(),
+                "target":    record.target(),
+                "message":   record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.try_init().ok();
 }
 
+/// Environment variables options read secrets from.
+const SECRET_VARS: &[&str] = &["OXY_IDENTITY", "OXY_OBFUSCATE_SECRET"];
+
 crate fn mode() -> String {
     MATCHES.subcommand_name().unwrap().to_string()
 }
@@ -216,13 +1568,639 @@ crate fn destination() -> String {
     MATCHES.subcommand_matches(mode()).unwrap().value_of("destination").unwrap().to_string()
 }
 
-crate fn bind_address() -> String {
-    MATCHES
-        .subcommand_matches(mode())
-        .unwrap()
-        .value_of("bind-address")
-        .unwrap_or("0.0.0.0")
-        .to_string()
+/// The --port given on the command line, if dest is the destination it was
+/// given for. It overrides the port from the destination's config entry, but
+/// not those of the hops it's reached through.
+crate fn explicit_port(dest: &str) -> Option<u16> {
+    if matches().occurrences_of("port") == 0 || matches().value_of("destination") != Some(dest) {
+        return None;
+    }
+    matches().value_of("port")?.parse().ok()
+}
+
+/// The -L, -R or -D forwards to set up: those on the command line, or if there
+/// are none of that kind, the destination's defaults from its config entry.
+crate fn port_forwards(name: &str) -> Vec<String> {
+    if let Some(values) = matches().values_of(name) {
+        return values.map(|x| x.to_string()).collect();
+    }
+    let key = match name {
+        "local port forward" => "local_forwards",
+        "remote port forward" => "remote_forwards",
+        _ => "socks",
+    };
+    match matches().value_of("destination") {
+        Some(dest) => crate::conf::forwards_for_dest(dest, key),
+        None => Vec::new(),
+    }
+}
+
+/// Where to listen. Subcommands without a bind address listen on the default,
+/// at --port.
+crate fn bind_address() -> SocketAddr {
+    let value = matches().value_of("bind-address").unwrap_or(DEFAULT_BIND_ADDRESS);
+    let port = matches().value_of("port").map(|x| x.parse().unwrap()).unwrap_or(2600);
+    parse_bind_address(value, port).unwrap()
+}
+
+/// Listening on :: also takes IPv4 connections, where the system allows it.
+const DEFAULT_BIND_ADDRESS: &str = "::";
+
+fn validate_bind_address(value: String) -> Result<(), String> {
+    parse_bind_address(&value, 0).map(|_| ())
+}
+
+/// Parses an IP address to listen on, as 0.0.0.0, ::, [::1] or with a port
+/// as 127.0.0.1:2600 or [::1]:2600. The port is used if none is given.
+fn parse_bind_address(value: &str, port: u16) -> Result<SocketAddr, String> {
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+    if value.starts_with('[') && value.ends_with(']') {
+        if let Ok(ip) = value[1..value.len() - 1].parse::<Ipv6Addr>() {
+            return Ok(SocketAddr::new(IpAddr::V6(ip), port));
+        }
+    }
+    value.parse::<SocketAddr>().map_err(|_| format!("{:?} is not an IP address to listen on", value))
+}
+
+/// The long option a client argument is given with, or its short option for
+/// the ones that don't have one.
+fn client_option(name: &str) -> String {
+    match name {
+        "local port forward" => "-L".to_string(),
+        "remote port forward" => "-R".to_string(),
+        "compression" => "--compress".to_string(),
+        _ => format!("--{}", name.to_lowercase().replace(' ', "-")),
+    }
+}
+
+/// A canonical `oxy client` command line that reproduces this invocation, for
+/// --print-connection-string. Options keep the order they were given in, with
+/// the destination and command last, and the identity is redacted.
+crate fn connection_string() -> String {
+    let mut options: Vec<_> = matches()
+        .args
+        .iter()
+        .filter(|(name, _)| !["print connection string", "destination", "command"].contains(*name))
+        .map(|(name, arg)| (arg.indices.iter().cloned().min().unwrap_or(0), *name, arg.occurs, &arg.vals))
+        .collect();
+    options.sort_by_key(|x| x.0);
+    let mut words = vec!["oxy".to_string(), "client".to_string()];
+    for (_, name, occurs, values) in options {
+        let option = client_option(name);
+        if values.is_empty() {
+            for _ in 0..occurs {
+                words.push(option.clone());
+            }
+            continue;
+        }
+        for value in values {
+            if name == "compression" {
+                // --compress only takes its value after an =.
+                words.push(format!("{}={}", option, value.to_string_lossy()));
+                continue;
+            }
+            words.push(option.clone());
+            if name == "identity" {
+                words.push("<redacted>".to_string());
+            } else {
+                words.push(::shlex::quote(&value.to_string_lossy()).into_owned());
+            }
+        }
+    }
+    words.push(::shlex::quote(&destination()).into_owned());
+    if let Some(command) = matches().value_of("command") {
+        words.push(::shlex::quote(command).into_owned());
+    }
+    words.join(" ")
+}
+
+fn validate_size(value: String) -> Result<(), String> {
+    match crate::util::parse_size(&value)? {
+        0 => Err("Size must be greater than zero".to_string()),
+        _ => Ok(()),
+    }
+}
+
+fn validate_tos(value: String) -> Result<(), String> {
+    crate::util::parse_tos(&value).map(|_| ())
+}
+
+fn validate_duration(value: String) -> Result<(), String> {
+    crate::util::parse_duration(&value).map(|_| ())
+}
+
+fn validate_speed(value: String) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(speed) if speed > 0.0 => Ok(()),
+        _ => Err(format!("Invalid speed: {:?}", value)),
+    }
+}
+
+fn validate_compress_level(value: String) -> Result<(), String> {
+    match value.parse::<i32>() {
+        Ok(level) if level >= 1 && level <= 22 => Ok(()),
+        _ => Err(format!("Expected a compression level from 1 to 22, got {:?}", value)),
+    }
+}
+
+fn validate_count(value: String) -> Result<(), String> {
+    match value.parse::<u32>() {
+        Ok(count) if count > 0 => Ok(()),
+        _ => Err(format!("Expected a positive number, got {:?}", value)),
+    }
+}
+
+fn validate_max_startups(value: String) -> Result<(), String> {
+    parse_max_startups(&value).map(|_| ())
+}
+
+/// Parses sshd's MaxStartups syntax, either "full" or "start:rate:full".
+fn parse_max_startups(value: &str) -> Result<(u32, u32, u32), String> {
+    let parts: Vec<Option<u32>> = value.split(':').map(|x| x.parse().ok()).collect();
+    match parts.as_slice() {
+        &[Some(full)] if full > 0 => Ok((full, 100, full)),
+        &[Some(start), Some(rate), Some(full)] if start > 0 && rate <= 100 && full >= start => Ok((start, rate, full)),
+        _ => Err(format!("Expected a count or start:rate:full with start <= full and rate <= 100, got {:?}", value)),
+    }
+}
+
+fn validate_socks_spec(value: String) -> Result<(), String> {
+    match value.find(",auth=") {
+        Some(index) => parse_socks_auth(&value[index + 6..]).map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+fn validate_socks_auth(value: String) -> Result<(), String> {
+    parse_socks_auth(&value).map(|_| ())
+}
+
+/// Splits SOCKS credentials given as user:pass. RFC 1929 limits each to 255
+/// bytes.
+crate fn parse_socks_auth(value: &str) -> Result<(String, String), String> {
+    let mut parts = value.splitn(2, ':');
+    let user = parts.next().unwrap();
+    let pass = parts.next().ok_or_else(|| "SOCKS credentials must be given as user:pass".to_string())?;
+    if user.len() > 255 || pass.len() > 255 {
+        return Err("SOCKS usernames and passwords can't be longer than 255 bytes".to_string());
+    }
+    Ok((user.to_string(), pass.to_string()))
+}
+
+fn validate_pty_size(value: String) -> Result<(), String> {
+    parse_pty_size(&value).map(|_| ())
+}
+
+fn parse_pty_size(value: &str) -> Result<(u16, u16), String> {
+    let parts: Vec<Option<u16>> = value.split('x').map(|x| x.parse().ok()).collect();
+    match parts.as_slice() {
+        &[Some(cols), Some(rows)] if cols > 0 && rows > 0 => Ok((cols, rows)),
+        _ => Err(format!("Expected a size like 120x40, got {:?}", value)),
+    }
+}
+
+fn validate_umask(value: String) -> Result<(), String> {
+    match u32::from_str_radix(&value, 8) {
+        Ok(mask) if mask <= 0o777 => Ok(()),
+        _ => Err(format!("Expected an octal umask like 022, got {:?}", value)),
+    }
+}
+
+fn validate_version_string(value: String) -> Result<(), String> {
+    if value.is_empty() || value.len() > 255 {
+        return Err("must be 1 to 255 characters long".to_string());
+    }
+    if !value.bytes().all(|x| x >= 0x20 && x < 0x7f) {
+        return Err("may only contain printable ASCII characters".to_string());
+    }
+    Ok(())
+}
+
+fn validate_auth(value: String) -> Result<(), String> {
+    if value != "key" && !cfg!(all(unix, feature = "pam")) {
+        return Err("this oxy was built without the pam feature".to_string());
+    }
+    Ok(())
+}
+
+fn validate_config_url(value: String) -> Result<(), String> {
+    if !value.starts_with("https://") {
+        return Err("only https URLs are accepted".to_string());
+    }
+    Ok(())
+}
+
+fn validate_metacommand(value: String) -> Result<(), String> {
+    crate::util::split_metacommands(&value).map(|_| ())
+}
+
+const CAPABILITIES: &[&str] = &["shell", "exec", "copy", "forward", "x11", "tunnel"];
+
+fn validate_capabilities(value: String) -> Result<(), String> {
+    for capability in value.split(',').map(str::trim) {
+        if !CAPABILITIES.contains(&capability) {
+            return Err(format!("Unknown capability {:?}. Expected some of {}", capability, CAPABILITIES.join(", ")));
+        }
+    }
+    Ok(())
+}
+
+fn validate_keep_fds(value: String) -> Result<(), String> {
+    parse_keep_fds(&value).map(|_| ())
+}
+
+fn parse_keep_fds(value: &str) -> Result<Vec<i32>, String> {
+    value
+        .split(',')
+        .map(|x| match x.trim().parse() {
+            Ok(fd) if fd > 2 => Ok(fd),
+            _ => Err(format!("Expected a file descriptor above 2, got {:?}", x)),
+        })
+        .collect()
+}
+
+fn validate_knock_ports(value: String) -> Result<(), String> {
+    parse_knock_ports(&value).map(|_| ())
+}
+
+fn validate_knock_sequence(value: String) -> Result<(), String> {
+    if value.trim().is_empty() {
+        return Err("Expected a list of ports or a secret".to_string());
+    }
+    Ok(())
+}
+
+fn parse_knock_ports(value: &str) -> Result<Vec<u16>, String> {
+    value
+        .split(',')
+        .map(|x| x.trim().parse().map_err(|_| format!("Invalid knock port: {:?}", x)))
+        .collect()
+}
+
+fn validate_hostkey_algorithms(value: String) -> Result<(), String> {
+    for algorithm in value.split(',').map(str::trim) {
+        if !crate::keys::HOST_KEY_ALGORITHMS.contains(&algorithm) {
+            return Err(format!(
+                "Unsupported host key algorithm {:?}. Supported algorithms: {}",
+                algorithm,
+                crate::keys::HOST_KEY_ALGORITHMS.join(",")
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn validate_resolve(value: String) -> Result<(), String> {
+    crate::util::parse_resolve_override(&value).map(|_| ())
+}
+
+fn validate_virtual_server(value: String) -> Result<(), String> {
+    match value.find(':') {
+        Some(index) if index > 0 && index + 1 < value.len() => Ok(()),
+        _ => Err(format!("Invalid virtual server {:?}, expected name:config", value)),
+    }
+}
+
+fn validate_fd(value: String) -> Result<(), String> {
+    value.parse::<i32>().map(|_| ()).map_err(|_| format!("Invalid file descriptor: {:?}", value))
+}
+
+/// Whether a resolved server address is usable under --address-family.
+crate fn allows_address(addr: &SocketAddr) -> bool {
+    match matches().value_of("address family") {
+        Some("inet") => addr.is_ipv4(),
+        Some("inet6") => addr.is_ipv6(),
+        _ => true,
+    }
+}
+
+/// Puts the family chosen with --prefer-ipv4 or --prefer-ipv6 first, keeping
+/// the resolver's order otherwise.
+crate fn sort_addresses(addrs: &mut Vec<SocketAddr>) {
+    if matches().is_present("prefer ipv4") {
+        addrs.sort_by_key(|x| !x.is_ipv4());
+    } else if matches().is_present("prefer ipv6") {
+        addrs.sort_by_key(|x| !x.is_ipv6());
+    }
+}
+
+/// Whether --hostkey-algorithms permits server keys of this type.
+crate fn allows_host_key_algorithm(algorithm: &str) -> bool {
+    match matches().value_of("hostkey algorithms") {
+        Some(allowed) => allowed.split(',').map(str::trim).any(|x| x == algorithm),
+        None => true,
+    }
+}
+
+/// The ports to knock on, from --knock-ports or --knock-sequence. A sequence
+/// that isn't a list of ports is a secret to derive them from.
+crate fn knock_ports() -> Option<Vec<u16>> {
+    if let Some(sequence) = matches().value_of("knock sequence") {
+        return Some(parse_knock_ports(sequence).unwrap_or_else(|_| crate::keys::knock_sequence_ports(sequence)));
+    }
+    matches().value_of("knock ports").map(|x| parse_knock_ports(x).unwrap())
+}
+
+crate fn max_reconnects() -> Option<u64> {
+    matches().value_of("max reconnects").map(|x| x.parse().unwrap())
+}
+
+crate fn connect_retries() -> Option<u64> {
+    matches().value_of("connect retries").map(|x| x.parse().unwrap())
+}
+
+crate fn keep_fds() -> Vec<i32> {
+    matches().value_of("keep fds").map(|x| parse_keep_fds(x).unwrap()).unwrap_or_default()
+}
+
+/// Whether --capabilities, if given, lists this capability.
+crate fn capability_enabled(capability: &str) -> bool {
+    match matches().value_of("capabilities") {
+        Some(list) => list.split(',').any(|x| x.trim() == capability),
+        None => true,
+    }
+}
+
+crate fn connection_attempts() -> u32 {
+    matches().value_of("connection attempts").map(|x| x.parse().unwrap()).unwrap_or(1)
+}
+
+crate fn retry_delay() -> Duration {
+    matches().value_of("retry delay").map(|x| crate::util::parse_duration(x).unwrap()).unwrap_or(Duration::from_secs(1))
+}
+
+crate fn retry_max_delay() -> Duration {
+    matches().value_of("retry max delay").map(|x| crate::util::parse_duration(x).unwrap()).unwrap_or(Duration::from_secs(60))
+}
+
+crate fn pty_size() -> Option<(u16, u16)> {
+    matches().value_of("pty size").map(|x| parse_pty_size(x).unwrap())
+}
+
+crate fn pty_output_limit() -> Option<u64> {
+    matches().value_of("pty output limit").map(|x| crate::util::parse_size(x).unwrap())
+}
+
+crate fn pty_flood_threshold() -> Option<u64> {
+    matches().value_of("pty flood threshold").map(|x| crate::util::parse_size(x).unwrap())
+}
+
+crate fn no_knock() -> bool {
+    matches().is_present("no knock")
+}
+
+crate fn knock_secret() -> Option<String> {
+    matches().value_of("knock secret").map(|x| x.to_string())
+}
+
+/// The secret to disguise connections with, under --obfuscate.
+crate fn obfuscate_secret() -> Option<&'static str> {
+    matches().value_of("obfuscate secret")
+}
+
+crate fn knock_window() -> Duration {
+    matches()
+        .value_of("knock window")
+        .map(|x| crate::util::parse_duration(x).unwrap())
+        .unwrap_or_else(|| Duration::from_secs(10))
+}
+
+crate fn knock_timeout() -> Duration {
+    matches()
+        .value_of("knock timeout")
+        .map(|x| crate::util::parse_duration(x).unwrap())
+        .unwrap_or_else(|| Duration::from_secs(50))
+}
+
+crate fn umask() -> Option<u32> {
+    matches().value_of("umask").map(|x| u32::from_str_radix(x, 8).unwrap())
+}
+
+crate fn auth_method() -> &'static str {
+    matches().value_of("auth").unwrap_or("key")
+}
+
+/// Whether --auth has the server check clients through PAM.
+#[cfg(all(unix, feature = "pam"))]
+crate fn pam_auth() -> bool {
+    auth_method() != "key"
+}
+
+crate fn max_auth_tries() -> u32 {
+    matches().value_of("max auth tries").map(|x| x.parse().unwrap()).unwrap_or(6)
+}
+
+crate fn max_startups() -> Option<(u32, u32, u32)> {
+    matches().value_of("max startups").map(|x| parse_max_startups(x).unwrap())
+}
+
+crate fn watch_interval() -> Option<Duration> {
+    matches().value_of("watch").map(|x| crate::util::parse_duration(x).unwrap())
+}
+
+crate fn proxy_protocol() -> Option<u8> {
+    matches().value_of("proxy protocol").and_then(crate::proxy_protocol::parse_version)
+}
+
+crate fn max_connections_per_forward() -> Option<u64> {
+    matches().value_of("max connections per forward").map(|x| x.parse().unwrap())
+}
+
+crate fn forward_idle_timeout() -> Option<Duration> {
+    matches().value_of("forward idle timeout").map(|x| crate::util::parse_duration(x).unwrap())
+}
+
+/// What this end identifies as to its peer.
+crate fn version_string() -> String {
+    matches().value_of("version string").map(|x| x.to_string()).unwrap_or_else(|| format!("oxy {}", crate_version!()))
+}
+
+crate fn stdin_eof_action() -> &'static str {
+    matches().value_of("stdin eof action").unwrap_or("close")
+}
+
+crate fn on_hup() -> &'static str {
+    matches().value_of("on hup").unwrap_or("disconnect")
+}
+
+/// How often to send keepalives, or None under --keepalive-interval=0.
+crate fn keepalive_interval() -> Option<Duration> {
+    let interval = matches().value_of("keepalive interval").map(|x| crate::util::parse_duration(x).unwrap());
+    Some(interval.unwrap_or(Duration::from_secs(60))).filter(|x| *x > Duration::from_secs(0))
+}
+
+crate fn keepalive_count() -> u32 {
+    matches().value_of("keepalive count").map(|x| x.parse().unwrap()).unwrap_or(3)
+}
+
+/// How long the peer may go without answering keepalives: the interval times
+/// --keepalive-count.
+crate fn keepalive_budget(interval: Duration) -> Result<Duration, String> {
+    interval
+        .checked_mul(keepalive_count())
+        .ok_or_else(|| "--keepalive-interval times --keepalive-count is too long".to_string())
+}
+
+crate fn keepalive_unanswered_action() -> &'static str {
+    matches().value_of("keepalive unanswered action").unwrap_or("disconnect")
+}
+
+/// A file in --config-dir, with ~ not yet expanded. None if this mode doesn't
+/// read config files.
+crate fn config_dir_file(file_name: &str) -> Option<String> {
+    let dir = matches().value_of("config dir")?;
+    Some(format!("{}/{}", dir.trim_right_matches('/'), file_name))
+}
+
+/// The path given by a per-file option, or else that file in --config-dir.
+crate fn config_file(arg: &str, file_name: &str) -> Option<String> {
+    matches().value_of(arg).map(|x| x.to_string()).or_else(|| config_dir_file(file_name))
+}
+
+/// The FileHashRequest algorithm for copy --verify, if it was given.
+crate fn verify_algorithm() -> Option<u64> {
+    if !matches().is_present("verify") {
+        return None;
+    }
+    match matches().value_of("verify").unwrap_or("sha256") {
+        "sha1" => Some(1),
+        "sha512" => Some(3),
+        _ => Some(2),
+    }
+}
+
+/// When to add a server's key to known_hosts, from --write-known-hosts-on or
+/// --strict-host-key-checking.
+crate fn write_known_hosts_on() -> &'static str {
+    match matches().value_of("strict host key checking") {
+        Some("no") => "first",
+        Some("ask") => "verify",
+        Some(_) => "never",
+        None => matches().value_of("write known hosts on").unwrap_or("never"),
+    }
+}
+
+/// The algorithm -C asks for.
+crate fn compression_algorithm() -> &'static str {
+    matches().value_of("compression").unwrap_or("zlib")
+}
+
+crate fn compress_level() -> i32 {
+    matches().value_of("compress level").map(|x| x.parse().unwrap()).unwrap_or(3)
+}
+
+crate fn gateway_ports() -> &'static str {
+    matches().value_of("gateway ports").unwrap_or("no")
+}
+
+crate fn tcp_user_timeout() -> Option<Duration> {
+    matches().value_of("tcp user timeout").map(|x| crate::util::parse_duration(x).unwrap())
+}
+
+crate fn x_cookie_timeout() -> Duration {
+    matches().value_of("x cookie timeout").map(|x| crate::util::parse_duration(x).unwrap()).unwrap_or(Duration::from_secs(3600))
+}
+
+/// The config path of the --virtual-server with this name.
+crate fn virtual_server_config(name: &str) -> Option<String> {
+    matches()
+        .values_of("virtual server")?
+        .filter_map(|x| {
+            let index = x.find(':')?;
+            Some((&x[..index], &x[index + 1..]))
+        })
+        .find(|(x, _)| *x == name)
+        .map(|(_, path)| path.to_string())
+}
+
+crate fn has_virtual_servers() -> bool {
+    matches().is_present("virtual server")
+}
+
+crate fn x_display_offset() -> u64 {
+    matches().value_of("x display offset").and_then(|x| x.parse().ok()).unwrap_or(10)
+}
+
+crate fn login_grace_time() -> Option<Duration> {
+    let time = crate::util::parse_duration(matches().value_of("login grace time")?).unwrap();
+    if time == Duration::from_secs(0) {
+        return None;
+    }
+    Some(time)
+}
+
+crate fn suspend_timeout() -> Option<Duration> {
+    let time = crate::util::parse_duration(matches().value_of("suspend timeout")?).unwrap();
+    if time == Duration::from_secs(0) {
+        return None;
+    }
+    Some(time)
+}
+
+crate fn handshake_timeout() -> Option<Duration> {
+    matches().value_of("handshake timeout").map(|x| crate::util::parse_duration(x).unwrap())
+}
+
+crate fn jump_timeout() -> Option<Duration> {
+    matches().value_of("jump timeout").map(|x| crate::util::parse_duration(x).unwrap())
+}
+
+crate fn command_timeout() -> Option<Duration> {
+    matches().value_of("command timeout").map(|x| crate::util::parse_duration(x).unwrap())
+}
+
+crate fn channel_window() -> usize {
+    matches()
+        .value_of("channel window")
+        .map(|x| crate::util::parse_size(x).unwrap() as usize)
+        .unwrap_or(1024 * 1024)
+}
+
+crate fn channel_max_packet() -> usize {
+    matches()
+        .value_of("channel max packet")
+        .map(|x| crate::util::parse_size(x).unwrap() as usize)
+        .unwrap_or(match priority() {
+            "interactive" => 4096,
+            "bulk" => 65536,
+            _ => 16384,
+        })
+}
+
+/// Whether to run no command on the server, only forwards.
+crate fn no_command() -> bool {
+    matches().is_present("no command") || matches().is_present("detach after auth")
+}
+
+crate fn security_level() -> &'static str {
+    matches().value_of("security level").unwrap_or("legacy")
+}
+
+crate fn priority() -> &'static str {
+    matches().value_of("priority").unwrap_or("fair")
+}
+
+/// The type of service to mark connections with: --dscp if it was given,
+/// otherwise one that suits --priority or the kind of connection, like
+/// OpenSSH's IPQoS defaults.
+crate fn tos() -> Option<u8> {
+    if let Some(value) = matches().value_of("dscp") {
+        return crate::util::parse_tos(value).unwrap();
+    }
+    let lowdelay = crate::util::parse_tos("lowdelay").unwrap();
+    let throughput = crate::util::parse_tos("throughput").unwrap();
+    match (priority(), mode().as_str()) {
+        ("interactive", _) => lowdelay,
+        ("bulk", _) | (_, "copy") => throughput,
+        (_, "client") | (_, "reverse-client") => lowdelay,
+        _ => None,
+    }
+}
+
+crate fn tunnel_mtu() -> Option<usize> {
+    matches().value_of("tunnel mtu").map(|x| crate::util::parse_size(x).unwrap() as usize)
 }
 
 crate fn perspective() -> EncryptionPerspective {
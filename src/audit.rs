@@ -0,0 +1,124 @@
+use crate::message::OxyMessage::{self, *};
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use serde_derive::Serialize;
+use serde_json;
+use std::{
+    cell::RefCell,
+    fs::{File, OpenOptions},
+    io::Write,
+    time::UNIX_EPOCH,
+};
+
+thread_local! {
+    static AUDIT: RefCell<Option<(File, AuditRecord)>> = RefCell::new(None);
+}
+
+#[derive(Serialize, Default)]
+struct AuditRecord {
+    source:       Option<String>,
+    peer_name:    Option<String>,
+    client_key:   Option<String>,
+    username:     Option<String>,
+    key_exchange: String,
+    cipher:       String,
+    compression:  bool,
+    commands:     Vec<String>,
+    forwards:     Vec<String>,
+    tunnels:      Vec<String>,
+    transfers:    Vec<String>,
+    start:        u64,
+    end:          u64,
+    bytes_in:     u64,
+    bytes_out:    u64,
+}
+
+/// Begin the audit record for this process's connection, if --audit-log was
+/// given. The log file is opened immediately, because the connection may drop
+/// privileges before the record is written.
+crate fn start(source: Option<String>) {
+    let path = crate::arg::matches().value_of("audit log");
+    if path.is_none() {
+        return;
+    }
+    let path = path.unwrap();
+    let file = OpenOptions::new().append(true).create(true).open(path);
+    if file.is_err() {
+        error!("Failed to open audit log {:?}: {:?}", path, file);
+        crate::exit::exit(crate::exit::EXIT_FAILURE);
+    }
+    let record = AuditRecord {
+        source,
+        key_exchange: "ed25519+x25519".to_string(),
+        cipher: "aes-256-gcm".to_string(),
+        start: UNIX_EPOCH.elapsed().unwrap().as_secs(),
+        ..Default::default()
+    };
+    AUDIT.with(|x| *x.borrow_mut() = Some((file.unwrap(), record)));
+    crate::exit::push_hook(finish);
+}
+
+fn update<T: FnOnce(&mut AuditRecord)>(callback: T) {
+    AUDIT.with(|x| {
+        if let Some((_, record)) = x.borrow_mut().as_mut() {
+            callback(record);
+        }
+    });
+}
+
+crate fn set_peer(peer_name: Option<String>, client_key: &[u8]) {
+    update(|record| {
+        record.peer_name = peer_name;
+        record.client_key = Some(::data_encoding::BASE32_NOPAD.encode(client_key));
+    });
+}
+
+crate fn observe_inbound(message: &OxyMessage) {
+    update(|record| {
+        record.bytes_in += payload_len(message);
+        match message {
+            UsernameAdvertisement { username } => record.username = Some(username.to_string()),
+            CompressionRequest { .. } => record.compression = true,
+            BasicCommand { command } | PipeCommand { command } => record.commands.push(command.to_string()),
+            PtyRequest { command } => record.commands.push(command.clone().unwrap_or_else(|| "<login shell>".to_string())),
+            RemoteOpen { addr } => record.forwards.push(format!("open {}", addr)),
+            RemoteBind { addr } => record.forwards.push(format!("bind {}", addr)),
+            TunnelRequest { tap, name } => record.tunnels.push(format!("{} {}", if *tap { "tap" } else { "tun" }, name)),
+            DownloadRequest { path, .. } => record.transfers.push(format!("download {}", path)),
+            FollowRequest { path, .. } => record.transfers.push(format!("follow {}", path)),
+            UploadRequest { path, .. } => record.transfers.push(format!("upload {}", path)),
+            _ => (),
+        }
+    });
+}
+
+crate fn observe_outbound(message: &OxyMessage) {
+    update(|record| record.bytes_out += payload_len(message));
+}
+
+fn payload_len(message: &OxyMessage) -> u64 {
+    let len = match message {
+        PipeCommandOutput { stdout, stderr, .. } => stdout.len() + stderr.len(),
+        PipeCommandInput { input, .. } => input.len(),
+        PtyInput { data } | PtyOutput { data } => data.len(),
+        FileData { data, .. } => data.len(),
+        RemoteStreamData { data, .. } | LocalStreamData { data, .. } => data.len(),
+        TunnelData { data, .. } => data.len(),
+        _ => 0,
+    };
+    len as u64
+}
+
+fn finish() {
+    AUDIT.with(|x| {
+        if let Some((mut file, mut record)) = x.borrow_mut().take() {
+            record.end = UNIX_EPOCH.elapsed().unwrap().as_secs();
+            let line = serde_json::to_string(&record).unwrap() + "\n";
+            // One write, so that records from concurrent sessions appending to
+            // the same file don't interleave.
+            if let Err(err) = file.write_all(line.as_bytes()) {
+                error!("Failed to write audit record: {:?}", err);
+            }
+        }
+    });
+}
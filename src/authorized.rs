@@ -0,0 +1,163 @@
+// The --authorized-identities file, which lists client keys the server
+// accepts alongside the [[clients]] in its config, in the spirit of OpenSSH's
+// authorized_keys. Each line is an optional comma-separated list of options,
+// then a base32 public key, then an optional comment:
+//
+//     command="uptime",from="10.0.0.0/8" AU6MM3AQTMIPBBH26Q5YTIRDVIPIJHFKBKCKURJN4DVHH5XNBXIA monitoring
+//
+// The options of the entry a client authenticated with restrict its session
+// on top of the server's own options.
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use std::{cell::RefCell, net::SocketAddr};
+
+thread_local! {
+    static SOURCE: RefCell<Option<SocketAddr>> = RefCell::new(None);
+    static MATCHED: RefCell<Option<IdentityOptions>> = RefCell::new(None);
+}
+
+#[derive(Clone, Default, Debug)]
+struct IdentityOptions {
+    command:     Option<String>,
+    permit_open: Vec<String>,
+    no_pty:      bool,
+    from:        Vec<String>,
+}
+
+/// Remember where the connection came from, for from="..." options.
+crate fn start(source: Option<String>) {
+    let source = source.and_then(|x| x.parse().ok());
+    SOURCE.with(|x| *x.borrow_mut() = source);
+}
+
+/// Where the connection came from, if known.
+crate fn source() -> Option<SocketAddr> {
+    SOURCE.with(|x| *x.borrow())
+}
+
+/// Whether `key` is listed in the --authorized-identities file, for a
+/// connection from where this one came from. If it is, the entry's options
+/// apply for the rest of the session.
+crate fn authorize(key: &[u8]) -> bool {
+    let path = crate::arg::matches().value_of("authorized identities");
+    if path.is_none() {
+        return false;
+    }
+    let path = path.unwrap();
+    let contents = match ::std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            warn!("Failed to read --authorized-identities file {:?}: {}", path, err);
+            return false;
+        }
+    };
+    let source = SOURCE.with(|x| *x.borrow());
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (entry_key, options) = match parse_line(line) {
+            Ok(entry) => entry,
+            Err(message) => {
+                warn!("Ignoring line {} of {:?}: {}", number + 1, path, message);
+                continue;
+            }
+        };
+        if entry_key[..] != key[..] {
+            continue;
+        }
+        if !options.from.is_empty() {
+            let permitted = source.map(|x| options.from.iter().any(|pattern| crate::pattern::address_match(pattern, x.ip())));
+            if !permitted.unwrap_or(false) {
+                warn!("Key on line {} of {:?} isn't permitted from {:?}", number + 1, path, source);
+                continue;
+            }
+        }
+        info!("Client key is authorized by line {} of {:?}", number + 1, path);
+        debug!("Identity options: {:?}", options);
+        MATCHED.with(|x| *x.borrow_mut() = Some(options));
+        return true;
+    }
+    false
+}
+
+/// The command="..." of the matched entry.
+crate fn forced_command() -> Option<String> {
+    MATCHED.with(|x| x.borrow().as_ref().and_then(|x| x.command.clone()))
+}
+
+/// The permitopen="..." destinations of the matched entry, if it limits them.
+crate fn permit_open() -> Option<Vec<String>> {
+    MATCHED.with(|x| x.borrow().as_ref().map(|x| x.permit_open.clone()).and_then(|x| if x.is_empty() { None } else { Some(x) }))
+}
+
+/// Whether the matched entry has no-pty.
+crate fn no_pty() -> bool {
+    MATCHED.with(|x| x.borrow().as_ref().map(|x| x.no_pty).unwrap_or(false))
+}
+
+fn parse_line(line: &str) -> Result<(Vec<u8>, IdentityOptions), String> {
+    let first = line.split_whitespace().next().unwrap();
+    if let Some(key) = decode_key(first) {
+        return Ok((key, IdentityOptions::default()));
+    }
+    let (options, rest) = split_options(line)?;
+    let key = rest.split_whitespace().next().ok_or("Missing public key")?;
+    let key = decode_key(key).ok_or_else(|| format!("Invalid public key {:?}", key))?;
+    Ok((key, parse_options(&options)?))
+}
+
+fn decode_key(text: &str) -> Option<Vec<u8>> {
+    ::data_encoding::BASE32_NOPAD.decode(text.as_bytes()).ok().and_then(|x| if x.len() == 32 { Some(x) } else { None })
+}
+
+/// Splits the leading options from the rest of the line at the first space
+/// that isn't inside quotes, and splits the options at unquoted commas.
+fn split_options(line: &str) -> Result<(Vec<String>, &str), String> {
+    let mut options = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        if escaped {
+            current.push(c);
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if quoted => escaped = true,
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            }
+            ',' if !quoted => options.push(::std::mem::replace(&mut current, String::new())),
+            c if c.is_whitespace() && !quoted => {
+                options.push(current);
+                return Ok((options, &line[index..]));
+            }
+            c => current.push(c),
+        }
+    }
+    if quoted {
+        return Err("Unterminated quote".to_string());
+    }
+    Err("Missing public key".to_string())
+}
+
+fn parse_options(options: &[String]) -> Result<IdentityOptions, String> {
+    let mut result = IdentityOptions::default();
+    for option in options {
+        let mut parts = option.splitn(2, '=');
+        let name = parts.next().unwrap().to_lowercase();
+        let value = parts.next().map(|x| x.trim_matches('"').to_string());
+        match (name.as_str(), value) {
+            ("command", Some(value)) => result.command = Some(value),
+            ("permitopen", Some(value)) => result.permit_open.push(value),
+            ("from", Some(value)) => result.from.extend(value.split(',').map(|x| x.to_string())),
+            ("no-pty", None) => result.no_pty = true,
+            _ => return Err(format!("Unsupported option {:?}", option)),
+        }
+    }
+    Ok(result)
+}
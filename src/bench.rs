@@ -0,0 +1,157 @@
+// `oxy bench`: a diagnostic that measures a session to a server, for capacity
+// planning and for comparing settings like --compress and --channel-window.
+// It times ping round trips, then an upload of random data to /dev/null and a
+// download from /dev/urandom, so nothing touches the server's disk and an
+// ordinary server needs nothing special to be benchmarked.
+use crate::{client, core::Oxy, message::OxyMessage::*};
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+use transportation::{self, ring::rand::SecureRandom};
+
+crate fn run() -> ! {
+    let destination = crate::arg::destination();
+    let connection = client::connect(&destination);
+    connection.set_daemon();
+    connection.set_peer_name(&destination);
+    let proxy = connection.clone();
+    connection.push_post_auth_hook(Rc::new(move || {
+        println!(
+            "Benchmarking {}: compression {}, channel window {}, max packet {}",
+            destination,
+            if crate::arg::matches().is_present("compression") { crate::arg::compression_algorithm() } else { "off" },
+            crate::arg::channel_window(),
+            crate::arg::channel_max_packet()
+        );
+        let rounds = crate::arg::matches().value_of("rounds").unwrap().parse().unwrap();
+        ping(proxy.clone(), rounds, Rc::new(RefCell::new(Vec::new())));
+    }));
+    transportation::run();
+}
+
+/// Times round trips one at a time, then moves on to the upload.
+fn ping(connection: Oxy, remaining: u32, times: Rc<RefCell<Vec<Duration>>>) {
+    if remaining == 0 {
+        report_latency(&mut times.borrow_mut());
+        upload(connection);
+        return;
+    }
+    let start = Instant::now();
+    connection.send(Ping {});
+    let proxy = connection.clone();
+    connection.watch(Rc::new(move |message, _| match message {
+        Pong {} => {
+            times.borrow_mut().push(start.elapsed());
+            ping(proxy.clone(), remaining - 1, times.clone());
+            true
+        }
+        _ => false,
+    }));
+}
+
+fn report_latency(times: &mut Vec<Duration>) {
+    times.sort();
+    let percentile = |p: usize| millis(times[(times.len() - 1) * p / 100]);
+    println!(
+        "Round trip: {} samples, min {:.2} ms, median {:.2} ms, p90 {:.2} ms, p99 {:.2} ms, max {:.2} ms",
+        times.len(),
+        percentile(0),
+        percentile(50),
+        percentile(90),
+        percentile(99),
+        percentile(100)
+    );
+}
+
+fn upload(connection: Oxy) {
+    let size = bench_size();
+    let id = connection.send(UploadRequest {
+        path:         "/dev".to_string(),
+        filepart:     "null".to_string(),
+        offset_start: None,
+    });
+    let proxy = connection.clone();
+    connection.watch(Rc::new(move |message, _| match message {
+        Success { reference } if *reference == id => {
+            let mut block = vec![0u8; crate::arg::channel_max_packet()];
+            transportation::RNG.fill(&mut block).unwrap();
+            let start = Instant::now();
+            let sent = Rc::new(RefCell::new(0u64));
+            let connection = proxy.clone();
+            proxy.push_send_hook(Rc::new(move || {
+                while connection.has_write_space() {
+                    let remaining = size - *sent.borrow();
+                    let count = if remaining < block.len() as u64 { remaining as usize } else { block.len() };
+                    let send_id = connection.send(FileData {
+                        reference: id,
+                        data:      block[..count].to_vec(),
+                    });
+                    *sent.borrow_mut() += count as u64;
+                    if count == 0 {
+                        let proxy = connection.clone();
+                        connection.watch(Rc::new(move |message, _| match message {
+                            Success { reference } if *reference == send_id => {
+                                report_throughput("Upload", size, start.elapsed());
+                                download(proxy.clone());
+                                true
+                            }
+                            _ => false,
+                        }));
+                        return true;
+                    }
+                }
+                false
+            }));
+            true
+        }
+        Reject { reference, note } if *reference == id => {
+            error!("The server refused the upload to /dev/null: {:?}", note);
+            ::std::process::exit(1);
+        }
+        _ => false,
+    }));
+}
+
+fn download(connection: Oxy) {
+    let size = bench_size();
+    let start = Instant::now();
+    let received = RefCell::new(0u64);
+    let id = connection.send(DownloadRequest {
+        path:         "/dev/urandom".to_string(),
+        offset_start: None,
+        offset_end:   Some(size),
+    });
+    connection.watch(Rc::new(move |message, _| match message {
+        FileData { reference, data } if *reference == id => {
+            *received.borrow_mut() += data.len() as u64;
+            if data.is_empty() {
+                report_throughput("Download", *received.borrow(), start.elapsed());
+                crate::exit::exit(crate::exit::EXIT_OK);
+            }
+            false
+        }
+        Reject { reference, note } if *reference == id => {
+            error!("The server refused the download from /dev/urandom: {:?}", note);
+            ::std::process::exit(1);
+        }
+        _ => false,
+    }));
+}
+
+fn report_throughput(direction: &str, bytes: u64, elapsed: Duration) {
+    let seconds = millis(elapsed) / 1000.0;
+    let mebibytes = bytes as f64 / (1024.0 * 1024.0);
+    println!("{}: {:.1} MiB in {:.2} s, {:.1} MiB/s", direction, mebibytes, seconds, mebibytes / seconds.max(0.001));
+}
+
+fn bench_size() -> u64 {
+    crate::util::parse_size(crate::arg::matches().value_of("size").unwrap()).unwrap()
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + f64::from(duration.subsec_nanos()) / 1_000_000.0
+}
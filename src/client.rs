@@ -1,72 +1,319 @@
-use crate::{arg, core::Oxy, keys};
+use crate::{
+    arg,
+    core::Oxy,
+    exit::{fail, OxyError},
+    keys,
+};
 #[allow(unused_imports)]
 use log::{debug, error, info, log, trace, warn};
+use serde_json::json;
 use std::{
-    net::{TcpListener, TcpStream, UdpSocket},
+    net::{SocketAddr, TcpListener, UdpSocket},
     rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 use transportation;
 
-crate fn knock(peer: &str) {
-    let destinations = crate::conf::locate_destination(peer);
-    let port = keys::knock_port(Some(peer));
+/// How long to wait between --connection-attempts.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_secs(1);
+
+/// Resolves a destination, with --resolve overrides taking precedence over
+/// normal resolution.
+fn locate_destination(peer: &str) -> Vec<SocketAddr> {
+    let host = crate::conf::host_for_dest(peer);
+    let port = crate::conf::port_for_dest(peer);
+    let mut destinations = Vec::new();
+    if let Some(specs) = arg::matches().values_of("resolve") {
+        for spec in specs {
+            let (override_host, override_port, addrs) = crate::util::parse_resolve_override(spec).unwrap();
+            if override_host == host && override_port == port {
+                destinations.extend(addrs.into_iter().map(|x| SocketAddr::new(x, port)));
+            }
+        }
+    }
     if destinations.is_empty() {
-        error!("Failed to resolve {:?}", peer);
-        ::std::process::exit(1);
+        if arg::matches().is_present("no resolve") && !crate::util::is_ip_literal(&host) {
+            fail(OxyError::Connection(format!("{:?} is not an IP address, and --no-resolve forbids looking it up", host)));
+        }
+        destinations = crate::conf::locate_destination(peer);
+    } else {
+        debug!("Using --resolve override for {}:{}: {:?}", host, port, destinations);
     }
+    destinations.retain(arg::allows_address);
+    arg::sort_addresses(&mut destinations);
+    destinations
+}
+
+/// Knocks on the server that the peer name refers to, at the given addresses.
+fn knock(peer: &str, destinations: &[SocketAddr]) {
+    if arg::no_knock() {
+        debug!("Not knocking, because of --no-knock");
+        return;
+    }
+    let ports = keys::knock_ports(Some(peer));
     let knock = UdpSocket::bind("0.0.0.0:0").unwrap();
     let knock6 = UdpSocket::bind("[::0]:0").ok();
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::AsRawFd;
+        crate::util::apply_bind_interface(knock.as_raw_fd());
+        knock6.as_ref().map(|x| crate::util::apply_bind_interface(x.as_raw_fd()));
+    }
     let knock_value = keys::make_knock(Some(peer));
-    debug!("Knocking on port {}", port);
-    for destination in &destinations {
-        let mut destination = destination.clone();
-        destination.set_port(port);
-        if destination.is_ipv4() {
-            knock.send_to(&knock_value, destination).unwrap();
-        } else {
-            knock6.as_ref().map(|x| x.send_to(&knock_value, destination).unwrap());
+    for (i, port) in ports.iter().enumerate() {
+        if i > 0 {
+            // Space the knocks out so they arrive in order.
+            ::std::thread::sleep(::std::time::Duration::from_millis(50));
+        }
+        debug!("Knocking on port {}", port);
+        for destination in destinations {
+            let mut destination = *destination;
+            destination.set_port(*port);
+            if destination.is_ipv4() {
+                knock.send_to(&knock_value, destination).unwrap();
+            } else {
+                knock6.as_ref().map(|x| x.send_to(&knock_value, destination).unwrap());
+            }
         }
     }
     ::std::thread::sleep(::std::time::Duration::from_millis(500));
 }
 
-crate fn run() {
-    if let Some(hops) = crate::arg::matches().values_of("via") {
-        let mut prev = None;
-        for hop in hops.into_iter().rev() {
-            if prev.is_none() {
-                prev = Some(connect(hop));
-                continue;
+/// One server on the way to the destination, and how to reach it: over TCP, or
+/// through the stdin and stdout of a proxy command. A hop reached through an
+/// earlier hop runs its proxy command on that hop's server.
+struct Hop {
+    destination:   String,
+    proxy_command: Option<String>,
+}
+
+impl Hop {
+    /// Parses a --via spec, which is a destination optionally followed by
+    /// ",proxy-command=<command>". The command runs to the end of the spec, so
+    /// it may contain commas itself.
+    fn parse(spec: &str) -> Hop {
+        let mut parts = spec.splitn(2, ",proxy-command=");
+        Hop {
+            destination:   parts.next().unwrap().to_string(),
+            proxy_command: parts.next().map(|x| x.to_string()),
+        }
+    }
+
+    /// The proxy command with %h and %p replaced by the hop's host and port.
+    fn proxy_command(&self) -> Option<String> {
+        let host = crate::conf::host_for_dest(&self.destination);
+        let port = crate::conf::port_for_dest(&self.destination).to_string();
+        self.proxy_command.as_ref().map(|x| x.replace("%h", &host).replace("%p", &port))
+    }
+}
+
+/// The hops to go through, nearest the destination first, as with --via.
+/// Without --via, the destination's [[servers]] entry may list them. Either
+/// way, the farthest hop's own entry may list hops it's reached through, and
+/// so on.
+fn hop_specs() -> Vec<String> {
+    let destination = arg::destination();
+    let mut hops: Vec<String> = match arg::matches().values_of("via") {
+        Some(hops) => hops.map(|x| x.to_string()).collect(),
+        None => crate::conf::via_for_dest(&destination),
+    };
+    let mut seen: Vec<String> = hops.iter().map(|x| Hop::parse(x).destination).collect();
+    seen.push(destination.clone());
+    loop {
+        let next = match hops.last() {
+            Some(last) => crate::conf::via_for_dest(&Hop::parse(last).destination),
+            None => break,
+        };
+        if next.is_empty() {
+            break;
+        }
+        for hop in next {
+            let name = Hop::parse(&hop).destination;
+            if seen.contains(&name) {
+                fail(OxyError::Config(format!("The hops to {} loop back to {} through your client config", destination, name)));
             }
-            prev = Some(connect_via(prev.take().unwrap(), hop));
+            seen.push(name);
+            hops.push(hop);
+        }
+    }
+    hops
+}
+
+crate fn run() {
+    if arg::matches().is_present("hosts") {
+        crate::fanout::run();
+    }
+    if arg::matches().is_present("print connection string") {
+        println!("{}", arg::connection_string());
+        if let Some(entry) = crate::conf::redacted_server_entry(&arg::destination()) {
+            println!();
+            print!("{}", entry);
         }
-        connect_via(prev.take().unwrap(), &crate::arg::destination());
+        crate::exit::exit(crate::exit::EXIT_OK);
+    }
+    #[cfg(unix)]
+    {
+        if arg::matches().is_present("roam") {
+            crate::roam::supervise();
+        }
+    }
+    let destination = Hop {
+        destination:   arg::destination(),
+        proxy_command: arg::matches().value_of("proxy command").map(|x| x.to_string()),
+    };
+    let hops = hop_specs();
+    if !hops.is_empty() {
+        let hop_count = hops.len();
+        let mut prev: Option<Oxy> = None;
+        for (i, hop) in hops.iter().rev().enumerate() {
+            let hop = Hop::parse(hop);
+            let authenticated = Arc::new(AtomicBool::new(false));
+            let oxy = match prev.take() {
+                None => {
+                    start_hop_timer(authenticated.clone(), i + 1, &hop.destination);
+                    connect_first_hop(&hop)
+                }
+                Some(prev) => {
+                    // A hop is only dialed once the hop before it is up.
+                    let (authenticated, destination) = (authenticated.clone(), hop.destination.clone());
+                    prev.push_post_auth_hook(Rc::new(move || start_hop_timer(authenticated.clone(), i + 1, &destination)));
+                    connect_via(prev, &hop, i + 1)
+                }
+            };
+            oxy.push_post_auth_hook(Rc::new(move || authenticated.store(true, Ordering::SeqCst)));
+            prev = Some(oxy);
+        }
+        connect_via(prev.take().unwrap(), &destination, hop_count + 1);
         info!("Here");
         transportation::run();
     }
-    connect(&arg::destination());
+    connect_first_hop(&destination);
     info!("Connected");
     transportation::run();
 }
 
-crate fn connect_via(proxy_daemon: Oxy, dest: &str) -> Oxy {
+/// Gives up on the connection if the numbered --via hop hasn't authenticated
+/// within --jump-timeout. The timer runs on its own thread, so it also covers
+/// a first hop that's stuck in a blocking connect.
+fn start_hop_timer(authenticated: Arc<AtomicBool>, number: usize, destination: &str) {
+    let timeout = arg::jump_timeout();
+    if timeout.is_none() {
+        return;
+    }
+    let timeout = timeout.unwrap();
+    let destination = destination.to_string();
+    ::std::thread::spawn(move || {
+        ::std::thread::sleep(timeout);
+        if !authenticated.load(Ordering::SeqCst) {
+            let duration = crate::util::format_duration(timeout);
+            fail(OxyError::Connection(format!("hop {} ({}) timed out after {}", number, destination, duration)));
+        }
+    });
+}
+
+/// Connects to the first hop, which is dialed at --first-hop if given.
+fn connect_first_hop(hop: &Hop) -> Oxy {
+    let first_hop = arg::matches().value_of("first hop");
+    if first_hop.is_none() {
+        return connect_hop(hop);
+    }
+    let first_hop = first_hop.unwrap();
+    if hop.proxy_command.is_some() {
+        warn!("Ignoring --first-hop, since {} is reached through a proxy command", hop.destination);
+        return connect_hop(hop);
+    }
+    let port = crate::conf::port_for_dest(&hop.destination);
+    let addrs = if first_hop.starts_with('[') || first_hop.matches(':').count() == 1 {
+        crate::util::resolve_addr(first_hop)
+    } else {
+        crate::util::resolve_host(first_hop, port)
+    };
+    let mut addrs = addrs.unwrap_or_default();
+    addrs.retain(arg::allows_address);
+    arg::sort_addresses(&mut addrs);
+    debug!("Dialing {} at {:?}", hop.destination, addrs);
+    connect_addrs(&hop.destination, &addrs)
+}
+
+fn connect_hop(hop: &Hop) -> Oxy {
+    match hop.proxy_command() {
+        Some(command) => connect_proxy_command(&hop.destination, &command),
+        None => connect(&hop.destination),
+    }
+}
+
+/// Connects through a local proxy command, the way ssh's ProxyCommand does.
+fn connect_proxy_command(destination: &str, command: &str) -> Oxy {
+    use nix::{
+        fcntl::{fcntl, FcntlArg, FdFlag},
+        sys::socket::{socketpair, AddressFamily, SockFlag, SockType},
+        unistd::dup,
+    };
+    use std::{
+        os::unix::io::FromRawFd,
+        process::{Command, Stdio},
+    };
+    let destinations = locate_destination(destination);
+    if destinations.is_empty() {
+        warn!("{} doesn't resolve locally, so it can't be knocked on. The proxy command will have to get through on its own.", destination);
+    } else {
+        knock(destination, &destinations);
+    }
+    let (socka, sockb) = socketpair(AddressFamily::Unix, SockType::Stream, None, SockFlag::empty()).unwrap();
+    // The command mustn't hold our end open, or it would never see EOF.
+    fcntl(sockb, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).unwrap();
+    debug!("Running proxy command {:?}", command);
+    let child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(unsafe { Stdio::from_raw_fd(dup(socka).unwrap()) })
+        .stdout(unsafe { Stdio::from_raw_fd(socka) })
+        .spawn();
+    if child.is_err() {
+        fail(OxyError::Connection(format!("Failed to run proxy command {:?}: {}", command, child.unwrap_err())));
+    }
+    let peer = Oxy::create(crate::obfuscate::wrap(sockb, true));
+    peer.set_peer_name(destination);
+    peer
+}
+
+/// Connects to a hop through the one before it. `number` is the hop's place in
+/// the chain, counting from 1 for the first server dialed, for error messages.
+fn connect_via(proxy_daemon: Oxy, hop: &Hop, number: usize) -> Oxy {
     use crate::message::OxyMessage::*;
     use nix::sys::socket::{socketpair, AddressFamily, SockFlag, SockType};
     use transportation::BufferedTransport;
     let (socka, sockb) = socketpair(AddressFamily::Unix, SockType::Stream, None, SockFlag::empty()).unwrap();
     let bt = BufferedTransport::from(socka);
     proxy_daemon.set_daemon();
-    let dest = dest.to_string();
+    let dest = hop.destination.clone();
+    let proxy_command = hop.proxy_command();
     proxy_daemon.clone().push_post_auth_hook(Rc::new(move || {
         let proxy_daemon = proxy_daemon.clone();
-        let knock_port = keys::knock_port(Some(&dest));
         let knock_host = crate::conf::host_for_dest(&dest);
         let knock_value = keys::make_knock(Some(&dest));
-        let knock_dest = format!("{}:{}", knock_host, knock_port);
-        proxy_daemon.send(KnockForward {
-            destination: knock_dest,
-            knock:       knock_value,
-        });
+        // The server knocks for each message in turn, so a sequence arrives in
+        // order.
+        let knock_ports = if arg::no_knock() { Vec::new() } else { keys::knock_ports(Some(&dest)) };
+        for knock_port in knock_ports {
+            let knock_dest = if knock_host.contains(':') {
+                format!("[{}]:{}", knock_host, knock_port)
+            } else {
+                format!("{}:{}", knock_host, knock_port)
+            };
+            proxy_daemon.send(KnockForward {
+                destination: knock_dest,
+                knock:       knock_value.clone(),
+            });
+        }
+        if let Some(command) = proxy_command.clone() {
+            bridge_remote_command(proxy_daemon, bt.clone(), command);
+            return;
+        }
         let stream_number = proxy_daemon.send(RemoteOpen {
             addr: crate::conf::canonicalize_destination(&dest),
         });
@@ -85,6 +332,7 @@ crate fn connect_via(proxy_daemon: Oxy, dest: &str) -> Oxy {
         ::transportation::Notifies::set_notify(&bt.clone(), notify.clone());
         notify();
         let proxy_daemon = proxy_daemon2;
+        let dest = dest.clone();
         proxy_daemon.clone().watch(Rc::new(move |message, _| match message {
             LocalStreamData { data, reference } if *reference == stream_number => {
                 bt.put(&data[..]);
@@ -96,30 +344,125 @@ crate fn connect_via(proxy_daemon: Oxy, dest: &str) -> Oxy {
                 proxy_daemon.claim_message();
                 true
             }
+            Reject { reference, note } if *reference == stream_number => {
+                let hops = hop_specs().len();
+                let name = if number > hops { "the destination".to_string() } else { format!("hop {}", number) };
+                fail(OxyError::Connection(format!("{} ({}) couldn't be reached from the hop before it: {}", name, dest, note)))
+            }
             _ => false,
         }));
     }));
-    Oxy::create(sockb)
+    Oxy::create(crate::obfuscate::wrap(sockb, true))
+}
+
+/// Carries the connection to the next hop over a proxy command run on the
+/// previous hop's server. The command's stderr is passed through to ours.
+fn bridge_remote_command(proxy_daemon: Oxy, bt: ::transportation::BufferedTransport, command: String) {
+    use crate::message::OxyMessage::*;
+    use std::io::Write;
+    let reference = proxy_daemon.send(PipeCommand { command });
+    let bt2 = bt.clone();
+    let proxy_daemon2 = proxy_daemon.clone();
+    let notify = Rc::new(move || {
+        let input = bt.take();
+        // Empty input closes the command's stdin, so only send it at EOF.
+        if input.is_empty() && !bt.is_closed() {
+            return;
+        }
+        proxy_daemon.send(PipeCommandInput { reference, input });
+    });
+    let bt = bt2;
+    ::transportation::Notifies::set_notify(&bt.clone(), notify.clone());
+    notify();
+    let proxy_daemon = proxy_daemon2;
+    proxy_daemon.clone().watch(Rc::new(move |message, _| match message {
+        PipeCommandOutput {
+            reference: output_reference,
+            stdout,
+            stderr,
+        } if *output_reference == reference =>
+        {
+            bt.put(&stdout[..]);
+            ::std::io::stderr().write_all(&stderr[..]).ok();
+            proxy_daemon.claim_message();
+            false
+        }
+        PipeCommandExited { reference: exited_reference } if *exited_reference == reference => {
+            bt.close();
+            proxy_daemon.claim_message();
+            true
+        }
+        _ => false,
+    }));
 }
 
 crate fn connect(destination: &str) -> Oxy {
-    knock(destination);
-    let destinations = crate::conf::locate_destination(destination);
-    let stream = TcpStream::connect(&destinations[..]);
+    connect_addrs(destination, &locate_destination(destination))
+}
+
+/// Connects to the server the destination refers to, dialing the given
+/// addresses. Keys and config are still looked up by the destination name.
+fn connect_addrs(destination: &str, destinations: &[SocketAddr]) -> Oxy {
+    if destinations.is_empty() {
+        fail(OxyError::Connection(format!("Failed to resolve {:?}", destination)));
+    }
+    let attempts = arg::connection_attempts();
+    let mut attempt = 1;
+    let stream = loop {
+        knock(destination, destinations);
+        match crate::util::connect_tcp(destinations) {
+            Err(ref err) if attempt < attempts => {
+                debug!("Connection attempt {} of {} to {} failed: {}", attempt, attempts, destination, err);
+                ::std::thread::sleep(CONNECTION_ATTEMPT_DELAY);
+                attempt += 1;
+            }
+            stream => break stream,
+        }
+    };
     if stream.is_err() {
-        error!("Connection to {} failed: {:?}", destination, stream);
-        ::std::process::exit(1);
+        let hint = if arg::no_knock() { " (does the server expect a knock? --no-knock skipped it)" } else { "" };
+        fail(OxyError::Connection(format!("Connection to {} failed: {}{}", destination, stream.unwrap_err(), hint)));
     }
     let stream = stream.unwrap();
-    let peer = Oxy::create(stream);
+    let address = stream.peer_addr().map(|x| x.to_string()).unwrap_or_default();
+    crate::events::emit("connected", json!({ "destination": destination, "address": address }));
+    #[cfg(unix)]
+    {
+        let fd = ::std::os::unix::io::AsRawFd::as_raw_fd(&stream);
+        crate::util::apply_tcp_user_timeout(fd);
+        crate::util::apply_priority(fd);
+    }
+    let peer = Oxy::create(crate::obfuscate::transport(stream, true));
     peer.set_peer_name(destination);
     peer
 }
 
 crate fn reverse_client() {
-    let acceptor = TcpListener::bind(&arg::bind_address()).unwrap();
+    let bind_address = arg::bind_address();
+    let acceptor = match TcpListener::bind(&bind_address) {
+        Ok(acceptor) => acceptor,
+        Err(err) => fail(OxyError::Connection(format!("Failed to listen on {}: {}", bind_address, err))),
+    };
+    #[cfg(unix)]
+    crate::util::apply_bind_interface(::std::os::unix::io::AsRawFd::as_raw_fd(&acceptor));
     trace!("Bound");
+    #[cfg(unix)]
+    {
+        if arg::matches().is_present("hold") {
+            crate::util::hold(|| acceptor.accept().map(|x| x.0).map_err(|err| format!("Failed to accept a server: {}", err)), reverse_session);
+        }
+    }
     let (stream, _) = acceptor.accept().unwrap();
+    reverse_session(stream);
+}
+
+fn reverse_session(stream: ::std::net::TcpStream) -> ! {
     trace!("Connected");
-    Oxy::run(stream);
+    #[cfg(unix)]
+    {
+        let fd = ::std::os::unix::io::AsRawFd::as_raw_fd(&stream);
+        crate::util::apply_tcp_user_timeout(fd);
+        crate::util::apply_priority(fd);
+    }
+    Oxy::run(crate::obfuscate::transport(stream, false));
 }
@@ -1,12 +1,14 @@
 use lazy_static::{__lazy_static_create, __lazy_static_internal, lazy_static};
 #[allow(unused_imports)]
 use log::{debug, error, info, log, trace, warn};
+use parking_lot::Mutex;
 use std::{
     collections::BTreeMap,
     fs::File,
     io::Read,
-    net::{SocketAddr, ToSocketAddrs},
+    net::SocketAddr,
     str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
 };
 use toml::{
     self,
@@ -15,8 +17,51 @@ use toml::{
 
 lazy_static! {
     static ref CONF: Conf = load_conf();
+    static ref VIRTUAL_SERVER_CONFIG: Mutex<Option<String>> = Mutex::new(None);
+    static ref FETCHED_SERVER_CONFIG: Mutex<Option<String>> = Mutex::new(None);
 }
 
+/// Where a listening server puts the server.conf it fetched from --config-url
+/// for the connections it reexecs, so that they don't each fetch it again.
+crate const FETCHED_SERVER_CONFIG_VAR: &str = "OXY_FETCHED_SERVER_CONFIG";
+
+static LOADED: AtomicBool = AtomicBool::new(false);
+
+/// The keys each config file understands, for --strict-config: those at its
+/// top level, and those in the entries of its array of tables. Other tables,
+/// like [aliases], may hold anything.
+struct Schema {
+    keys:       &'static [&'static str],
+    entries:    &'static str,
+    entry_keys: &'static [&'static str],
+}
+
+const SERVER_SCHEMA: Schema = Schema {
+    keys:       &["identity", "privkey", "next_privkey", "my_pubkey", "psk", "knock", "clients"],
+    entries:    "clients",
+    entry_keys: &["name", "pubkey", "privkey", "my_pubkey", "psk", "knock", "setuser", "allowed_users"],
+};
+
+const CLIENT_SCHEMA: Schema = Schema {
+    keys:       &["identity", "privkey", "my_pubkey", "psk", "knock", "servers", "aliases"],
+    entries:    "servers",
+    entry_keys: &[
+        "name",
+        "host",
+        "port",
+        "pubkey",
+        "privkey",
+        "my_pubkey",
+        "psk",
+        "knock",
+        "identity",
+        "via",
+        "local_forwards",
+        "remote_forwards",
+        "socks",
+    ],
+};
+
 #[derive(Default, Debug)]
 struct Conf {
     server: Option<toml::Value>,
@@ -29,6 +74,7 @@ crate fn init() {
 
 fn load_conf() -> Conf {
     trace!("Loading configuration");
+    LOADED.store(true, Ordering::SeqCst);
     let mut result = Conf::default();
     result.load_server_conf();
     result.load_client_conf();
@@ -36,11 +82,26 @@ fn load_conf() -> Conf {
     result
 }
 
+/// Switches to the server config of the --virtual-server a client asked for.
+/// This has to happen before the config is first used, so it's only possible
+/// while the connection's handshake hasn't started.
+crate fn select_virtual_server(name: &str) -> Result<(), String> {
+    let path = crate::arg::virtual_server_config(name).ok_or_else(|| format!("No virtual server named {:?}", name))?;
+    if LOADED.load(Ordering::SeqCst) {
+        return Err(format!("The server config was loaded before virtual server {:?} could be selected", name));
+    }
+    debug!("Using virtual server {:?} with config {}", name, path);
+    *VIRTUAL_SERVER_CONFIG.lock() = Some(path);
+    Ok(())
+}
+
 crate fn has_server_conf() -> bool {
     CONF.server.is_some()
 }
 
-fn load_from_home(path: &str) -> Option<toml::Value> {
+/// Replaces a leading ~ with the home directory. None if there's a ~ but no
+/// home directory.
+crate fn expand_home(path: &str) -> Option<String> {
     let mut path = path.to_string();
     if path.starts_with("~") {
         let home = ::std::env::home_dir();
@@ -51,6 +112,15 @@ fn load_from_home(path: &str) -> Option<toml::Value> {
         let home = home.to_str().unwrap().to_string();
         path = path.replacen('~', &home, 1);
     }
+    Some(path)
+}
+
+fn load_from_home(path: &str, schema: &Schema) -> Option<toml::Value> {
+    if path == "none" {
+        debug!("Config file disabled, not loading one");
+        return None;
+    }
+    let path = expand_home(path)?;
     let file = File::open(&path);
     if file.is_err() {
         debug!("No {} config to load.", path);
@@ -65,35 +135,133 @@ fn load_from_home(path: &str) -> Option<toml::Value> {
     }
     let decode_result = String::from_utf8(data);
     if decode_result.is_err() {
-        warn!("Error decoding config as UTF-8");
-        return None;
+        crate::exit::fail(crate::exit::OxyError::Config(format!("{} is not valid UTF-8", path)));
     }
     let text = decode_result.unwrap();
-    let value = toml::Value::from_str(&text);
-    if value.is_err() {
-        warn!("Error parsing TOML: {:?}", value);
-    }
+    let value = parse(&path, &text, schema);
     debug!("Successfully loaded {:?}", path);
-    value.ok()
+    Some(value)
+}
+
+fn load_from_url(url: &str, text: &str, schema: &Schema) -> Option<toml::Value> {
+    let value = parse(url, text, schema);
+    debug!("Successfully loaded {:?}", url);
+    Some(value)
+}
+
+/// The server.conf the listening server fetched, in a reexec'd connection.
+/// It's removed from the environment, so that the session's commands don't
+/// inherit it.
+fn handed_over_server_config() -> Option<String> {
+    if crate::arg::mode() != "reexec" {
+        return None;
+    }
+    let text = ::std::env::var(FETCHED_SERVER_CONFIG_VAR).ok()?;
+    ::std::env::remove_var(FETCHED_SERVER_CONFIG_VAR);
+    Some(text)
+}
+
+/// The server.conf fetched from --config-url, if it was.
+crate fn fetched_server_config() -> Option<String> {
+    FETCHED_SERVER_CONFIG.lock().clone()
+}
+
+fn parse(name: &str, text: &str, schema: &Schema) -> toml::Value {
+    let value = toml::Value::from_str(text);
+    if let Err(err) = &value {
+        crate::exit::fail(crate::exit::OxyError::Config(format!("Error parsing {}: {}", name, err)));
+    }
+    if crate::arg::matches().is_present("strict config") {
+        check_keys(name, text, value.as_ref().unwrap(), schema);
+    }
+    value.unwrap()
+}
+
+/// Fails on any key the config file's schema doesn't know, naming each with
+/// its line.
+fn check_keys(path: &str, text: &str, value: &toml::Value, schema: &Schema) {
+    let mut unknown = Vec::new();
+    if let Table(table) = value {
+        for key in table.keys().filter(|x| !schema.keys.contains(&x.as_str())) {
+            unknown.push((key_line(text, None, key), key.clone()));
+        }
+        if let Some(Array(entries)) = table.get(schema.entries) {
+            for entry in entries.iter().filter_map(|x| x.as_table()) {
+                for key in entry.keys().filter(|x| !schema.entry_keys.contains(&x.as_str())) {
+                    unknown.push((key_line(text, Some(schema.entries), key), key.clone()));
+                }
+            }
+        }
+    }
+    if unknown.is_empty() {
+        return;
+    }
+    unknown.sort();
+    unknown.dedup();
+    let unknown: Vec<String> = unknown
+        .into_iter()
+        .map(|(line, key)| match line {
+            Some(line) => format!("{}:{}: unknown key {:?}", path, line, key),
+            None => format!("{}: unknown key {:?}", path, key),
+        })
+        .collect();
+    crate::exit::fail(crate::exit::OxyError::Config(unknown.join("\n")));
+}
+
+/// The line a key is first set on, at the top level or in a section of that
+/// name. A top-level key that's really a table is found by its header.
+fn key_line(text: &str, section: Option<&str>, key: &str) -> Option<usize> {
+    let mut current: Option<String> = None;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.starts_with('[') {
+            let name = line.trim_matches(|x| x == '[' || x == ']').trim().to_string();
+            if section.is_none() && name == key {
+                return Some(i + 1);
+            }
+            current = Some(name);
+            continue;
+        }
+        if current.as_ref().map(|x| x.as_str()) != section || !line.contains('=') {
+            continue;
+        }
+        if line.split('=').next().unwrap().trim().trim_matches('"') == key {
+            return Some(i + 1);
+        }
+    }
+    None
 }
 
 impl Conf {
     fn load_client_conf(&mut self) {
-        let path = crate::arg::matches().value_of("client config");
+        if let Some(url) = crate::arg::matches().value_of("client config url") {
+            self.client = load_from_url(url, &crate::config_url::fetch(url), &CLIENT_SCHEMA);
+            return;
+        }
+        let path = crate::arg::config_file("client config", "client.conf");
         if path.is_none() {
             return;
         }
-        let path = path.unwrap();
-        self.client = load_from_home(path);
+        self.client = load_from_home(&path.unwrap(), &CLIENT_SCHEMA);
     }
 
     fn load_server_conf(&mut self) {
-        let path = crate::arg::matches().value_of("server config");
+        if crate::arg::matches().is_present("ephemeral identity") {
+            debug!("Using an ephemeral identity, skipping server config");
+            return;
+        }
+        let virtual_server = VIRTUAL_SERVER_CONFIG.lock().clone();
+        if let Some(url) = crate::arg::matches().value_of("server config url").filter(|_| virtual_server.is_none()) {
+            let text = handed_over_server_config().unwrap_or_else(|| crate::config_url::fetch(url));
+            self.server = load_from_url(url, &text, &SERVER_SCHEMA);
+            *FETCHED_SERVER_CONFIG.lock() = Some(text);
+            return;
+        }
+        let path = virtual_server.or_else(|| crate::arg::config_file("server config", "server.conf"));
         if path.is_none() {
             return;
         }
-        let path = path.unwrap();
-        self.server = load_from_home(path);
+        self.server = load_from_home(&path.unwrap(), &SERVER_SCHEMA);
     }
 }
 
@@ -125,7 +293,7 @@ crate fn default_client_knock() -> Option<Vec<u8>> {
 crate fn peer_knock(peer: &str) -> Option<Vec<u8>> {
     let table = match crate::arg::mode().as_str() {
         "server" => client(peer),
-        "client" | "copy" => server(peer),
+        "client" | "copy" | "bench" => server(peer),
         _ => None,
     };
     Some(::data_encoding::BASE32_NOPAD.decode(table?.get("knock")?.as_str()?.as_bytes()).ok()?)
@@ -134,7 +302,7 @@ crate fn peer_knock(peer: &str) -> Option<Vec<u8>> {
 crate fn default_knock() -> Option<Vec<u8>> {
     match crate::arg::mode().as_str() {
         "server" => default_server_knock(),
-        "client" | "copy" => default_client_knock(),
+        "client" | "copy" | "bench" => default_client_knock(),
         _ => None,
     }
 }
@@ -144,19 +312,28 @@ crate fn default_asymmetric_key() -> Option<Vec<u8>> {
         "server" | "reexec" => ::data_encoding::BASE32_NOPAD
             .decode(CONF.server.as_ref()?.as_table()?.get("privkey")?.as_str()?.as_bytes())
             .ok(),
-        "client" | "copy" => ::data_encoding::BASE32_NOPAD
+        "client" | "copy" | "bench" => ::data_encoding::BASE32_NOPAD
             .decode(CONF.client.as_ref()?.as_table()?.get("privkey")?.as_str()?.as_bytes())
             .ok(),
         _ => None,
     }
 }
 
+crate fn next_asymmetric_key() -> Option<Vec<u8>> {
+    match crate::arg::mode().as_str() {
+        "server" | "reexec" => ::data_encoding::BASE32_NOPAD
+            .decode(CONF.server.as_ref()?.as_table()?.get("next_privkey")?.as_str()?.as_bytes())
+            .ok(),
+        _ => None,
+    }
+}
+
 crate fn peer_asymmetric_key(peer: &str) -> Option<Vec<u8>> {
     match crate::arg::mode().as_str() {
         "server" | "reexec" => ::data_encoding::BASE32_NOPAD
             .decode(client(peer)?.get("privkey")?.as_str()?.as_bytes())
             .ok(),
-        "client" | "copy" => ::data_encoding::BASE32_NOPAD
+        "client" | "copy" | "bench" => ::data_encoding::BASE32_NOPAD
             .decode(server(peer)?.get("privkey")?.as_str()?.as_bytes())
             .ok(),
         _ => None,
@@ -166,7 +343,7 @@ crate fn peer_asymmetric_key(peer: &str) -> Option<Vec<u8>> {
 crate fn peer_static_key(peer: &str) -> Option<Vec<u8>> {
     match crate::arg::mode().as_str() {
         "server" | "reexec" => ::data_encoding::BASE32_NOPAD.decode(client(peer)?.get("psk")?.as_str()?.as_bytes()).ok(),
-        "client" | "copy" => ::data_encoding::BASE32_NOPAD.decode(server(peer)?.get("psk")?.as_str()?.as_bytes()).ok(),
+        "client" | "copy" | "bench" => ::data_encoding::BASE32_NOPAD.decode(server(peer)?.get("psk")?.as_str()?.as_bytes()).ok(),
         _ => None,
     }
 }
@@ -176,7 +353,7 @@ crate fn peer_public_key(peer: &str) -> Option<Vec<u8>> {
         "server" | "reexec" => ::data_encoding::BASE32_NOPAD
             .decode(client(peer)?.get("pubkey")?.as_str()?.as_bytes())
             .ok(),
-        "client" | "copy" => ::data_encoding::BASE32_NOPAD
+        "client" | "copy" | "bench" => ::data_encoding::BASE32_NOPAD
             .decode(server(peer)?.get("pubkey")?.as_str()?.as_bytes())
             .ok(),
         _ => None,
@@ -188,7 +365,7 @@ crate fn default_static_key() -> Option<Vec<u8>> {
         "server" | "reexec" => ::data_encoding::BASE32_NOPAD
             .decode(CONF.server.as_ref()?.as_table()?.get("psk")?.as_str()?.as_bytes())
             .ok(),
-        "client" | "copy" => ::data_encoding::BASE32_NOPAD
+        "client" | "copy" | "bench" => ::data_encoding::BASE32_NOPAD
             .decode(CONF.client.as_ref()?.as_table()?.get("psk")?.as_str()?.as_bytes())
             .ok(),
         _ => None,
@@ -232,6 +409,34 @@ crate fn get_setuser(peer: &str) -> Option<String> {
     Some(client(peer)?.get("setuser")?.as_str()?.to_string())
 }
 
+/// The users a client may log in as with --user under --su-mode: its setuser
+/// and anything listed in its allowed_users.
+crate fn allowed_users(peer: &str) -> Vec<String> {
+    let mut users: Vec<String> = get_setuser(peer).into_iter().collect();
+    if let Some(Array(allowed)) = client(peer).and_then(|x| x.get("allowed_users").cloned()) {
+        users.extend(allowed.iter().filter_map(|x| x.as_str()).map(|x| x.to_string()));
+    }
+    users
+}
+
+/// The metacommands an alias in the client config's [aliases] table stands
+/// for, one per line. An alias may also be an array with one command in each
+/// element.
+crate fn metacommand_alias(name: &str) -> Option<String> {
+    let aliases = match &CONF.client {
+        Some(Table(table)) => table.get("aliases")?.as_table()?,
+        _ => return None,
+    };
+    match aliases.get(name)? {
+        toml::Value::String(commands) => Some(commands.clone()),
+        Array(commands) => Some(commands.iter().filter_map(|x| x.as_str()).collect::<Vec<&str>>().join("\n")),
+        _ => {
+            warn!("Ignoring alias {:?}, which isn't a string or an array of strings", name);
+            None
+        }
+    }
+}
+
 crate fn client_identity_for_peer(peer: &str) -> Option<&'static str> {
     debug!("Trying to load a client identity for {}", peer);
     match &CONF.client {
@@ -268,11 +473,18 @@ fn host_part<'a>(dest: &'a str) -> &'a str {
     if dest.starts_with('[') {
         return dest.splitn(2, '[').nth(1).unwrap().splitn(2, ']').next().unwrap();
     }
+    if dest.matches(':').count() > 1 {
+        // A bare IPv6 address, possibly with a zone, like fe80::1%eth0.
+        return dest;
+    }
     dest.splitn(2, ':').next().unwrap()
 }
 
 fn port_part(dest: &str) -> Option<u16> {
-    if !dest.contains(':') {
+    if dest.starts_with('[') {
+        return dest.splitn(2, "]:").nth(1)?.parse().ok();
+    }
+    if dest.matches(':').count() != 1 {
         return None;
     }
     dest.splitn(2, ':').nth(1).unwrap().parse().ok()
@@ -360,6 +572,20 @@ crate fn server(server: &str) -> Option<BTreeMap<String, toml::Value>> {
     servers().get(server).map(|x| x.clone())
 }
 
+/// The client config entry for a server, as a `[[servers]]` block with its
+/// secrets redacted, for --print-connection-string.
+crate fn redacted_server_entry(name: &str) -> Option<String> {
+    let mut entry = server(name)?;
+    for key in &["identity", "privkey", "psk", "knock"] {
+        if entry.contains_key(*key) {
+            entry.insert(key.to_string(), toml::Value::String("<redacted>".to_string()));
+        }
+    }
+    let mut root = BTreeMap::new();
+    root.insert("servers".to_string(), Array(vec![Table(entry)]));
+    toml::to_string(&Table(root)).ok()
+}
+
 crate fn pubkey_for_client(client: &str) -> Option<Vec<u8>> {
     let clients = clients();
     let client = clients.get(client)?;
@@ -391,6 +617,35 @@ crate fn host_for_dest(dest: &str) -> String {
     entry.as_str().unwrap().to_string()
 }
 
+/// The hops a [[servers]] entry says it's reached through, in the same order
+/// as --via: the one nearest the server first.
+crate fn via_for_dest(dest: &str) -> Vec<String> {
+    strings_for_dest(dest, "via")
+}
+
+/// The forwards a [[servers]] entry sets up by default, under local_forwards,
+/// remote_forwards or socks, each written as its -L, -R or -D would be.
+crate fn forwards_for_dest(dest: &str, key: &str) -> Vec<String> {
+    strings_for_dest(dest, key)
+}
+
+/// A [[servers]] entry's key that holds a string or an array of strings.
+fn strings_for_dest(dest: &str, key: &str) -> Vec<String> {
+    let table = match server(dest) {
+        Some(table) => table,
+        None => return Vec::new(),
+    };
+    match table.get(key) {
+        None => Vec::new(),
+        Some(toml::Value::String(value)) => vec![value.clone()],
+        Some(Array(values)) => values.iter().filter_map(|x| x.as_str()).map(|x| x.to_string()).collect(),
+        Some(_) => {
+            warn!("Ignoring {} for {:?}, which isn't a string or an array of strings", key, dest);
+            Vec::new()
+        }
+    }
+}
+
 fn conf_port_for_dest(dest: &str) -> Option<u16> {
     if let Some(table) = server(dest) {
         let port = table.get("port");
@@ -415,7 +670,13 @@ fn conf_port_for_dest(dest: &str) -> Option<u16> {
     None
 }
 
+/// The port to reach a destination on: a --port given on the command line,
+/// then the port in its [[servers]] entry, then one in the destination itself,
+/// then 2600.
 crate fn port_for_dest(dest: &str) -> u16 {
+    if let Some(port) = crate::arg::explicit_port(dest) {
+        return port;
+    }
     let port = conf_port_for_dest(dest);
     if port.is_some() {
         return port.unwrap();
@@ -430,9 +691,12 @@ crate fn port_for_dest(dest: &str) -> u16 {
 crate fn canonicalize_destination(dest: &str) -> String {
     let table = server(dest);
     if table.is_none() {
-        let port = port_part(dest);
+        let port = port_for_dest(dest);
         let host = host_part(dest);
-        return format!("{}:{}", host, port.unwrap_or(2600));
+        if host.contains(':') {
+            return format!("[{}]:{}", host, port);
+        }
+        return format!("{}:{}", host, port);
     }
     let host = host_for_dest(dest);
     let port = port_for_dest(dest);
@@ -442,18 +706,19 @@ crate fn canonicalize_destination(dest: &str) -> String {
 crate fn locate_destination(dest: &str) -> Vec<SocketAddr> {
     let host = host_for_dest(dest);
     let port = port_for_dest(dest);
-    let result = (host.as_str(), port).to_socket_addrs();
-    if result.is_err() {
-        return Vec::new();
-    }
-    return result.unwrap().collect();
+    crate::util::resolve_host(&host, port).unwrap_or_default()
+}
+
+crate fn is_loopback_destination(dest: &str) -> bool {
+    let addrs = locate_destination(dest);
+    !addrs.is_empty() && addrs.iter().all(|x| x.ip().is_loopback())
 }
 
 crate fn identity() -> Option<&'static str> {
     match crate::arg::mode().as_str() {
         "server" => server_identity(),
         "serve-one" => server_identity(),
-        "client" => client_identity(),
+        "client" | "bench" => client_identity(),
         _ => None,
     }
 }
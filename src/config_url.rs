@@ -0,0 +1,99 @@
+// --config-url: fetching client.conf or server.conf from an HTTPS endpoint at
+// startup, for fleets whose config is managed centrally rather than baked into
+// images. The text is parsed exactly as the file would be. The fetch itself is
+// done by running curl, rather than linking an HTTP and TLS stack into oxy.
+//
+// The endpoint is trusted as much as the file it replaces: whoever controls it,
+// or can get a certificate for its name, decides which keys this oxy trusts.
+// So only https URLs are accepted, redirects may not leave https, certificates
+// are checked against the system's roots, and a failed fetch is fatal. With
+// --allow-stale-config, the last copy kept in --config-cache is used instead,
+// which also trusts whoever can write that file.
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_REDIRECTS: usize = 10;
+
+/// The config text at url. Exits if it can't be fetched and there's no cached
+/// copy to fall back on.
+crate fn fetch(url: &str) -> String {
+    let matches = crate::arg::matches();
+    let cache = matches.value_of("config cache");
+    let err = match get(url) {
+        Ok(text) => {
+            if let Some(cache) = cache {
+                if let Err(err) = write_cache(cache, &text) {
+                    warn!("Failed to write the config cache {}: {}", cache, err);
+                }
+            }
+            return text;
+        }
+        Err(err) => err,
+    };
+    if matches.is_present("allow stale config") {
+        let cache = cache.unwrap();
+        match fs::read_to_string(cache) {
+            Ok(text) => {
+                warn!("Failed to fetch {}: {}. Using the cached copy in {}.", url, err, cache);
+                return text;
+            }
+            Err(cache_err) => warn!("Failed to read the config cache {}: {}", cache, cache_err),
+        }
+    }
+    crate::exit::fail(crate::exit::OxyError::Config(format!("Failed to fetch {}: {}", url, err)))
+}
+
+/// Replaces the cache with text. The cache may hold private keys, so it's
+/// readable only by its owner, and it's written to a temporary file that's
+/// renamed over it, so that a reader never sees half of it.
+fn write_cache(cache: &str, text: &str) -> io::Result<()> {
+    let temp = format!("{}.{}.tmp", cache, ::std::process::id());
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let result = options.open(&temp).and_then(|mut file| {
+        file.write_all(text.as_bytes())?;
+        file.sync_all()
+    });
+    if let Err(err) = result.and_then(|_| fs::rename(&temp, cache)) {
+        fs::remove_file(&temp).ok();
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Fetches url with curl. --proto and --proto-redir keep it, and any redirect,
+/// on https, and --fail turns an error status into a failed fetch.
+fn get(url: &str) -> Result<String, String> {
+    debug!("Fetching config from {}", url);
+    let output = Command::new("curl")
+        .args(&["--silent", "--show-error", "--fail", "--location"])
+        .args(&["--proto", "=https", "--proto-redir", "=https"])
+        .arg(format!("--max-redirs={}", MAX_REDIRECTS))
+        .arg(format!("--max-time={}", FETCH_TIMEOUT.as_secs()))
+        .arg("--")
+        .arg(url)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|x| format!("couldn't run curl: {}", x))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stderr = stderr.trim();
+        if stderr.is_empty() {
+            return Err(format!("curl {}", output.status));
+        }
+        return Err(stderr.to_string());
+    }
+    String::from_utf8(output.stdout).map_err(|_| "the config isn't valid UTF-8".to_string())
+}
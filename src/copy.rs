@@ -1,18 +1,20 @@
 use crate::{client, core::Oxy, message::OxyMessage::*};
 #[allow(unused_imports)]
 use log::{debug, error, info, log, trace, warn};
+use serde_derive::Serialize;
+use serde_json::{self, json};
+use shlex;
 use std::{
     cell::RefCell,
-    collections::HashMap,
-    fs::{metadata, read_dir, File},
-    io::{Read, Write},
-    path::PathBuf,
+    collections::{HashMap, HashSet},
+    fs::{metadata, read_dir, symlink_metadata, File, Metadata, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
     rc::Rc,
-    time::Instant,
+    time::{Instant, SystemTime},
 };
-use transportation;
-
-const PEER_TO_PEER_BUFFER_AMT: u64 = 1024 * 1024;
+use transportation::{self, BufferedTransport, Notifies};
 
 crate fn run() -> ! {
     CopyManager::create();
@@ -29,11 +31,76 @@ struct CopyManagerInternal {
     connections:           RefCell<HashMap<String, Oxy>>,
     destination:           RefCell<String>,
     sources:               RefCell<Vec<String>>,
-    synthetic_sources:     RefCell<Vec<(Option<String>, String, String)>>,
+    synthetic_sources:     RefCell<Vec<Source>>,
     auth_ticker:           RefCell<u64>,
     progress:              RefCell<u64>,
     throughput_total:      RefCell<u64>,
     throughput_total_time: RefCell<Option<Instant>>,
+    archive_failed:        RefCell<bool>,
+    full_copies:           RefCell<HashSet<PathBuf>>,
+    checked:               RefCell<HashSet<Source>>,
+    streaming:             RefCell<bool>,
+    current:               RefCell<Option<Source>>,
+    retried:               RefCell<HashSet<Source>>,
+    verify_failed:         RefCell<bool>,
+    started:               RefCell<Option<Instant>>,
+    file_started:          RefCell<Option<Instant>>,
+    file_bytes:            RefCell<u64>,
+    reports:               RefCell<Vec<FileReport>>,
+    direct_failed:         RefCell<HashSet<String>>,
+    current_mode:          RefCell<Option<u32>>,
+    real_paths:            RefCell<HashMap<Source, String>>,
+    chains:                RefCell<HashMap<Source, Vec<PathBuf>>>,
+    directory_modes:       RefCell<Vec<(FileRef, u32)>>,
+}
+
+/// A file's entry in the --json summary. status is one of "transferred",
+/// "skipped" or "failed". checksum is "algorithm:hex digest", for files
+/// checked with --verify.
+#[derive(Serialize)]
+struct FileReport {
+    source:      String,
+    destination: String,
+    status:      &'static str,
+    bytes:       u64,
+    seconds:     f64,
+    checksum:    Option<String>,
+}
+
+/// A source file or directory: its peer, if it's remote, the directory it's
+/// relative to, and its path relative to that.
+type Source = (Option<String>, String, String);
+
+/// A file on either end of a copy, for comparing a source with its destination.
+#[derive(Clone)]
+enum FileRef {
+    Local(PathBuf),
+    Remote(Oxy, String),
+}
+
+/// The block size for --update. Only blocks whose digests differ between the
+/// source and an existing destination are transferred.
+const DELTA_BLOCK_SIZE: u64 = 64 * 1024;
+
+/// The smallest range worth giving a --streams download of its own.
+const MIN_STREAM_LEN: u64 = 1024 * 1024;
+
+/// A file being brought up to date by --update, one changed block at a time.
+#[derive(Clone)]
+struct DeltaTransfer {
+    connection:   Oxy,
+    remote_path:  String,
+    local:        Rc<RefCell<File>>,
+    blocks:       Rc<RefCell<Vec<u64>>>,
+    total_blocks: usize,
+    len:          u64,
+    file_name:    String,
+}
+
+/// Where an --archive stream goes. Writing an empty chunk ends the archive.
+struct ArchiveSink {
+    connection: Option<Oxy>,
+    write:      Rc<dyn Fn(&[u8])>,
 }
 
 impl CopyManager {
@@ -45,7 +112,12 @@ impl CopyManager {
 
     fn init(&self) {
         *self.i.progress.borrow_mut() = 1001;
+        *self.i.started.borrow_mut() = Some(Instant::now());
         let mut locations: Vec<String> = crate::arg::matches().values_of("location").unwrap().map(|x| x.to_string()).collect();
+        let follow = crate::arg::matches().is_present("follow");
+        if follow && locations.len() == 1 {
+            locations.push("-".to_string());
+        }
         if locations.len() < 2 {
             error!("Must provide at least two locations (a source and a destination)");
             ::std::process::exit(1);
@@ -53,6 +125,30 @@ impl CopyManager {
         let len = locations.len();
         let destination = locations.remove(len - 1);
         let sources = locations;
+        let dashes = sources.iter().chain(Some(&destination)).filter(|x| *x == "-").count();
+        if dashes > 0 {
+            if dashes > 1 || sources.len() > 1 {
+                error!("- (stdin or stdout) can only be used with exactly one source and one destination");
+                ::std::process::exit(1);
+            }
+            for (name, flag) in &[
+                ("archive", "--archive"),
+                ("update", "--update"),
+                ("skip existing", "--skip-existing"),
+                ("checksum", "--checksum"),
+                ("json", "--json"),
+            ] {
+                if crate::arg::matches().is_present(name) {
+                    error!("{} can't be used when copying from stdin or to stdout", flag);
+                    ::std::process::exit(1);
+                }
+            }
+            *self.i.streaming.borrow_mut() = true;
+        }
+        if follow && (destination != "-" || get_peer(&sources[0]).is_none()) {
+            error!("--follow takes one remote file, and writes what's appended to it to stdout");
+            ::std::process::exit(1);
+        }
         for source in &sources {
             if let Some(dest) = get_peer(source) {
                 self.create_connection(dest);
@@ -61,13 +157,178 @@ impl CopyManager {
         if let Some(dest) = get_peer(&destination) {
             self.create_connection(dest);
         }
+        if crate::arg::matches().is_present("json") {
+            let proxy = self.clone();
+            crate::exit::push_hook(move || proxy.print_summary());
+        }
         *self.i.destination.borrow_mut() = destination;
         *self.i.sources.borrow_mut() = sources;
         if self.i.connections.borrow().is_empty() {
+            self.start();
+        }
+    }
+
+    fn start(&self) {
+        if *self.i.streaming.borrow() {
+            let source = self.i.sources.borrow()[0].clone();
+            let destination = self.i.destination.borrow().clone();
+            if crate::arg::matches().is_present("follow") {
+                self.follow_to_stdout(&source);
+            } else if source == "-" {
+                self.stream_from_stdin(&destination);
+            } else {
+                self.stream_to_stdout(&source);
+            }
+        } else if crate::arg::matches().is_present("archive") {
+            self.tick_archive();
+        } else {
             self.tick_transfers();
         }
     }
 
+    /// Copies stdin to a single destination file, for `oxy copy - host:file`.
+    fn stream_from_stdin(&self, destination: &str) {
+        let path = PathBuf::from(get_path(destination));
+        let peer = get_peer(destination);
+        if peer.is_none() {
+            let result = File::create(&path).and_then(|mut file| ::std::io::copy(&mut ::std::io::stdin(), &mut file));
+            if let Err(err) = result {
+                error!("Failed to write {:?}: {}", path, err);
+                ::std::process::exit(1);
+            }
+            info!("Finished!");
+            ::std::process::exit(0);
+        }
+        let connection = self.i.connections.borrow().get(peer.unwrap()).unwrap().clone();
+        if path.file_name().is_none() {
+            error!("Copying from stdin needs a destination file name, got {:?}", destination);
+            ::std::process::exit(1);
+        }
+        let id = connection.send(UploadRequest {
+            path:         path.parent().and_then(|x| x.to_str()).unwrap_or("").to_string(),
+            filepart:     path.file_name().unwrap().to_str().unwrap().to_string(),
+            offset_start: None,
+        });
+        connection.clone().watch(Rc::new(move |message, _| match message {
+            Success { reference } if *reference == id => {
+                debug!("Upload request accepted, sending stdin");
+                let stdin = Rc::new(BufferedTransport::from(0));
+                let finished = Rc::new(RefCell::new(false));
+                let pump = {
+                    let connection = connection.clone();
+                    let stdin = stdin.clone();
+                    Rc::new(move || {
+                        if *finished.borrow() || !connection.has_write_space() {
+                            return;
+                        }
+                        let closed = stdin.is_closed();
+                        let available = stdin.available().min(crate::arg::channel_max_packet());
+                        if available == 0 && !closed {
+                            return;
+                        }
+                        let data = stdin.take_chunk(available).unwrap();
+                        let done = data.is_empty();
+                        let send_id = connection.send(FileData { reference: id, data });
+                        if done {
+                            *finished.borrow_mut() = true;
+                            connection.watch(Rc::new(move |message, _| match message {
+                                Success { reference } if *reference == send_id => {
+                                    info!("Finished!");
+                                    ::std::process::exit(0);
+                                }
+                                Reject { reference, note } if *reference == send_id => {
+                                    error!("Upload failed: {:?}", note);
+                                    ::std::process::exit(1);
+                                }
+                                _ => false,
+                            }));
+                        }
+                    })
+                };
+                let notify = pump.clone();
+                stdin.set_notify(Rc::new(move || (notify)()));
+                connection.push_send_hook(Rc::new(move || {
+                    (pump)();
+                    false
+                }));
+                true
+            }
+            Reject { reference, note } if *reference == id => {
+                error!("Upload request failed: {:?}", note);
+                ::std::process::exit(1);
+            }
+            _ => false,
+        }));
+    }
+
+    /// Copies a single source file to stdout, for `oxy copy host:file -`.
+    fn stream_to_stdout(&self, source: &str) {
+        let path = get_path(source).to_string();
+        let peer = get_peer(source);
+        if peer.is_none() {
+            let result = File::open(&path).and_then(|mut file| ::std::io::copy(&mut file, &mut ::std::io::stdout()));
+            if let Err(err) = result {
+                error!("Failed to read {:?}: {}", path, err);
+                ::std::process::exit(1);
+            }
+            ::std::process::exit(0);
+        }
+        let connection = self.i.connections.borrow().get(peer.unwrap()).unwrap().clone();
+        let id = connection.send(DownloadRequest {
+            path:         path.clone(),
+            offset_start: None,
+            offset_end:   None,
+        });
+        connection.watch(Rc::new(move |message, _| match message {
+            FileData { reference, data } if *reference == id => {
+                let stdout = ::std::io::stdout();
+                let mut lock = stdout.lock();
+                if data.is_empty() {
+                    lock.flush().ok();
+                    info!("Finished!");
+                    ::std::process::exit(0);
+                }
+                if lock.write_all(&data[..]).is_err() {
+                    error!("Failed to write to stdout");
+                    ::std::process::exit(1);
+                }
+                false
+            }
+            Reject { reference, note } if *reference == id => {
+                error!("Failed to read {:?}: {:?}", path, note);
+                ::std::process::exit(1);
+            }
+            _ => false,
+        }));
+    }
+
+    /// Writes data appended to a remote file to stdout until interrupted, for
+    /// `oxy copy --follow host:file`.
+    fn follow_to_stdout(&self, source: &str) {
+        let path = get_path(source).to_string();
+        let connection = self.i.connections.borrow().get(get_peer(source).unwrap()).unwrap().clone();
+        let id = connection.send(FollowRequest { path: path.clone(), offset_start: None });
+        connection.watch(Rc::new(move |message, _| match message {
+            Success { reference } if *reference == id => {
+                info!("Following {:?}", path);
+                false
+            }
+            FileData { reference, data } if *reference == id => {
+                let stdout = ::std::io::stdout();
+                let mut lock = stdout.lock();
+                if lock.write_all(&data[..]).and_then(|_| lock.flush()).is_err() {
+                    ::std::process::exit(0);
+                }
+                false
+            }
+            Reject { reference, note } if *reference == id => {
+                error!("Failed to follow {:?}: {:?}", path, note);
+                ::std::process::exit(1);
+            }
+            _ => false,
+        }));
+    }
+
     fn create_connection(&self, peer: &str) {
         if self.i.connections.borrow().contains_key(peer) {
             return;
@@ -84,12 +345,14 @@ impl CopyManager {
 
     fn print_progress(&self, progress: u64, filename: &str, bytes: u64) {
         let prev = *self.i.progress.borrow();
+        let mut data = Vec::new();
         if progress < prev {
-            print!("\n\n");
+            data.extend(b"\n\n");
             *self.i.throughput_total.borrow_mut() = 0;
             *self.i.throughput_total_time.borrow_mut() = Some(Instant::now());
         }
         *self.i.progress.borrow_mut() = progress;
+        *self.i.file_bytes.borrow_mut() += bytes;
 
         let total_seconds = self.i.throughput_total_time.borrow_mut().map(|x| x.elapsed().as_secs()).unwrap_or(0);
         *self.i.throughput_total.borrow_mut() += bytes;
@@ -111,13 +374,20 @@ impl CopyManager {
             line2.remove(len - 1);
             line2.push('>');
         }
-        let mut data = Vec::new();
         data.extend(b"\x1b[2A"); // Move up two lines
         data.extend(b"\x1b[0K"); // Clear the line
         data.extend(line1.as_bytes());
         data.extend(b"\n\x1b[0K");
         data.extend(line2.as_bytes());
         data.extend(b"\n");
+        // Under --json, stdout is for the summary.
+        if crate::arg::matches().is_present("json") {
+            let stderr = ::std::io::stderr();
+            let mut lock = stderr.lock();
+            lock.write_all(&data[..]).unwrap();
+            lock.flush().unwrap();
+            return;
+        }
         let stdout = ::std::io::stdout();
         let mut lock = stdout.lock();
         lock.write_all(&data[..]).unwrap();
@@ -132,17 +402,45 @@ impl CopyManager {
         path.push(tailbuf);
         let path = path.to_str().unwrap().to_string();
         let path = path.trim_right_matches('/').to_string();
+        // A link that's being followed is read from where it points.
+        let source = (Some(peer.clone()), head.clone(), tail.clone());
+        let path = self.i.real_paths.borrow().get(&source).cloned().unwrap_or(path);
         info!("Processing {:?}", path);
         let id = connection.send(StatRequest { path: path.clone() });
         let proxy = self.clone();
         connection.clone().watch(Rc::new(move |message, _| match message {
-            StatResult { reference, is_dir, len, .. } if *reference == id => {
+            StatResult {
+                reference,
+                is_dir,
+                is_file,
+                len,
+                mtime,
+                octal_permissions,
+                ..
+            } if *reference == id =>
+            {
                 let head = head.clone();
                 let tail = tail.clone();
                 let path = path.clone();
                 let len = *len;
                 debug!("Got stat result: {:?}", message);
+                if !*is_dir && !*is_file {
+                    proxy.remote_link(connection.clone(), source.clone(), path);
+                    return true;
+                }
                 if *is_dir {
+                    if !crate::arg::matches().is_present("recursive") {
+                        warn!("{:?} is a directory, pass -r to copy it", path);
+                        proxy.transfer_failed();
+                        return true;
+                    }
+                    let real = proxy.i.real_paths.borrow().get(&source).map(PathBuf::from);
+                    if !proxy.enter_directory(&source, real) {
+                        proxy.report("skipped", None);
+                        proxy.tick_transfers();
+                        return true;
+                    }
+                    proxy.create_directory(&tail, Some(u32::from(*octal_permissions)).filter(|x| *x != 0));
                     let id = connection.send(ReadDir { path: path.clone() });
                     let proxy = proxy.clone();
                     let peer = peer.clone();
@@ -168,6 +466,14 @@ impl CopyManager {
                         _ => false,
                     }));
                 } else {
+                    if *octal_permissions != 0 {
+                        *proxy.i.current_mode.borrow_mut() = Some(u32::from(*octal_permissions));
+                    }
+                    let source = (Some(peer.clone()), head.clone(), tail.clone());
+                    if proxy.needs_check(&source) {
+                        proxy.check_destination(source, FileRef::Remote(connection.clone(), path.clone()), len, *mtime);
+                        return true;
+                    }
                     let dest_peer = get_peer(&proxy.i.destination.borrow().clone()).map(|x| x.to_string());
                     let dest_path = get_path(&proxy.i.destination.borrow().clone()).to_string();
                     if let Some(dest_peer) = dest_peer {
@@ -210,10 +516,11 @@ impl CopyManager {
                                         return true;
                                     }
                                     if download_id.borrow().is_none() && dest_connection.has_write_space() {
-                                        let end = if len - *written.borrow() <= PEER_TO_PEER_BUFFER_AMT {
+                                        let window = crate::arg::channel_window() as u64;
+                                        let end = if len - *written.borrow() <= window {
                                             None
                                         } else {
-                                            Some(*written.borrow() + PEER_TO_PEER_BUFFER_AMT)
+                                            Some(*written.borrow() + window)
                                         };
                                         debug!("Initiating a peer-to-peer chunk-download {:?}", end);
                                         *download_id.borrow_mut() = Some(source_connection.send(DownloadRequest {
@@ -250,7 +557,7 @@ impl CopyManager {
                                                     dest_connection.watch(Rc::new(move |message, _| match message {
                                                         Success { reference } if *reference == send_id => {
                                                             info!("Transfer complete.");
-                                                            proxy.tick_transfers();
+                                                            proxy.transfer_finished();
                                                             *done.borrow_mut() = true;
                                                             return true;
                                                         }
@@ -262,7 +569,7 @@ impl CopyManager {
                                             }
                                             Reject { reference, note } if *reference == download_id.borrow().unwrap() => {
                                                 warn!("Error retrieving file: {:?}", note);
-                                                proxy.tick_transfers();
+                                                proxy.transfer_failed();
                                                 *done.borrow_mut() = true;
                                                 return true;
                                             }
@@ -275,12 +582,25 @@ impl CopyManager {
                             }
                             Reject { reference, note } if *reference == upload_id => {
                                 warn!("Upload request failed: {:?}", note);
-                                proxy.tick_transfers();
+                                proxy.transfer_failed();
                                 return true;
                             }
                             _ => false,
                         }));
                     } else {
+                        let mut delta_dest = PathBuf::from(proxy.i.destination.borrow().clone());
+                        delta_dest.push(&tail);
+                        if delta_dest.is_file() && proxy.use_delta(&delta_dest) {
+                            proxy.delta_download(connection.clone(), (Some(peer.clone()), head, tail), path, delta_dest);
+                            return true;
+                        }
+                        let streams = crate::arg::matches().value_of("streams").map(|x| x.parse().unwrap()).unwrap_or(1);
+                        if streams > 1 && len >= 2 * MIN_STREAM_LEN {
+                            let mut dest = PathBuf::from(proxy.i.destination.borrow().clone());
+                            dest.push(&tail);
+                            proxy.parallel_download(connection.clone(), path, dest, len, streams);
+                            return true;
+                        }
                         let id = connection.send(DownloadRequest {
                             path:         path.clone(),
                             offset_start: None,
@@ -295,7 +615,7 @@ impl CopyManager {
                         let file = File::create(&dest);
                         if file.is_err() {
                             warn!("Failed to create local file for writing: {:?}", dest);
-                            proxy.tick_transfers();
+                            proxy.transfer_failed();
                             return true;
                         }
                         let file = Rc::new(RefCell::new(file.unwrap()));
@@ -306,13 +626,13 @@ impl CopyManager {
                                 if data.is_empty() {
                                     proxy.print_progress(1000, &file_name, 0);
                                     info!("Transfer finished.");
-                                    proxy.tick_transfers();
+                                    proxy.transfer_finished();
                                     return true;
                                 }
                                 let result = file.borrow_mut().write_all(&data[..]);
                                 if result.is_err() {
                                     warn!("Error writing data to local file");
-                                    proxy.tick_transfers();
+                                    proxy.transfer_failed();
                                     return true;
                                 }
                                 *written.borrow_mut() += data.len() as u64;
@@ -322,7 +642,7 @@ impl CopyManager {
                             }
                             Reject { reference, note } if *reference == id => {
                                 warn!("Error reading file: {:?}", note);
-                                proxy.tick_transfers();
+                                proxy.transfer_failed();
                                 return true;
                             }
                             _ => false,
@@ -333,7 +653,7 @@ impl CopyManager {
             }
             Reject { reference, note } if *reference == id => {
                 warn!("Failed to stat remote file {:?}, {:?}", path, note);
-                proxy.tick_transfers();
+                proxy.transfer_failed();
                 return true;
             }
             _ => false,
@@ -344,21 +664,60 @@ impl CopyManager {
         let mut fullpath = PathBuf::from(&head);
         fullpath.push(PathBuf::from(&tail));
         info!("Trying to upload {:?}", fullpath);
-        let md = metadata(&fullpath);
-        if md.is_err() {
-            warn!("Failed to stat local file {:?}", fullpath);
-            self.tick_transfers();
+        let md = if follow_links() { metadata(&fullpath) } else { symlink_metadata(&fullpath) };
+        let md = match md {
+            Ok(md) => md,
+            Err(_) => {
+                warn!("Failed to stat local file {:?}", fullpath);
+                self.transfer_failed();
+                return;
+            }
+        };
+        if md.file_type().is_symlink() {
+            match ::std::fs::read_link(&fullpath) {
+                Ok(target) => self.copy_link(&tail, target.to_string_lossy().into_owned()),
+                Err(err) => {
+                    warn!("Failed to read symlink {:?}: {}", fullpath, err);
+                    self.transfer_failed();
+                }
+            }
+            return;
         }
-        let md = md.unwrap();
         if md.is_dir() {
-            for i in read_dir(&fullpath).unwrap() {
-                let i = i.unwrap();
-                let tail = format!("{}/{}", tail, i.file_name().into_string().unwrap());
+            if !crate::arg::matches().is_present("recursive") {
+                warn!("{:?} is a directory, pass -r to copy it", fullpath);
+                self.transfer_failed();
+                return;
+            }
+            if !self.enter_directory(&(None, head.clone(), tail.clone()), fullpath.canonicalize().ok()) {
+                self.report("skipped", None);
+                self.tick_transfers();
+                return;
+            }
+            self.create_directory(&tail, source_mode(&md));
+            let entries = match read_dir(&fullpath) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    warn!("Failed to list {:?}: {}", fullpath, err);
+                    self.transfer_failed();
+                    return;
+                }
+            };
+            for i in entries.filter_map(Result::ok) {
+                let mut tail = PathBuf::from(&tail);
+                tail.push(i.file_name());
+                let tail = tail.to_str().unwrap().to_string();
                 self.i.synthetic_sources.borrow_mut().push((None, head.clone(), tail));
             }
             self.tick_transfers();
             return;
         } else {
+            *self.i.current_mode.borrow_mut() = source_mode(&md);
+            let source = (None, head.clone(), tail.clone());
+            if self.needs_check(&source) {
+                self.check_destination(source, FileRef::Local(fullpath.clone()), md.len(), md.modified().ok());
+                return;
+            }
             let dest = self.i.destination.borrow().clone();
             let dest_peer = get_peer(&dest);
             let dest_path = get_path(&dest);
@@ -366,9 +725,18 @@ impl CopyManager {
                 let mut dest_path = PathBuf::from(dest_path);
                 dest_path.push(PathBuf::from(&tail));
                 ::std::fs::create_dir_all(dest_path.parent().unwrap()).ok();
-                ::std::fs::copy(&fullpath, &dest_path).unwrap();
+                let bytes = match ::std::fs::copy(&fullpath, &dest_path) {
+                    Ok(bytes) => bytes,
+                    Err(err) => {
+                        warn!("Failed to copy {:?}: {}", fullpath, err);
+                        self.transfer_failed();
+                        return;
+                    }
+                };
+                let file_name = fullpath.file_name().unwrap().to_str().unwrap().to_string();
+                self.print_progress(1000, &file_name, bytes);
                 info!("Uploaded {:?}", fullpath);
-                self.tick_transfers();
+                self.transfer_finished();
                 return;
             } else {
                 let dest_connection = {
@@ -378,6 +746,10 @@ impl CopyManager {
                 let proxy = self.clone();
                 let mut dest_path = PathBuf::from(&dest_path);
                 dest_path.push(PathBuf::from(&tail));
+                if self.use_delta(&fullpath) {
+                    self.delta_upload(dest_connection, (None, head, tail), fullpath, dest_path);
+                    return;
+                }
 
                 let file_name = fullpath.file_name().unwrap().to_str().unwrap().to_string();
                 let file = File::open(&fullpath);
@@ -388,6 +760,10 @@ impl CopyManager {
                 let len = file.metadata().unwrap().len();
                 let file = Rc::new(RefCell::new(file));
                 let written = Rc::new(RefCell::new(0));
+                let compress = dest_connection.should_compress(&fullpath);
+                if !compress {
+                    info!("Sending {:?} uncompressed, since it doesn't look compressible", fullpath);
+                }
 
                 let id = dest_connection.send(UploadRequest {
                     path:         dest_path.parent().unwrap().to_str().unwrap().to_string(),
@@ -404,14 +780,15 @@ impl CopyManager {
                         let proxy = proxy.clone();
                         dest_connection.clone().push_send_hook(Rc::new(move || {
                             if dest_connection.has_write_space() {
-                                let mut buf = [0u8; 8192];
+                                let mut buf = vec![0u8; crate::arg::channel_max_packet()];
                                 let result = file.borrow_mut().read(&mut buf);
                                 if result.is_err() {
                                     warn!("Failed to read file");
-                                    proxy.tick_transfers();
+                                    proxy.transfer_failed();
                                     return true;
                                 }
                                 let result = result.unwrap();
+                                dest_connection.set_outbound_compression(compress);
                                 let send_id = dest_connection.send(FileData {
                                     reference: id,
                                     data:      buf[..result].to_vec(),
@@ -420,11 +797,12 @@ impl CopyManager {
                                 let progress = if len != 0 { (*written.borrow() * 1000) / len } else { 1000 };
                                 proxy.print_progress(progress, &file_name, result as u64);
                                 if result == 0 {
+                                    dest_connection.set_outbound_compression(true);
                                     let proxy = proxy.clone();
                                     dest_connection.watch(Rc::new(move |message, _| match message {
                                         Success { reference } if *reference == send_id => {
                                             info!("Upload finished");
-                                            proxy.tick_transfers();
+                                            proxy.transfer_finished();
                                             return true;
                                         }
                                         _ => false,
@@ -439,7 +817,7 @@ impl CopyManager {
                     }
                     Reject { reference, note } if *reference == id => {
                         warn!("Upload request failed: {:?}", note);
-                        proxy.tick_transfers();
+                        proxy.transfer_failed();
                         return true;
                     }
                     _ => false,
@@ -450,8 +828,12 @@ impl CopyManager {
 
     fn tick_transfers(&self) {
         if self.i.sources.borrow().is_empty() && self.i.synthetic_sources.borrow().is_empty() {
+            if self.apply_directory_modes() {
+                return;
+            }
             info!("Finished!");
-            ::std::process::exit(0);
+            let failed = *self.i.verify_failed.borrow();
+            crate::exit::exit(if failed { crate::exit::EXIT_FAILURE } else { crate::exit::EXIT_OK });
         }
         *self.i.progress.borrow_mut() = 1001;
         let peer;
@@ -479,7 +861,14 @@ impl CopyManager {
                     .unwrap_or("".to_string());
             }
             peer = get_peer(&source).map(|x| x.to_string());
+            if peer.is_some() && self.copy_direct(&source, &head, &tail) {
+                return;
+            }
         }
+        *self.i.current.borrow_mut() = Some((peer.clone(), head.clone(), tail.clone()));
+        *self.i.file_started.borrow_mut() = Some(Instant::now());
+        *self.i.file_bytes.borrow_mut() = 0;
+        *self.i.current_mode.borrow_mut() = None;
         if let Some(peer) = peer {
             self.tick_remote_source(peer, head, tail);
         } else {
@@ -487,14 +876,1086 @@ impl CopyManager {
         }
     }
 
+    /// Under --remote-to-remote-direct, has the source's server copy a source to
+    /// the remote destination itself, with its own oxy and its own config for
+    /// the destination, instead of relaying the data through here. A source
+    /// that can't be copied that way, say because its server can't reach the
+    /// destination, is relayed after all.
+    fn copy_direct(&self, source: &str, head: &str, tail: &str) -> bool {
+        let destination = self.i.destination.borrow().clone();
+        if !crate::arg::matches().is_present("remote to remote direct")
+            || get_peer(&destination).is_none()
+            || self.i.direct_failed.borrow().contains(source)
+        {
+            return false;
+        }
+        let peer = get_peer(source).unwrap().to_string();
+        let connection = self.i.connections.borrow().get(&peer).unwrap().clone();
+        let matches = crate::arg::matches();
+        let mut flags = String::new();
+        for (name, flag) in &[
+            ("update", "--update"),
+            ("skip existing", "--skip-existing"),
+            ("checksum", "--checksum"),
+            ("recursive", "-r"),
+            ("dereference", "--dereference"),
+            ("verify", "--verify"),
+            ("streams", "--streams"),
+            ("compression", "--compress"),
+            ("compress level", "--compress-level"),
+        ] {
+            if matches.is_present(name) {
+                flags.push(' ');
+                flags.push_str(flag);
+                if let Some(value) = matches.value_of(name) {
+                    flags.push_str(&format!("={}", shlex::quote(value)));
+                }
+            }
+        }
+        if let Some(exts) = matches.values_of("no compress ext") {
+            for ext in exts {
+                flags.push_str(&format!(" --no-compress-ext={}", shlex::quote(ext)));
+            }
+        }
+        // The remote copy's output goes to stderr, leaving stdout for its
+        // exit status.
+        let command = format!(
+            "oxy copy{} -- {} {} >&2; echo $?",
+            flags,
+            shlex::quote(get_path(source)),
+            shlex::quote(&destination)
+        );
+        info!("Copying {} directly from {}", get_path(source), peer);
+        *self.i.current.borrow_mut() = Some((Some(peer.clone()), head.to_string(), tail.to_string()));
+        *self.i.file_started.borrow_mut() = Some(Instant::now());
+        *self.i.file_bytes.borrow_mut() = 0;
+        let reference = connection.send(PipeCommand { command });
+        let status = Rc::new(RefCell::new(Vec::new()));
+        let source = source.to_string();
+        let proxy = self.clone();
+        connection.clone().watch(Rc::new(move |message, _| match message {
+            PipeCommandOutput {
+                reference: output_reference,
+                stdout,
+                stderr,
+            } if *output_reference == reference =>
+            {
+                ::std::io::stderr().write_all(&stderr[..]).ok();
+                status.borrow_mut().extend(stdout);
+                connection.claim_message();
+                false
+            }
+            PipeCommandExited { reference: exited_reference } if *exited_reference == reference => {
+                connection.claim_message();
+                let status = String::from_utf8_lossy(&status.borrow()).trim().to_string();
+                if status == "0" {
+                    info!("Copied {} directly", source);
+                    proxy.report("transferred", None);
+                    proxy.tick_transfers();
+                } else {
+                    warn!("Direct copy of {} failed (status {:?}), relaying it instead", source, status);
+                    proxy.relay_instead(&source);
+                }
+                true
+            }
+            Reject { reference: rejected, note } if *rejected == reference => {
+                warn!("The server refused to copy {} directly ({:?}), relaying it instead", source, note);
+                proxy.relay_instead(&source);
+                true
+            }
+            _ => false,
+        }));
+        true
+    }
+
+    fn relay_instead(&self, source: &str) {
+        self.i.direct_failed.borrow_mut().insert(source.to_string());
+        self.i.sources.borrow_mut().insert(0, source.to_string());
+        self.tick_transfers();
+    }
+
     fn post_auth_hook(&self) {
         *self.i.auth_ticker.borrow_mut() += 1;
         if *self.i.auth_ticker.borrow() == self.i.connections.borrow().len() as u64 {
+            self.start();
+        }
+    }
+
+    /// Whether --skip-existing, --update or --checksum still has to compare
+    /// this source with its destination before copying it.
+    fn needs_check(&self, source: &Source) -> bool {
+        let matches = crate::arg::matches();
+        let mode = matches.is_present("skip existing") || matches.is_present("update") || matches.is_present("checksum");
+        mode && !self.i.checked.borrow().contains(source)
+    }
+
+    fn destination_file(&self, tail: &str) -> FileRef {
+        let dest = self.i.destination.borrow().clone();
+        let mut path = PathBuf::from(get_path(&dest));
+        path.push(tail);
+        match get_peer(&dest) {
+            Some(peer) => FileRef::Remote(self.i.connections.borrow().get(peer).unwrap().clone(), path.to_str().unwrap().to_string()),
+            None => FileRef::Local(path),
+        }
+    }
+
+    /// Decides whether a source file needs copying, and either skips it or
+    /// queues it again to be copied.
+    fn check_destination(&self, source: Source, origin: FileRef, len: u64, mtime: Option<SystemTime>) {
+        let dest = self.destination_file(&source.2);
+        let proxy = self.clone();
+        self.file_info(
+            &dest.clone(),
+            Rc::new(move |info| {
+                let matches = crate::arg::matches();
+                let skip = match info {
+                    None => Some(false),
+                    Some(_) if matches.is_present("skip existing") => Some(true),
+                    Some((_, dest_mtime)) if matches.is_present("update") => Some(mtime.is_some() && dest_mtime >= mtime),
+                    Some((dest_len, _)) if dest_len != len => Some(false),
+                    Some(_) if len == 0 => Some(true),
+                    Some(_) => None,
+                };
+                if let Some(skip) = skip {
+                    proxy.finish_check(source.clone(), skip);
+                    return;
+                }
+                let proxy = proxy.clone();
+                let source = source.clone();
+                let dest = dest.clone();
+                proxy.clone().file_digest(
+                    &origin,
+                    2,
+                    Rc::new(move |origin_digest| {
+                        let proxy = proxy.clone();
+                        let source = source.clone();
+                        proxy.clone().file_digest(
+                            &dest,
+                            2,
+                            Rc::new(move |dest_digest| {
+                                proxy.finish_check(source.clone(), origin_digest.is_some() && dest_digest == origin_digest);
+                            }),
+                        );
+                    }),
+                );
+            }),
+        );
+    }
+
+    fn finish_check(&self, source: Source, skip: bool) {
+        if skip {
+            info!("Skipping {:?}, which is already up to date", source.2);
+            self.report("skipped", None);
             self.tick_transfers();
+            return;
+        }
+        self.i.checked.borrow_mut().insert(source.clone());
+        self.i.synthetic_sources.borrow_mut().insert(0, source);
+        self.tick_transfers();
+    }
+
+    fn source_file(&self, source: &Source) -> FileRef {
+        let mut path = PathBuf::from(&source.1);
+        path.push(&source.2);
+        match &source.0 {
+            Some(peer) => {
+                let path = path.to_str().unwrap().trim_right_matches('/').to_string();
+                FileRef::Remote(self.i.connections.borrow().get(peer).unwrap().clone(), path)
+            }
+            None => FileRef::Local(path),
+        }
+    }
+
+    /// Moves on once the current file has been copied. With --verify, the
+    /// source and destination are both read back and their digests compared
+    /// first.
+    fn transfer_finished(&self) {
+        self.apply_mode();
+        let algorithm = crate::arg::verify_algorithm();
+        let source = self.i.current.borrow().clone();
+        if algorithm.is_none() || source.is_none() {
+            self.report("transferred", None);
+            self.tick_transfers();
+            return;
+        }
+        let algorithm = algorithm.unwrap();
+        let source = source.unwrap();
+        let origin = self.source_file(&source);
+        let dest = self.destination_file(&source.2);
+        let proxy = self.clone();
+        self.file_digest(
+            &origin.clone(),
+            algorithm,
+            Rc::new(move |origin_digest| {
+                let proxy = proxy.clone();
+                let source = source.clone();
+                let origin = origin.clone();
+                let dest = dest.clone();
+                proxy.clone().file_digest(
+                    &dest.clone(),
+                    algorithm,
+                    Rc::new(move |dest_digest| {
+                        proxy.finish_verify(source.clone(), &[&origin, &dest], origin_digest.clone(), dest_digest);
+                    }),
+                );
+            }),
+        );
+    }
+
+    /// Gives the file just copied its source's mode.
+    fn apply_mode(&self) {
+        let mode = self.i.current_mode.borrow_mut().take();
+        let source = self.i.current.borrow().clone();
+        let (mode, source) = match (mode, source) {
+            (Some(mode), Some(source)) => (mode, source),
+            _ => return,
+        };
+        match self.destination_file(&source.2) {
+            FileRef::Local(path) => set_local_mode(&path, mode),
+            FileRef::Remote(connection, path) => {
+                let id = connection.send(SetPermissionsRequest {
+                    path:              path.clone(),
+                    octal_permissions: mode as u16,
+                });
+                connection.clone().watch(Rc::new(move |message, _| match message {
+                    Success { reference } if *reference == id => true,
+                    Reject { reference, note } if *reference == id => {
+                        warn!("Failed to set the mode of {:?}: {:?}", path, note);
+                        true
+                    }
+                    _ => false,
+                }));
+            }
+        }
+    }
+
+    /// Under -r, creates a directory that's being walked at the destination,
+    /// so that empty directories are copied too. Its source's mode is only
+    /// applied once the copy is done, as a directory that isn't writable
+    /// would otherwise keep its own contents from being copied into it.
+    fn create_directory(&self, tail: &str, mode: Option<u32>) {
+        let dest = self.destination_file(tail);
+        match &dest {
+            FileRef::Local(path) => {
+                if let Err(err) = ::std::fs::create_dir_all(path) {
+                    warn!("Failed to create directory {:?}: {}", path, err);
+                }
+            }
+            FileRef::Remote(connection, path) => {
+                let id = connection.send(CreateDirectoryRequest { path: path.clone() });
+                let path = path.clone();
+                connection.clone().watch(Rc::new(move |message, _| match message {
+                    Success { reference } if *reference == id => true,
+                    Reject { reference, note } if *reference == id => {
+                        warn!("Failed to create directory {:?}: {:?}", path, note);
+                        true
+                    }
+                    _ => false,
+                }));
+            }
+        }
+        if let Some(mode) = mode {
+            self.i.directory_modes.borrow_mut().push((dest, mode));
+        }
+    }
+
+    /// Gives the directories copied their sources' modes, deepest first, now
+    /// that everything has been copied into them. Returns whether there are
+    /// replies still to wait for, after which the copy finishes.
+    fn apply_directory_modes(&self) -> bool {
+        let modes = ::std::mem::replace(&mut *self.i.directory_modes.borrow_mut(), Vec::new());
+        let pending = Rc::new(RefCell::new(0));
+        for (dest, mode) in modes.into_iter().rev() {
+            match dest {
+                FileRef::Local(path) => set_local_mode(&path, mode),
+                FileRef::Remote(connection, path) => {
+                    let id = connection.send(SetPermissionsRequest {
+                        path:              path.clone(),
+                        octal_permissions: mode as u16,
+                    });
+                    *pending.borrow_mut() += 1;
+                    let pending = pending.clone();
+                    let proxy = self.clone();
+                    connection.clone().watch(Rc::new(move |message, _| {
+                        match message {
+                            Success { reference } if *reference == id => (),
+                            Reject { reference, note } if *reference == id => {
+                                warn!("Failed to set the mode of {:?}: {:?}", path, note);
+                            }
+                            _ => return false,
+                        }
+                        *pending.borrow_mut() -= 1;
+                        if *pending.borrow() == 0 {
+                            proxy.tick_transfers();
+                        }
+                        true
+                    }));
+                }
+            }
+        }
+        let waiting = *pending.borrow() > 0;
+        waiting
+    }
+
+    /// A remote source that's neither a file nor a directory, which is most
+    /// likely a symlink. Unless links are followed, the link itself is copied.
+    /// Otherwise the source is queued again, to be read from where it points.
+    fn remote_link(&self, connection: Oxy, source: Source, path: String) {
+        let proxy = self.clone();
+        if !follow_links() {
+            let id = connection.send(ReadLinkRequest { path });
+            connection.clone().watch(Rc::new(move |message, _| match message {
+                ReadLinkResult { reference, target } if *reference == id => {
+                    proxy.copy_link(&source.2, target.clone());
+                    true
+                }
+                Reject { reference, .. } if *reference == id => {
+                    proxy.skip_special(&source.2);
+                    true
+                }
+                _ => false,
+            }));
+            return;
+        }
+        if self.i.real_paths.borrow().contains_key(&source) {
+            self.skip_special(&source.2);
+            return;
+        }
+        let id = connection.send(RealPathRequest { path });
+        connection.clone().watch(Rc::new(move |message, _| match message {
+            RealPathResult { reference, path } if *reference == id => {
+                proxy.i.real_paths.borrow_mut().insert(source.clone(), path.clone());
+                proxy.i.synthetic_sources.borrow_mut().insert(0, source.clone());
+                proxy.tick_transfers();
+                true
+            }
+            Reject { reference, note } if *reference == id => {
+                warn!("Failed to follow {:?}: {:?}", source.2, note);
+                proxy.transfer_failed();
+                true
+            }
+            _ => false,
+        }));
+    }
+
+    fn skip_special(&self, tail: &str) {
+        warn!("Skipping {:?}, which isn't a regular file, directory or symlink", tail);
+        self.report("skipped", None);
+        self.tick_transfers();
+    }
+
+    /// Recreates a symlink at the destination, pointing where the source's
+    /// does.
+    fn copy_link(&self, tail: &str, target: String) {
+        let name = PathBuf::from(tail).file_name().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default();
+        match self.destination_file(tail) {
+            FileRef::Local(path) => {
+                if let Some(parent) = path.parent() {
+                    ::std::fs::create_dir_all(parent).ok();
+                }
+                if symlink_metadata(&path).map(|x| !x.is_dir()).unwrap_or(false) {
+                    ::std::fs::remove_file(&path).ok();
+                }
+                if let Err(err) = create_symlink(&target, &path) {
+                    warn!("Failed to create symlink {:?}: {}", path, err);
+                    self.transfer_failed();
+                    return;
+                }
+                self.link_copied(&name, &target);
+            }
+            FileRef::Remote(connection, path) => {
+                let id = connection.send(SymlinkRequest {
+                    path,
+                    target: target.clone(),
+                });
+                let proxy = self.clone();
+                connection.clone().watch(Rc::new(move |message, _| match message {
+                    Success { reference } if *reference == id => {
+                        proxy.link_copied(&name, &target);
+                        true
+                    }
+                    Reject { reference, note } if *reference == id => {
+                        warn!("Failed to create symlink {:?}: {:?}", name, note);
+                        proxy.transfer_failed();
+                        true
+                    }
+                    _ => false,
+                }));
+            }
+        }
+    }
+
+    fn link_copied(&self, name: &str, target: &str) {
+        info!("Copied symlink {:?} -> {:?}", name, target);
+        self.print_progress(1000, name, 0);
+        self.report("transferred", None);
+        self.tick_transfers();
+    }
+
+    /// Under --dereference, notes that a directory is about to be walked, and
+    /// returns false if it shouldn't be: if it's a link back to a directory
+    /// that's already being walked, which would go on forever. real is the
+    /// directory's path with links resolved. Without it, the path is worked
+    /// out from the parent directory's.
+    fn enter_directory(&self, source: &Source, real: Option<PathBuf>) -> bool {
+        if !crate::arg::matches().is_present("dereference") {
+            return true;
+        }
+        let tail = PathBuf::from(&source.2);
+        let parent_tail = tail.parent().map(|x| x.to_str().unwrap().to_string()).unwrap_or_default();
+        let parent = (source.0.clone(), source.1.clone(), parent_tail);
+        let mut chain = self.i.chains.borrow().get(&parent).cloned().unwrap_or_default();
+        let real = real.unwrap_or_else(|| match (chain.last(), tail.file_name()) {
+            (Some(dir), Some(name)) => dir.join(name),
+            _ => PathBuf::from(&source.1).join(&tail),
+        });
+        if chain.iter().any(|x| x.starts_with(&real)) {
+            warn!("Skipping {:?}, a link back to {:?}, which is already being copied", source.2, real);
+            return false;
+        }
+        chain.push(real);
+        self.i.chains.borrow_mut().insert(source.clone(), chain);
+        true
+    }
+
+    /// Copies a file that didn't match its source once more, in full, and
+    /// fails the copy if it didn't match the second time either.
+    fn finish_verify(&self, source: Source, files: &[&FileRef], origin_digest: Option<Vec<u8>>, dest_digest: Option<Vec<u8>>) {
+        if origin_digest.is_none() || dest_digest.is_none() {
+            error!("Failed to read {:?} back to verify it", source.2);
+            *self.i.verify_failed.borrow_mut() = true;
+            self.report("failed", None);
+        } else if origin_digest == dest_digest {
+            info!("Verified {:?}", source.2);
+            self.report("transferred", origin_digest.as_ref().map(|x| &x[..]));
+        } else if self.i.retried.borrow_mut().insert(source.clone()) {
+            warn!("{:?} doesn't match its source, copying it again", source.2);
+            for file in files {
+                if let FileRef::Local(path) = file {
+                    self.i.full_copies.borrow_mut().insert(path.clone());
+                }
+            }
+            self.i.checked.borrow_mut().insert(source.clone());
+            self.i.synthetic_sources.borrow_mut().insert(0, source);
+        } else {
+            error!("{:?} still doesn't match its source after copying it again", source.2);
+            *self.i.verify_failed.borrow_mut() = true;
+            self.report("failed", None);
+        }
+        self.tick_transfers();
+    }
+
+    /// Gives up on the current file and moves on to the next.
+    fn transfer_failed(&self) {
+        self.report("failed", None);
+        self.tick_transfers();
+    }
+
+    /// Adds the current file to the --json summary.
+    fn report(&self, status: &'static str, checksum: Option<&[u8]>) {
+        let source = self.i.current.borrow().clone();
+        if !crate::arg::matches().is_present("json") || source.is_none() {
+            return;
+        }
+        let source = source.unwrap();
+        let mut path = PathBuf::from(&source.1);
+        path.push(&source.2);
+        let dest = self.i.destination.borrow().clone();
+        let mut dest_path = PathBuf::from(get_path(&dest));
+        dest_path.push(&source.2);
+        let checksum = checksum.map(|x| {
+            let algorithm = crate::arg::matches().value_of("verify").unwrap_or("sha256");
+            format!("{}:{}", algorithm, ::data_encoding::HEXLOWER.encode(x))
+        });
+        let report = FileReport {
+            source: match &source.0 {
+                Some(peer) => format!("{}:{}", peer, path.display()),
+                None => path.display().to_string(),
+            },
+            destination: match get_peer(&dest) {
+                Some(peer) => format!("{}:{}", peer, dest_path.display()),
+                None => dest_path.display().to_string(),
+            },
+            status,
+            bytes: *self.i.file_bytes.borrow(),
+            seconds: self.i.file_started.borrow().map(seconds_since).unwrap_or(0.0),
+            checksum,
+        };
+        self.i.reports.borrow_mut().push(report);
+    }
+
+    /// Writes the --json summary to stdout: {"files": [FileReport, ...],
+    /// "totals": {"files", "transferred", "skipped", "failed", "bytes",
+    /// "seconds"}}.
+    fn print_summary(&self) {
+        let reports = self.i.reports.borrow();
+        let count = |status| reports.iter().filter(|x| x.status == status).count();
+        let summary = json!({
+            "files": &*reports,
+            "totals": {
+                "files": reports.len(),
+                "transferred": count("transferred"),
+                "skipped": count("skipped"),
+                "failed": count("failed"),
+                "bytes": reports.iter().map(|x| x.bytes).sum::<u64>(),
+                "seconds": self.i.started.borrow().map(seconds_since).unwrap_or(0.0),
+            },
+        });
+        println!("{}", serde_json::to_string(&summary).unwrap());
+    }
+
+    /// The length and modification time of a file, or None if it doesn't
+    /// exist or isn't a regular file.
+    fn file_info(&self, file: &FileRef, callback: Rc<dyn Fn(Option<(u64, Option<SystemTime>)>)>) {
+        match file {
+            FileRef::Local(path) => {
+                let info = metadata(path).ok().and_then(|x| if x.is_file() { Some((x.len(), x.modified().ok())) } else { None });
+                (callback)(info);
+            }
+            FileRef::Remote(connection, path) => {
+                let id = connection.send(StatRequest { path: path.clone() });
+                connection.watch(Rc::new(move |message, _| match message {
+                    StatResult {
+                        reference,
+                        is_file,
+                        len,
+                        mtime,
+                        ..
+                    } if *reference == id =>
+                    {
+                        (callback)(if *is_file { Some((*len, *mtime)) } else { None });
+                        true
+                    }
+                    Reject { reference, .. } if *reference == id => {
+                        (callback)(None);
+                        true
+                    }
+                    _ => false,
+                }));
+            }
+        }
+    }
+
+    /// The digest of a whole file, using a FileHashRequest hash_algorithm, or
+    /// None if it can't be read.
+    fn file_digest(&self, file: &FileRef, algorithm: u64, callback: Rc<dyn Fn(Option<Vec<u8>>)>) {
+        match file {
+            FileRef::Local(path) => (callback)(crate::util::file_digest(path, crate::util::digest_algorithm(algorithm).unwrap()).ok()),
+            FileRef::Remote(connection, path) => {
+                let id = connection.send(FileHashRequest {
+                    path:           path.clone(),
+                    offset_start:   None,
+                    offset_end:     None,
+                    hash_algorithm: algorithm,
+                });
+                connection.watch(Rc::new(move |message, _| match message {
+                    FileHashData { reference, digest } if *reference == id => {
+                        (callback)(Some(digest.clone()));
+                        true
+                    }
+                    Reject { reference, .. } if *reference == id => {
+                        (callback)(None);
+                        true
+                    }
+                    _ => false,
+                }));
+            }
+        }
+    }
+
+    fn use_delta(&self, path: &PathBuf) -> bool {
+        crate::arg::matches().is_present("update") && !self.i.full_copies.borrow().contains(path)
+    }
+
+    /// Gives up on a delta transfer, usually because the destination doesn't
+    /// exist yet, and copies the source again in full.
+    fn copy_in_full(&self, path: PathBuf, source: Source) {
+        debug!("Copying {:?} in full", path);
+        self.i.full_copies.borrow_mut().insert(path);
+        self.i.synthetic_sources.borrow_mut().insert(0, source);
+        self.tick_transfers();
+    }
+
+    fn delta_upload(&self, connection: Oxy, source: Source, fullpath: PathBuf, dest_path: PathBuf) {
+        let remote_path = dest_path.to_str().unwrap().to_string();
+        let id = connection.send(FileSignatureRequest {
+            path:       remote_path.clone(),
+            block_size: DELTA_BLOCK_SIZE,
+        });
+        let proxy = self.clone();
+        connection.clone().watch(Rc::new(move |message, _| match message {
+            FileSignatureData { reference, digests, .. } if *reference == id => {
+                let file = File::open(&fullpath);
+                if file.is_err() {
+                    warn!("Failed to open local file {:?}", fullpath);
+                    proxy.transfer_failed();
+                    return true;
+                }
+                let mut file = file.unwrap();
+                let local = crate::util::block_signatures(&mut file, DELTA_BLOCK_SIZE).unwrap_or_default();
+                let blocks = changed_blocks(&local, digests);
+                info!("{} of {} blocks of {:?} changed", blocks.len(), local.len(), fullpath);
+                proxy.upload_next_block(DeltaTransfer {
+                    connection:   connection.clone(),
+                    remote_path:  remote_path.clone(),
+                    len:          file.metadata().map(|x| x.len()).unwrap_or(0),
+                    local:        Rc::new(RefCell::new(file)),
+                    total_blocks: blocks.len(),
+                    blocks:       Rc::new(RefCell::new(blocks)),
+                    file_name:    fullpath.file_name().unwrap().to_str().unwrap().to_string(),
+                });
+                true
+            }
+            Reject { reference, .. } if *reference == id => {
+                proxy.copy_in_full(fullpath.clone(), source.clone());
+                true
+            }
+            _ => false,
+        }));
+    }
+
+    fn upload_next_block(&self, transfer: DeltaTransfer) {
+        let connection = transfer.connection.clone();
+        let proxy = self.clone();
+        let block = transfer.blocks.borrow_mut().pop();
+        if block.is_none() {
+            let id = connection.send(FileTruncateRequest {
+                path: transfer.remote_path.clone(),
+                len:  transfer.len,
+            });
+            connection.watch(Rc::new(move |message, _| match message {
+                Success { reference } if *reference == id => {
+                    proxy.print_progress(1000, &transfer.file_name, 0);
+                    info!("Upload finished");
+                    proxy.transfer_finished();
+                    true
+                }
+                Reject { reference, note } if *reference == id => {
+                    warn!("Failed to truncate {:?}: {:?}", transfer.remote_path, note);
+                    proxy.transfer_failed();
+                    true
+                }
+                _ => false,
+            }));
+            return;
+        }
+        let start = block.unwrap() * DELTA_BLOCK_SIZE;
+        let mut data = Vec::new();
+        let result = {
+            let mut file = transfer.local.borrow_mut();
+            file.seek(SeekFrom::Start(start)).and_then(|_| Read::by_ref(&mut *file).take(DELTA_BLOCK_SIZE).read_to_end(&mut data))
+        };
+        if result.is_err() {
+            warn!("Failed to read file");
+            self.transfer_failed();
+            return;
+        }
+        let remote_path = PathBuf::from(&transfer.remote_path);
+        let id = connection.send(UploadRequest {
+            path:         remote_path.parent().unwrap().to_str().unwrap().to_string(),
+            filepart:     remote_path.file_name().unwrap().to_str().unwrap().to_string(),
+            offset_start: Some(start),
+        });
+        let data = RefCell::new(data);
+        connection.clone().watch(Rc::new(move |message, _| match message {
+            Success { reference } if *reference == id => {
+                let data = ::std::mem::replace(&mut *data.borrow_mut(), Vec::new());
+                let bytes = data.len() as u64;
+                connection.send(FileData { reference: id, data });
+                let send_id = connection.send(FileData {
+                    reference: id,
+                    data:      Vec::new(),
+                });
+                let proxy = proxy.clone();
+                let transfer = transfer.clone();
+                connection.watch(Rc::new(move |message, _| match message {
+                    Success { reference } if *reference == send_id => {
+                        let done = transfer.total_blocks - transfer.blocks.borrow().len();
+                        proxy.print_progress((done * 1000 / transfer.total_blocks) as u64, &transfer.file_name, bytes);
+                        proxy.upload_next_block(transfer.clone());
+                        true
+                    }
+                    _ => false,
+                }));
+                true
+            }
+            Reject { reference, note } if *reference == id => {
+                warn!("Upload request failed: {:?}", note);
+                proxy.transfer_failed();
+                true
+            }
+            _ => false,
+        }));
+    }
+
+    fn delta_download(&self, connection: Oxy, source: Source, path: String, dest: PathBuf) {
+        let id = connection.send(FileSignatureRequest {
+            path:       path.clone(),
+            block_size: DELTA_BLOCK_SIZE,
+        });
+        let proxy = self.clone();
+        connection.clone().watch(Rc::new(move |message, _| match message {
+            FileSignatureData { reference, len, digests } if *reference == id => {
+                let local = File::open(&dest)
+                    .and_then(|mut x| crate::util::block_signatures(&mut x, DELTA_BLOCK_SIZE))
+                    .unwrap_or_default();
+                let blocks = changed_blocks(digests, &local);
+                info!("{} of {} blocks of {:?} changed", blocks.len(), digests.len(), path);
+                let file = OpenOptions::new().write(true).open(&dest);
+                if file.is_err() {
+                    warn!("Failed to open local file for writing: {:?}", dest);
+                    proxy.transfer_failed();
+                    return true;
+                }
+                proxy.download_next_block(DeltaTransfer {
+                    connection:   connection.clone(),
+                    remote_path:  path.clone(),
+                    local:        Rc::new(RefCell::new(file.unwrap())),
+                    total_blocks: blocks.len(),
+                    blocks:       Rc::new(RefCell::new(blocks)),
+                    len:          *len,
+                    file_name:    dest.file_name().unwrap().to_str().unwrap().to_string(),
+                });
+                true
+            }
+            Reject { reference, .. } if *reference == id => {
+                proxy.copy_in_full(dest.clone(), source.clone());
+                true
+            }
+            _ => false,
+        }));
+    }
+
+    fn download_next_block(&self, transfer: DeltaTransfer) {
+        let block = transfer.blocks.borrow_mut().pop();
+        if block.is_none() {
+            if let Err(err) = transfer.local.borrow_mut().set_len(transfer.len) {
+                warn!("Failed to truncate local file: {:?}", err);
+            }
+            self.print_progress(1000, &transfer.file_name, 0);
+            info!("Transfer finished.");
+            self.transfer_finished();
+            return;
+        }
+        let start = block.unwrap() * DELTA_BLOCK_SIZE;
+        let end = ::std::cmp::min(start + DELTA_BLOCK_SIZE, transfer.len);
+        if transfer.local.borrow_mut().seek(SeekFrom::Start(start)).is_err() {
+            warn!("Failed to seek in local file");
+            self.transfer_failed();
+            return;
+        }
+        let id = transfer.connection.send(DownloadRequest {
+            path:         transfer.remote_path.clone(),
+            offset_start: Some(start),
+            offset_end:   Some(end),
+        });
+        let proxy = self.clone();
+        transfer.connection.clone().watch(Rc::new(move |message, _| match message {
+            FileData { reference, data } if *reference == id => {
+                if data.is_empty() {
+                    let done = transfer.total_blocks - transfer.blocks.borrow().len();
+                    proxy.print_progress((done * 1000 / transfer.total_blocks) as u64, &transfer.file_name, end - start);
+                    proxy.download_next_block(transfer.clone());
+                    return true;
+                }
+                if transfer.local.borrow_mut().write_all(&data[..]).is_err() {
+                    warn!("Error writing data to local file");
+                    proxy.transfer_failed();
+                    return true;
+                }
+                false
+            }
+            Reject { reference, note } if *reference == id => {
+                warn!("Error reading file: {:?}", note);
+                proxy.transfer_failed();
+                true
+            }
+            _ => false,
+        }));
+    }
+
+    /// Downloads a file as --streams ranges at once, each written at its own
+    /// offset through a file handle of its own.
+    fn parallel_download(&self, connection: Oxy, path: String, dest: PathBuf, len: u64, streams: u64) {
+        let streams = ::std::cmp::min(streams, len / MIN_STREAM_LEN);
+        let stream_len = (len + streams - 1) / streams;
+        let file_name = dest.file_name().unwrap().to_str().unwrap().to_string();
+        ::std::fs::create_dir_all(dest.parent().unwrap()).ok();
+        if let Err(err) = File::create(&dest).and_then(|x| x.set_len(len)) {
+            warn!("Failed to create local file for writing: {:?}: {}", dest, err);
+            self.transfer_failed();
+            return;
+        }
+        debug!("Downloading {:?} as {} streams", path, streams);
+        let remaining = Rc::new(RefCell::new(streams));
+        let failed = Rc::new(RefCell::new(false));
+        let written = Rc::new(RefCell::new(0u64));
+        for stream in 0..streams {
+            let start = stream * stream_len;
+            let end = ::std::cmp::min(start + stream_len, len);
+            let file = OpenOptions::new().write(true).open(&dest).and_then(|mut x| x.seek(SeekFrom::Start(start)).map(|_| x));
+            if file.is_err() {
+                warn!("Failed to open local file for writing: {:?}", dest);
+                *failed.borrow_mut() = true;
+                self.transfer_failed();
+                return;
+            }
+            let file = RefCell::new(file.unwrap());
+            let id = connection.send(DownloadRequest {
+                path:         path.clone(),
+                offset_start: Some(start),
+                offset_end:   Some(end),
+            });
+            let proxy = self.clone();
+            let remaining = remaining.clone();
+            let failed = failed.clone();
+            let written = written.clone();
+            let file_name = file_name.clone();
+            connection.watch(Rc::new(move |message, _| match message {
+                _ if *failed.borrow() => true,
+                FileData { reference, data } if *reference == id => {
+                    if data.is_empty() {
+                        *remaining.borrow_mut() -= 1;
+                        if *remaining.borrow() == 0 {
+                            proxy.print_progress(1000, &file_name, 0);
+                            info!("Transfer finished.");
+                            proxy.transfer_finished();
+                        }
+                        return true;
+                    }
+                    if file.borrow_mut().write_all(&data[..]).is_err() {
+                        warn!("Error writing data to local file");
+                        *failed.borrow_mut() = true;
+                        proxy.transfer_failed();
+                        return true;
+                    }
+                    *written.borrow_mut() += data.len() as u64;
+                    proxy.print_progress(*written.borrow() * 1000 / len, &file_name, data.len() as u64);
+                    false
+                }
+                Reject { reference, note } if *reference == id => {
+                    warn!("Error reading file: {:?}", note);
+                    *failed.borrow_mut() = true;
+                    proxy.transfer_failed();
+                    true
+                }
+                _ => false,
+            }));
+        }
+    }
+
+    /// Copies the next source for --archive: tar runs where the source is,
+    /// and its output is unpacked by tar running where the destination is.
+    fn tick_archive(&self) {
+        if self.i.sources.borrow().is_empty() {
+            info!("Finished!");
+            let failed = *self.i.archive_failed.borrow();
+            crate::exit::exit(if failed { crate::exit::EXIT_FAILURE } else { crate::exit::EXIT_OK });
+        }
+        let source = self.i.sources.borrow_mut().remove(0);
+        let (head, tail) = archive_parts(get_path(&source));
+        let dest = self.i.destination.borrow().clone();
+        info!("Archiving {:?}", source);
+        let sink = match get_peer(&dest) {
+            Some(peer) => self.remote_archive_sink(peer, get_path(&dest)),
+            None => match self.local_archive_sink(get_path(&dest)) {
+                Some(sink) => sink,
+                None => {
+                    *self.i.archive_failed.borrow_mut() = true;
+                    self.tick_archive();
+                    return;
+                }
+            },
+        };
+        match get_peer(&source) {
+            Some(peer) => self.remote_archive_source(peer, &head, &tail, sink),
+            None => self.local_archive_source(&head, &tail, sink),
+        }
+    }
+
+    fn local_archive_source(&self, head: &str, tail: &str, sink: ArchiveSink) {
+        let ArchiveSink { connection, write } = sink;
+        let child = Command::new("tar").args(&["-cf", "-", "-C", head, tail]).stdout(Stdio::piped()).spawn();
+        if child.is_err() {
+            error!("Failed to run tar: {:?}", child);
+            *self.i.archive_failed.borrow_mut() = true;
+            (write)(&[]);
+            return;
+        }
+        let mut child = child.unwrap();
+        let stdout = RefCell::new(child.stdout.take().unwrap());
+        let child = RefCell::new(child);
+        let source = PathBuf::from(head).join(tail);
+        let proxy = self.clone();
+        let read_chunk = Rc::new(move || {
+            let mut buf = vec![0u8; crate::arg::channel_max_packet()];
+            let count = stdout.borrow_mut().read(&mut buf).unwrap_or(0);
+            if count == 0 && !child.borrow_mut().wait().map(|x| x.success()).unwrap_or(false) {
+                error!("tar failed to archive {:?}", source);
+                *proxy.i.archive_failed.borrow_mut() = true;
+            }
+            (write)(&buf[..count]);
+            count == 0
+        });
+        match connection {
+            Some(connection) => {
+                let connection2 = connection.clone();
+                connection.push_send_hook(Rc::new(move || {
+                    if !connection2.has_write_space() {
+                        return false;
+                    }
+                    (read_chunk)()
+                }));
+            }
+            None => while !(read_chunk)() {},
+        }
+    }
+
+    fn remote_archive_source(&self, peer: &str, head: &str, tail: &str, sink: ArchiveSink) {
+        let connection = self.i.connections.borrow().get(peer).unwrap().clone();
+        let command = format!("tar -cf - -C {} {}", shlex::quote(head), shlex::quote(tail));
+        let reference = connection.send(PipeCommand { command });
+        let proxy = self.clone();
+        connection.clone().watch(Rc::new(move |message, _| match message {
+            PipeCommandOutput {
+                reference: output_reference,
+                stdout,
+                stderr,
+            } if *output_reference == reference =>
+            {
+                ::std::io::stderr().write_all(&stderr[..]).ok();
+                if !stdout.is_empty() {
+                    (sink.write)(&stdout[..]);
+                }
+                connection.claim_message();
+                false
+            }
+            PipeCommandExited { reference: exited_reference } if *exited_reference == reference => {
+                (sink.write)(&[]);
+                connection.claim_message();
+                true
+            }
+            Reject { reference: rejected, note } if *rejected == reference => {
+                error!("The server refused to run tar: {:?}", note);
+                *proxy.i.archive_failed.borrow_mut() = true;
+                (sink.write)(&[]);
+                true
+            }
+            _ => false,
+        }));
+    }
+
+    fn local_archive_sink(&self, path: &str) -> Option<ArchiveSink> {
+        let path = PathBuf::from(if path.is_empty() { "." } else { path });
+        if path.exists() && !path.is_dir() {
+            error!("The destination {:?} is not a directory", path);
+            return None;
+        }
+        if let Err(err) = ::std::fs::create_dir_all(&path) {
+            error!("Failed to create the destination {:?}: {:?}", path, err);
+            return None;
+        }
+        let child = Command::new("tar").arg("-xpf").arg("-").arg("-C").arg(&path).stdin(Stdio::piped()).spawn();
+        if child.is_err() {
+            error!("Failed to run tar: {:?}", child);
+            return None;
+        }
+        let child = RefCell::new(child.unwrap());
+        let proxy = self.clone();
+        let write = Rc::new(move |data: &[u8]| {
+            if !data.is_empty() {
+                let mut child = child.borrow_mut();
+                if child.stdin.as_mut().map(|x| x.write_all(data).is_err()).unwrap_or(false) {
+                    child.stdin.take();
+                }
+                return;
+            }
+            child.borrow_mut().stdin.take();
+            if !child.borrow_mut().wait().map(|x| x.success()).unwrap_or(false) {
+                error!("tar failed to unpack into {:?}", path);
+                *proxy.i.archive_failed.borrow_mut() = true;
+            }
+            proxy.tick_archive();
+        });
+        Some(ArchiveSink { connection: None, write })
+    }
+
+    fn remote_archive_sink(&self, peer: &str, path: &str) -> ArchiveSink {
+        let connection = self.i.connections.borrow().get(peer).unwrap().clone();
+        let path = shlex::quote(if path.is_empty() { "." } else { path }).into_owned();
+        let command = format!(
+            "if [ -e {0} ] && [ ! -d {0} ]; then echo oxy: {0} is not a directory >&2; exit 1; fi; mkdir -p {0} && tar -xpf - -C {0}",
+            path
+        );
+        let reference = connection.send(PipeCommand { command });
+        let finished = Rc::new(RefCell::new(false));
+        let finished2 = finished.clone();
+        let proxy = self.clone();
+        let connection2 = connection.clone();
+        connection.clone().watch(Rc::new(move |message, _| match message {
+            PipeCommandOutput {
+                reference: output_reference,
+                stderr,
+                ..
+            } if *output_reference == reference =>
+            {
+                ::std::io::stderr().write_all(&stderr[..]).ok();
+                connection2.claim_message();
+                false
+            }
+            PipeCommandExited { reference: exited_reference } if *exited_reference == reference => {
+                connection2.claim_message();
+                if !*finished2.borrow() {
+                    error!("Unpacking on the destination stopped before the archive was complete");
+                    crate::exit::exit(crate::exit::EXIT_FAILURE);
+                }
+                proxy.tick_archive();
+                true
+            }
+            _ => false,
+        }));
+        let connection2 = connection.clone();
+        let write = Rc::new(move |data: &[u8]| {
+            if data.is_empty() {
+                *finished.borrow_mut() = true;
+            }
+            connection2.send(PipeCommandInput {
+                reference,
+                input: data.to_vec(),
+            });
+        });
+        ArchiveSink {
+            connection: Some(connection),
+            write,
         }
     }
 }
 
+/// The blocks of wanted that differ from, or are missing in, have, in reverse
+/// order so that they can be popped off in file order.
+fn changed_blocks(wanted: &[Vec<u8>], have: &[Vec<u8>]) -> Vec<u64> {
+    (0..wanted.len()).rev().filter(|&i| have.get(i) != Some(&wanted[i])).map(|i| i as u64).collect()
+}
+
+/// The time since start in seconds, for the --json summary.
+fn seconds_since(start: Instant) -> f64 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1_000_000_000.0
+}
+
+/// Splits a source path into the directory tar runs in and the entry it
+/// archives. A trailing slash copies a directory's contents rather than the
+/// directory itself, as in the default mode.
+fn archive_parts(path: &str) -> (String, String) {
+    if path.ends_with('/') {
+        return (path.to_string(), ".".to_string());
+    }
+    let path = PathBuf::from(path);
+    let head = path.parent().map(|x| x.to_str().unwrap().to_string()).unwrap_or_default();
+    let tail = path.file_name().map(|x| x.to_str().unwrap().to_string()).unwrap_or_else(|| ".".to_string());
+    (if head.is_empty() { ".".to_string() } else { head }, tail)
+}
+
 fn get_peer<'a>(location: &'a str) -> Option<&'a str> {
     if !location.splitn(2, '/').next().unwrap().contains(':') {
         return None;
@@ -514,3 +1975,44 @@ fn get_path<'a>(location: &'a str) -> &'a str {
     }
     location.splitn(2, ':').nth(1).unwrap()
 }
+
+/// The permission bits of a local source, to give its copy. Windows has no
+/// mode to carry over.
+#[cfg(unix)]
+fn source_mode(md: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(md.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn source_mode(_md: &Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn set_local_mode(path: &Path, mode: u32) {
+    use std::os::unix::fs::PermissionsExt;
+    if let Err(err) = ::std::fs::set_permissions(path, ::std::fs::Permissions::from_mode(mode)) {
+        warn!("Failed to set the mode of {:?}: {}", path, err);
+    }
+}
+
+#[cfg(not(unix))]
+fn set_local_mode(_path: &Path, _mode: u32) {}
+
+#[cfg(unix)]
+fn create_symlink(target: &str, path: &Path) -> ::std::io::Result<()> {
+    ::std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_target: &str, _path: &Path) -> ::std::io::Result<()> {
+    Err(::std::io::Error::new(::std::io::ErrorKind::Other, "symlinks can only be recreated on unix"))
+}
+
+/// Whether symlinks among the sources are followed. They are, except under -r
+/// without --dereference, where links are copied as links.
+fn follow_links() -> bool {
+    let matches = crate::arg::matches();
+    !matches.is_present("recursive") || matches.is_present("dereference")
+}
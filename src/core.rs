@@ -1,6 +1,10 @@
 mod handle_message;
+mod compression;
+#[cfg(unix)]
+mod hangup;
 mod kex;
 mod metacommands;
+mod pty_output;
 mod restrict_message;
 
 use self::kex::{KexData, NakedState};
@@ -12,25 +16,28 @@ use crate::tuntap::TunTap;
 use crate::{
     arg, keys,
     message::OxyMessage::{self, *},
+    secret::SecretBytes,
     ui::Ui,
 };
 #[allow(unused_imports)]
 use log::{debug, error, info, log, trace, warn};
-use shlex;
+use serde_json::json;
 use std::{
     cell::RefCell,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::File,
-    io::Read,
+    io::{Read, Seek, SeekFrom, Write},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
     rc::Rc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 use transportation::{
     self,
     mio::net::TcpListener,
     set_timeout, BufferedTransport, EncryptedTransport,
     EncryptionPerspective::{Alice, Bob},
-    MessageTransport, Notifiable, Notifies, ProtocolTransport,
+    MessageTransport, Notifiable, Notifies, ProtocolTransport, Transport,
 };
 
 #[derive(Clone)]
@@ -43,8 +50,20 @@ crate struct TransferOut {
     file:             File,
     current_position: u64,
     cutoff_position:  u64,
+    compress:         bool,
+}
+
+/// A file being sent to the peer as it grows, for copy --follow.
+crate struct FollowedFile {
+    reference: u64,
+    path:      PathBuf,
+    file:      File,
+    position:  u64,
 }
 
+/// How often a followed file is checked for new data.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+
 crate struct PipeChild {
     child: ::std::process::Child,
     inp:   BufferedTransport,
@@ -57,11 +76,17 @@ crate struct OxyInternal {
     naked_transport: RefCell<Option<MessageTransport>>,
     underlying_transport: RefCell<Option<ProtocolTransport>>,
     peer_name: RefCell<Option<String>>,
+    peer_version: RefCell<Option<String>>,
+    socket_addresses: RefCell<(Option<String>, Option<String>)>,
     piped_children: RefCell<HashMap<u64, PipeChild>>,
     ui: RefCell<Option<Ui>>,
     outgoing_ticker: RefCell<u64>,
     incoming_ticker: RefCell<u64>,
     transfers_out: RefCell<Vec<TransferOut>>,
+    compression_negotiated: RefCell<bool>,
+    compression_algorithm: RefCell<u64>,
+    outbound_zstd: RefCell<bool>,
+    inbound_zstd: RefCell<bool>,
     port_binds: RefCell<HashMap<u64, PortBind>>,
     local_streams: RefCell<HashMap<u64, PortStream>>,
     remote_streams: RefCell<HashMap<u64, PortStream>>,
@@ -70,6 +95,20 @@ crate struct OxyInternal {
     kex_data: RefCell<KexData>,
     socks_binds: RefCell<HashMap<u64, SocksBind>>,
     last_message_seen: RefCell<Option<Instant>>,
+    last_keepalive: RefCell<Option<SystemTime>>,
+    keepalive_unanswered: RefCell<bool>,
+    suspended: RefCell<bool>,
+    suspensions: RefCell<u64>,
+    no_shell: RefCell<bool>,
+    login_shell: RefCell<bool>,
+    remote_shell: RefCell<Option<String>>,
+    clear_env: RefCell<bool>,
+    advertised_env: RefCell<HashSet<String>>,
+    authenticated: RefCell<bool>,
+    interactive_auth_pending: RefCell<bool>,
+    interactive_auth_failures: RefCell<u32>,
+    interactive_auth_keyed: RefCell<bool>,
+    interactive_auth_notice: RefCell<String>,
     launched: RefCell<bool>,
     response_watchers: RefCell<Vec<Rc<dyn Fn(&OxyMessage, u64) -> bool>>>,
     metacommand_queue: RefCell<Vec<Vec<String>>>,
@@ -77,18 +116,42 @@ crate struct OxyInternal {
     post_auth_hooks: RefCell<Vec<Rc<dyn Fn() -> ()>>>,
     send_hooks: RefCell<Vec<Rc<dyn Fn() -> bool>>>,
     pipecmd_reference: RefCell<Option<u64>>,
+    pipecmd_timed_out: RefCell<bool>,
+    pipecmd_status: RefCell<Option<i32>>,
     stdin_bt: RefCell<Option<BufferedTransport>>,
+    hangup_bt: RefCell<Option<BufferedTransport>>,
+    commands_detached: RefCell<bool>,
+    lingering: RefCell<bool>,
     remote_bind_cleaners: RefCell<HashMap<u64, Rc<dyn Fn() -> ()>>>,
     socks_bind_cleaners: RefCell<HashMap<String, Rc<dyn Fn() -> ()>>>,
     local_bind_cleaners: RefCell<HashMap<String, Rc<dyn Fn() -> ()>>>,
+    local_bind_destinations: RefCell<HashMap<String, String>>,
+    logged_forwards: RefCell<HashSet<String>>,
+    forward_connection_limits: RefCell<HashMap<String, u64>>,
+    forward_accept_filters: RefCell<HashMap<String, Vec<String>>>,
+    forward_proxy_protocols: RefCell<HashMap<String, u8>>,
+    socks_credentials: RefCell<HashMap<String, (String, String)>>,
+    bind_connection_sources: RefCell<HashMap<u64, (Option<SocketAddr>, Option<SocketAddr>)>>,
+    forward_streams: RefCell<HashMap<(bool, u64), ForwardStream>>,
     kr_references: RefCell<HashMap<String, u64>>,
     peer_user: RefCell<Option<String>>,
     message_claim: RefCell<bool>,
     privs_dropped: RefCell<bool>,
+    su_pending: RefCell<bool>,
+    su_refused: RefCell<bool>,
     #[cfg(unix)]
     pty: RefCell<Option<Pty>>,
     #[cfg(unix)]
     tuntaps: RefCell<HashMap<u64, TunTap>>,
+    pty_paused: RefCell<bool>,
+    pty_exited: RefCell<bool>,
+    pty_output_backlog: RefCell<Vec<u8>>,
+    pty_output_paused: RefCell<bool>,
+    pty_output_held: RefCell<bool>,
+    pty_output_drain_scheduled: RefCell<bool>,
+    pty_output_refilled: RefCell<Option<Instant>>,
+    pty_output_budget: RefCell<f64>,
+    pty_flood_window: RefCell<Option<(Instant, u64)>>,
 }
 
 impl Oxy {
@@ -112,8 +175,10 @@ impl Oxy {
 
     pub fn create<T: Into<BufferedTransport>>(transport: T) -> Oxy {
         let bt: BufferedTransport = transport.into();
+        let addresses = socket_addresses(&bt);
         let mt = <MessageTransport as From<BufferedTransport>>::from(bt);
         let internal = OxyInternal::default();
+        *internal.socket_addresses.borrow_mut() = addresses;
         *internal.naked_transport.borrow_mut() = Some(mt);
         *internal.last_message_seen.borrow_mut() = Some(Instant::now());
         let x = Oxy { internal: Rc::new(internal) };
@@ -124,13 +189,35 @@ impl Oxy {
             .as_mut()
             .unwrap()
             .set_notify(Rc::new(move || proxy.notify_naked()));
-        let y = x.clone();
-        set_timeout(Rc::new(move || y.notify_keepalive()), Duration::from_secs(60));
+        if let Some(interval) = arg::keepalive_interval() {
+            let y = x.clone();
+            set_timeout(Rc::new(move || y.notify_keepalive()), interval);
+        }
         let y = x.clone();
         transportation::set_timeout(Rc::new(move || y.launch()), Duration::from_secs(0));
         x
     }
 
+    /// Drops a client that hasn't authenticated within --login-grace-time, so
+    /// that it doesn't hold on to this process forever.
+    fn check_login_grace_time(&self, grace: Duration) {
+        if *self.internal.authenticated.borrow() {
+            return;
+        }
+        warn!("Client didn't authenticate within {}. Closing the connection.", crate::util::format_duration(grace));
+        crate::exit::exit(crate::exit::EXIT_FAILURE);
+    }
+
+    /// Ends a session the client suspended and never resumed, so that it
+    /// doesn't hold on to this process, its pty and its forwards forever.
+    fn check_suspend_timeout(&self, suspension: u64, timeout: Duration) {
+        if !*self.internal.suspended.borrow() || *self.internal.suspensions.borrow() != suspension {
+            return;
+        }
+        warn!("Client didn't resume the session within {}. Closing the connection.", crate::util::format_duration(timeout));
+        crate::exit::fail(crate::exit::OxyError::Connection("The suspended session wasn't resumed".to_string()));
+    }
+
     pub fn set_peer_name(&self, name: &str) {
         trace!("Setting peer name to {:?}", name);
         *self.internal.peer_name.borrow_mut() = Some(name.to_string());
@@ -207,7 +294,15 @@ impl Oxy {
             panic!("Attempted to launch an Oxy instance twice.");
         }
         *self.internal.launched.borrow_mut() = true;
+        if self.perspective() == Bob {
+            if let Some(grace) = arg::login_grace_time() {
+                let proxy = self.clone();
+                set_timeout(Rc::new(move || proxy.check_login_grace_time(grace)), grace);
+            }
+        }
         if self.perspective() == Alice {
+            #[cfg(unix)]
+            self.register_hangup_handler();
             self.advertise_client_key();
         }
         if self.perspective() == Bob {
@@ -224,11 +319,17 @@ impl Oxy {
         let message_number = self.tick_outgoing();
         debug!("Sending message {}", message_number);
         trace!("Sending message {}: {:?}", message_number, message);
+        crate::audit::observe_outbound(&message);
+        crate::protocol_trace::observe_outbound(message_number, &message);
+        if *self.internal.lingering.borrow() {
+            // The connection is gone, and its transport can't take more.
+            return message_number;
+        }
         if self.internal.underlying_transport.borrow().is_none() {
             error!("Attempted to send protocol message before key-exchange completed.");
             crate::exit::exit(1);
         }
-        self.internal.underlying_transport.borrow().as_ref().unwrap().send(message);
+        self.transmit(message);
         message_number
     }
 
@@ -249,11 +350,22 @@ impl Oxy {
         let remote_addr = self.internal.port_binds.borrow_mut().get_mut(&token).unwrap().remote_spec.clone();
         let local_addr = self.internal.port_binds.borrow_mut().get_mut(&token).unwrap().local_spec.clone();
         debug!("Accepting a connection for local bind {}", local_addr);
+        if self.perspective() == Alice && (!self.forward_accepts(&local_addr, stream.1.ip()) || self.forward_at_capacity(&local_addr)) {
+            return;
+        }
+        let (source, destination) = (stream.1, stream.0.local_addr().ok());
         let stream_token = match self.perspective() {
-            Alice => self.send(RemoteOpen { addr: remote_addr }),
-            Bob => self.send(BindConnectionAccepted { reference: token }),
+            Alice => self.send(RemoteOpen { addr: remote_addr.clone() }),
+            Bob => {
+                self.send(BindConnectionSource {
+                    reference:   token,
+                    source:      source.to_string(),
+                    destination: destination.map(|x| x.to_string()).unwrap_or_default(),
+                });
+                self.send(BindConnectionAccepted { reference: token })
+            }
         };
-        let bt = BufferedTransport::from(stream.0);
+        let bt = stream_transport(stream.0);
         let stream = PortStream {
             stream: bt,
             token:  stream_token,
@@ -263,6 +375,15 @@ impl Oxy {
         let stream2 = Rc::new(stream.clone());
         stream.stream.set_notify(stream2);
         self.internal.local_streams.borrow_mut().insert(stream_token, stream);
+        if self.perspective() == Alice {
+            self.track_forward_stream(true, stream_token, &local_addr, &remote_addr);
+            if let Some(header) = self.forward_proxy_header(&local_addr, Some(source), destination) {
+                self.send(RemoteStreamData {
+                    reference: stream_token,
+                    data:      header,
+                });
+            }
+        }
     }
 
     fn notify_ui(&self) {
@@ -273,9 +394,13 @@ impl Oxy {
                     if parts.is_empty() {
                         continue;
                     }
-                    self.handle_metacommand(parts);
+                    self.run_metacommand(parts);
                 }
-                RawInput { input } => {
+                RawInput { mut input } => {
+                    self.filter_held_pty_input(&mut input);
+                    if input.is_empty() {
+                        continue;
+                    }
                     self.send(PtyInput { data: input });
                 }
             }
@@ -284,9 +409,17 @@ impl Oxy {
 
     #[cfg(unix)]
     fn notify_pty(&self) {
+        if *self.internal.pty_paused.borrow() {
+            // The pty's buffer fills up and stops being read until the client
+            // resumes it.
+            return;
+        }
         let data = self.internal.pty.borrow_mut().as_mut().unwrap().underlying.take();
         debug!("PTY Data: {:?}", data);
         if !data.is_empty() {
+            if self.internal.pty.borrow_mut().as_mut().unwrap().local_echo_overridden() {
+                self.send(PtyModeChanged {});
+            }
             self.send(PtyOutput { data });
         }
     }
@@ -320,10 +453,12 @@ impl Oxy {
             file,
             current_position,
             cutoff_position,
+            compress,
         } in self.internal.transfers_out.borrow_mut().iter_mut()
         {
             debug!("Servicing transfer {}", reference);
-            let mut data = [0; 16384];
+            self.set_outbound_compression(*compress);
+            let mut data = vec![0; arg::channel_max_packet()];
             let amt = file.read(&mut data[..]).unwrap();
             if *current_position + amt as u64 > *cutoff_position {
                 let to_take = (*cutoff_position - *current_position) as usize;
@@ -359,12 +494,69 @@ impl Oxy {
             }
         }
         self.internal.transfers_out.borrow_mut().retain(|x| !to_remove.contains(&x.reference));
+        if self.internal.transfers_out.borrow().is_empty() {
+            self.set_outbound_compression(true);
+        }
         if !to_remove.is_empty() {
             self.pop_metacommand();
         }
     }
 
+    /// Sends whatever has been appended to a followed file since the last
+    /// check, then checks again. Once caught up, a file that has shrunk is
+    /// read again from the start, and a path that now names a different file
+    /// is reopened, so log rotation doesn't end the stream.
+    fn poll_followed_file(&self, mut followed: FollowedFile) {
+        let mut caught_up = false;
+        while self.has_write_space() {
+            let mut data = vec![0; arg::channel_max_packet()];
+            let amt = match followed.file.read(&mut data[..]) {
+                Ok(amt) => amt,
+                Err(err) => {
+                    warn!("Error reading followed file {:?}: {}", followed.path, err);
+                    0
+                }
+            };
+            if amt == 0 {
+                caught_up = true;
+                break;
+            }
+            followed.position += amt as u64;
+            self.send(FileData {
+                reference: followed.reference,
+                data:      data[..amt].to_vec(),
+            });
+        }
+        if caught_up {
+            if let Ok(current) = ::std::fs::metadata(&followed.path) {
+                let replaced = followed.file.metadata().map(|x| !crate::util::same_file(&x, &current)).unwrap_or(true);
+                if replaced {
+                    if let Ok(file) = File::open(&followed.path) {
+                        info!("{:?} was replaced, following the new file", followed.path);
+                        followed.file = file;
+                        followed.position = 0;
+                    }
+                } else if current.len() < followed.position {
+                    info!("{:?} was truncated, following it from the start", followed.path);
+                    followed.file.seek(SeekFrom::Start(0)).ok();
+                    followed.position = 0;
+                }
+            }
+        }
+        let proxy = self.clone();
+        let followed = RefCell::new(Some(followed));
+        set_timeout(
+            Rc::new(move || {
+                if let Some(followed) = followed.borrow_mut().take() {
+                    proxy.poll_followed_file(followed);
+                }
+            }),
+            FOLLOW_INTERVAL,
+        );
+    }
+
     fn paint_progress_bar(&self, progress: u64, bytes: u64) {
+        crate::events::transfer_progress(progress);
         self.internal.ui.borrow().as_ref().map(|x| x.paint_progress_bar(progress, bytes));
     }
 
@@ -407,9 +599,16 @@ impl Oxy {
     fn notify_local_stream(&self, token: u64) {
         debug!("Local stream notify for stream {}", token);
         let data = self.internal.local_streams.borrow_mut().get_mut(&token).unwrap().stream.take();
-        self.send(RemoteStreamData { reference: token, data });
+        self.count_forward_bytes(true, token, 0, data.len());
+        for chunk in data.chunks(arg::channel_max_packet()) {
+            self.send(RemoteStreamData {
+                reference: token,
+                data:      chunk.to_vec(),
+            });
+        }
         if self.internal.local_streams.borrow_mut().get_mut(&token).unwrap().stream.is_closed() {
             self.internal.local_streams.borrow_mut().get_mut(&token).unwrap().stream.close();
+            self.untrack_forward_stream(true, token);
             self.send(RemoteStreamClosed { reference: token });
             debug!("Stream closed");
         }
@@ -418,14 +617,153 @@ impl Oxy {
     fn notify_remote_stream(&self, token: u64) {
         debug!("Remote stream notify for stream {}", token);
         let data = self.internal.remote_streams.borrow_mut().get_mut(&token).unwrap().stream.take();
-        self.send(LocalStreamData { reference: token, data });
+        self.count_forward_bytes(false, token, 0, data.len());
+        for chunk in data.chunks(arg::channel_max_packet()) {
+            self.send(LocalStreamData {
+                reference: token,
+                data:      chunk.to_vec(),
+            });
+        }
         if self.internal.remote_streams.borrow_mut().get_mut(&token).unwrap().stream.is_closed() {
             self.internal.remote_streams.borrow_mut().get_mut(&token).unwrap().stream.close();
+            self.untrack_forward_stream(false, token);
             debug!("Stream closed.");
             self.send(LocalStreamClosed { reference: token });
         }
     }
 
+    /// Remember which forward a stream belongs to, so that its connections
+    /// can be logged if the forward has logging turned on.
+    fn track_forward_stream(&self, local: bool, token: u64, forward: &str, destination: &str) {
+        if self.internal.logged_forwards.borrow().contains(forward) {
+            self.log_info(&format!("Forward {}: connection opened to {}", forward, destination));
+        }
+        crate::events::emit("forward_opened", json!({ "forward": forward, "destination": destination }));
+        let stream = ForwardStream {
+            forward:       forward.to_string(),
+            destination:   destination.to_string(),
+            bytes_in:      0,
+            bytes_out:     0,
+            last_activity: Instant::now(),
+        };
+        self.internal.forward_streams.borrow_mut().insert((local, token), stream);
+    }
+
+    /// Whether a local forward's --allow filter, if it has one, lets a
+    /// connection from this address through. Logs the refused connection if
+    /// not.
+    fn forward_accepts(&self, forward: &str, addr: IpAddr) -> bool {
+        let filters = self.internal.forward_accept_filters.borrow();
+        let allow = filters.get(forward);
+        if allow.is_none() {
+            return true;
+        }
+        // IPv4 clients of a listener bound to :: show up as mapped addresses.
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4().filter(|_| v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff]).map(IpAddr::V4).unwrap_or(addr),
+            addr => addr,
+        };
+        if allow.unwrap().iter().any(|x| crate::pattern::address_match(x, addr)) {
+            return true;
+        }
+        self.log_warn(&format!("Forward {}: refused a connection from {}, which --allow doesn't permit", forward, addr));
+        false
+    }
+
+    /// The PROXY protocol header to start a forward's connections with, if
+    /// its proxy-protocol option or --proxy-protocol asks for one.
+    fn forward_proxy_header(&self, forward: &str, source: Option<SocketAddr>, destination: Option<SocketAddr>) -> Option<Vec<u8>> {
+        let version = self.internal.forward_proxy_protocols.borrow().get(forward).cloned().or_else(arg::proxy_protocol)?;
+        Some(crate::proxy_protocol::header(version, source, destination))
+    }
+
+    /// Whether the client has any local, remote or SOCKS forwards set up.
+    fn has_forwards(&self) -> bool {
+        !self.internal.local_bind_cleaners.borrow().is_empty()
+            || !self.internal.socks_bind_cleaners.borrow().is_empty()
+            || !self.internal.kr_references.borrow().is_empty()
+    }
+
+    /// Whether a forward already has as many connections open as its
+    /// --max-connections, or --max-connections-per-forward, allows. Logs the
+    /// refused connection if so.
+    fn forward_at_capacity(&self, forward: &str) -> bool {
+        let limit = self.internal.forward_connection_limits.borrow().get(forward).cloned();
+        let limit = limit.or_else(arg::max_connections_per_forward);
+        if limit.is_none() {
+            return false;
+        }
+        let limit = limit.unwrap();
+        let open = self.internal.forward_streams.borrow().values().filter(|x| x.forward == forward).count() as u64;
+        if open < limit {
+            return false;
+        }
+        self.log_warn(&format!("Forward {}: refused a connection, {} already open", forward, open));
+        true
+    }
+
+    fn count_forward_bytes(&self, local: bool, token: u64, bytes_in: usize, bytes_out: usize) {
+        if let Some(stream) = self.internal.forward_streams.borrow_mut().get_mut(&(local, token)) {
+            stream.bytes_in += bytes_in as u64;
+            stream.bytes_out += bytes_out as u64;
+            if bytes_in + bytes_out > 0 {
+                stream.last_activity = Instant::now();
+            }
+        }
+    }
+
+    /// Closes forwarded connections that haven't carried any data, in either
+    /// direction, for --forward-idle-timeout.
+    fn notify_forward_idle_sweep(&self) {
+        let timeout = arg::forward_idle_timeout().unwrap();
+        let idle: Vec<(bool, u64)> = self
+            .internal
+            .forward_streams
+            .borrow()
+            .iter()
+            .filter(|(_, stream)| stream.last_activity.elapsed() >= timeout)
+            .map(|(key, _)| *key)
+            .collect();
+        for (local, token) in idle {
+            debug!("Closing forwarded connection {} after {} idle", token, crate::util::format_duration(timeout));
+            self.untrack_forward_stream(local, token);
+            if local {
+                if let Some(stream) = self.internal.local_streams.borrow_mut().get_mut(&token) {
+                    stream.stream.close();
+                }
+                self.send(RemoteStreamClosed { reference: token });
+            } else {
+                if let Some(stream) = self.internal.remote_streams.borrow_mut().get_mut(&token) {
+                    stream.stream.close();
+                }
+                self.send(LocalStreamClosed { reference: token });
+            }
+        }
+        let proxy = self.clone();
+        set_timeout(Rc::new(move || proxy.notify_forward_idle_sweep()), forward_idle_sweep_interval(timeout));
+    }
+
+    fn untrack_forward_stream(&self, local: bool, token: u64) {
+        let stream = self.internal.forward_streams.borrow_mut().remove(&(local, token));
+        if let Some(stream) = stream {
+            crate::events::emit(
+                "forward_closed",
+                json!({
+                    "forward": stream.forward,
+                    "destination": stream.destination,
+                    "bytes_in": stream.bytes_in,
+                    "bytes_out": stream.bytes_out,
+                }),
+            );
+            if self.internal.logged_forwards.borrow().contains(&stream.forward) {
+                self.log_info(&format!(
+                    "Forward {}: connection to {} closed, {} bytes in, {} bytes out",
+                    stream.forward, stream.destination, stream.bytes_in, stream.bytes_out
+                ));
+            }
+        }
+    }
+
     fn upgrade_to_encrypted(&self) {
         if self.is_encrypted() {
             return;
@@ -436,16 +774,177 @@ impl Oxy {
             MessageTransport::BufferedTransport(bt) => bt,
             _ => panic!(),
         };
-        let mut key = self.internal.kex_data.borrow_mut().keymaterial.as_ref().unwrap().to_vec();
         let peer = self.internal.peer_name.borrow().clone();
-        key.extend(keys::static_key(peer.as_ref().map(|x| &**x)));
+        // A client that failed key auth doesn't share our static key, so an
+        // interactive-auth session is keyed by the ephemeral agreement alone.
+        let pending = *self.internal.interactive_auth_pending.borrow();
+        let static_key = if pending && !*self.internal.interactive_auth_keyed.borrow() {
+            SecretBytes::from(Vec::new())
+        } else {
+            keys::static_key(peer.as_ref().map(|x| &**x))
+        };
+        let key = SecretBytes::concat(&[&self.internal.kex_data.borrow().keymaterial.as_ref().unwrap()[..], &static_key[..]]);
         let et = EncryptedTransport::create(bt, self.perspective(), &key);
         let pt = ProtocolTransport::create(et);
         let proxy = self.clone();
         pt.set_notify(Rc::new(move || proxy.notify_main_transport()));
         *self.internal.underlying_transport.borrow_mut() = Some(pt);
         self.notify_main_transport();
-        self.do_post_auth();
+        if !pending {
+            self.do_post_auth();
+        } else if self.perspective() == Bob {
+            self.send_auth_prompt();
+        }
+    }
+
+    fn send_auth_prompt(&self) {
+        #[cfg(all(unix, feature = "pam"))]
+        {
+            if crate::arg::pam_auth() {
+                self.start_pam_auth();
+                return;
+            }
+        }
+        let prompt = crate::arg::matches().value_of("interactive auth prompt").unwrap_or("Verification code: ");
+        self.send_auth_prompt_text(prompt, false);
+    }
+
+    /// Sends a prompt, preceded by anything the client should be told first,
+    /// such as that its last answer was wrong.
+    fn send_auth_prompt_text(&self, prompt: &str, echo: bool) {
+        let notice = ::std::mem::replace(&mut *self.internal.interactive_auth_notice.borrow_mut(), String::new());
+        self.send(AuthPrompt {
+            prompt: format!("{}{}", notice, prompt),
+            echo,
+        });
+    }
+
+    #[cfg(all(unix, feature = "pam"))]
+    fn start_pam_auth(&self) {
+        let user = crate::util::current_user_pw();
+        if user.is_err() {
+            error!("Failed to look up the session user for PAM");
+            self.finish_interactive_auth(false);
+            return;
+        }
+        let service = crate::arg::matches().value_of("pam service").unwrap();
+        let proxy = self.clone();
+        crate::pam::start(service, &user.unwrap().name, Rc::new(move |event| proxy.handle_pam_event(event)));
+    }
+
+    #[cfg(all(unix, feature = "pam"))]
+    fn handle_pam_event(&self, event: crate::pam::Event) {
+        match event {
+            crate::pam::Event::Info(text) => {
+                let mut notice = self.internal.interactive_auth_notice.borrow_mut();
+                notice.push_str(&text);
+                notice.push('\n');
+            }
+            crate::pam::Event::Prompt { prompt, echo } => self.send_auth_prompt_text(&prompt, echo),
+            crate::pam::Event::Done(accepted) => self.finish_interactive_auth(accepted),
+        }
+    }
+
+    fn answer_auth_prompt(&self, prompt: &str, echo: bool) {
+        use termion::input::TermRead;
+        let answer = match crate::arg::matches().value_of("interactive auth") {
+            Some("no") => false,
+            Some("yes") => true,
+            _ => self.interactive(),
+        };
+        if !answer {
+            crate::exit::fail(crate::exit::OxyError::Auth("The server requires interactive auth".to_string()));
+        }
+        let tty = ::termion::get_tty();
+        if tty.is_err() {
+            let message = format!("The server wants interactive auth, but no terminal is available: {:?}", tty);
+            crate::exit::fail(crate::exit::OxyError::Auth(message));
+        }
+        let mut tty = tty.unwrap();
+        write!(tty, "{}", prompt).ok();
+        tty.flush().ok();
+        let response = if echo {
+            tty.read_line()
+        } else {
+            let mut writer = tty.try_clone().unwrap();
+            let response = tty.read_passwd(&mut writer);
+            writeln!(tty).ok();
+            response
+        };
+        let response = response.ok().and_then(|x| x);
+        if response.is_none() {
+            crate::exit::fail(crate::exit::OxyError::Auth("Interactive auth was cancelled".to_string()));
+        }
+        self.send(AuthResponse { response: response.unwrap() });
+    }
+
+    fn check_auth_response(&self, response: &str) {
+        #[cfg(all(unix, feature = "pam"))]
+        {
+            if crate::arg::pam_auth() {
+                crate::pam::respond(response.to_string());
+                return;
+            }
+        }
+        let command = crate::arg::matches().value_of("interactive auth command").unwrap();
+        let client_key = ::data_encoding::BASE32_NOPAD.encode(self.internal.kex_data.borrow().connection_client_key.as_ref().unwrap());
+        let child = ::std::process::Command::new("/bin/sh")
+            .arg("-c")
+            .arg(command)
+            .env("OXY_CLIENT_KEY", &client_key)
+            .stdin(::std::process::Stdio::piped())
+            .spawn();
+        let accepted = match child {
+            Ok(mut child) => {
+                child.stdin.take().unwrap().write_all(format!("{}\n", response).as_bytes()).ok();
+                child.wait().map(|x| x.success()).unwrap_or(false)
+            }
+            Err(err) => {
+                error!("Failed to run the interactive auth command: {:?}", err);
+                false
+            }
+        };
+        self.finish_interactive_auth(accepted);
+    }
+
+    fn finish_interactive_auth(&self, accepted: bool) {
+        let client_key = ::data_encoding::BASE32_NOPAD.encode(self.internal.kex_data.borrow().connection_client_key.as_ref().unwrap());
+        if accepted {
+            info!("Accepted interactive auth for client key {}", client_key);
+            *self.internal.interactive_auth_pending.borrow_mut() = false;
+            self.send(AuthResult { accepted: true });
+            self.do_post_auth();
+            return;
+        }
+        *self.internal.interactive_auth_failures.borrow_mut() += 1;
+        warn!("Interactive auth failed for client key {}", client_key);
+        if *self.internal.interactive_auth_failures.borrow() >= 3 {
+            self.send(AuthResult { accepted: false });
+            set_timeout(Rc::new(|| crate::exit::exit(crate::exit::EXIT_LOGIN_REJECTED)), Duration::from_secs(1));
+            return;
+        }
+        self.internal.interactive_auth_notice.borrow_mut().push_str("Permission denied, please try again.\n");
+        self.send_auth_prompt();
+    }
+
+    crate fn interactive_auth_pending(&self) -> bool {
+        *self.internal.interactive_auth_pending.borrow()
+    }
+
+    /// Tells the server the size of our terminal, for the remote pty. The
+    /// pixel size goes separately, so that servers that don't know about it
+    /// still get the rows and columns.
+    #[cfg(unix)]
+    crate fn send_pty_size(&self) {
+        let size = self.internal.ui.borrow().as_ref().map(|ui| (ui.pty_size(), ui.pixel_size()));
+        // Without a terminal there's no UI, but --pty-size can still say what to ask for.
+        let size = size.or_else(|| crate::arg::pty_size().map(|size| (size, None)));
+        if let Some(((w, h), pixels)) = size {
+            self.send(PtySizeAdvertisement { w, h });
+            if let Some((xpixel, ypixel)) = pixels {
+                self.send(PtyPixelSizeAdvertisement { xpixel, ypixel });
+            }
+        }
     }
 
     #[cfg(unix)]
@@ -458,9 +957,8 @@ impl Oxy {
     fn notify_signal(&self) {
         match transportation::get_signal_name().as_str() {
             "SIGWINCH" => {
-                if self.perspective() == Alice && self.internal.ui.borrow().is_some() {
-                    let (w, h) = self.internal.ui.borrow_mut().as_mut().unwrap().pty_size();
-                    self.send(PtySizeAdvertisement { w, h });
+                if self.perspective() == Alice {
+                    self.send_pty_size();
                 }
             }
             "SIGCHLD" => {
@@ -469,11 +967,18 @@ impl Oxy {
                     let ptypid = self.internal.pty.borrow().as_ref().unwrap().child_pid;
                     let flags = ::nix::sys::wait::WaitPidFlag::WNOHANG;
                     let waitresult = ::nix::sys::wait::waitpid(ptypid, Some(flags));
-                    use nix::sys::wait::WaitStatus::Exited;
+                    use nix::sys::wait::WaitStatus::{Exited, Signaled};
                     match waitresult {
                         Ok(Exited(_pid, status)) => {
+                            *self.internal.pty_exited.borrow_mut() = true;
                             self.send(PtyExited { status });
                         }
+                        Ok(Signaled(_pid, signal, _)) => {
+                            *self.internal.pty_exited.borrow_mut() = true;
+                            self.send(PtyExited {
+                                status: crate::exit::signal_status(signal as i32),
+                            });
+                        }
                         _ => (),
                     };
                 }
@@ -482,36 +987,100 @@ impl Oxy {
                     if let Ok(result) = pipe_child.child.try_wait() {
                         debug!("Pipe child exited. {:?}", result);
                         to_remove.push(*k);
+                        if let Some(status) = result {
+                            use std::os::unix::process::ExitStatusExt;
+                            let status = status.code().unwrap_or_else(|| crate::exit::signal_status(status.signal().unwrap_or(0)));
+                            self.send(PipeCommandStatus { reference: *k, status });
+                        }
                         self.send(PipeCommandExited { reference: *k });
                     }
                 }
                 for k in to_remove {
                     self.internal.piped_children.borrow_mut().remove(&k);
                 }
+                self.check_lingering();
             }
             _ => (),
         };
     }
 
+    /// Sends our LANG and LC_* variables, per --forward-locale. Under auto they
+    /// are only sent for a pty session.
+    fn advertise_locale(&self, pty: bool) {
+        let forward = match crate::arg::matches().value_of("forward locale") {
+            Some("yes") => true,
+            Some("no") => false,
+            _ => pty,
+        };
+        if !forward {
+            return;
+        }
+        for (key, value) in ::std::env::vars() {
+            if crate::util::is_locale_var(&key) {
+                self.send(EnvironmentAdvertisement { key, value });
+            }
+        }
+    }
+
     fn interactive(&self) -> bool {
-        ::termion::is_tty(&::std::io::stdout()) && ::termion::is_tty(&::std::io::stdin())
+        !arg::matches().is_present("no stdin") && ::termion::is_tty(&::std::io::stdout()) && ::termion::is_tty(&::std::io::stdin())
+    }
+
+    /// For --detach-after-auth: waits for a round trip, so that the server has
+    /// answered every remote forward request, then goes to the background.
+    /// A remote forward the server refused is an error instead.
+    #[cfg(unix)]
+    fn detach_when_forwarding(&self) {
+        let binds: HashMap<u64, String> = self.internal.kr_references.borrow().iter().map(|(spec, id)| (*id, spec.clone())).collect();
+        self.send(Ping {});
+        self.watch(Rc::new(move |message, _| match message {
+            Reject { reference, note } if binds.contains_key(reference) => {
+                let message = format!("Remote forward {} failed: {}", binds[reference], note);
+                crate::exit::fail(crate::exit::OxyError::Connection(message));
+            }
+            Pong {} => {
+                info!("Forwards are up. Going to the background.");
+                crate::util::daemonize();
+                true
+            }
+            _ => false,
+        }));
     }
 
     fn do_post_auth(&self) {
+        *self.internal.authenticated.borrow_mut() = true;
+        self.send(VersionAdvertisement {
+            version: arg::version_string(),
+        });
         if self.perspective() == Alice {
+            let destination = self.internal.peer_name.borrow().clone();
+            crate::events::emit("authenticated", json!({ "destination": destination }));
+            if crate::arg::matches().is_present("test connection") && !*self.internal.is_daemon.borrow() {
+                info!("Connection test succeeded");
+                crate::exit::exit(crate::exit::EXIT_OK);
+            }
             self.pop_metacommand();
             self.activate_compression();
-            if !*self.internal.is_daemon.borrow() {
+            if let Some(addr) = crate::arg::matches().value_of("stdio") {
+                self.forward_stdio(addr.to_string());
+            } else if !*self.internal.is_daemon.borrow() {
                 self.run_batched_metacommands();
                 #[cfg(unix)]
                 {
-                    if self.interactive() {
+                    if crate::arg::matches().is_present("detach after auth") {
+                        self.detach_when_forwarding();
+                    } else if crate::arg::no_command() {
+                        info!("Connected. Not running a command because of --no-command.");
+                    } else if crate::arg::watch_interval().is_some() {
+                        self.run_watch_iteration();
+                    } else if self.interactive() {
                         if let Ok(term) = ::std::env::var("TERM") {
                             self.send(EnvironmentAdvertisement {
                                 key:   "TERM".to_string(),
                                 value: term,
                             });
                         }
+                        self.advertise_locale(true);
                         let mut cmd = vec!["pty".to_string()];
                         if let Some(command) = crate::arg::matches().value_of("command") {
                             cmd.push(command.to_string());
@@ -519,17 +1088,42 @@ impl Oxy {
                         self.handle_metacommand(cmd);
                     } else {
                         if let Some(cmd) = crate::arg::matches().value_of("command") {
-                            let stdin_bt = BufferedTransport::from(0);
-                            let proxy = self.clone();
-                            stdin_bt.set_notify(Rc::new(move || {
-                                proxy.notify_pipe_stdin();
-                            }));
-                            *self.internal.stdin_bt.borrow_mut() = Some(stdin_bt);
-                            self.handle_metacommand(vec!["pipe".to_string(), cmd.to_string()]);
+                            self.advertise_locale(false);
+                            if crate::arg::matches().is_present("no stdin") {
+                                self.handle_metacommand(vec!["pipe".to_string(), cmd.to_string()]);
+                                if crate::arg::stdin_eof_action() != "keep" {
+                                    let reference = self.internal.pipecmd_reference.borrow().unwrap();
+                                    self.send(PipeCommandInput { reference, input: Vec::new() });
+                                }
+                            } else {
+                                let stdin_bt = BufferedTransport::from(0);
+                                let proxy = self.clone();
+                                stdin_bt.set_notify(Rc::new(move || {
+                                    proxy.notify_pipe_stdin();
+                                }));
+                                *self.internal.stdin_bt.borrow_mut() = Some(stdin_bt);
+                                self.handle_metacommand(vec!["pipe".to_string(), cmd.to_string()]);
+                            }
                         }
                     }
                 }
-                self.create_ui();
+                // --watch output is plain lines, which the raw-mode UI would mangle.
+                // Without a command there's nothing for the UI to talk to.
+                if crate::arg::watch_interval().is_none() && !crate::arg::no_command() {
+                    self.create_ui();
+                }
+            }
+        }
+        if let Some(timeout) = arg::forward_idle_timeout() {
+            let proxy = self.clone();
+            set_timeout(Rc::new(move || proxy.notify_forward_idle_sweep()), forward_idle_sweep_interval(timeout));
+        }
+        if self.perspective() == Bob {
+            #[cfg(unix)]
+            crate::reexec::notify_authenticated();
+            crate::session_env::activate();
+            if let Some(pubkey) = keys::next_public_key() {
+                self.send(HostKeyRotation { pubkey });
             }
         }
         #[cfg(unix)]
@@ -541,12 +1135,107 @@ impl Oxy {
         }
     }
 
+    /// Greets an interactive session with --motd-command output and the key's
+    /// last login time, before the shell starts.
+    fn send_motd(&self) {
+        let mut motd = String::new();
+        if crate::arg::matches().is_present("last login") {
+            if let Some(key) = self.internal.kex_data.borrow().connection_client_key.as_ref() {
+                let fingerprint = ::data_encoding::BASE32_NOPAD.encode(key);
+                if let Some(last) = crate::lastlog::update(&fingerprint) {
+                    motd.push_str(&format!("Last login: {} ago\n", crate::util::format_duration(last)));
+                }
+            }
+        }
+        if let Some(command) = crate::arg::matches().value_of("motd command") {
+            match ::std::process::Command::new("/bin/sh").arg("-c").arg(command).output() {
+                Ok(output) => motd.push_str(&String::from_utf8_lossy(&output.stdout)),
+                Err(err) => warn!("Failed to run motd command: {:?}", err),
+            }
+        }
+        if !motd.is_empty() {
+            let data = motd.replace("\r\n", "\n").replace("\n", "\r\n").into_bytes();
+            self.send(PtyOutput { data });
+        }
+    }
+
+    /// Bridges stdin and stdout to a single connection opened from the server,
+    /// for use as a ProxyCommand. Exits once the remote end closes and stdout
+    /// has been flushed.
+    fn forward_stdio(&self, addr: String) {
+        let reference = self.send(RemoteOpen { addr });
+        let stdout_bt = BufferedTransport::from(1);
+        // Never read from stdout.
+        *stdout_bt.read_limit.borrow_mut() = 0;
+        let stream = PortStream {
+            stream: stdout_bt.clone(),
+            token:  reference,
+            oxy:    self.clone(),
+            local:  true,
+        };
+        stream.stream.set_notify(Rc::new(stream.clone()));
+        self.internal.local_streams.borrow_mut().insert(reference, stream);
+        let stdin_bt = BufferedTransport::from(0);
+        let proxy = self.clone();
+        let stdin_bt2 = stdin_bt.clone();
+        stdin_bt.set_notify(Rc::new(move || {
+            let data = stdin_bt2.take();
+            for chunk in data.chunks(arg::channel_max_packet()) {
+                proxy.send(RemoteStreamData {
+                    reference,
+                    data: chunk.to_vec(),
+                });
+            }
+            if stdin_bt2.is_closed() {
+                proxy.send(RemoteStreamClosed { reference });
+            }
+        }));
+        let proxy = self.clone();
+        self.watch(Rc::new(move |message, _| match message {
+            Reject { reference: r, note } if *r == reference => {
+                error!("Failed to open the stdio forward: {}", note);
+                proxy.exit(crate::exit::EXIT_FAILURE);
+            }
+            LocalStreamClosed { reference: r } if *r == reference => {
+                stdout_bt.close();
+                exit_when_flushed(stdout_bt.clone());
+                true
+            }
+            _ => false,
+        }));
+    }
+
+    /// Suspends or resumes compression of what we send, once -C has turned it
+    /// on, so that files that wouldn't shrink aren't compressed. The peer is
+    /// told in-band, so the switch happens between two messages.
+    crate fn set_outbound_compression(&self, enabled: bool) {
+        if !*self.internal.compression_negotiated.borrow() {
+            return;
+        }
+        if self.outbound_compressed() == enabled {
+            return;
+        }
+        if enabled {
+            let compression_type = *self.internal.compression_algorithm.borrow();
+            self.send(CompressionStart { compression_type });
+        } else {
+            self.send(CompressionStop {});
+        }
+        self.set_outbound_compressed(enabled);
+    }
+
+    /// Whether a file being sent should be compressed: always without -C,
+    /// otherwise only if it looks compressible.
+    crate fn should_compress(&self, path: &::std::path::Path) -> bool {
+        !*self.internal.compression_negotiated.borrow() || crate::util::worth_compressing(path)
+    }
+
     fn activate_compression(&self) {
         if crate::arg::matches().is_present("compression") {
             // This v is intended to block compression for via forwarders, because they'll
             // just be handling encrypted data, which isn't very compressible
-            if !*self.internal.is_daemon.borrow() || crate::arg::mode() == "copy" {
-                self.send(CompressionRequest { compression_type: 0 });
+            if !*self.internal.is_daemon.borrow() || crate::arg::mode() == "copy" || crate::arg::mode() == "bench" {
+                self.request_compression();
             }
         }
     }
@@ -560,7 +1249,8 @@ impl Oxy {
         }
         let closed = self.internal.stdin_bt.borrow_mut().as_mut().unwrap().is_closed();
         let available2 = self.internal.stdin_bt.borrow_mut().as_mut().unwrap().available();
-        let available = if available2 > 8192 { 8192 } else { available2 };
+        let max_packet = arg::channel_max_packet();
+        let available = if available2 > max_packet { max_packet } else { available2 };
         if available == 0 && !closed {
             return;
         }
@@ -568,6 +1258,10 @@ impl Oxy {
         let input = self.internal.stdin_bt.borrow_mut().as_mut().unwrap().take_chunk(available).unwrap();
         if closed && available == 0 {
             self.internal.stdin_bt.borrow_mut().take();
+            if arg::stdin_eof_action() == "keep" {
+                debug!("Stdin closed, leaving the command's stdin open");
+                return;
+            }
         }
         let reference = self.internal.pipecmd_reference.borrow_mut().unwrap();
         self.send(PipeCommandInput { reference, input });
@@ -575,35 +1269,81 @@ impl Oxy {
 
     fn run_batched_metacommands(&self) {
         if let Some(user) = arg::matches().value_of("user") {
-            self.send(UsernameAdvertisement { username: user.to_string() });
+            let id = self.send(UsernameAdvertisement { username: user.to_string() });
+            let user = user.to_string();
+            self.watch(Rc::new(move |message, _| match message {
+                Reject { reference, note } if *reference == id => {
+                    crate::exit::fail(crate::exit::OxyError::Auth(format!("The server won't let you log in as {}: {}", user, note)));
+                }
+                _ => false,
+            }));
         }
-        for command in arg::batched_metacommands() {
-            let parts = shlex::split(&command).unwrap();
-            self.handle_metacommand(parts);
+        if arg::matches().is_present("no shell") {
+            self.send(NoShellAdvertisement {});
         }
-        let ls = arg::matches().values_of("local port forward");
-        if ls.is_some() {
-            for l in ls.unwrap() {
-                self.handle_metacommand(vec!["L".to_string(), l.to_string()]);
+        if arg::matches().is_present("login shell") {
+            self.send(LoginShellAdvertisement {});
+        }
+        if let Some(shell) = arg::matches().value_of("remote shell") {
+            let id = self.send(RemoteShellAdvertisement { shell: shell.to_string() });
+            let shell = shell.to_string();
+            self.watch(Rc::new(move |message, _| match message {
+                Reject { reference, note } if *reference == id => {
+                    crate::exit::fail(crate::exit::OxyError::Config(format!("The server won't run {} as your shell: {}", shell, note)));
+                }
+                _ => false,
+            }));
+        }
+        if let Some(path) = arg::matches().value_of("working directory") {
+            let id = self.send(WorkingDirectoryAdvertisement { path: path.to_string() });
+            let path = path.to_string();
+            self.watch(Rc::new(move |message, _| match message {
+                Reject { reference, note } if *reference == id => {
+                    crate::exit::fail(crate::exit::OxyError::Config(format!("Can't start in {} on the server: {}", path, note)));
+                }
+                _ => false,
+            }));
+        }
+        if arg::matches().is_present("clear env") {
+            self.send(ClearEnvironmentAdvertisement {});
+        }
+        for command in arg::batched_metacommands() {
+            for parts in crate::util::split_metacommands(&command).unwrap() {
+                self.run_metacommand(parts);
             }
         }
-        let rs = arg::matches().values_of("remote port forward");
-        if rs.is_some() {
-            for r in rs.unwrap() {
-                self.handle_metacommand(vec!["R".to_string(), r.to_string()]);
+        for l in arg::port_forwards("local port forward") {
+            self.handle_metacommand(vec!["L".to_string(), l]);
+        }
+        for r in arg::port_forwards("remote port forward") {
+            self.handle_metacommand(vec!["R".to_string(), r]);
+        }
+        for d in arg::port_forwards("socks") {
+            let mut parts = vec!["D".to_string(), d.clone()];
+            if let Some(auth) = arg::matches().value_of("socks auth").filter(|_| !d.contains(",auth=")) {
+                parts.push(format!("--auth={}", auth));
             }
+            self.handle_metacommand(parts);
         }
-        let ds = arg::matches().values_of("socks");
-        if ds.is_some() {
-            for d in ds.unwrap() {
-                self.handle_metacommand(vec!["D".to_string(), d.to_string()]);
+        for (arg_name, metacommand) in &[("tun", "tun"), ("tap", "tap")] {
+            if let Some(specs) = arg::matches().values_of(arg_name) {
+                for spec in specs {
+                    let mut parts = spec.splitn(2, ':');
+                    let local = parts.next().unwrap().to_string();
+                    let remote = parts.next().map(|x| x.to_string()).unwrap_or_else(|| local.clone());
+                    self.handle_metacommand(vec![metacommand.to_string(), local, remote]);
+                }
             }
         }
-        if arg::matches().is_present("X Forwarding") {
-            self.initiate_x_forwarding();
+        #[cfg(unix)]
+        {
+            if arg::matches().is_present("X Forwarding") {
+                self.initiate_x_forwarding();
+            }
         }
     }
 
+    #[cfg(unix)]
     fn initiate_x_forwarding(&self) {
         warn!(r"X Forwarding counts on xauth to set a good umask. If xauth doesn't set a umask, there's a brief window where someone could steal an xauthority cookie out of /tmp. It sets umask on my system! ¯\_(ツ)_/¯");
         let trust = if arg::matches().is_present("Trusted X Forwarding") {
@@ -611,27 +1351,33 @@ impl Oxy {
         } else {
             "untrusted"
         };
+        let cookie_file = crate::tempfile::TempFile::create("oxy-xcookie");
+        if let Err(err) = cookie_file {
+            warn!("Failed to create a file for the xauthority cookie: {:?}", err);
+            return;
+        }
+        let (cookie_file, _) = cookie_file.unwrap();
+        let display = crate::xforward::local_display();
         let xauth = ::std::process::Command::new("xauth")
             .arg("-f")
-            .arg("/tmp/xcookie")
+            .arg(cookie_file.path())
             .arg("generate")
-            .arg(":0")
+            .arg(format!(":{}", display))
             .arg(".")
             .arg(trust)
             .arg("timeout")
-            .arg("3600")
+            .arg(arg::x_cookie_timeout().as_secs().to_string())
             .output();
         if xauth.is_err() {
             warn!("Failed to generate an xauthority cookie");
             return;
         }
-        let cookie = ::std::process::Command::new("xauth").arg("-f").arg("/tmp/xcookie").arg("list").output();
+        let cookie = ::std::process::Command::new("xauth").arg("-f").arg(cookie_file.path()).arg("list").output();
         if cookie.is_err() {
             warn!("Failed to retrieve the xauthority cookie");
-            ::std::fs::remove_file("/tmp/xcookie").ok();
             return;
         }
-        ::std::fs::remove_file("/tmp/xcookie").unwrap();
+        ::std::mem::drop(cookie_file);
         let cookie = cookie.unwrap();
         let cookie = String::from_utf8(cookie.stdout.clone());
         if cookie.is_err() {
@@ -640,31 +1386,131 @@ impl Oxy {
         let cookie = cookie.unwrap();
         let cookie = cookie.rsplit(" ").next().unwrap().to_string();
         debug!("xcookie: {:?}", cookie);
-        self.send(AdvertiseXAuth { cookie });
-        self.handle_metacommand(vec!["sh".to_string(), "mkdir /tmp/.X11-unix".to_string()]);
-        self.handle_metacommand(vec!["R".to_string(), "/tmp/.X11-unix/X10".to_string(), "/tmp/.X11-unix/X0".to_string()]);
+        let id = self.send(XForwardRequest { cookie });
+        self.internal.remote_bind_destinations.borrow_mut().insert(id, crate::xforward::socket_path(display));
+        let proxy = self.clone();
+        self.watch(Rc::new(move |message, _| match message {
+            XDisplayAllocated { reference, .. } if *reference == id => true,
+            Reject { reference, note } if *reference == id => {
+                proxy.log_warn(&format!("The server refused X forwarding: {}", note));
+                true
+            }
+            _ => false,
+        }));
+    }
+
+    /// The host name --output-prefix tags lines with.
+    fn output_host(&self) -> String {
+        self.internal
+            .peer_name
+            .borrow()
+            .clone()
+            .or_else(|| arg::matches().value_of("destination").map(|x| x.to_string()))
+            .unwrap_or_default()
+    }
+
+    /// Runs the --watch command once. It's run again after each exit, so
+    /// every iteration reuses this session rather than reconnecting.
+    crate fn run_watch_iteration(&self) {
+        if crate::arg::matches().is_present("watch clear") {
+            let stdout = ::std::io::stdout();
+            let mut lock = stdout.lock();
+            lock.write_all(b"\x1b[H\x1b[2J").ok();
+            lock.flush().ok();
+        }
+        let command = crate::arg::matches().value_of("command").unwrap().to_string();
+        self.handle_metacommand(vec!["pipe".to_string(), command]);
+        // The command gets no stdin, as with watch(1).
+        let reference = self.internal.pipecmd_reference.borrow().unwrap();
+        self.send(PipeCommandInput { reference, input: Vec::new() });
+    }
+
+    crate fn notify_command_timeout(&self, reference: u64) {
+        if *self.internal.pipecmd_reference.borrow() != Some(reference) {
+            return;
+        }
+        self.log_warn("Remote command timed out. Terminating it.");
+        *self.internal.pipecmd_timed_out.borrow_mut() = true;
+        self.send(PipeCommandTerminate { reference });
+        // Don't hang around forever if the server never reports the exit.
+        set_timeout(
+            Rc::new(|| {
+                crate::exit::exit(crate::exit::EXIT_COMMAND_TIMEOUT);
+            }),
+            Duration::from_secs(10),
+        );
     }
 
     fn notify_keepalive(&self) {
         trace!("Keepalive!");
-        if self.internal.last_message_seen.borrow().as_ref().unwrap().elapsed() > Duration::from_secs(180) {
-            trace!("Exiting due to lack of keepalives");
-            self.exit(2);
+        if *self.internal.lingering.borrow() {
+            return;
+        }
+        // The scheduler runs on a clock that stops while the system is
+        // suspended, so a large jump in wall-clock time between keepalives
+        // means we were asleep.
+        let interval = arg::keepalive_interval().unwrap();
+        let now = SystemTime::now();
+        let previous = ::std::mem::replace(&mut *self.internal.last_keepalive.borrow_mut(), Some(now));
+        if let Some(gap) = previous.and_then(|x| now.duration_since(x).ok()) {
+            if gap > interval * 2 {
+                info!("No keepalive for {}, the system was probably suspended", crate::util::format_duration(gap));
+                if *self.internal.suspended.borrow() && self.perspective() == Alice {
+                    self.resume_session();
+                }
+            }
+        }
+        if *self.internal.suspended.borrow() {
+            let proxy = self.clone();
+            set_timeout(Rc::new(move || proxy.notify_keepalive()), interval);
+            return;
+        }
+        let budget = match arg::keepalive_budget(interval) {
+            Ok(budget) => budget,
+            Err(err) => crate::exit::fail(crate::exit::OxyError::Config(err)),
+        };
+        let silence = self.internal.last_message_seen.borrow().as_ref().unwrap().elapsed();
+        if silence > budget {
+            if arg::keepalive_unanswered_action() == "disconnect" {
+                trace!("Exiting due to lack of keepalives");
+                crate::exit::fail(crate::exit::OxyError::Connection("The peer stopped answering keepalives".to_string()));
+            }
+            if !::std::mem::replace(&mut *self.internal.keepalive_unanswered.borrow_mut(), true) {
+                warn!("The peer stopped answering keepalives. Waiting for it to come back.");
+            }
+        } else if ::std::mem::replace(&mut *self.internal.keepalive_unanswered.borrow_mut(), false) {
+            info!("The peer is answering keepalives again");
         }
         self.send(Ping {});
         let proxy = self.clone();
-        set_timeout(Rc::new(move || proxy.notify_keepalive()), Duration::from_secs(60));
+        set_timeout(Rc::new(move || proxy.notify_keepalive()), interval);
+    }
+
+    /// Stop keepalives on both ends, so that the connection can sit idle while
+    /// the client is asleep.
+    fn suspend_session(&self) {
+        self.send(SessionSuspend {});
+        *self.internal.suspended.borrow_mut() = true;
+    }
+
+    fn resume_session(&self) {
+        self.send(SessionResume {});
+        *self.internal.suspended.borrow_mut() = false;
+        *self.internal.last_message_seen.borrow_mut() = Some(Instant::now());
+        self.send(Ping {});
+        self.log_info("Session resumed.");
     }
 
     fn notify_socks_bind(&self, token: u64) {
         let mut borrow = self.internal.socks_binds.borrow_mut();
         let bind = borrow.get_mut(&token).unwrap();
         let stream = bind.listener.accept().unwrap().0;
-        let bt = BufferedTransport::from(stream);
+        let bt = stream_transport(stream);
         let proxy = SocksConnectionNotificationProxy {
             oxy: self.clone(),
             bt,
             state: Rc::new(RefCell::new(SocksState::Initial)),
+            forward: bind.spec.clone(),
         };
         let proxy = Rc::new(proxy);
         proxy.bt.set_notify(proxy.clone());
@@ -680,7 +1526,37 @@ impl Oxy {
         match state {
             SocksState::Initial => {
                 assert!(data[0] == 5);
-                proxy.bt.put(b"\x05\x00");
+                if !self.internal.socks_credentials.borrow().contains_key(&proxy.forward) {
+                    proxy.bt.put(b"\x05\x00");
+                    *proxy.state.borrow_mut() = SocksState::Authed;
+                    return;
+                }
+                let methods = data.get(2..2 + data.get(1).cloned().unwrap_or(0) as usize).unwrap_or(&[]);
+                if !methods.contains(&2) {
+                    self.log_warn(&format!("Refusing a SOCKS client on {} that doesn't offer password authentication", proxy.forward));
+                    proxy.bt.put(b"\x05\xff");
+                    proxy.bt.close();
+                    return;
+                }
+                proxy.bt.put(b"\x05\x02");
+                *proxy.state.borrow_mut() = SocksState::Password;
+            }
+            SocksState::Password => {
+                let accepted = match (parse_socks_password(&data), self.internal.socks_credentials.borrow().get(&proxy.forward)) {
+                    (Some((user, pass)), Some((expected_user, expected_pass))) => {
+                        let user_ok = ::ring::constant_time::verify_slices_are_equal(user, expected_user.as_bytes()).is_ok();
+                        let pass_ok = ::ring::constant_time::verify_slices_are_equal(pass, expected_pass.as_bytes()).is_ok();
+                        user_ok && pass_ok
+                    }
+                    _ => false,
+                };
+                if !accepted {
+                    self.log_warn(&format!("SOCKS authentication failed on {}", proxy.forward));
+                    proxy.bt.put(b"\x01\x01");
+                    proxy.bt.close();
+                    return;
+                }
+                proxy.bt.put(b"\x01\x00");
                 *proxy.state.borrow_mut() = SocksState::Authed;
             }
             SocksState::Authed => {
@@ -708,7 +1584,12 @@ impl Oxy {
                     _ => panic!(),
                 }
                 debug!("Socks dest: {}", dest);
-                let reference = self.send(RemoteOpen { addr: dest });
+                if self.forward_at_capacity(&proxy.forward) {
+                    proxy.bt.put(b"\x05\x02\x00\x01\x00\x00\x00\x00\x00\x00");
+                    proxy.bt.close();
+                    return;
+                }
+                let reference = self.send(RemoteOpen { addr: dest.clone() });
                 proxy.bt.put(b"\x05\x00\x00\x01\x00\x00\x00\x00\x00\x00"); // TODO: Maybe provide like... connection refused by destination host
                 let stream = PortStream {
                     stream: proxy.bt.clone(),
@@ -719,6 +1600,7 @@ impl Oxy {
                 let stream2 = Rc::new(stream.clone());
                 stream.stream.set_notify(stream2);
                 self.internal.local_streams.borrow_mut().insert(reference, stream);
+                self.track_forward_stream(true, reference, &proxy.forward, &dest);
             }
         }
     }
@@ -737,18 +1619,25 @@ impl Oxy {
     pub fn notify_main_transport(&self) {
         debug!("Core notified. Has write space: {}", self.has_write_space());
         if self.internal.underlying_transport.borrow().as_ref().unwrap().is_closed() {
+            #[cfg(unix)]
+            {
+                if self.linger() {
+                    return;
+                }
+            }
             eprint!("\n\r");
             self.log_info("Connection loss detected.");
-            crate::exit::exit(0);
+            crate::exit::exit(crate::exit::connection_lost_status());
         }
         loop {
-            let message = self.internal.underlying_transport.borrow().as_ref().unwrap().recv_tolerant();
+            let message = self.receive();
             if message.is_none() {
                 break;
             }
             let message = message.unwrap();
             let message_number = self.tick_incoming();
             if message.is_none() {
+                crate::protocol_trace::observe_undecodable(message_number);
                 self.send(Reject {
                     reference: message_number,
                     note:      "Invalid message".to_string(),
@@ -756,6 +1645,7 @@ impl Oxy {
                 continue;
             }
             let message = message.unwrap();
+            crate::protocol_trace::observe_inbound(message_number, &message);
             let result = self.handle_message(message, message_number);
             if result.is_err() {
                 self.send(Reject {
@@ -776,8 +1666,45 @@ impl Oxy {
     }
 }
 
+/// The local and remote addresses of the connection, where it's a socket.
+fn socket_addresses(bt: &BufferedTransport) -> (Option<String>, Option<String>) {
+    match &*bt.underlying.borrow() {
+        Transport::TcpStream(stream) => (
+            stream.local_addr().ok().map(|x| x.to_string()),
+            stream.peer_addr().ok().map(|x| x.to_string()),
+        ),
+        #[cfg(unix)]
+        Transport::FdAdapter(adapter) => (
+            ::nix::sys::socket::getsockname(adapter.fd).ok().map(|x| x.to_str()),
+            ::nix::sys::socket::getpeername(adapter.fd).ok().map(|x| x.to_str()),
+        ),
+    }
+}
+
+fn exit_when_flushed(bt: BufferedTransport) {
+    if bt.write_buffer.borrow().is_empty() || bt.is_closed() {
+        crate::exit::exit(crate::exit::EXIT_OK);
+    }
+    set_timeout(Rc::new(move || exit_when_flushed(bt.clone())), Duration::from_millis(10));
+}
+
+/// How often to look for idle forwarded connections: often enough that one is
+/// closed within half of the timeout after it expires.
+fn forward_idle_sweep_interval(timeout: Duration) -> Duration {
+    ::std::cmp::max(timeout / 2, Duration::from_secs(1))
+}
+
+/// Wraps a forwarded stream, capping how much unsent data it may buffer at the
+/// configured channel window.
+fn stream_transport<T: Into<BufferedTransport>>(transport: T) -> BufferedTransport {
+    let bt: BufferedTransport = transport.into();
+    *bt.read_limit.borrow_mut() = arg::channel_window();
+    bt
+}
+
 struct SocksBind {
     listener: TcpListener,
+    spec:     String,
 }
 
 struct SocksBindNotificationProxy {
@@ -791,6 +1718,14 @@ impl Notifiable for SocksBindNotificationProxy {
     }
 }
 
+struct ForwardStream {
+    forward:       String,
+    destination:   String,
+    bytes_in:      u64,
+    bytes_out:     u64,
+    last_activity: Instant,
+}
+
 struct PortBind {
     listener:    TcpListener,
     remote_spec: String,
@@ -816,9 +1751,10 @@ impl Notifiable for PortStream {
 }
 
 struct SocksConnectionNotificationProxy {
-    oxy:   Oxy,
-    bt:    BufferedTransport,
-    state: Rc<RefCell<SocksState>>,
+    oxy:     Oxy,
+    bt:      BufferedTransport,
+    state:   Rc<RefCell<SocksState>>,
+    forward: String,
 }
 
 impl Notifiable for SocksConnectionNotificationProxy {
@@ -830,5 +1766,18 @@ impl Notifiable for SocksConnectionNotificationProxy {
 #[derive(PartialEq, Clone, Debug)]
 enum SocksState {
     Initial,
+    Password,
     Authed,
 }
+
+/// Splits an RFC 1929 username/password request into its two fields.
+fn parse_socks_password(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    if data.first() != Some(&1) {
+        return None;
+    }
+    let user_len = *data.get(1)? as usize;
+    let user = data.get(2..2 + user_len)?;
+    let pass_len = *data.get(2 + user_len)? as usize;
+    let pass = data.get(3 + user_len..3 + user_len + pass_len)?;
+    Some((user, pass))
+}
@@ -0,0 +1,157 @@
+// -C: compressing what's sent. zlib is done by transportation's
+// ProtocolTransport. It knows no other algorithm, so zstd is done here instead,
+// on each message after it's serialized, with the transport's own compression
+// left off.
+//
+// The client asks for an algorithm with a CompressionRequest, and the server
+// answers with a CompressionStart naming the one it picked, which then applies
+// both ways. A server that doesn't know the requested algorithm picks zlib.
+// Servers from before zstd reject anything but zlib, so the client asks again
+// for zlib when its request is rejected.
+use crate::{
+    core::Oxy,
+    message::OxyMessage::{self, *},
+};
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use std::rc::Rc;
+
+const ZLIB: u64 = 0;
+const ZSTD: u64 = 1;
+
+fn algorithm_name(algorithm: u64) -> &'static str {
+    match algorithm {
+        ZSTD => "zstd",
+        _ => "zlib",
+    }
+}
+
+impl Oxy {
+    /// Whether what we send is being compressed at the moment.
+    pub(super) fn outbound_compressed(&self) -> bool {
+        if *self.internal.compression_algorithm.borrow() == ZSTD {
+            return *self.internal.outbound_zstd.borrow();
+        }
+        self.internal.underlying_transport.borrow().as_ref().unwrap().outbound_compression
+    }
+
+    pub(super) fn inbound_compressed(&self) -> bool {
+        if *self.internal.compression_algorithm.borrow() == ZSTD {
+            return *self.internal.inbound_zstd.borrow();
+        }
+        self.internal.underlying_transport.borrow().as_ref().unwrap().inbound_compression
+    }
+
+    /// The name of the algorithm messages are compressed with when they are.
+    pub(super) fn compression_name(&self) -> &'static str {
+        algorithm_name(*self.internal.compression_algorithm.borrow())
+    }
+
+    pub(super) fn set_outbound_compressed(&self, enabled: bool) {
+        if *self.internal.compression_algorithm.borrow() == ZSTD {
+            *self.internal.outbound_zstd.borrow_mut() = enabled;
+            return;
+        }
+        self.internal.underlying_transport.borrow_mut().as_mut().unwrap().outbound_compression = enabled;
+    }
+
+    pub(super) fn set_inbound_compressed(&self, enabled: bool) {
+        if *self.internal.compression_algorithm.borrow() == ZSTD {
+            *self.internal.inbound_zstd.borrow_mut() = enabled;
+            return;
+        }
+        self.internal.underlying_transport.borrow_mut().as_mut().unwrap().inbound_compression = enabled;
+    }
+
+    /// Hands a message to the transport, compressing it first under zstd.
+    pub(super) fn transmit(&self, message: OxyMessage) {
+        let transport = self.internal.underlying_transport.borrow();
+        let transport = transport.as_ref().unwrap();
+        if !*self.internal.outbound_zstd.borrow() {
+            transport.send(message);
+            return;
+        }
+        let data = ::serde_cbor::ser::to_vec_packed(&message).unwrap();
+        let data = ::zstd::stream::encode_all(&data[..], crate::arg::compress_level()).expect("Failed to compress outbound message");
+        transport.mt.send(&data[..]);
+    }
+
+    /// The next message from the transport, if one has arrived, or Some(None)
+    /// if it couldn't be decoded.
+    pub(super) fn receive(&self) -> Option<Option<OxyMessage>> {
+        let transport = self.internal.underlying_transport.borrow();
+        let transport = transport.as_ref().unwrap();
+        if !*self.internal.inbound_zstd.borrow() {
+            return transport.recv_tolerant();
+        }
+        let data = transport.mt.recv()?;
+        Some(::zstd::stream::decode_all(&data[..]).ok().and_then(|x| ::serde_cbor::from_slice(&x).ok()))
+    }
+
+    /// On the client, asks for the algorithm -C names.
+    pub(super) fn request_compression(&self) {
+        let algorithm = match crate::arg::compression_algorithm() {
+            "zstd" => ZSTD,
+            _ => ZLIB,
+        };
+        let id = self.send(CompressionRequest {
+            compression_type: algorithm,
+        });
+        if algorithm == ZLIB {
+            return;
+        }
+        let proxy = self.clone();
+        self.watch(Rc::new(move |message, _| match message {
+            Reject { reference, .. } if *reference == id => {
+                warn!("The server doesn't support {} compression, using zlib", algorithm_name(algorithm));
+                proxy.send(CompressionRequest { compression_type: ZLIB });
+                true
+            }
+            _ => false,
+        }));
+    }
+
+    /// On the server, picks the algorithm for a CompressionRequest and starts
+    /// compressing with it.
+    pub(super) fn handle_compression_request(&self, compression_type: u64) {
+        let algorithm = match compression_type {
+            ZLIB | ZSTD => compression_type,
+            _ => {
+                debug!("The client asked for unknown compression algorithm {}, using zlib", compression_type);
+                ZLIB
+            }
+        };
+        // The algorithm can't change once messages have been compressed with it.
+        if !::std::mem::replace(&mut *self.internal.compression_negotiated.borrow_mut(), true) {
+            *self.internal.compression_algorithm.borrow_mut() = algorithm;
+        }
+        if !self.outbound_compressed() {
+            let algorithm = *self.internal.compression_algorithm.borrow();
+            debug!("Activating {} compression", algorithm_name(algorithm));
+            self.send(CompressionStart {
+                compression_type: algorithm,
+            });
+            self.set_outbound_compressed(true);
+        }
+    }
+
+    /// The peer compresses what it sends from here on: with the algorithm the
+    /// server picked, which the client adopts for its side too.
+    pub(super) fn handle_compression_start(&self, compression_type: u64) {
+        if compression_type != ZLIB && compression_type != ZSTD {
+            panic!("Unknown compression algorithm");
+        }
+        // Once compression has been negotiated, a CompressionStart only
+        // resumes the peer's side after a CompressionStop.
+        let negotiated = ::std::mem::replace(&mut *self.internal.compression_negotiated.borrow_mut(), true);
+        if !negotiated {
+            *self.internal.compression_algorithm.borrow_mut() = compression_type;
+        }
+        self.set_inbound_compressed(true);
+        if !negotiated && !self.outbound_compressed() {
+            debug!("Activating {} compression.", algorithm_name(compression_type));
+            self.send(CompressionStart { compression_type });
+            self.set_outbound_compressed(true);
+        }
+    }
+}
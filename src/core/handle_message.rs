@@ -1,4 +1,4 @@
-use super::{PortBind, PortStream};
+use super::{stream_transport, PortBind, PortStream};
 #[cfg(unix)]
 use crate::pty::Pty;
 #[cfg(unix)]
@@ -10,11 +10,11 @@ use crate::{
 };
 #[allow(unused_imports)]
 use log::{debug, error, info, log, trace, warn};
+use shlex;
 use std::{
     cell::RefCell,
     fs::{read_dir, symlink_metadata, File},
     io::Write,
-    net::ToSocketAddrs,
     path::PathBuf,
     rc::Rc,
     time::Instant,
@@ -45,6 +45,108 @@ impl Oxy {
         *self.internal.message_claim.borrow_mut() = true;
     }
 
+    /// The program and arguments to run for a client's command: the command
+    /// given to the shell, or under --no-shell, the command split into words
+    /// with shell quoting rules. Under --login-shell the shell is a login
+    /// shell, so it reads the user's profile first.
+    fn command_argv(&self, command: &str) -> Result<Vec<String>, &'static str> {
+        if *self.internal.no_shell.borrow() {
+            let argv = shlex::split(command).ok_or("Unbalanced quotes in command")?;
+            if argv.is_empty() {
+                Err("Empty command")?;
+            }
+            return Ok(argv);
+        }
+        #[cfg(unix)]
+        let flag = if *self.internal.login_shell.borrow() { "-lc" } else { "-c" };
+        #[cfg(unix)]
+        let argv = vec!["/bin/sh".to_string(), flag.to_string(), command.to_string()];
+        #[cfg(windows)]
+        let argv = vec!["cmd.exe".to_string(), "/c".to_string(), command.to_string()];
+        Ok(argv)
+    }
+
+    /// Under --clear-env, empties the environment commands inherit, apart from
+    /// PATH, HOME, TERM, what the client sent, and oxy's own session
+    /// variables.
+    fn clear_environment(&self) {
+        if !*self.internal.clear_env.borrow() {
+            return;
+        }
+        let advertised = self.internal.advertised_env.borrow();
+        let keep = ["PATH", "HOME", "TERM", "DISPLAY", "OXY_SESSION_ID", "OXY_SESSION_ENV_FILE"];
+        for (key, _) in ::std::env::vars_os() {
+            let kept = key.to_str().map(|x| keep.contains(&x) || advertised.contains(x)).unwrap_or(false);
+            if !kept {
+                ::std::env::remove_var(key);
+            }
+        }
+        if ::std::env::var_os("PATH").is_none() {
+            ::std::env::set_var("PATH", "/usr/local/bin:/usr/bin:/bin");
+        }
+    }
+
+    /// Listens on a unix socket for a remote forward, or X forwarding.
+    /// Connections to it are announced with BindConnectionAccepted, referring
+    /// to the message that asked for it.
+    fn bind_unix_socket(&self, addr: String, message_number: u64) -> Result<(), String> {
+        use nix::{
+            sys::socket::{accept, bind, listen, socket, AddressFamily, SockAddr, SockFlag, SockType},
+            unistd::{close, unlink},
+        };
+        let socket = socket(AddressFamily::Unix, SockType::Stream, SockFlag::empty(), None).map_err(|_| "Failed to create socket")?;
+        let sockaddr = SockAddr::new_unix(&PathBuf::from(addr.clone())).map_err(|_| "Failed to parse socket address")?;
+        bind(socket, &sockaddr).map_err(|_| "Failed to bind")?;
+        listen(socket, 10).map_err(|_| "Failed to listen")?;
+        debug!("Remote bind successful");
+        let token = Rc::new(RefCell::new(0));
+        let token2 = token.clone();
+        let proxy = self.clone();
+        let addr2 = addr.clone();
+        let token3 = transportation::insert_listener(Rc::new(move || {
+            let addr = addr.clone();
+            let token = token.clone();
+            let peer = accept(socket);
+            debug!("Accepted connection");
+            if peer.is_err() {
+                proxy.log_warn(&format!("Failed to accept connection on {}", addr));
+                transportation::remove_listener(*token.borrow());
+                return;
+            }
+            let peer = peer.unwrap();
+            let stream_token = proxy.send(BindConnectionAccepted { reference: message_number });
+            let bt = stream_transport(peer);
+            let tracker = super::PortStream {
+                stream: bt,
+                token:  stream_token,
+                oxy:    proxy.clone(),
+                local:  true,
+            };
+            let tracker2 = Rc::new(tracker.clone());
+            tracker.stream.set_notify(tracker2);
+            proxy.internal.local_streams.borrow_mut().insert(stream_token, tracker);
+        }));
+        *token2.borrow_mut() = token3;
+        transportation::borrow_poll(|poll| {
+            poll.register(&EventedFd(&socket), Token(token3), Ready::readable(), PollOpt::level())
+                .unwrap()
+        });
+
+        crate::session_env::add_forward(message_number, &addr2);
+        self.internal.remote_bind_cleaners.borrow_mut().insert(
+            message_number,
+            Rc::new(move || {
+                debug!("Closing remote bind.");
+                transportation::remove_listener(token3);
+                close(socket).map_err(|x| warn!("While closing remote bind: {:?}", x)).ok();
+                unlink(&PathBuf::from(addr2.clone()))
+                    .map_err(|x| warn!("While unlinking remote bind: {:?}", x))
+                    .ok();
+            }),
+        );
+        Ok(())
+    }
+
     fn qualify_path(&self, path: String) -> PathBuf {
         let mut path: PathBuf = path.into();
         if !path.is_absolute() && self.internal.pty.borrow_mut().is_some() {
@@ -58,7 +160,9 @@ impl Oxy {
     pub(crate) fn handle_message(&self, message: OxyMessage, message_number: u64) -> Result<(), String> {
         debug!("Recieved message {}", message_number);
         trace!("Received message {}: {:?}", message_number, message);
+        self.check_server_policy(&message)?;
         let message = self.restrict_message(message).map_err(|_| "Permission denied")?;
+        crate::audit::observe_inbound(&message);
         *self.internal.message_claim.borrow_mut() = false;
         self.dispatch_watchers(&message, message_number);
         if *self.internal.message_claim.borrow() {
@@ -70,6 +174,10 @@ impl Oxy {
                 let message = format!("Server rejected a request: {:?}", note);
                 self.log_debug(&message);
             }
+            VersionAdvertisement { version } => {
+                info!("Peer identifies as {:?}", version);
+                *self.internal.peer_version.borrow_mut() = Some(version);
+            }
             Ping {} => {
                 self.send(Pong {});
             }
@@ -79,28 +187,104 @@ impl Oxy {
             Exit {} => {
                 crate::exit::exit(0);
             }
+            AuthPrompt { prompt, echo } => {
+                self.alice_only();
+                self.answer_auth_prompt(&prompt, echo);
+            }
+            AuthResponse { response } => {
+                self.bob_only();
+                if !self.interactive_auth_pending() {
+                    Err("Unexpected auth response")?;
+                }
+                self.check_auth_response(&response);
+            }
+            AuthResult { accepted } => {
+                self.alice_only();
+                if !accepted {
+                    crate::exit::fail(crate::exit::OxyError::Auth("Interactive auth failed".to_string()));
+                }
+                *self.internal.interactive_auth_pending.borrow_mut() = false;
+                self.do_post_auth();
+            }
             UsernameAdvertisement { username } => {
                 self.bob_only();
+                if *self.internal.su_pending.borrow() {
+                    let peer = self.internal.peer_name.borrow().clone().unwrap_or_default();
+                    if !crate::conf::allowed_users(&peer).contains(&username) {
+                        warn!("Refusing to let {} log in as {:?}, which isn't in its allowed_users", peer, username);
+                        *self.internal.su_refused.borrow_mut() = true;
+                        transportation::set_timeout(
+                            Rc::new(|| crate::exit::exit(crate::exit::EXIT_LOGIN_REJECTED)),
+                            ::std::time::Duration::from_secs(1),
+                        );
+                        Err("Not permitted")?;
+                    }
+                    self.become_user(Some(username.clone()));
+                }
                 *self.internal.peer_user.borrow_mut() = Some(username);
             }
             EnvironmentAdvertisement { key, value } => {
                 self.bob_only();
-                if key.as_str() != "TERM" {
+                let accepted = crate::arg::matches().values_of("accept env");
+                let default = key.as_str() == "TERM" || crate::util::is_locale_var(&key);
+                if !default && !accepted.map(|x| crate::pattern::glob_match_any(x, &key)).unwrap_or(false) {
                     Err("Unsupported")?;
                 }
+                self.internal.advertised_env.borrow_mut().insert(key.clone());
                 ::std::env::set_var(key, value);
             }
-            BasicCommand { command } => {
+            ClearEnvironmentAdvertisement {} => {
+                self.bob_only();
+                *self.internal.clear_env.borrow_mut() = true;
+            }
+            WhoamiRequest {} => {
                 self.bob_only();
                 #[cfg(unix)]
-                let sh = "/bin/sh";
+                let user = crate::util::current_user_pw().map(|x| x.name).unwrap_or_default();
+                #[cfg(not(unix))]
+                let user = ::std::env::var("USERNAME").unwrap_or_default();
                 #[cfg(unix)]
-                let flag = "-c";
-                #[cfg(windows)]
-                let sh = "cmd.exe";
-                #[cfg(windows)]
-                let flag = "/c";
-                let result = ::std::process::Command::new(sh).arg(flag).arg(command).output();
+                let uid = unsafe { ::libc::getuid() };
+                #[cfg(not(unix))]
+                let uid = 0;
+                self.send(WhoamiResult {
+                    reference: message_number,
+                    user,
+                    uid,
+                    client_name: self.internal.peer_name.borrow().clone(),
+                });
+            }
+            NoShellAdvertisement {} => {
+                self.bob_only();
+                *self.internal.no_shell.borrow_mut() = true;
+            }
+            RemoteShellAdvertisement { shell } => {
+                self.bob_only();
+                if !crate::arg::matches().is_present("allow shell override") {
+                    Err("This server doesn't allow choosing the shell")?;
+                }
+                if !shell.starts_with('/') || !crate::util::is_executable(&shell) {
+                    Err("No such shell, or it isn't executable")?;
+                }
+                debug!("Using shell {:?} for the pty", shell);
+                *self.internal.remote_shell.borrow_mut() = Some(shell);
+            }
+            WorkingDirectoryAdvertisement { path } => {
+                self.bob_only();
+                // Commands and the pty inherit our directory. This runs after
+                // the switch to the user, so their permissions apply.
+                ::std::env::set_current_dir(&path).map_err(|err| format!("Failed to enter {}: {}", path, err))?;
+                debug!("Changed directory to {:?}", path);
+            }
+            LoginShellAdvertisement {} => {
+                self.bob_only();
+                *self.internal.login_shell.borrow_mut() = true;
+            }
+            BasicCommand { command } => {
+                self.bob_only();
+                let argv = self.command_argv(&command)?;
+                self.clear_environment();
+                let result = ::std::process::Command::new(&argv[0]).args(&argv[1..]).output();
                 if let Ok(result) = result {
                     self.send(BasicCommandOutput {
                         stdout: result.stdout,
@@ -110,69 +294,22 @@ impl Oxy {
             }
             CompressionRequest { compression_type } => {
                 self.bob_only();
-                if compression_type != 0 {
-                    Err("Unsupported compression algorithm")?;
-                }
-                let outbound_compression: bool = self
-                    .internal
-                    .underlying_transport
-                    .borrow()
-                    .as_ref()
-                    .expect("Shouldn't happen")
-                    .outbound_compression;
-                if !outbound_compression {
-                    debug!("Activating compression");
-                    self.send(CompressionStart { compression_type: 0 });
-                    self.internal
-                        .underlying_transport
-                        .borrow_mut()
-                        .as_mut()
-                        .expect("Shouldn't happen")
-                        .outbound_compression = true;
-                }
+                self.handle_compression_request(compression_type);
             }
             CompressionStart { compression_type } => {
-                if compression_type != 0 {
-                    panic!("Unknown compression algorithm");
-                }
-                self.internal
-                    .underlying_transport
-                    .borrow_mut()
-                    .as_mut()
-                    .expect("Shouldn't happen")
-                    .inbound_compression = true;
-                if !self
-                    .internal
-                    .underlying_transport
-                    .borrow()
-                    .as_ref()
-                    .expect("Shouldn't happen")
-                    .outbound_compression
-                {
-                    debug!("Activating compression.");
-                    self.send(CompressionStart { compression_type: 0 });
-                    self.internal
-                        .underlying_transport
-                        .borrow_mut()
-                        .as_mut()
-                        .expect("Shouldn't happen")
-                        .outbound_compression = true;
-                }
+                self.handle_compression_start(compression_type);
+            }
+            CompressionStop {} => {
+                debug!("Peer suspended compression");
+                self.set_inbound_compressed(false);
             }
             PipeCommand { command } => {
                 self.bob_only();
                 use std::process::Stdio;
-                #[cfg(unix)]
-                let sh = "/bin/sh";
-                #[cfg(unix)]
-                let flag = "-c";
-                #[cfg(windows)]
-                let sh = "cmd.exe";
-                #[cfg(windows)]
-                let flag = "/c";
-                let mut result = ::std::process::Command::new(sh)
-                    .arg(flag)
-                    .arg(command)
+                let argv = self.command_argv(&command)?;
+                self.clear_environment();
+                let mut result = ::std::process::Command::new(&argv[0])
+                    .args(&argv[1..])
                     .stdout(Stdio::piped())
                     .stderr(Stdio::piped())
                     .stdin(Stdio::piped())
@@ -217,29 +354,49 @@ impl Oxy {
                     .inp
                     .put(&input);
             }
-            PipeCommandOutput {
-                reference: _,
-                stdout,
-                stderr,
-            } => {
+            #[cfg(unix)]
+            PipeCommandTerminate { reference } => {
+                self.bob_only();
+                use nix::{
+                    sys::signal::{kill, Signal::SIGTERM},
+                    unistd::Pid,
+                };
+                let pid = self
+                    .internal
+                    .piped_children
+                    .borrow()
+                    .get(&reference)
+                    .ok_or("Invalid reference")?
+                    .child
+                    .id();
+                kill(Pid::from_raw(pid as i32), SIGTERM).map_err(|_| "Failed to signal command")?;
+                let proxy = self.clone();
+                transportation::set_timeout(
+                    Rc::new(move || {
+                        if let Some(child) = proxy.internal.piped_children.borrow_mut().get_mut(&reference) {
+                            debug!("Pipe child survived SIGTERM, sending SIGKILL");
+                            child.child.kill().ok();
+                        }
+                    }),
+                    ::std::time::Duration::from_secs(5),
+                );
+            }
+            PipeCommandOutput { reference, stdout, stderr } => {
                 self.alice_only();
+                let host = self.output_host();
+                crate::output_file::write(false, &stdout);
+                crate::output_file::write(true, &stderr);
                 if !stdout.is_empty() {
-                    let a = ::std::io::stdout();
-                    let mut lock = a.lock();
-                    let status = lock.write_all(&stdout);
+                    let status = crate::output_prefix::write(&host, reference, false, &stdout);
                     if status.is_err() {
                         self.log_warn(&format!("Error writing to stdout: {:?}", status));
                     }
-                    lock.flush().ok();
                 }
                 if !stderr.is_empty() {
-                    let a = ::std::io::stderr();
-                    let mut lock = a.lock();
-                    let status = lock.write_all(&stderr);
+                    let status = crate::output_prefix::write(&host, reference, true, &stderr);
                     if status.is_err() {
                         self.log_warn(&format!("Error writing to stderr: {:?}", status));
                     }
-                    lock.flush().ok();
                 }
             }
             AdvertiseXAuth { cookie } => {
@@ -253,20 +410,75 @@ impl Oxy {
                     .map_err(|_| "Xauth failed")?;
                 ::std::env::set_var("DISPLAY", ":10");
             }
-            PipeCommandExited { reference: _ } => {
+            #[cfg(unix)]
+            XForwardRequest { cookie } => {
+                self.bob_only();
+                let display = crate::xforward::allocate_display()?;
+                crate::exit::push_hook(move || crate::xforward::release_display(display));
+                self.bind_unix_socket(crate::xforward::socket_path(display), message_number)?;
+                ::std::process::Command::new("xauth")
+                    .arg("add")
+                    .arg(format!(":{}", display))
+                    .arg(".")
+                    .arg(&cookie)
+                    .output()
+                    .map_err(|_| "Xauth failed")?;
+                ::std::env::set_var("DISPLAY", format!(":{}", display));
+                info!("Forwarding X on display :{}", display);
+                self.send(XDisplayAllocated {
+                    reference: message_number,
+                    display,
+                });
+            }
+            XDisplayAllocated { display, .. } => {
                 self.alice_only();
+                self.log_info(&format!("X forwarding is on the server's display :{}", display));
+            }
+            PipeCommandStatus { reference, status } => {
+                self.alice_only();
+                if *self.internal.pipecmd_reference.borrow() == Some(reference) {
+                    *self.internal.pipecmd_status.borrow_mut() = Some(status);
+                }
+            }
+            PipeCommandExited { reference } => {
+                self.alice_only();
+                crate::output_prefix::finish(&self.output_host(), reference);
+                // Only the last command started decides when the session ends,
+                // and how the client exits.
+                if *self.internal.pipecmd_reference.borrow() != Some(reference) {
+                    return Ok(());
+                }
                 // This is crude and temporary
                 // It'd be nice to like... check if we're actually waiting on a pipecommand/if
                 // we're doing anything else also
-                crate::exit::exit(0);
+                if *self.internal.pipecmd_timed_out.borrow() {
+                    crate::exit::exit(crate::exit::EXIT_COMMAND_TIMEOUT);
+                }
+                if let Some(interval) = crate::arg::watch_interval() {
+                    let proxy = self.clone();
+                    ::transportation::set_timeout(Rc::new(move || proxy.run_watch_iteration()), interval);
+                    return Ok(());
+                }
+                if crate::arg::stdin_eof_action() == "keep" && self.has_forwards() {
+                    info!("The command exited, keeping the session open for its port forwards");
+                    return Ok(());
+                }
+                let status = self.internal.pipecmd_status.borrow().unwrap_or(crate::exit::EXIT_OK);
+                crate::exit::exit(status);
             }
             #[cfg(unix)]
             PtyRequest { command } => {
                 self.bob_only();
 
-                let command2 = command.as_ref().map(|x| x.as_str());
+                let argv = match command {
+                    Some(command) => Some(self.command_argv(&command)?),
+                    None => None,
+                };
+                self.send_motd();
+                self.clear_environment();
 
-                let pty = Pty::forkpty(command2).map_err(|_| "forkpty failed")?;
+                let shell = self.internal.remote_shell.borrow().clone();
+                let pty = Pty::forkpty(argv, shell).map_err(|_| "forkpty failed")?;
                 let proxy = self.clone();
                 pty.underlying.set_notify(Rc::new(move || proxy.notify_pty()));
                 *self.internal.pty.borrow_mut() = Some(pty);
@@ -279,6 +491,11 @@ impl Oxy {
                 self.internal.pty.borrow_mut().as_mut().ok_or("No PTY exists")?.set_size(w, h);
             }
             #[cfg(unix)]
+            PtyPixelSizeAdvertisement { xpixel, ypixel } => {
+                self.bob_only();
+                self.internal.pty.borrow_mut().as_mut().ok_or("No PTY exists")?.set_pixel_size(xpixel, ypixel);
+            }
+            #[cfg(unix)]
             PtyInput { data } => {
                 self.bob_only();
                 self.internal.pty.borrow_mut().as_mut().ok_or("No PTY exists")?.underlying.put(&data[..]);
@@ -286,14 +503,45 @@ impl Oxy {
             #[cfg(unix)]
             PtyOutput { data } => {
                 self.alice_only();
-                if self.internal.ui.borrow().is_some() {
-                    self.internal.ui.borrow_mut().as_mut().unwrap().pty_data(&data);
+                crate::record::pty_output(&data);
+                crate::output_file::write(false, &data);
+                self.queue_pty_output(data);
+            }
+            #[cfg(unix)]
+            Hangup { keep_running } => {
+                self.bob_only();
+                self.handle_hangup(keep_running);
+            }
+            #[cfg(unix)]
+            PtyFlowControl { paused } => {
+                self.bob_only();
+                *self.internal.pty_paused.borrow_mut() = paused;
+                if !paused && self.internal.pty.borrow().is_some() {
+                    self.notify_pty();
+                }
+            }
+            #[cfg(unix)]
+            PtyLocalEcho { enabled } => {
+                self.bob_only();
+                let mut pty = self.internal.pty.borrow_mut();
+                let pty = pty.as_mut().ok_or("No PTY exists")?;
+                if enabled {
+                    pty.start_local_echo();
                 } else {
-                    let stdout = ::std::io::stdout();
-                    let mut lock = stdout.lock();
-                    lock.write_all(&data).unwrap();
+                    pty.stop_local_echo();
                 }
             }
+            PtyModeChanged {} => {
+                self.alice_only();
+                if let Some(ui) = self.internal.ui.borrow().as_ref() {
+                    let line = ui.set_line_mode(false);
+                    ui.set_local_echo(false);
+                    if !line.is_empty() {
+                        self.send(PtyInput { data: line });
+                    }
+                }
+                self.log_info("The remote program changed the terminal's settings; back to raw mode with remote echo.");
+            }
             BasicCommandOutput { stdout, stderr } => {
                 self.alice_only();
                 self.log_debug(&format!("BasicCommandOutput {:?}, {:?}", stdout, stderr));
@@ -309,18 +557,20 @@ impl Oxy {
                 use std::io::{Seek, SeekFrom};
                 self.bob_only();
                 let path = self.qualify_path(path);
-                let mut file = File::open(path).map_err(|_| "Failed to open file")?;
+                let mut file = File::open(&path).map_err(|_| "Failed to open file")?;
                 if let Some(offset_start) = offset_start {
                     file.seek(SeekFrom::Start(offset_start)).map_err(|_| "Start-seek failed")?;
                 }
                 let metadata = file.metadata().unwrap();
                 let offset_end = offset_end.unwrap_or(metadata.len());
+                let compress = self.should_compress(&path);
                 use super::TransferOut;
                 self.internal.transfers_out.borrow_mut().push(TransferOut {
                     reference: message_number,
                     file,
                     current_position: offset_start.unwrap_or(0),
                     cutoff_position: offset_end,
+                    compress,
                 });
                 self.send(Success { reference: message_number });
             }
@@ -409,7 +659,47 @@ impl Oxy {
                         return Err("Truncate failed".to_string());
                     }
                 }
-                ();
+                self.send(Success { reference: message_number });
+            }
+            FollowRequest { path, offset_start } => {
+                use std::io::{Seek, SeekFrom};
+                self.bob_only();
+                let path = self.qualify_path(path);
+                let mut file = File::open(&path).map_err(|_| "Failed to open file")?;
+                let len = file.metadata().map_err(|_| "Failed to stat file")?.len();
+                let position = offset_start.unwrap_or(len);
+                file.seek(SeekFrom::Start(position)).map_err(|_| "Start-seek failed")?;
+                self.send(Success { reference: message_number });
+                self.poll_followed_file(super::FollowedFile {
+                    reference: message_number,
+                    path,
+                    file,
+                    position,
+                });
+            }
+            FileSignatureRequest { path, block_size } => {
+                self.bob_only();
+                if block_size == 0 {
+                    Err("Invalid block size")?;
+                }
+                let path = self.qualify_path(path);
+                let mut file = File::open(path).map_err(|_| "Failed to open file")?;
+                let len = file.metadata().map_err(|_| "Failed to stat file")?.len();
+                let digests = crate::util::block_signatures(&mut file, block_size).map_err(|_| "Error reading file")?;
+                self.send(FileSignatureData {
+                    reference: message_number,
+                    len,
+                    digests,
+                });
+            }
+            BindConnectionSource {
+                reference,
+                source,
+                destination,
+            } => {
+                self.alice_only();
+                let addresses = (source.parse().ok(), destination.parse().ok());
+                self.internal.bind_connection_sources.borrow_mut().insert(reference, addresses);
             }
             BindConnectionAccepted { reference } => {
                 assert!(perspective() == Alice);
@@ -420,12 +710,29 @@ impl Oxy {
                     .get(&reference)
                     .ok_or("invalid_reference")?
                     .clone();
+                let forward = self
+                    .internal
+                    .kr_references
+                    .borrow()
+                    .iter()
+                    .find(|x| *x.1 == reference)
+                    .map(|x| x.0.clone())
+                    .unwrap_or_default();
+                let (source, destination) = self.internal.bind_connection_sources.borrow_mut().remove(&reference).unwrap_or((None, None));
+                if self.forward_at_capacity(&forward) {
+                    self.send(LocalStreamClosed { reference: message_number });
+                    return Ok(());
+                }
+                let header = self.forward_proxy_header(&forward, source, destination);
                 if addr.contains('/') {
                     use nix::sys::socket::{connect, socket, AddressFamily, SockAddr, SockFlag, SockType};
                     let socket = socket(AddressFamily::Unix, SockType::Stream, SockFlag::empty(), None).map_err(|_| "Failed to create socket")?;
                     let sockaddr = SockAddr::new_unix(&PathBuf::from(addr.clone())).map_err(|_| "Failed to parse socket address")?;
                     connect(socket, &sockaddr).map_err(|_| "Failed to connect")?;
-                    let bt = BufferedTransport::from(socket);
+                    let bt = stream_transport(socket);
+                    if let Some(header) = &header {
+                        bt.put(header);
+                    }
                     let stream = PortStream {
                         stream: bt,
                         token:  message_number,
@@ -435,12 +742,17 @@ impl Oxy {
                     let stream2 = Rc::new(stream.clone());
                     stream.stream.set_notify(stream2);
                     self.internal.remote_streams.borrow_mut().insert(message_number, stream);
+                    self.track_forward_stream(false, message_number, &forward, &addr);
                     return Ok(());
                 }
-                let mut addr = addr.to_socket_addrs().map_err(|_| "failed to resolve destination")?;
-                let addr = addr.next().ok_or("Failed to resolve_destination")?;
+                let destination = addr.clone();
+                let addr = crate::util::resolve_addr(&addr).map_err(|_| "failed to resolve destination")?;
+                let addr = addr.into_iter().next().ok_or("Failed to resolve_destination")?;
                 let stream = TcpStream::connect(&addr).map_err(|_| "Forward-connection failed")?;
-                let bt = BufferedTransport::from(stream);
+                let bt = stream_transport(stream);
+                if let Some(header) = &header {
+                    bt.put(header);
+                }
                 let stream = PortStream {
                     stream: bt,
                     token:  message_number,
@@ -450,6 +762,7 @@ impl Oxy {
                 let stream2 = Rc::new(stream.clone());
                 stream.stream.set_notify(stream2);
                 self.internal.remote_streams.borrow_mut().insert(message_number, stream);
+                self.track_forward_stream(false, message_number, &forward, &destination);
             }
             RemoteOpen { addr } => {
                 assert!(perspective() == Bob);
@@ -458,7 +771,7 @@ impl Oxy {
                     let socket = socket(AddressFamily::Unix, SockType::Stream, SockFlag::empty(), None).map_err(|_| "Failed to create socket")?;
                     let sockaddr = SockAddr::new_unix(&PathBuf::from(addr.clone())).map_err(|_| "Failed to parse socket address")?;
                     connect(socket, &sockaddr).map_err(|_| "Failed to connect")?;
-                    let bt = BufferedTransport::from(socket);
+                    let bt = stream_transport(socket);
                     let stream = PortStream {
                         stream: bt,
                         token:  message_number,
@@ -470,14 +783,14 @@ impl Oxy {
                     self.internal.remote_streams.borrow_mut().insert(message_number, stream);
                     return Ok(());
                 }
-                let dest = addr
-                    .to_socket_addrs()
+                let dest = crate::util::resolve_addr(&addr)
                     .map_err(|_| "Resolving address failed.")?
+                    .into_iter()
                     .next()
                     .ok_or("Resolving address failed.")?;
                 debug!("Resolved RemoteOpen destination to {:?}", dest);
                 let stream = TcpStream::connect(&dest).map_err(|_| "Forward-connection failed")?;
-                let bt = BufferedTransport::from(stream);
+                let bt = stream_transport(stream);
                 let stream = PortStream {
                     stream: bt,
                     token:  message_number,
@@ -497,75 +810,34 @@ impl Oxy {
                     .remove(&reference)
                     .ok_or("Invalid reference")?;
                 (callback)();
+                crate::session_env::remove_forward(reference);
             }
             RemoteBind { addr } => {
                 assert!(perspective() == Bob);
                 if addr.contains("/") {
-                    use nix::{
-                        sys::socket::{accept, bind, listen, socket, AddressFamily, SockAddr, SockFlag, SockType},
-                        unistd::{close, unlink},
-                    };
-                    let socket = socket(AddressFamily::Unix, SockType::Stream, SockFlag::empty(), None).map_err(|_| "Failed to create socket")?;
-                    let sockaddr = SockAddr::new_unix(&PathBuf::from(addr.clone())).map_err(|_| "Failed to parse socket address")?;
-                    bind(socket, &sockaddr).map_err(|_| "Failed to bind")?;
-                    listen(socket, 10).map_err(|_| "Failed to listen")?;
-                    debug!("Remote bind successful");
-                    let token = Rc::new(RefCell::new(0));
-                    let token2 = token.clone();
-                    let proxy = self.clone();
-                    let addr2 = addr.clone();
-                    let token3 = transportation::insert_listener(Rc::new(move || {
-                        let addr = addr.clone();
-                        let token = token.clone();
-                        let peer = accept(socket);
-                        debug!("Accepted connection");
-                        if peer.is_err() {
-                            proxy.log_warn(&format!("Failed to accept connection on {}", addr));
-                            transportation::remove_listener(*token.borrow());
-                            return;
-                        }
-                        let peer = peer.unwrap();
-                        let stream_token = proxy.send(BindConnectionAccepted { reference: message_number });
-                        let bt = BufferedTransport::from(peer);
-                        let tracker = super::PortStream {
-                            stream: bt,
-                            token:  stream_token,
-                            oxy:    proxy.clone(),
-                            local:  true,
-                        };
-                        let tracker2 = Rc::new(tracker.clone());
-                        tracker.stream.set_notify(tracker2);
-                        proxy.internal.local_streams.borrow_mut().insert(stream_token, tracker);
-                    }));
-                    *token2.borrow_mut() = token3;
-                    transportation::borrow_poll(|poll| {
-                        poll.register(&EventedFd(&socket), Token(token3), Ready::readable(), PollOpt::level())
-                            .unwrap()
-                    });
-
-                    self.internal.remote_bind_cleaners.borrow_mut().insert(
-                        message_number,
-                        Rc::new(move || {
-                            debug!("Closing remote bind.");
-                            transportation::remove_listener(token3);
-                            close(socket).map_err(|x| warn!("While closing remote bind: {:?}", x)).ok();
-                            unlink(&PathBuf::from(addr2.clone()))
-                                .map_err(|x| warn!("While unlinking remote bind: {:?}", x))
-                                .ok();
-                        }),
-                    );
-
-                    return Ok(());
+                    return self.bind_unix_socket(addr, message_number);
                 }
                 let addr = if !addr.contains(':') { format!("localhost:{}", addr) } else { addr };
-                let bind = ::std::net::TcpListener::bind(&addr).map_err(|_| "bind failed")?;
+                let bind = crate::util::resolve_addr(&addr).and_then(|x| ::std::net::TcpListener::bind(&x[..]));
+                let bind = bind.map_err(|_| "bind failed")?;
                 let bind = TcpListener::from_std(bind).map_err(|_| "bind failed")?;
+                if addr.ends_with(":0") {
+                    let port = bind.local_addr().map_err(|_| "bind failed")?.port();
+                    self.send(RemoteBindAllocated {
+                        reference: message_number,
+                        port,
+                    });
+                }
                 let proxy = self.clone();
                 let proxy = Rc::new(move || proxy.notify_bind(message_number));
                 let token = transportation::insert_listener(proxy);
                 transportation::borrow_poll(|poll| {
                     poll.register(&bind, Token(token), Ready::readable(), PollOpt::level()).unwrap();
                 });
+                match bind.local_addr() {
+                    Ok(bound) => crate::session_env::add_forward(message_number, &bound.to_string()),
+                    Err(_) => crate::session_env::add_forward(message_number, &addr),
+                }
                 let bind = PortBind {
                     listener:    bind,
                     local_spec:  addr,
@@ -580,7 +852,19 @@ impl Oxy {
                     }),
                 );
             }
+            RemoteBindAllocated { reference, port } => {
+                self.alice_only();
+                let destination = self
+                    .internal
+                    .remote_bind_destinations
+                    .borrow()
+                    .get(&reference)
+                    .ok_or("Invalid reference")?
+                    .clone();
+                self.log_info(&format!("Allocated port {} for remote forward to {}", port, destination));
+            }
             RemoteStreamData { reference, data } => {
+                self.count_forward_bytes(false, reference, data.len(), 0);
                 self.internal
                     .remote_streams
                     .borrow_mut()
@@ -590,6 +874,7 @@ impl Oxy {
                     .put(&data[..]);
             }
             RemoteStreamClosed { reference } => {
+                self.untrack_forward_stream(false, reference);
                 self.internal
                     .remote_streams
                     .borrow_mut()
@@ -599,6 +884,7 @@ impl Oxy {
                     .close();
             }
             LocalStreamData { reference, data } => {
+                self.count_forward_bytes(true, reference, data.len(), 0);
                 self.internal
                     .local_streams
                     .borrow_mut()
@@ -608,6 +894,7 @@ impl Oxy {
                     .put(&data[..]);
             }
             LocalStreamClosed { reference } => {
+                self.untrack_forward_stream(true, reference);
                 self.internal
                     .local_streams
                     .borrow_mut()
@@ -624,9 +911,22 @@ impl Oxy {
                 self.internal.tuntaps.borrow_mut().insert(message_number, tuntap);
             }
             #[cfg(unix)]
+            TunnelMtu { reference, mtu } => {
+                self.bob_only();
+                if let Some(own) = crate::arg::tunnel_mtu() {
+                    if own as u64 != mtu {
+                        return Err(format!("This end's tunnel MTU is {}", own));
+                    }
+                } else {
+                    let borrow = self.internal.tuntaps.borrow();
+                    borrow.get(&reference).ok_or("Invalid reference")?.set_mtu(mtu as usize);
+                }
+                self.send(Success { reference: message_number });
+            }
+            #[cfg(unix)]
             TunnelData { reference, data } => {
                 let borrow = self.internal.tuntaps.borrow_mut();
-                borrow.get(&reference).unwrap().send(&data);
+                borrow.get(&reference).ok_or("Invalid reference")?.send(&data);
             }
             StatRequest { path } => {
                 self.bob_only();
@@ -637,15 +937,66 @@ impl Oxy {
                     len:               info.len(),
                     is_dir:            info.is_dir(),
                     is_file:           info.is_file(),
-                    atime:             None,
+                    atime:             info.accessed().ok(),
                     ctime:             None,
-                    mtime:             None,
+                    mtime:             info.modified().ok(),
                     owner:             "".to_string(),
                     group:             "".to_string(),
-                    octal_permissions: 0,
+                    octal_permissions: permission_bits(&info),
                 };
                 self.send(message);
             }
+            ReadLinkRequest { path } => {
+                self.bob_only();
+                let path = self.qualify_path(path);
+                let target = ::std::fs::read_link(path).map_err(|_| "Not a symlink")?;
+                self.send(ReadLinkResult {
+                    reference: message_number,
+                    target:    target.to_string_lossy().into_owned(),
+                });
+            }
+            RealPathRequest { path } => {
+                self.bob_only();
+                let path = self.qualify_path(path);
+                let path = ::std::fs::canonicalize(path).map_err(|_| "Failed to resolve path")?;
+                self.send(RealPathResult {
+                    reference: message_number,
+                    path:      path.to_string_lossy().into_owned(),
+                });
+            }
+            SymlinkRequest { path, target } => {
+                self.bob_only();
+                #[cfg(unix)]
+                {
+                    let path = self.qualify_path(path);
+                    if let Some(parent) = path.parent() {
+                        ::std::fs::create_dir_all(parent).ok();
+                    }
+                    // Like a file, a link replaces whatever was there, short
+                    // of a directory.
+                    if symlink_metadata(&path).map(|x| !x.is_dir()).unwrap_or(false) {
+                        ::std::fs::remove_file(&path).map_err(|_| "Failed to replace existing file")?;
+                    }
+                    ::std::os::unix::fs::symlink(target, path).map_err(|_| "Failed to create symlink")?;
+                }
+                self.send(Success { reference: message_number });
+            }
+            CreateDirectoryRequest { path } => {
+                self.bob_only();
+                let path = self.qualify_path(path);
+                ::std::fs::create_dir_all(path).map_err(|_| "Failed to create directory")?;
+                self.send(Success { reference: message_number });
+            }
+            SetPermissionsRequest { path, octal_permissions } => {
+                self.bob_only();
+                #[cfg(unix)]
+                {
+                    use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+                    let path = self.qualify_path(path);
+                    ::std::fs::set_permissions(path, Permissions::from_mode(octal_permissions as u32)).map_err(|_| "Failed to set permissions")?;
+                }
+                self.send(Success { reference: message_number });
+            }
             ReadDir { path } => {
                 self.bob_only();
                 let path = self.qualify_path(path);
@@ -666,7 +1017,7 @@ impl Oxy {
             PtyExited { status } => {
                 self.alice_only();
                 debug!("Remote PTY process exited with status {}", status);
-                self.exit(0);
+                self.exit(status);
             }
             FileHashRequest {
                 path,
@@ -675,21 +1026,15 @@ impl Oxy {
                 hash_algorithm,
             } => {
                 use std::io::{Read, Seek, SeekFrom};
-                use transportation::ring::digest::{Context, SHA1, SHA256, SHA512};
-                let algorithm = match hash_algorithm {
-                    0 => unimplemented!("MD5"),
-                    1 => &SHA1,
-                    2 => &SHA256,
-                    3 => &SHA512,
-                    _ => panic!(),
-                };
+                use transportation::ring::digest::Context;
+                let algorithm = crate::util::digest_algorithm(hash_algorithm).ok_or("Unsupported hash algorithm")?;
                 let mut file = File::open(path).map_err(|_| "Failed to open file")?;
                 let mut context = Context::new(algorithm);
                 file.seek(SeekFrom::Start(offset_start.unwrap_or(0))).unwrap();
                 let mut ticker = offset_start.unwrap_or(0);
                 let mut data = [0u8; 4096];
                 let offset_end = offset_end.unwrap_or(file.metadata().unwrap().len());
-                loop {
+                while ticker < offset_end {
                     let result = file.read(&mut data[..]).map_err(|_| "Error reading file")? as u64;
                     if result == 0 {
                         Err("Reached end of file")?;
@@ -716,12 +1061,46 @@ impl Oxy {
                     sock = Ok(::std::net::UdpSocket::bind("0.0.0.0:0").map_err(|_| "Failed to create UDP socket")?);
                 }
                 let sock = sock.unwrap();
-                let destination = ::std::net::ToSocketAddrs::to_socket_addrs(&destination).map_err(|_| "Failed to resolve destination")?;
+                let destination = crate::util::resolve_addr(&destination).map_err(|_| "Failed to resolve destination")?;
                 for destination in destination {
                     sock.send_to(&knock, &destination).ok();
                 }
                 ::std::thread::sleep(::std::time::Duration::from_millis(500)); // TODO: Now HERE's a hack-and-a-half.
             }
+            SessionSuspend {} => {
+                self.bob_only();
+                info!("Client suspended the session");
+                *self.internal.suspended.borrow_mut() = true;
+                *self.internal.suspensions.borrow_mut() += 1;
+                if let Some(timeout) = crate::arg::suspend_timeout() {
+                    let suspension = *self.internal.suspensions.borrow();
+                    let proxy = self.clone();
+                    ::transportation::set_timeout(Rc::new(move || proxy.check_suspend_timeout(suspension, timeout)), timeout);
+                }
+            }
+            SessionResume {} => {
+                self.bob_only();
+                info!("Client resumed the session");
+                *self.internal.suspended.borrow_mut() = false;
+                *self.internal.last_message_seen.borrow_mut() = Some(Instant::now());
+            }
+            HostKeyRotation { pubkey } => {
+                self.alice_only();
+                if let Some(algorithm) = crate::keys::host_key_algorithm(&pubkey) {
+                    if !crate::arg::allows_host_key_algorithm(algorithm) {
+                        self.log_warn(&format!(
+                            "The server is rotating to a {} host key, which --hostkey-algorithms doesn't allow. Its next public key is {:?}.",
+                            algorithm,
+                            ::data_encoding::BASE32_NOPAD.encode(&pubkey)
+                        ));
+                        return Ok(());
+                    }
+                }
+                self.log_warn(&format!(
+                    "The server is rotating its host key. Its next public key is {:?}. Update the pubkey for this server in your client config.",
+                    ::data_encoding::BASE32_NOPAD.encode(&pubkey)
+                ));
+            }
             _ => {
                 debug!("A not-statically supported message type came through.");
             }
@@ -729,3 +1108,17 @@ impl Oxy {
         Ok(())
     }
 }
+
+/// A file's mode bits, for StatResult, or 0 where there are none.
+fn permission_bits(info: &::std::fs::Metadata) -> u16 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        (info.permissions().mode() & 0o7777) as u16
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = info;
+        0
+    }
+}
@@ -0,0 +1,127 @@
+// --on-hup: what a SIGHUP to the client, as when its terminal window closes,
+// means for the remote command.
+//
+// disconnect: the client exits, and the session ends as if the connection had
+// been lost, taking the command with it.
+// nohup: the server is asked to leave the command running. It stays around
+// after the connection is gone, discarding output, until the command exits.
+// signal: the server sends the command SIGHUP, then the client exits.
+//
+// transportation's signalfd only covers SIGWINCH and SIGCHLD, so the signal
+// handler writes to a pipe that the event loop watches instead.
+use crate::{core::Oxy, message::OxyMessage::*};
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use std::{
+    rc::Rc,
+    sync::atomic::{AtomicIsize, Ordering},
+    time::Duration,
+};
+use transportation::{set_timeout, BufferedTransport, EncryptionPerspective::Alice, Notifies};
+
+/// The write end of the pipe, for the signal handler.
+static HANGUP_PIPE: AtomicIsize = AtomicIsize::new(-1);
+/// How long to wait for the server to take note of a hangup before exiting
+/// anyway.
+const HANGUP_GRACE: Duration = Duration::from_secs(2);
+/// The status to exit with after a hangup, as a shell reports one.
+const EXIT_HANGUP: i32 = 128 + ::libc::SIGHUP;
+
+extern "C" fn note_hangup(_signal: ::libc::c_int) {
+    let fd = HANGUP_PIPE.load(Ordering::SeqCst) as ::libc::c_int;
+    unsafe {
+        ::libc::write(fd, [0u8].as_ptr() as *const ::libc::c_void, 1);
+    }
+}
+
+impl Oxy {
+    pub(super) fn register_hangup_handler(&self) {
+        let mut fds = [0; 2];
+        if unsafe { ::libc::pipe(fds.as_mut_ptr()) } != 0 {
+            warn!("Failed to create a pipe for SIGHUP: {}", ::std::io::Error::last_os_error());
+            return;
+        }
+        for fd in &fds {
+            unsafe {
+                ::libc::fcntl(*fd, ::libc::F_SETFD, ::libc::FD_CLOEXEC);
+                ::libc::fcntl(*fd, ::libc::F_SETFL, ::libc::O_NONBLOCK);
+            }
+        }
+        HANGUP_PIPE.store(fds[1] as isize, Ordering::SeqCst);
+        let bt = BufferedTransport::from(fds[0]);
+        let proxy = self.clone();
+        let bt2 = bt.clone();
+        bt.set_notify(Rc::new(move || {
+            if !bt2.take().is_empty() {
+                proxy.notify_hangup();
+            }
+        }));
+        *self.internal.hangup_bt.borrow_mut() = Some(bt);
+        unsafe {
+            ::libc::signal(::libc::SIGHUP, note_hangup as ::libc::sighandler_t);
+        }
+    }
+
+    fn notify_hangup(&self) {
+        let action = crate::arg::on_hup();
+        info!("Received SIGHUP");
+        if action == "disconnect" || !*self.internal.authenticated.borrow() {
+            self.exit(EXIT_HANGUP);
+        }
+        self.send(Hangup {
+            keep_running: action == "nohup",
+        });
+        self.send(Ping {});
+        self.watch(Rc::new(|message, _| match message {
+            Pong {} => crate::exit::exit(EXIT_HANGUP),
+            _ => false,
+        }));
+        set_timeout(Rc::new(|| crate::exit::exit(EXIT_HANGUP)), HANGUP_GRACE);
+    }
+
+    /// On the server, acts on a client's Hangup.
+    pub(super) fn handle_hangup(&self, keep_running: bool) {
+        if keep_running {
+            debug!("The client hung up, keeping its commands running after it disconnects");
+            *self.internal.commands_detached.borrow_mut() = true;
+            return;
+        }
+        debug!("The client hung up, passing SIGHUP on to its commands");
+        use nix::{
+            sys::signal::{kill, Signal::SIGHUP},
+            unistd::Pid,
+        };
+        if let Some(pty) = self.internal.pty.borrow().as_ref() {
+            kill(pty.child_pid, SIGHUP).ok();
+        }
+        for child in self.internal.piped_children.borrow().values() {
+            kill(Pid::from_raw(child.child.id() as i32), SIGHUP).ok();
+        }
+    }
+
+    /// Called once the connection is lost. Whether the server should outlive
+    /// it, because the client hung up under --on-hup=nohup and its commands
+    /// are still running.
+    pub(super) fn linger(&self) -> bool {
+        if self.perspective() == Alice || !*self.internal.commands_detached.borrow() || !self.commands_running() {
+            return false;
+        }
+        if !::std::mem::replace(&mut *self.internal.lingering.borrow_mut(), true) {
+            info!("The client hung up. Waiting for its commands to exit.");
+        }
+        true
+    }
+
+    /// Exits once nothing is left running for a server that lingered.
+    pub(super) fn check_lingering(&self) {
+        if *self.internal.lingering.borrow() && !self.commands_running() {
+            debug!("The commands left by the client have exited");
+            self.exit(crate::exit::EXIT_OK);
+        }
+    }
+
+    fn commands_running(&self) -> bool {
+        let pty = self.internal.pty.borrow().is_some() && !*self.internal.pty_exited.borrow();
+        pty || !self.internal.piped_children.borrow().is_empty()
+    }
+}
@@ -1,13 +1,20 @@
 use byteorder::{self, ByteOrder};
-use crate::{core::Oxy, keys};
+use crate::{
+    core::Oxy,
+    exit::{fail, OxyError},
+    keys,
+    secret::SecretBytes,
+};
 use data_encoding::BASE32_NOPAD;
 #[allow(unused_imports)]
 use log::{debug, error, info, log, trace, warn};
 use std::{
     ffi::CString,
-    time::{SystemTime, UNIX_EPOCH},
+    rc::Rc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use transportation::{
+    set_timeout,
     ring::{
         agreement::{self, agree_ephemeral, EphemeralPrivateKey, X25519},
         signature,
@@ -16,12 +23,16 @@ use transportation::{
     RNG,
 };
 
+/// The algorithms every session uses, as named in session-info.
+pub(super) const SESSION_CIPHER: &str = "aes256-gcm";
+pub(super) const SESSION_KEX: &str = "x25519";
+
 #[derive(Default)]
 pub(super) struct KexData {
     crate connection_client_key: Option<Vec<u8>>,
     crate client_key_evidence:   Option<Vec<u8>>,
     crate my_ephemeral_key:      Option<EphemeralPrivateKey>,
-    crate keymaterial:           Option<Vec<u8>>,
+    crate keymaterial:           Option<SecretBytes>,
     crate server_key:            Option<Vec<u8>>,
     crate server_ephemeral:      Option<Vec<u8>>,
 }
@@ -48,6 +59,11 @@ impl Oxy {
         let peer_name = self.internal.peer_name.borrow().clone();
         trace!("x Peer name: {:?}", peer_name);
         let key = keys::asymmetric_key(peer_name.as_ref().map(|x| &**x));
+        if let Some(name) = crate::arg::matches().value_of("server name") {
+            let mut message = name.as_bytes().to_vec();
+            message.insert(0, crate::reexec::SERVER_NAME_VERSION);
+            self.send_naked(&message);
+        }
         let mut pubkey: Vec<u8> = key.public_key_bytes().to_vec();
         pubkey.insert(0, 0);
         self.send_naked(&pubkey);
@@ -62,6 +78,25 @@ impl Oxy {
         self.send_naked(msg.as_ref());
         self.internal.kex_data.borrow_mut().my_ephemeral_key = Some(ephemeral_key);
         *self.internal.naked_state.borrow_mut() = NakedState::WaitingForServerKey;
+        if let Some(timeout) = crate::arg::handshake_timeout() {
+            let proxy = self.clone();
+            set_timeout(Rc::new(move || proxy.check_handshake_timeout(timeout)), timeout);
+        }
+    }
+
+    /// Fails the connection if the server hasn't finished the handshake within
+    /// --handshake-timeout. A server that never answered at all is most likely
+    /// something other than oxy.
+    fn check_handshake_timeout(&self, timeout: Duration) {
+        if self.is_encrypted() {
+            return;
+        }
+        let peer = self.internal.peer_name.borrow().clone().unwrap_or_else(|| "the server".to_string());
+        let duration = crate::util::format_duration(timeout);
+        if *self.internal.naked_state.borrow() == NakedState::WaitingForServerKey {
+            fail(OxyError::Connection(format!("{} didn't answer the handshake within {}: not an oxy server, or the wrong port?", peer, duration)));
+        }
+        fail(OxyError::Connection(format!("The handshake with {} didn't finish within {}", peer, duration)));
     }
 
     fn send_naked(&self, message: &[u8]) {
@@ -79,57 +114,50 @@ impl Oxy {
             }
             ::std::env::remove_var(&k);
         }
+        if crate::arg::matches().is_present("su mode") {
+            debug!("Waiting for the client to choose a user before dropping privileges");
+            *self.internal.su_pending.borrow_mut() = true;
+            return;
+        }
         let peer = self.internal.peer_name.borrow().clone();
         if let Some(peer) = peer {
-            let setuser = crate::conf::get_setuser(&peer);
-            if let Some(setuser) = setuser {
-                info!("Setting user: {}", setuser);
-                let pwent = crate::util::getpwnam(&setuser);
-                if pwent.is_err() {
-                    error!("Failed to gather user information for {}", setuser);
-                    ::std::process::exit(1);
-                }
-                let pwent = pwent.unwrap();
-                ::std::env::set_var("HOME", &pwent.home);
-                ::std::env::set_var("SHELL", &pwent.shell);
-                ::std::env::set_var("USER", &pwent.name);
-                let gid = ::nix::unistd::Gid::from_raw(pwent.gid);
-                let cstr_setuser = CString::new(setuser).unwrap();
-                let grouplist = ::nix::unistd::getgrouplist(&cstr_setuser, gid);
-                if grouplist.is_err() {
-                    error!("Failed to get supplementary group list");
-                    ::std::process::exit(1);
-                }
-                let grouplist = grouplist.unwrap();
-                let result = ::nix::unistd::setgroups(&grouplist[..]);
-                if result.is_err() {
-                    error!("Failed to set supplementary group list");
-                    ::std::process::exit(1);
-                }
+            self.become_user(crate::conf::get_setuser(&peer));
+        } else {
+            self.become_user(None);
+        }
+    }
 
-                let result = ::nix::unistd::setgid(gid);
-                if result.is_err() {
-                    error!("Failed to setgid");
-                    ::std::process::exit(1);
-                }
-                let result = ::nix::unistd::setuid(::nix::unistd::Uid::from_raw(pwent.uid));
+    /// Gives up root for `setuser`, or for the server's own user when there's
+    /// none, and exits rather than carry on with root privileges by mistake.
+    pub(super) fn become_user(&self, setuser: Option<String>) {
+        *self.internal.su_pending.borrow_mut() = false;
+        if let Some(setuser) = setuser {
+            info!("Setting user: {}", setuser);
+            let pwent = crate::util::getpwnam(&setuser);
+            if pwent.is_err() {
+                error!("Failed to gather user information for {}", setuser);
+                ::std::process::exit(1);
+            }
+            let pwent = pwent.unwrap();
+            if let Err(message) = set_user(&pwent) {
+                error!("Failed to switch to {}: {}", setuser, message);
+                ::std::process::exit(1);
+            }
+            ::std::env::set_var("HOME", &pwent.home);
+            ::std::env::set_var("SHELL", &pwent.shell);
+            ::std::env::set_var("USER", &pwent.name);
+            let result = ::std::env::set_current_dir(pwent.home);
+            if result.is_err() {
+                let result = ::std::env::set_current_dir("/");
                 if result.is_err() {
-                    error!("Failed to setuid");
+                    error!("Failed to change directory");
                     ::std::process::exit(1);
                 }
-                let result = ::std::env::set_current_dir(pwent.home);
-                if result.is_err() {
-                    let result = ::std::env::set_current_dir("/");
-                    if result.is_err() {
-                        error!("Failed to change directory");
-                        ::std::process::exit(1);
-                    }
-                }
-                *self.internal.privs_dropped.borrow_mut() = true;
-            } else {
-                if let Some(home) = ::std::env::home_dir() {
-                    ::std::env::set_current_dir(home).ok();
-                }
+            }
+            *self.internal.privs_dropped.borrow_mut() = true;
+        } else {
+            if let Some(home) = ::std::env::home_dir() {
+                ::std::env::set_current_dir(home).ok();
             }
         }
 
@@ -137,14 +165,21 @@ impl Oxy {
             error!("Running as root, but did not drop privileges. Exiting.");
             ::std::process::exit(1);
         }
+        #[cfg(unix)]
+        {
+            crate::util::apply_umask();
+            crate::util::apply_session_limits();
+        }
     }
 
     pub(super) fn notify_naked(&self) {
         trace!("notify_naked called");
 
         if self.internal.naked_transport.borrow_mut().as_mut().unwrap().is_closed() {
-            warn!("The peer hung up on us. Auth failed?");
-            ::std::process::exit(1);
+            if self.perspective() == transportation::EncryptionPerspective::Alice && crate::arg::no_knock() {
+                fail(OxyError::Auth("The peer hung up during the handshake. Auth failed, or does the server expect a knock?".to_string()));
+            }
+            fail(OxyError::Auth("The peer hung up during the handshake. Auth failed?".to_string()));
         }
 
         let state = self.internal.naked_state.borrow().clone();
@@ -154,16 +189,38 @@ impl Oxy {
                 self.bob_only();
                 if let Some(mut msg) = self.recv_naked() {
                     let version_indicator = msg.remove(0);
-                    assert!(version_indicator == 0);
+                    if version_indicator == crate::reexec::SERVER_NAME_VERSION {
+                        let name = String::from_utf8_lossy(&msg).to_string();
+                        fail(OxyError::Config(format!("The client asked for virtual server {:?}, but there are none here", name)));
+                    }
+                    if version_indicator != 0 {
+                        fail(OxyError::Protocol(format!("Unsupported handshake version {}", version_indicator)));
+                    }
                     let mut peer = self.internal.peer_name.borrow().clone();
                     if peer.is_none() {
                         peer = crate::keys::get_peer_for_public_key(&msg);
                         *self.internal.peer_name.borrow_mut() = peer.clone();
                     }
-                    if !keys::validate_peer_public_key(&msg, peer.as_ref().map(String::as_ref)) {
-                        panic!("Incorrect client key");
+                    if !keys::validate_peer_public_key(&msg, peer.as_ref().map(String::as_ref)) && !crate::authorized::authorize(&msg) {
+                        let fallback = match crate::arg::auth_method() {
+                            "pam" => true,
+                            "key+pam" => false,
+                            _ => crate::arg::matches().is_present("interactive auth command"),
+                        };
+                        if !fallback {
+                            crate::exit::reject_login(OxyError::Auth(format!("Incorrect client key {}", BASE32_NOPAD.encode(&msg))));
+                        }
+                        info!("Unknown client key {}, falling back to interactive auth", BASE32_NOPAD.encode(&msg));
+                        *self.internal.interactive_auth_pending.borrow_mut() = true;
+                    } else {
+                        debug!("Accepted client key {:?}", BASE32_NOPAD.encode(&msg));
+                        if crate::arg::auth_method() == "key+pam" {
+                            *self.internal.interactive_auth_pending.borrow_mut() = true;
+                            *self.internal.interactive_auth_keyed.borrow_mut() = true;
+                        }
                     }
-                    debug!("Accepted client key {:?}", BASE32_NOPAD.encode(&msg));
+                    crate::audit::set_peer(peer.clone(), &msg);
+                    crate::session_env::set_peer(peer.clone(), &msg);
                     self.internal.kex_data.borrow_mut().connection_client_key = Some(msg.to_vec());
                     *self.internal.naked_state.borrow_mut() = NakedState::WaitingForClientEphemeral;
                     self.notify_naked();
@@ -190,8 +247,7 @@ impl Oxy {
                         Input::from(&msg),
                     );
                     if result.is_err() {
-                        error!("Client kex signature verification failed.");
-                        ::std::process::exit(1);
+                        crate::exit::reject_login(OxyError::Auth("Client kex signature verification failed".to_string()));
                     }
                     self.drop_privs();
                     ::std::mem::drop(kex_data);
@@ -199,7 +255,14 @@ impl Oxy {
                     let peer_name = self.internal.peer_name.borrow().clone();
                     let server_key = keys::asymmetric_key(peer_name.as_ref().map(|x| &**x));
                     let mut public_key_message: Vec<u8> = server_key.public_key_bytes().to_vec();
-                    public_key_message.insert(0, 0);
+                    let pending = *self.internal.interactive_auth_pending.borrow();
+                    let keyed = *self.internal.interactive_auth_keyed.borrow();
+                    let version_indicator = match (pending, keyed) {
+                        (false, _) => 0,
+                        (true, false) => 1,
+                        (true, true) => 2,
+                    };
+                    public_key_message.insert(0, version_indicator);
                     self.send_naked(&public_key_message);
                     let mut buf = Vec::new();
                     buf.resize(ephemeral.public_key_len() + 8, 0);
@@ -213,7 +276,7 @@ impl Oxy {
                         &X25519,
                         Input::from(&self.internal.kex_data.borrow_mut().client_key_evidence.as_ref().unwrap()[8..]),
                         (),
-                        |x| Ok(x.to_vec()),
+                        |x| Ok(SecretBytes::from(x.to_vec())),
                     ).unwrap();
                     debug!("Got keymaterial: {:?}", keymaterial);
                     self.internal.kex_data.borrow_mut().keymaterial = Some(keymaterial);
@@ -223,12 +286,45 @@ impl Oxy {
             NakedState::WaitingForServerKey => {
                 self.alice_only();
                 if let Some(mut msg) = self.recv_naked() {
-                    let version_indicator = msg.remove(0);
-                    assert!(version_indicator == 0);
+                    match msg.remove(0) {
+                        0 => (),
+                        1 => *self.internal.interactive_auth_pending.borrow_mut() = true,
+                        2 => {
+                            *self.internal.interactive_auth_pending.borrow_mut() = true;
+                            *self.internal.interactive_auth_keyed.borrow_mut() = true;
+                        }
+                        version_indicator => fail(OxyError::Protocol(format!("Unsupported handshake version {}", version_indicator))),
+                    }
                     debug!("Host key: {}", BASE32_NOPAD.encode(&msg));
+                    match keys::host_key_algorithm(&msg) {
+                        Some(algorithm) if !crate::arg::allows_host_key_algorithm(algorithm) => {
+                            fail(OxyError::HostKey(format!("The server's host key is {}, which --hostkey-algorithms doesn't allow", algorithm)));
+                        }
+                        Some(_) => (),
+                        None => fail(OxyError::HostKey(format!("The server's host key is of an unknown type ({} bytes)", msg.len()))),
+                    }
                     let peer = self.internal.peer_name.borrow().clone();
+                    if crate::arg::matches().is_present("scan key") {
+                        println!("{} {}", peer.unwrap_or_default(), BASE32_NOPAD.encode(&msg));
+                        crate::exit::exit(crate::exit::EXIT_OK);
+                    }
                     if !keys::validate_peer_public_key(&msg, peer.as_ref().map(String::as_ref)) {
-                        panic!("Invalid host key!");
+                        let loopback = peer.as_ref().map(|x| crate::conf::is_loopback_destination(x)).unwrap_or(false);
+                        let known = peer.as_ref().and_then(|x| crate::known_hosts::check(x, &msg));
+                        // Only servers without a pubkey in the client config are left to known_hosts.
+                        let unconfigured = peer.as_ref().map(|x| crate::conf::public_key(Some(x)).is_none()).unwrap_or(false);
+                        if known == Some(true) && unconfigured {
+                            debug!("Host key found in known_hosts");
+                        } else if loopback && crate::arg::matches().is_present("no host check for localhost") {
+                            warn!("Accepting an unverified host key for a loopback destination");
+                        } else if known == Some(false) && !(unconfigured && crate::known_hosts::consider_changed(peer.as_ref().unwrap(), &msg)) {
+                            fail(OxyError::HostKey(format!(
+                                "Host key {} doesn't match the one in known_hosts. This could be an impostor!",
+                                BASE32_NOPAD.encode(&msg)
+                            )));
+                        } else if !(unconfigured && crate::known_hosts::consider_unknown(peer.as_ref().unwrap(), &msg)) {
+                            fail(OxyError::HostKey(format!("Invalid host key {}", BASE32_NOPAD.encode(&msg))));
+                        }
                     }
                     self.internal.kex_data.borrow_mut().server_key = Some(msg);
                     *self.internal.naked_state.borrow_mut() = NakedState::WaitingForServerEphemeral;
@@ -247,39 +343,108 @@ impl Oxy {
                 self.alice_only();
                 if let Some(msg) = self.recv_naked() {
                     let mut kex_data = self.internal.kex_data.borrow_mut();
-                    signature::verify(
+                    let result = signature::verify(
                         &signature::ED25519,
                         Input::from(kex_data.server_key.as_ref().unwrap()),
                         Input::from(kex_data.server_ephemeral.as_ref().unwrap()),
                         Input::from(&msg),
-                    ).unwrap();
+                    );
+                    if result.is_err() {
+                        fail(OxyError::HostKey("Server kex signature verification failed".to_string()));
+                    }
                     assert_timestamp(&kex_data.server_ephemeral.as_ref().unwrap()[..8]);
                     let keymaterial = agree_ephemeral(
                         kex_data.my_ephemeral_key.take().unwrap(),
                         &X25519,
                         Input::from(&kex_data.server_ephemeral.as_ref().unwrap()[8..]),
                         (),
-                        |x| Ok(x.to_vec()),
+                        |x| Ok(SecretBytes::from(x.to_vec())),
                     ).unwrap();
                     debug!("Got keymaterial: {:?}", keymaterial);
                     kex_data.keymaterial = Some(keymaterial);
                     ::std::mem::drop(kex_data);
+                    self.check_security_level();
                     self.upgrade_to_encrypted();
                 }
             }
         }
     }
+
+    /// Fails the connection if what was negotiated falls short of
+    /// --security-level.
+    fn check_security_level(&self) {
+        let required = level_rank(crate::arg::security_level());
+        if required == 0 {
+            return;
+        }
+        let host_key = keys::host_key_algorithm(self.internal.kex_data.borrow().server_key.as_ref().unwrap()).unwrap_or("unknown");
+        for (kind, algorithm) in &[("cipher", SESSION_CIPHER), ("key exchange", SESSION_KEX), ("host key", host_key)] {
+            if algorithm_rank(algorithm) < required {
+                fail(OxyError::Protocol(format!(
+                    "The session's {} is {}, which is below --security-level {}",
+                    kind,
+                    algorithm,
+                    crate::arg::security_level()
+                )));
+            }
+        }
+        let unkeyed = *self.internal.interactive_auth_pending.borrow() && !*self.internal.interactive_auth_keyed.borrow();
+        if unkeyed && required >= level_rank("high") {
+            fail(OxyError::Protocol(
+                "The server fell back to interactive auth, leaving the static key out of the session key, which --security-level high \
+                 doesn't allow"
+                    .to_string(),
+            ));
+        }
+    }
+}
+
+fn level_rank(level: &str) -> u8 {
+    match level {
+        "high" => 2,
+        "medium" => 1,
+        _ => 0,
+    }
+}
+
+/// How each algorithm rates for --security-level. Everything oxy speaks today
+/// is high; anything added later for compatibility rates lower.
+fn algorithm_rank(algorithm: &str) -> u8 {
+    match algorithm {
+        "aes256-gcm" | "x25519" | "ed25519" => level_rank("high"),
+        _ => level_rank("legacy"),
+    }
 }
 
 fn assert_timestamp(timestamp: &[u8]) {
     let time = byteorder::BE::read_u64(&timestamp);
     let expected_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
     if !((time > (expected_time - 60)) && (time < (expected_time + 60))) {
-        error!("Out-of-date kex signature detected. This either means clock-skew or malice.");
-        ::std::process::exit(1);
+        fail(OxyError::Auth("Out-of-date kex signature detected. This either means clock-skew or malice.".to_string()));
         #[allow(unreachable_code)]
         {
             panic!();
         }
     }
 }
+
+/// Switches every id to `pwent`'s user. Supplementary groups go first and the
+/// uid last, since each step needs the privileges the next one gives up. The
+/// result is checked afterwards, including that root can't be regained.
+fn set_user(pwent: &crate::util::Pwent) -> Result<(), String> {
+    use nix::unistd::{getegid, geteuid, getgid, getgrouplist, getuid, setgid, setgroups, setuid, Gid, Uid};
+    let uid = Uid::from_raw(pwent.uid);
+    let gid = Gid::from_raw(pwent.gid);
+    let name = CString::new(pwent.name.clone()).map_err(|_| "Invalid user name")?;
+    let grouplist = getgrouplist(&name, gid).map_err(|_| "Failed to get supplementary group list")?;
+    setgroups(&grouplist[..]).map_err(|_| "Failed to set supplementary group list")?;
+    setgid(gid).map_err(|_| "Failed to setgid")?;
+    setuid(uid).map_err(|_| "Failed to setuid")?;
+    if getuid() != uid || geteuid() != uid || getgid() != gid || getegid() != gid {
+        return Err("User and group ids didn't all change".to_string());
+    }
+    if !uid.is_root() && setuid(Uid::from_raw(0)).is_ok() {
+        return Err("Root privileges could be regained".to_string());
+    }
+    Ok(())
+}
@@ -2,25 +2,30 @@ use clap::{self, App, AppSettings, Arg, SubCommand};
 #[cfg(unix)]
 use crate::tuntap::{TunTap, TunTapType};
 use crate::{
-    core::{Oxy, PortBind, SocksBind, SocksBindNotificationProxy},
+    core::{stream_transport, Oxy, PortBind, SocksBind, SocksBindNotificationProxy},
+    exit::OxyError,
     message::OxyMessage::*,
 };
 #[allow(unused_imports)]
 use log::{debug, error, info, log, trace, warn};
 use num;
+use shlex;
 use std::{
     cell::RefCell,
-    fs::{metadata, read_dir, File},
+    collections::BTreeMap,
+    fs::{metadata, read_dir, read_to_string, File},
     io::Write,
     path::PathBuf,
+    process::Command,
     rc::Rc,
+    time::Instant,
 };
 #[cfg(unix)]
 use transportation::mio::unix::EventedFd;
 use transportation::{
     self,
     mio::{net::TcpListener, PollOpt, Ready, Token},
-    BufferedTransport, Notifies,
+    Notifies,
 };
 
 fn create_app() -> App<'static, 'static> {
@@ -28,11 +33,31 @@ fn create_app() -> App<'static, 'static> {
         SubCommand::with_name("L")
             .about("Create a local portforward.")
             .arg(Arg::with_name("local spec").index(1))
-            .arg(Arg::with_name("remote spec").index(2)),
+            .arg(Arg::with_name("remote spec").index(2))
+            .arg(Arg::with_name("log").long("log").help("Log each connection through this forward"))
+            .arg(max_connections_arg())
+            .arg(
+                Arg::with_name("allow")
+                    .long("allow")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .value_name("cidr")
+                    .help("Only accept connections from these addresses, given in CIDR notation or as globs. Also written spec,allow=cidr."),
+            )
+            .arg(proxy_protocol_arg()),
         SubCommand::with_name("R")
             .about("Create a remote portforward.")
+            .long_about(
+                "Create a remote portforward. \
+                 Use port 0 as the remote spec to have the server pick a free port, \
+                 which is reported once the bind succeeds.",
+            )
             .arg(Arg::with_name("remote spec").index(1))
-            .arg(Arg::with_name("local spec").index(2)),
+            .arg(Arg::with_name("local spec").index(2))
+            .arg(Arg::with_name("log").long("log").help("Log each connection through this forward"))
+            .arg(max_connections_arg())
+            .arg(proxy_protocol_arg()),
         SubCommand::with_name("download")
             .about("Download a file")
             .arg(Arg::with_name("remote path").help("Remote file path to download from.").index(1))
@@ -60,7 +85,16 @@ fn create_app() -> App<'static, 'static> {
             .arg(Arg::with_name("remote tap").index(2)),
         SubCommand::with_name("socks")
             .about("Bind a local port as a SOCKS5 proxy server")
-            .arg(Arg::with_name("bind spec").index(1)),
+            .arg(Arg::with_name("bind spec").index(1))
+            .arg(Arg::with_name("log").long("log").help("Log each connection through this forward"))
+            .arg(
+                Arg::with_name("auth")
+                    .long("auth")
+                    .takes_value(true)
+                    .value_name("user:pass")
+                    .help("Only let in SOCKS clients that log in with this username and password. Also written spec,auth=user:pass."),
+            )
+            .arg(max_connections_arg()),
         SubCommand::with_name("pty")
             .about(
                 "Open a remote PTY. \
@@ -73,7 +107,7 @@ fn create_app() -> App<'static, 'static> {
                  Useful for Windows servers.",
             )
             .arg(Arg::with_name("command").index(1).required(true)),
-        SubCommand::with_name("exit").about("Exits the Oxy client."),
+        SubCommand::with_name("exit").alias("quit").about("Exits the Oxy client."),
         SubCommand::with_name("f10").about("Send F10 to the remote"),
         SubCommand::with_name("f12").about("Send F12 to the remote"),
         SubCommand::with_name("hash")
@@ -93,6 +127,43 @@ fn create_app() -> App<'static, 'static> {
         SubCommand::with_name("KD")
             .about("Terminate a SOCKS portforward")
             .arg(Arg::with_name("spec").index(1).required(true)),
+        SubCommand::with_name("forward-log")
+            .about("Toggle connection logging for a portforward")
+            .arg(Arg::with_name("spec").index(1).required(true))
+            .arg(Arg::with_name("state").index(2).required(true).possible_values(&["on", "off"])),
+        SubCommand::with_name("echo")
+            .about("Toggle echoing typed input locally")
+            .long_about(
+                "Toggle echoing typed input locally. \
+                 While on, the remote terminal's own echo is turned off, until the remote program changes the terminal's settings.",
+            )
+            .arg(Arg::with_name("state").index(1).required(true).possible_values(&["on", "off"])),
+        SubCommand::with_name("mode")
+            .about("Switch between sending each key as it's typed and editing lines locally")
+            .long_about(
+                "Switch between sending each key as it's typed (raw) and editing lines locally, sending them on enter (line). \
+                 Line mode echoes locally, which helps on slow links. \
+                 Raw mode comes back on its own when the remote program changes the terminal's settings, as editors and shells do.",
+            )
+            .arg(Arg::with_name("mode").index(1).required(true).possible_values(&["raw", "line"])),
+        SubCommand::with_name("suspend")
+            .about("Park the session before the machine sleeps.")
+            .long_about(
+                "Park the session before the machine sleeps. \
+                 Keepalives stop on both ends so that the idle connection isn't torn down. \
+                 The session resumes on its own after a suspend is detected, or with 'resume'.",
+            ),
+        SubCommand::with_name("resume").about("Resume a suspended session."),
+        SubCommand::with_name("whoami").about("Show the key this session authenticated with, and the user it runs as on the server."),
+        SubCommand::with_name("session-info").about("Show this session's encryption, compression, keys, addresses and versions."),
+        SubCommand::with_name("edit-forwards")
+            .about("Edit the active portforwards in $EDITOR")
+            .long_about(
+                "Edit the active portforwards in $EDITOR. \
+                 Each line is an L, R, or D metacommand. \
+                 On save, removed lines are terminated and new lines are created. \
+                 If any line fails to parse, nothing is changed.",
+            ),
     ];
     let subcommands: Vec<App<'static, 'static>> = subcommands
         .into_iter()
@@ -108,6 +179,47 @@ fn create_app() -> App<'static, 'static> {
     app
 }
 
+fn max_connections_arg() -> Arg<'static, 'static> {
+    Arg::with_name("max connections")
+        .long("max-connections")
+        .takes_value(true)
+        .value_name("count")
+        .help("Refuse new connections through this forward while this many are open. Overrides --max-connections-per-forward.")
+}
+
+fn proxy_protocol_arg() -> Arg<'static, 'static> {
+    Arg::with_name("proxy protocol")
+        .long("proxy-protocol")
+        .takes_value(true)
+        .possible_values(&["v1", "v2"])
+        .help("Start each connection with a PROXY protocol header carrying where it came from. Also written spec,proxy-protocol=v1.")
+}
+
+/// Replaces a command named after an alias in the client config with the
+/// commands it stands for, expanding aliases within those in turn. Any
+/// arguments after the alias are appended to its last command.
+fn expand_aliases(parts: Vec<String>, expanding: &mut Vec<String>) -> Result<Vec<Vec<String>>, String> {
+    let alias = parts.first().and_then(|x| crate::conf::metacommand_alias(x));
+    if alias.is_none() {
+        return Ok(vec![parts]);
+    }
+    let name = parts[0].clone();
+    if expanding.contains(&name) {
+        return Err(format!("Alias {:?} refers back to itself: {} -> {}", name, expanding.join(" -> "), name));
+    }
+    let mut commands = crate::util::split_metacommands(&alias.unwrap()).map_err(|err| format!("Invalid alias {:?}: {}", name, err))?;
+    if let Some(last) = commands.last_mut() {
+        last.extend(parts[1..].iter().cloned());
+    }
+    expanding.push(name);
+    let mut result = Vec::new();
+    for command in commands {
+        result.extend(expand_aliases(command, expanding)?);
+    }
+    expanding.pop();
+    Ok(result)
+}
+
 fn preprocess_parts(mut parts: Vec<String>) -> Vec<String> {
     // TODO: This whole thing is probably not the most robust way to accomplish
     // what it does
@@ -126,44 +238,95 @@ fn preprocess_parts(mut parts: Vec<String>) -> Vec<String> {
     if parts[0].as_str() == "D" {
         parts[0] = "socks".to_string();
     }
-    if parts[0].as_str() == "L" || parts[0].as_str() == "R" {
-        if parts.len() > 2 {
-            return parts;
-        }
-        let spec = parts[1].clone(); // TODO:  This should be the first positional argument, we might have --flags
-        let parse_result: Option<u16> = spec.parse().ok();
-        if let Some(port) = parse_result {
-            return vec![parts[0].clone(), format!("localhost:{}", port), format!("localhost:{}", port)];
-        }
-        let colon_count = spec.matches(':').count();
-        if colon_count == 0 {
-            // Maybe spec is a unix socket path that should be used on both sides?
-            return vec![parts[0].clone(), spec.clone(), spec.clone()];
-        }
-        if colon_count == 1 {
-            return vec![
-                parts[0].clone(),
-                spec.split(':').next().unwrap().to_string(),
-                spec.split(':').nth(1).unwrap().to_string(),
-            ];
-        }
-        if colon_count == 2 {
-            return vec![
-                parts[0].clone(),
-                spec.splitn(2, ':').next().unwrap().to_string(),
-                spec.splitn(2, ':').nth(1).unwrap().to_string(),
-            ];
-        }
-        if colon_count == 3 {
-            let first_half = spec.split(':').take(2).collect::<Vec<&str>>().join(":");
-            let second_half = spec.split(':').skip(2).collect::<Vec<&str>>().join(":");
-            return vec![parts[0].clone(), first_half, second_half];
+    if parts[0].as_str() == "socks" {
+        // Options written after the spec, like 1080,auth=user:pass.
+        let mut flags = Vec::new();
+        for part in parts.iter_mut().skip(1).filter(|x| !x.starts_with("--")) {
+            if let Some(index) = part.find(",auth=") {
+                flags.push(format!("--{}", &part[index + 1..]));
+                part.truncate(index);
+            }
         }
+        parts.extend(flags);
+        return parts;
+    }
+    if parts[0].as_str() == "L" || parts[0].as_str() == "R" {
+        // Set flags aside, so that the spec shorthands below only see positional
+        // arguments.
+        let (mut flags, positional): (Vec<String>, Vec<String>) = parts.into_iter().partition(|x| x.starts_with("--"));
+        // So are options written after the spec, like 8080:remote:80,allow=10.0.0.0/8.
+        let positional = positional
+            .into_iter()
+            .map(|x| {
+                let mut pieces = x.split(',');
+                let mut spec = pieces.next().unwrap().to_string();
+                for piece in pieces {
+                    if piece.starts_with("allow=") || piece.starts_with("proxy-protocol=") {
+                        flags.push(format!("--{}", piece));
+                    } else {
+                        spec = format!("{},{}", spec, piece);
+                    }
+                }
+                spec
+            })
+            .collect();
+        let mut parts = expand_forward_spec(positional);
+        parts.extend(flags);
+        return parts;
+    }
+    parts
+}
+
+fn expand_forward_spec(parts: Vec<String>) -> Vec<String> {
+    if parts.len() != 2 {
+        return parts;
+    }
+    let spec = parts[1].clone();
+    let parse_result: Option<u16> = spec.parse().ok();
+    if let Some(port) = parse_result {
+        return vec![parts[0].clone(), format!("localhost:{}", port), format!("localhost:{}", port)];
+    }
+    let colon_count = spec.matches(':').count();
+    if colon_count == 0 {
+        // Maybe spec is a unix socket path that should be used on both sides?
+        return vec![parts[0].clone(), spec.clone(), spec.clone()];
+    }
+    if colon_count == 1 {
+        return vec![
+            parts[0].clone(),
+            spec.split(':').next().unwrap().to_string(),
+            spec.split(':').nth(1).unwrap().to_string(),
+        ];
+    }
+    if colon_count == 2 {
+        return vec![
+            parts[0].clone(),
+            spec.splitn(2, ':').next().unwrap().to_string(),
+            spec.splitn(2, ':').nth(1).unwrap().to_string(),
+        ];
+    }
+    if colon_count == 3 {
+        let first_half = spec.split(':').take(2).collect::<Vec<&str>>().join(":");
+        let second_half = spec.split(':').skip(2).collect::<Vec<&str>>().join(":");
+        return vec![parts[0].clone(), first_half, second_half];
     }
     parts
 }
 
 impl Oxy {
+    /// Runs a metacommand the user typed or passed with --metacommand, after
+    /// expanding any aliases from the client config.
+    crate fn run_metacommand(&self, parts: Vec<String>) {
+        match expand_aliases(parts, &mut Vec::new()) {
+            Ok(commands) => {
+                for parts in commands {
+                    self.handle_metacommand(parts);
+                }
+            }
+            Err(message) => println!("{}", message),
+        }
+    }
+
     crate fn handle_metacommand(&self, parts: Vec<String>) {
         let parts = preprocess_parts(parts);
         let matches = create_app().get_matches_from_safe(parts.clone());
@@ -183,6 +346,7 @@ impl Oxy {
                     "pty" => {
                         let command = matches.value_of("command").map(|x| x.to_string());
                         let id = self.send(PtyRequest { command: command });
+                        crate::exit::expect_remote_status();
                         let proxy = self.clone();
                         #[cfg(unix)]
                         self.watch(Rc::new(move |message, _| match message {
@@ -190,18 +354,14 @@ impl Oxy {
                                 if *reference != id {
                                     return false;
                                 }
-                                if proxy.internal.ui.borrow().is_some() {
-                                    let (w, h) = proxy.internal.ui.borrow_mut().as_mut().unwrap().pty_size();
-                                    proxy.send(PtySizeAdvertisement { w, h });
-                                    return true;
-                                }
+                                proxy.send_pty_size();
                                 return true;
                             }
-                            Reject { reference, .. } => {
+                            Reject { reference, note } => {
                                 if *reference != id {
                                     return false;
                                 }
-                                warn!("PTY open failed");
+                                warn!("PTY open failed: {}", note);
                                 return true;
                             }
                             _ => false,
@@ -372,16 +532,21 @@ impl Oxy {
                             offset_start: matches.value_of("offset start").map(|x| x.parse().unwrap()),
                         });
                         let file = Rc::new(RefCell::new(Some(file)));
+                        let compress = self.should_compress(&buf);
                         let proxy = self.clone();
                         self.watch(Rc::new(move |message, _| match message {
                             Success { reference } if *reference == id => {
                                 let len = file.borrow().as_ref().unwrap().metadata().unwrap().len();
                                 proxy.log_info("Upload started");
+                                if !compress {
+                                    proxy.log_info("Sending it uncompressed, since it doesn't look compressible");
+                                }
                                 proxy.internal.transfers_out.borrow_mut().push(super::TransferOut {
                                     reference:        id,
                                     file:             file.borrow_mut().take().unwrap(),
                                     current_position: 0,
                                     cutoff_position:  len,
+                                    compress,
                                 });
                                 return true;
                             }
@@ -403,6 +568,11 @@ impl Oxy {
                             return;
                         }
                         (cleaner.unwrap())();
+                        self.internal.local_bind_destinations.borrow_mut().remove(&spec);
+                        self.internal.logged_forwards.borrow_mut().remove(&spec);
+                        self.internal.forward_connection_limits.borrow_mut().remove(&spec);
+                        self.internal.forward_accept_filters.borrow_mut().remove(&spec);
+                        self.internal.forward_proxy_protocols.borrow_mut().remove(&spec);
                         self.log_info("Port forward closed.");
                     }
                     "L" => {
@@ -411,6 +581,22 @@ impl Oxy {
                         if !local_spec.contains(':') && !local_spec.contains('/') {
                             local_spec = format!("localhost:{}", local_spec);
                         }
+                        if !self.limit_forward_connections(&local_spec, matches.value_of("max connections")) {
+                            return;
+                        }
+                        if matches.is_present("log") {
+                            self.internal.logged_forwards.borrow_mut().insert(local_spec.clone());
+                        }
+                        if let Some(version) = matches.value_of("proxy protocol").and_then(crate::proxy_protocol::parse_version) {
+                            self.internal.forward_proxy_protocols.borrow_mut().insert(local_spec.clone(), version);
+                        }
+                        if let Some(allow) = matches.values_of("allow") {
+                            if local_spec.contains('/') {
+                                self.log_warn("--allow has no effect on unix socket forwards");
+                            }
+                            let allow = allow.map(|x| x.to_string()).collect();
+                            self.internal.forward_accept_filters.borrow_mut().insert(local_spec.clone(), allow);
+                        }
                         if local_spec.contains('/') {
                             use nix::sys::socket::{accept, bind, listen, socket, AddressFamily, SockAddr, SockFlag, SockType};
                             let socket = socket(AddressFamily::Unix, SockType::Stream, SockFlag::empty(), None);
@@ -434,6 +620,10 @@ impl Oxy {
                             if listen_result.is_err() {
                                 self.log_warn(&format!("Failed to listen {}", local_spec));
                             }
+                            self.internal
+                                .local_bind_destinations
+                                .borrow_mut()
+                                .insert(local_spec.clone(), remote_spec.clone());
                             let token = Rc::new(RefCell::new(0));
                             let token2 = token.clone();
                             let proxy = self.clone();
@@ -447,10 +637,14 @@ impl Oxy {
                                     return;
                                 }
                                 let peer = peer.unwrap();
+                                if proxy.forward_at_capacity(&local_spec) {
+                                    ::nix::unistd::close(peer).ok();
+                                    return;
+                                }
                                 let stream_token = proxy.send(RemoteOpen {
                                     addr: remote_spec.to_string(),
                                 });
-                                let bt = BufferedTransport::from(peer);
+                                let bt = stream_transport(peer);
                                 let tracker = super::PortStream {
                                     stream: bt,
                                     token:  stream_token,
@@ -460,6 +654,13 @@ impl Oxy {
                                 let tracker2 = Rc::new(tracker.clone());
                                 tracker.stream.set_notify(tracker2);
                                 proxy.internal.local_streams.borrow_mut().insert(stream_token, tracker);
+                                proxy.track_forward_stream(true, stream_token, &local_spec, &remote_spec);
+                                if let Some(header) = proxy.forward_proxy_header(&local_spec, None, None) {
+                                    proxy.send(RemoteStreamData {
+                                        reference: stream_token,
+                                        data:      header,
+                                    });
+                                }
                             }));
                             *token2.borrow_mut() = token3;
                             transportation::borrow_poll(|poll| {
@@ -482,7 +683,12 @@ impl Oxy {
                             );
                             return;
                         }
-                        let bind = ::std::net::TcpListener::bind(&local_spec).unwrap();
+                        let bind = crate::util::resolve_addr(&local_spec).and_then(|x| ::std::net::TcpListener::bind(&x[..]));
+                        if let Err(err) = &bind {
+                            self.log_warn(&OxyError::Forward(format!("Failed to bind {}: {}", local_spec, err)).to_string());
+                            return;
+                        }
+                        let bind = bind.unwrap();
                         let bind = TcpListener::from_std(bind).unwrap();
                         let token_holder = Rc::new(RefCell::new(0));
                         let token_holder2 = token_holder.clone();
@@ -494,6 +700,10 @@ impl Oxy {
                         transportation::borrow_poll(|poll| {
                             poll.register(&bind, Token(token), Ready::readable(), PollOpt::level()).unwrap();
                         });
+                        self.internal
+                            .local_bind_destinations
+                            .borrow_mut()
+                            .insert(local_spec.clone(), remote_spec.clone());
                         let bind = PortBind {
                             listener: bind,
                             local_spec: local_spec.clone(),
@@ -524,11 +734,23 @@ impl Oxy {
                             return;
                         }
                         let reference = reference.unwrap();
+                        self.internal.logged_forwards.borrow_mut().remove(&spec);
+                        self.internal.forward_connection_limits.borrow_mut().remove(&spec);
+                        self.internal.forward_proxy_protocols.borrow_mut().remove(&spec);
                         self.send(CloseRemoteBind { reference });
                         self.log_info("Remote forward closed");
                     }
                     "R" => {
                         let remote_spec = matches.value_of("remote spec").unwrap().to_string();
+                        if !self.limit_forward_connections(&remote_spec, matches.value_of("max connections")) {
+                            return;
+                        }
+                        if matches.is_present("log") {
+                            self.internal.logged_forwards.borrow_mut().insert(remote_spec.clone());
+                        }
+                        if let Some(version) = matches.value_of("proxy protocol").and_then(crate::proxy_protocol::parse_version) {
+                            self.internal.forward_proxy_protocols.borrow_mut().insert(remote_spec.clone(), version);
+                        }
                         let bind_id = self.send(RemoteBind { addr: remote_spec.clone() });
                         self.internal.kr_references.borrow_mut().insert(remote_spec, bind_id);
                         self.internal
@@ -545,6 +767,7 @@ impl Oxy {
                         });
                         let tuntap = TunTap::create(TunTapType::Tun, matches.value_of("local tun").unwrap(), reference_number, self.clone());
                         self.internal.tuntaps.borrow_mut().insert(reference_number, tuntap);
+                        self.send_tunnel_mtu(reference_number);
                     }
                     #[cfg(unix)]
                     "tap" => {
@@ -554,6 +777,7 @@ impl Oxy {
                         });
                         let tuntap = TunTap::create(TunTapType::Tap, matches.value_of("local tap").unwrap(), reference_number, self.clone());
                         self.internal.tuntaps.borrow_mut().insert(reference_number, tuntap);
+                        self.send_tunnel_mtu(reference_number);
                     }
                     "KD" => {
                         let mut spec = matches.value_of("spec").unwrap().to_string();
@@ -566,6 +790,9 @@ impl Oxy {
                             return;
                         }
                         (cleaner.unwrap())();
+                        self.internal.logged_forwards.borrow_mut().remove(&spec);
+                        self.internal.forward_connection_limits.borrow_mut().remove(&spec);
+                        self.internal.socks_credentials.borrow_mut().remove(&spec);
                         self.log_info("SOCKS proxy closed.");
                     }
                     "socks" => {
@@ -573,6 +800,22 @@ impl Oxy {
                         if !local_spec.contains(':') && !local_spec.contains('/') {
                             local_spec = format!("localhost:{}", local_spec);
                         }
+                        if !self.limit_forward_connections(&local_spec, matches.value_of("max connections")) {
+                            return;
+                        }
+                        if let Some(auth) = matches.value_of("auth") {
+                            let credentials = match crate::arg::parse_socks_auth(auth) {
+                                Ok(credentials) => credentials,
+                                Err(err) => {
+                                    self.log_warn(&err);
+                                    return;
+                                }
+                            };
+                            self.internal.socks_credentials.borrow_mut().insert(local_spec.clone(), credentials);
+                        }
+                        if matches.is_present("log") {
+                            self.internal.logged_forwards.borrow_mut().insert(local_spec.clone());
+                        }
                         if local_spec.contains("/") {
                             use nix::sys::socket::{accept, bind, listen, socket, AddressFamily, SockAddr, SockFlag, SockType};
                             let socket = socket(AddressFamily::Unix, SockType::Stream, SockFlag::empty(), None);
@@ -610,11 +853,12 @@ impl Oxy {
                                 }
                                 proxy.log_info("Accepted SOCKS connection");
                                 let peer = peer.unwrap();
-                                let bt = BufferedTransport::from(peer);
+                                let bt = stream_transport(peer);
                                 let sproxy = super::SocksConnectionNotificationProxy {
                                     oxy: proxy.clone(),
                                     bt,
                                     state: Rc::new(RefCell::new(super::SocksState::Initial)),
+                                    forward: local_spec.clone(),
                                 };
                                 let sproxy = Rc::new(sproxy);
                                 sproxy.bt.set_notify(sproxy.clone());
@@ -639,7 +883,12 @@ impl Oxy {
                             );
                             return;
                         }
-                        let bind = ::std::net::TcpListener::bind(&local_spec).unwrap();
+                        let bind = crate::util::resolve_addr(&local_spec).and_then(|x| ::std::net::TcpListener::bind(&x[..]));
+                        if let Err(err) = &bind {
+                            self.log_warn(&OxyError::Forward(format!("Failed to bind {}: {}", local_spec, err)).to_string());
+                            return;
+                        }
+                        let bind = bind.unwrap();
                         let bind = TcpListener::from_std(bind).unwrap();
                         let proxy = SocksBindNotificationProxy {
                             oxy:   self.clone(),
@@ -652,7 +901,10 @@ impl Oxy {
                         transportation::borrow_poll(|poll| {
                             poll.register(&bind, Token(token), Ready::readable(), PollOpt::level()).unwrap();
                         });
-                        let socks = SocksBind { listener: bind };
+                        let socks = SocksBind {
+                            listener: bind,
+                            spec:     local_spec.clone(),
+                        };
                         self.internal.socks_binds.borrow_mut().insert(token_sized, socks);
                         self.log_info("SOCKS proxy established.");
                         let selfproxy = self.clone();
@@ -668,7 +920,7 @@ impl Oxy {
                         );
                     }
                     "exit" => {
-                        ::std::process::exit(0);
+                        crate::exit::exit(0);
                     }
                     "f10" => {
                         let f10 = [27, 91, 50, 49, 126];
@@ -694,10 +946,310 @@ impl Oxy {
                         let command = command.unwrap().to_string();
                         let reference = self.send(PipeCommand { command });
                         *self.internal.pipecmd_reference.borrow_mut() = Some(reference);
+                        crate::exit::expect_remote_status();
+                        if let Some(timeout) = crate::arg::command_timeout() {
+                            let proxy = self.clone();
+                            transportation::set_timeout(Rc::new(move || proxy.notify_command_timeout(reference)), timeout);
+                        }
+                    }
+                    "forward-log" => {
+                        let mut spec = matches.value_of("spec").unwrap().to_string();
+                        if !spec.contains(":") && !spec.contains("/") {
+                            spec = format!("localhost:{}", spec);
+                        }
+                        if !self.active_forwards().keys().any(|x| x.split(' ').nth(1) == Some(spec.as_str())) {
+                            self.log_warn("Could not find forward.");
+                            return;
+                        }
+                        if matches.value_of("state") == Some("on") {
+                            self.internal.logged_forwards.borrow_mut().insert(spec);
+                            self.log_info("Forward logging enabled.");
+                        } else {
+                            self.internal.logged_forwards.borrow_mut().remove(&spec);
+                            self.log_info("Forward logging disabled.");
+                        }
+                    }
+                    "echo" => {
+                        let enabled = matches.value_of("state") == Some("on");
+                        self.set_local_echo(enabled);
+                        self.log_info(if enabled { "Local echo enabled." } else { "Local echo disabled." });
+                    }
+                    "mode" => {
+                        let line_mode = matches.value_of("mode") == Some("line");
+                        let line = self.internal.ui.borrow().as_ref().map(|ui| ui.set_line_mode(line_mode)).unwrap_or_default();
+                        if !line.is_empty() {
+                            self.send(PtyInput { data: line });
+                        }
+                        self.set_local_echo(line_mode);
+                        self.log_info(if line_mode { "Line mode enabled." } else { "Raw mode enabled." });
+                    }
+                    "suspend" => {
+                        self.suspend_session();
+                        self.log_info("Session suspended.");
+                    }
+                    "resume" => {
+                        if !*self.internal.suspended.borrow() {
+                            self.log_warn("Session is not suspended.");
+                            return;
+                        }
+                        self.resume_session();
+                    }
+                    "edit-forwards" => {
+                        self.edit_forwards();
+                    }
+                    "whoami" => {
+                        self.whoami();
+                    }
+                    "session-info" => {
+                        self.session_info();
                     }
                     _ => (),
                 }
             }
         }
     }
+
+    /// Asks the server to give its end of a tunnel the same --tunnel-mtu as
+    /// ours, since packets that fit one end but not the other are silently
+    /// dropped.
+    #[cfg(unix)]
+    /// Echoes typed input locally, and has the server turn the remote
+    /// terminal's echo off meanwhile.
+    fn set_local_echo(&self, enabled: bool) {
+        if let Some(ui) = self.internal.ui.borrow().as_ref() {
+            ui.set_local_echo(enabled);
+        }
+        self.send(PtyLocalEcho { enabled });
+    }
+
+    fn send_tunnel_mtu(&self, reference: u64) {
+        let mtu = crate::arg::tunnel_mtu();
+        if mtu.is_none() {
+            return;
+        }
+        let id = self.send(TunnelMtu { reference, mtu: mtu.unwrap() as u64 });
+        let proxy = self.clone();
+        self.watch(Rc::new(move |message, _| match message {
+            Success { reference } if *reference == id => true,
+            Reject { reference, note } if *reference == id => {
+                proxy.log_warn(&format!("The server didn't match the tunnel MTU: {}", note));
+                true
+            }
+            _ => false,
+        }));
+    }
+
+    /// Records a forward's --max-connections, if it was given one. False if
+    /// the limit isn't a usable number, after saying so.
+    fn limit_forward_connections(&self, forward: &str, limit: Option<&str>) -> bool {
+        if limit.is_none() {
+            return true;
+        }
+        match limit.unwrap().parse::<u64>() {
+            Ok(limit) if limit > 0 => {
+                self.internal.forward_connection_limits.borrow_mut().insert(forward.to_string(), limit);
+                true
+            }
+            _ => {
+                self.log_warn("--max-connections expects a positive number");
+                false
+            }
+        }
+    }
+
+    fn whoami(&self) {
+        let peer = self.internal.peer_name.borrow().clone();
+        let key = crate::keys::asymmetric_key(peer.as_ref().map(|x| &**x));
+        self.log_info(&format!("Client key: {}", crate::keys::key_fingerprint(key.public_key_bytes())));
+        if let Some(server_key) = self.internal.kex_data.borrow().server_key.as_ref() {
+            self.log_info(&format!("Server key: {}", crate::keys::key_fingerprint(server_key)));
+        }
+        let id = self.send(WhoamiRequest {});
+        let proxy = self.clone();
+        self.watch(Rc::new(move |message, _| match message {
+            WhoamiResult {
+                reference,
+                user,
+                uid,
+                client_name,
+            } if *reference == id =>
+            {
+                match client_name {
+                    Some(name) => proxy.log_info(&format!("The server knows this key as {:?}", name)),
+                    None => proxy.log_info("The server doesn't know this key by name"),
+                }
+                proxy.log_info(&format!("Remote user: {} (uid {})", user, uid));
+                true
+            }
+            Reject { reference, note } if *reference == id => {
+                proxy.log_warn(&format!("The server didn't answer whoami: {:?}", note));
+                true
+            }
+            _ => false,
+        }));
+    }
+
+    /// One "name: value" line for each of the session's parameters.
+    fn session_info(&self) {
+        let compression = |x: bool| if x { self.compression_name() } else { "off" };
+        let (local, remote) = self.internal.socket_addresses.borrow().clone();
+        let peer = self.internal.peer_name.borrow().clone();
+        let key = crate::keys::asymmetric_key(peer.as_ref().map(|x| &**x));
+        let (outbound, inbound) = (self.outbound_compressed(), self.inbound_compressed());
+        let mut lines = vec![
+            format!("version: {}", crate::arg::version_string()),
+            format!("peer-version: {}", self.internal.peer_version.borrow().clone().unwrap_or_else(|| "unknown".to_string())),
+            format!("cipher: {}", super::kex::SESSION_CIPHER),
+            format!("kex: {}", super::kex::SESSION_KEX),
+            "signatures: ed25519".to_string(),
+            format!("compression-out: {}", compression(outbound)),
+            format!("compression-in: {}", compression(inbound)),
+            format!("client-key: {}", crate::keys::key_fingerprint(key.public_key_bytes())),
+        ];
+        if let Some(server_key) = self.internal.kex_data.borrow().server_key.as_ref() {
+            lines.push(format!("server-key: {}", crate::keys::key_fingerprint(server_key)));
+        }
+        lines.push(format!("local-address: {}", local.unwrap_or_else(|| "unknown".to_string())));
+        lines.push(format!("remote-address: {}", remote.unwrap_or_else(|| "unknown".to_string())));
+        lines.push(format!("messages-sent: {}", *self.internal.outgoing_ticker.borrow()));
+        lines.push(format!("messages-received: {}", *self.internal.incoming_ticker.borrow()));
+        for line in lines {
+            self.log_info(&line);
+        }
+    }
+
+    /// The active forwards as metacommand lines, along with whether each has
+    /// logging turned on. The second word of each line identifies the forward.
+    fn active_forwards(&self) -> BTreeMap<String, bool> {
+        let logged = self.internal.logged_forwards.borrow();
+        let mut forwards = BTreeMap::new();
+        for (local, remote) in self.internal.local_bind_destinations.borrow().iter() {
+            forwards.insert(format!("L {} {}", local, remote), logged.contains(local));
+        }
+        for (remote, reference) in self.internal.kr_references.borrow().iter() {
+            if let Some(local) = self.internal.remote_bind_destinations.borrow().get(reference) {
+                forwards.insert(format!("R {} {}", remote, local), logged.contains(remote));
+            }
+        }
+        for spec in self.internal.socks_bind_cleaners.borrow().keys() {
+            forwards.insert(format!("D {}", spec), logged.contains(spec));
+        }
+        forwards
+    }
+
+    fn edit_forwards(&self) {
+        let current = self.active_forwards();
+        let file = crate::tempfile::TempFile::create("oxy-forwards");
+        if let Err(err) = file {
+            self.log_warn(&format!("Failed to create a file to edit forwards in: {:?}", err));
+            return;
+        }
+        let (temp_file, mut file) = file.unwrap();
+        let path = temp_file.path().to_path_buf();
+        let mut text = "# One forward per line: L <local> <remote>, R <remote> <local>, or D <spec>, optionally with --log\n".to_string();
+        for (line, logged) in &current {
+            text.push_str(line);
+            if *logged {
+                text.push_str(" --log");
+            }
+            text.push('\n');
+        }
+        if file.write_all(text.as_bytes()).is_err() {
+            self.log_warn(&format!("Failed to write {:?}", path));
+            return;
+        }
+        let editor = ::std::env::var("VISUAL")
+            .or_else(|_| ::std::env::var("EDITOR"))
+            .unwrap_or_else(|_| "vi".to_string());
+        let editor = shlex::split(&editor).filter(|x| !x.is_empty()).unwrap_or_else(|| vec!["vi".to_string()]);
+        if let Some(ui) = self.internal.ui.borrow().as_ref() {
+            ui.cooked();
+        }
+        let status = Command::new(&editor[0]).args(&editor[1..]).arg(&path).status();
+        // The event loop was blocked while the editor ran, so don't count that time against keepalives.
+        *self.internal.last_message_seen.borrow_mut() = Some(Instant::now());
+        let text = read_to_string(&path);
+        ::std::mem::drop(temp_file);
+        if status.is_err() || !status.unwrap().success() {
+            self.log_warn("Editor exited unsuccessfully, forwards left unchanged.");
+            return;
+        }
+        if text.is_err() {
+            self.log_warn("Failed to read edited forwards, forwards left unchanged.");
+            return;
+        }
+
+        let mut wanted = BTreeMap::new();
+        let mut errors = Vec::new();
+        for (number, line) in text.unwrap().lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match parse_forward_line(line) {
+                Ok((forward, logged)) => {
+                    wanted.insert(forward, logged);
+                }
+                Err(err) => errors.push(format!("line {}: {}", number + 1, err)),
+            }
+        }
+        if !errors.is_empty() {
+            for error in errors {
+                self.log_warn(&error);
+            }
+            self.log_warn("Forwards left unchanged.");
+            return;
+        }
+
+        for forward in current.keys().filter(|x| !wanted.contains_key(*x)) {
+            let parts: Vec<&str> = forward.split(' ').collect();
+            let kill = match parts[0] {
+                "L" => "KL",
+                "R" => "KR",
+                _ => "KD",
+            };
+            self.handle_metacommand(vec![kill.to_string(), parts[1].to_string()]);
+        }
+        for forward in wanted.keys().filter(|x| !current.contains_key(*x)) {
+            self.handle_metacommand(forward.split(' ').map(|x| x.to_string()).collect());
+        }
+        for (forward, logged) in &wanted {
+            let id = forward.split(' ').nth(1).unwrap().to_string();
+            if *logged {
+                self.internal.logged_forwards.borrow_mut().insert(id);
+            } else {
+                self.internal.logged_forwards.borrow_mut().remove(&id);
+            }
+        }
+    }
+}
+
+/// Parses one line of the edit-forwards buffer with the metacommand parser,
+/// returning it in the same canonical form as active_forwards.
+fn parse_forward_line(line: &str) -> Result<(String, bool), String> {
+    let parts = shlex::split(line).ok_or("unbalanced quotes")?;
+    if parts.len() < 2 {
+        return Err("expected a forward spec".to_string());
+    }
+    let parts = preprocess_parts(parts);
+    let matches = create_app()
+        .get_matches_from_safe(parts)
+        .map_err(|x| x.message.lines().next().unwrap_or("").to_string())?;
+    let name = matches.subcommand_name().unwrap();
+    let matches = matches.subcommand_matches(name).unwrap();
+    let localize = |spec: &str| {
+        if !spec.contains(':') && !spec.contains('/') {
+            format!("localhost:{}", spec)
+        } else {
+            spec.to_string()
+        }
+    };
+    let value = |arg: &str| matches.value_of(arg).map(|x| x.to_string()).ok_or(format!("missing {}", arg));
+    let forward = match name {
+        "L" => format!("L {} {}", localize(&value("local spec")?), value("remote spec")?),
+        "R" => format!("R {} {}", value("remote spec")?, value("local spec")?),
+        "socks" => format!("D {}", localize(&value("bind spec")?)),
+        _ => return Err(format!("{} is not a forward", name)),
+    };
+    Ok((forward, matches.is_present("log")))
 }
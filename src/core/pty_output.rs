@@ -0,0 +1,162 @@
+// Throttling of pty output on the client, for --pty-output-limit and
+// --pty-flood-threshold.
+//
+// Output that can't be written yet is held in a backlog. Once the backlog
+// grows past PAUSE_BACKLOG, the server is asked to stop reading the pty, which
+// in turn blocks the remote program once the pty's own buffer fills.
+use crate::{core::Oxy, message::OxyMessage::*};
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use std::{
+    cmp::{max, min},
+    io::Write,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+use transportation::set_timeout;
+
+/// How often a throttled backlog is written out.
+const DRAIN_INTERVAL: Duration = Duration::from_millis(50);
+/// Backlog sizes at which the server is asked to pause and resume the pty.
+const PAUSE_BACKLOG: usize = 1 << 20;
+const RESUME_BACKLOG: usize = 1 << 18;
+/// Ctrl-Q resumes output held by the flood detector, Ctrl-O drops it.
+const RESUME_KEY: u8 = 0x11;
+const DISCARD_KEY: u8 = 0x0f;
+
+impl Oxy {
+    /// Passes pty output on to the terminal, through the backlog if it needs
+    /// throttling.
+    pub(super) fn queue_pty_output(&self, data: Vec<u8>) {
+        if crate::arg::pty_output_limit().is_none() && crate::arg::pty_flood_threshold().is_none() {
+            self.write_pty_output(&data);
+            return;
+        }
+        self.check_pty_flood(data.len());
+        self.internal.pty_output_backlog.borrow_mut().extend(data);
+        self.drain_pty_output();
+    }
+
+    fn write_pty_output(&self, data: &[u8]) {
+        if self.internal.ui.borrow().is_some() {
+            self.internal.ui.borrow().as_ref().unwrap().pty_data(data);
+        } else {
+            let stdout = ::std::io::stdout();
+            let mut lock = stdout.lock();
+            lock.write_all(data).unwrap();
+        }
+    }
+
+    fn drain_pty_output(&self) {
+        if !*self.internal.pty_output_held.borrow() {
+            let allowance = match crate::arg::pty_output_limit() {
+                Some(rate) => self.pty_output_allowance(rate),
+                None => usize::max_value(),
+            };
+            let data: Vec<u8> = {
+                let mut backlog = self.internal.pty_output_backlog.borrow_mut();
+                let count = min(allowance, backlog.len());
+                backlog.drain(..count).collect()
+            };
+            if !data.is_empty() {
+                self.write_pty_output(&data);
+            }
+        }
+        let backlog = self.internal.pty_output_backlog.borrow().len();
+        self.update_pty_flow_control(backlog);
+        if backlog > 0 && !*self.internal.pty_output_held.borrow() && !*self.internal.pty_output_drain_scheduled.borrow() {
+            *self.internal.pty_output_drain_scheduled.borrow_mut() = true;
+            let proxy = self.clone();
+            set_timeout(
+                Rc::new(move || {
+                    *proxy.internal.pty_output_drain_scheduled.borrow_mut() = false;
+                    proxy.drain_pty_output();
+                }),
+                DRAIN_INTERVAL,
+            );
+        }
+    }
+
+    /// How many bytes may be written now under --pty-output-limit. Unused
+    /// allowance carries over for up to a tenth of a second.
+    fn pty_output_allowance(&self, rate: u64) -> usize {
+        let now = Instant::now();
+        let elapsed = match *self.internal.pty_output_refilled.borrow() {
+            Some(last) => now.duration_since(last),
+            None => DRAIN_INTERVAL,
+        };
+        *self.internal.pty_output_refilled.borrow_mut() = Some(now);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        let mut budget = self.internal.pty_output_budget.borrow_mut();
+        *budget = (*budget + rate as f64 * elapsed).min(max(rate / 10, 1) as f64);
+        let count = *budget as usize;
+        *budget -= count as f64;
+        count
+    }
+
+    fn update_pty_flow_control(&self, backlog: usize) {
+        let paused = *self.internal.pty_output_paused.borrow();
+        if !paused && backlog > PAUSE_BACKLOG {
+            debug!("Pausing remote pty output with {} bytes waiting", backlog);
+            *self.internal.pty_output_paused.borrow_mut() = true;
+            self.send(PtyFlowControl { paused: true });
+        } else if paused && backlog < RESUME_BACKLOG && !*self.internal.pty_output_held.borrow() {
+            debug!("Resuming remote pty output");
+            *self.internal.pty_output_paused.borrow_mut() = false;
+            self.send(PtyFlowControl { paused: false });
+        }
+    }
+
+    /// Holds output once more than --pty-flood-threshold arrives within a
+    /// second, until the user resumes it.
+    fn check_pty_flood(&self, len: usize) {
+        let threshold = match crate::arg::pty_flood_threshold() {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        if *self.internal.pty_output_held.borrow() {
+            return;
+        }
+        let now = Instant::now();
+        let (start, count) = self.internal.pty_flood_window.borrow().unwrap_or((now, 0));
+        let (start, count) = if now.duration_since(start) < Duration::from_secs(1) {
+            (start, count + len as u64)
+        } else {
+            (now, len as u64)
+        };
+        *self.internal.pty_flood_window.borrow_mut() = Some((start, count));
+        if count > threshold {
+            *self.internal.pty_output_held.borrow_mut() = true;
+            *self.internal.pty_output_paused.borrow_mut() = true;
+            self.send(PtyFlowControl { paused: true });
+            let notice = format!(
+                "\r\n[oxy] Output paused: more than {} bytes arrived in a second. Ctrl-Q resumes, Ctrl-O drops the held output and \
+                 resumes. Other keys still go to the remote, so Ctrl-C works.\r\n",
+                threshold
+            );
+            self.write_pty_output(notice.as_bytes());
+        }
+    }
+
+    /// Looks for the resume and discard keys in terminal input while output is
+    /// held, removing them from what's sent to the server.
+    pub(super) fn filter_held_pty_input(&self, input: &mut Vec<u8>) {
+        if !*self.internal.pty_output_held.borrow() {
+            return;
+        }
+        let resume = input.contains(&RESUME_KEY);
+        let discard = input.contains(&DISCARD_KEY);
+        if !resume && !discard {
+            return;
+        }
+        input.retain(|x| *x != RESUME_KEY && *x != DISCARD_KEY);
+        if discard {
+            let dropped = self.internal.pty_output_backlog.borrow().len();
+            self.internal.pty_output_backlog.borrow_mut().clear();
+            self.write_pty_output(format!("\r\n[oxy] Dropped {} bytes of held output.\r\n", dropped).as_bytes());
+        }
+        *self.internal.pty_output_held.borrow_mut() = false;
+        *self.internal.pty_flood_window.borrow_mut() = None;
+        self.drain_pty_output();
+    }
+}
@@ -4,26 +4,209 @@ use crate::{
 };
 #[allow(unused_imports)]
 use log::{debug, error, info, log, trace, warn};
+use std::net::IpAddr;
+use transportation::EncryptionPerspective::Alice;
+
+/// The --capabilities entry a request falls under, if any.
+fn capability(message: &OxyMessage) -> Option<&'static str> {
+    match message {
+        PtyRequest { .. } => Some("shell"),
+        BasicCommand { .. } | PipeCommand { .. } => Some("exec"),
+        DownloadRequest { .. }
+        | UploadRequest { .. }
+        | StatRequest { .. }
+        | ReadDir { .. }
+        | FileHashRequest { .. }
+        | FileTruncateRequest { .. }
+        | FileSignatureRequest { .. }
+        | FollowRequest { .. }
+        | ReadLinkRequest { .. }
+        | SymlinkRequest { .. }
+        | SetPermissionsRequest { .. }
+        | CreateDirectoryRequest { .. }
+        | RealPathRequest { .. } => Some("copy"),
+        RemoteOpen { .. } | RemoteBind { .. } | KnockForward { .. } => Some("forward"),
+        XForwardRequest { .. } | AdvertiseXAuth { .. } => Some("x11"),
+        TunnelRequest { .. } => Some("tunnel"),
+        _ => None,
+    }
+}
+
+/// Whether --interactive-allow, if given, admits the connection's source.
+fn interactive_allowed() -> bool {
+    let patterns = match crate::arg::matches().values_of("interactive allow") {
+        Some(patterns) => patterns,
+        None => return true,
+    };
+    let source = match crate::authorized::source() {
+        Some(source) => source.ip(),
+        None => return false,
+    };
+    // IPv4 clients of a listener bound to :: show up as mapped addresses.
+    let source = match source {
+        IpAddr::V6(v6) => v6.to_ipv4().filter(|_| v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff]).map(IpAddr::V4).unwrap_or(source),
+        _ => source,
+    };
+    patterns.into_iter().any(|x| crate::pattern::address_match(x, source))
+}
 
 impl Oxy {
     crate fn restrict_message(&self, message: OxyMessage) -> Result<OxyMessage, ()> {
+        let message = self.restrict_interactive_auth(message)?;
+        let message = self.restrict_su_mode(message)?;
         let message = self.restrict_forcedcommand(message)?;
         let message = self.restrict_portforwards(message)?;
+        let message = self.restrict_gateway_ports(message)?;
+        let message = self.restrict_tunnels(message)?;
+        let message = self.restrict_pty(message)?;
         Ok(message)
     }
 
+    /// Applies --no-x-forwarding, --no-port-forwarding, --no-pty and
+    /// --capabilities. Unlike the other restrictions, these tell the client
+    /// why it was refused.
+    crate fn check_server_policy(&self, message: &OxyMessage) -> Result<(), String> {
+        if self.perspective() == Alice {
+            return Ok(());
+        }
+        let matches = crate::arg::matches();
+        let refused = match message {
+            XForwardRequest { .. } | AdvertiseXAuth { .. } if matches.is_present("no x forwarding") => {
+                Some("X forwarding is disabled on this server".to_string())
+            }
+            RemoteOpen { .. } | RemoteBind { .. } | KnockForward { .. } if matches.is_present("no port forwarding") => {
+                Some("Port forwarding is disabled on this server".to_string())
+            }
+            PtyRequest { .. } if matches.is_present("no pty") => Some("Pty allocation is disabled on this server".to_string()),
+            PtyRequest { .. } if !interactive_allowed() => Some("Interactive sessions aren't allowed from your address".to_string()),
+            _ => capability(message)
+                .filter(|x| !crate::arg::capability_enabled(x))
+                .map(|x| format!("This server doesn't offer the {} capability", x)),
+        };
+        match refused {
+            Some(refused) => {
+                warn!("Rejecting {:?}: {}", message, refused);
+                Err(refused)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn restrict_interactive_auth(&self, message: OxyMessage) -> Result<OxyMessage, ()> {
+        if self.perspective() == Alice || !self.interactive_auth_pending() {
+            return Ok(message);
+        }
+        match message {
+            AuthResponse { .. } | VersionAdvertisement { .. } | Ping {} | Pong {} => Ok(message),
+            _ => {
+                warn!("Rejecting {:?} before interactive auth completed", message);
+                Err(())
+            }
+        }
+    }
+
+    /// Under --su-mode, privileges are kept until the client's first message
+    /// after authenticating. A UsernameAdvertisement picks the user, and
+    /// anything else settles for the configured setuser.
+    fn restrict_su_mode(&self, message: OxyMessage) -> Result<OxyMessage, ()> {
+        if self.perspective() == Alice {
+            return Ok(message);
+        }
+        if *self.internal.su_refused.borrow() {
+            return Err(());
+        }
+        if !*self.internal.su_pending.borrow() {
+            return Ok(message);
+        }
+        match message {
+            UsernameAdvertisement { .. } | AuthResponse { .. } | VersionAdvertisement { .. } | Ping {} | Pong {} => Ok(message),
+            _ => {
+                let peer = self.internal.peer_name.borrow().clone();
+                self.become_user(peer.and_then(|x| crate::conf::get_setuser(&x)));
+                Ok(message)
+            }
+        }
+    }
+
+    fn restrict_tunnels(&self, message: OxyMessage) -> Result<OxyMessage, ()> {
+        match message {
+            TunnelRequest { .. } if !crate::arg::matches().is_present("permit tunnel") => {
+                warn!("Rejecting tunnel request. Run the server with --permit-tunnel to allow tunnels.");
+                Err(())
+            }
+            _ => Ok(message),
+        }
+    }
+
+    fn restrict_pty(&self, message: OxyMessage) -> Result<OxyMessage, ()> {
+        match message {
+            PtyRequest { .. } if crate::authorized::no_pty() => {
+                warn!("Rejecting pty request: the client's authorized identity has no-pty");
+                Err(())
+            }
+            _ => Ok(message),
+        }
+    }
+
     fn restrict_portforwards(&self, message: OxyMessage) -> Result<OxyMessage, ()> {
-        ();
-        // TODO
+        if let RemoteOpen { addr } = &message {
+            if let Some(permitted) = crate::arg::matches().values_of("permit open") {
+                let permitted: Vec<&str> = permitted.collect();
+                if !permitted.iter().any(|x| crate::pattern::destination_match(x, addr)) {
+                    warn!("Rejecting forward to {:?}: not permitted by --permit-open", addr);
+                    return Err(());
+                }
+            }
+            if let Some(permitted) = crate::authorized::permit_open() {
+                if !permitted.iter().any(|x| crate::pattern::destination_match(x, addr)) {
+                    warn!("Rejecting forward to {:?}: not permitted by the client's authorized identity", addr);
+                    return Err(());
+                }
+            }
+        }
         Ok(message)
     }
 
+    /// Applies --gateway-ports to remote forwards. no: they may only bind to
+    /// loopback addresses. yes: they bind to all interfaces, whatever the
+    /// client asked for. clientspecified: the client's bind address is used.
+    fn restrict_gateway_ports(&self, message: OxyMessage) -> Result<OxyMessage, ()> {
+        let addr = match &message {
+            RemoteBind { addr } if !addr.contains('/') => addr.clone(),
+            _ => return Ok(message),
+        };
+        let (host, port) = match addr.rfind(':') {
+            Some(index) => (&addr[..index], &addr[index + 1..]),
+            None => ("localhost", &addr[..]),
+        };
+        match crate::arg::gateway_ports() {
+            "yes" => {
+                debug!("Binding remote forward {:?} to all interfaces", addr);
+                Ok(RemoteBind { addr: format!("[::]:{}", port) })
+            }
+            "clientspecified" => Ok(message),
+            _ => {
+                let loopback = port
+                    .parse()
+                    .ok()
+                    .and_then(|port| crate::util::resolve_host(host, port).ok())
+                    .map(|addrs| !addrs.is_empty() && addrs.iter().all(|x| x.ip().is_loopback()))
+                    .unwrap_or(false);
+                if !loopback {
+                    warn!("Rejecting remote forward bind to {:?}: binding to non-loopback addresses needs --gateway-ports", addr);
+                    return Err(());
+                }
+                Ok(message)
+            }
+        }
+    }
+
     fn restrict_forcedcommand(&self, message: OxyMessage) -> Result<OxyMessage, ()> {
-        let forced_command = crate::arg::matches().value_of("forced command");
+        let forced_command = crate::authorized::forced_command().or_else(|| crate::arg::matches().value_of("forced command").map(|x| x.to_string()));
         if forced_command.is_none() {
             return Ok(message);
         }
-        let forced_command = forced_command.unwrap().to_string();
+        let forced_command = forced_command.unwrap();
         debug!("Processing restrictions");
 
         match message.clone() {
@@ -34,7 +217,17 @@ impl Oxy {
             }),
             UsernameAdvertisement { .. } => Ok(message),
             PtySizeAdvertisement { .. } => Ok(message),
+            PtyPixelSizeAdvertisement { .. } => Ok(message),
             PtyInput { .. } => Ok(message),
+            PtyFlowControl { .. } => Ok(message),
+            PtyLocalEcho { .. } => Ok(message),
+            PipeCommandTerminate { .. } => Ok(message),
+            Hangup { .. } => Ok(message),
+            SessionSuspend {} => Ok(message),
+            SessionResume {} => Ok(message),
+            AuthResponse { .. } => Ok(message),
+            WhoamiRequest {} => Ok(message),
+            VersionAdvertisement { .. } => Ok(message),
             Success { .. } => Ok(message),
             Reject { .. } => Ok(message),
             Ping {} => Ok(message),
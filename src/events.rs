@@ -0,0 +1,73 @@
+// --events-fd and --events-file: a stream of JSON lines describing what the
+// client's session is doing, for programs that wrap oxy. Each line has an
+// "event" naming it and a "time" in seconds since the epoch, along with
+// fields of its own. Unlike the logs, the format is meant to stay stable.
+//
+// connected: the TCP connection to the server is up ("destination", "address")
+// authenticated: the handshake with the server completed ("destination")
+// forward_opened, forward_closed: a connection through a forward ("forward",
+//     "destination", and once closed "bytes_in" and "bytes_out")
+// transfer_progress: a file transfer passed another tenth ("percent")
+// disconnected: the session is over
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use serde_json::{self, json};
+use std::{
+    cell::{Cell, RefCell},
+    fs::{File, OpenOptions},
+    io::Write,
+    time::UNIX_EPOCH,
+};
+
+thread_local! {
+    static SINK: RefCell<Option<File>> = RefCell::new(None);
+    static TRANSFER_TENTHS: Cell<u64> = Cell::new(0);
+}
+
+fn open() -> File {
+    let matches = crate::arg::matches();
+    #[cfg(unix)]
+    {
+        if let Some(fd) = matches.value_of("events fd") {
+            return unsafe { ::std::os::unix::io::FromRawFd::from_raw_fd(fd.parse().unwrap()) };
+        }
+    }
+    let path = matches.value_of("events file").unwrap();
+    match OpenOptions::new().append(true).create(true).open(path) {
+        Ok(file) => file,
+        Err(err) => crate::exit::fail(crate::exit::OxyError::Config(format!("Failed to open events file {}: {}", path, err))),
+    }
+}
+
+/// Writes an event, if --events-fd or --events-file asks for them. Fields
+/// must be a JSON object.
+crate fn emit(event: &str, mut fields: serde_json::Value) {
+    let matches = crate::arg::matches();
+    if !matches.is_present("events fd") && !matches.is_present("events file") {
+        return;
+    }
+    fields["event"] = json!(event);
+    let time = UNIX_EPOCH.elapsed().unwrap();
+    fields["time"] = json!(time.as_secs() as f64 + f64::from(time.subsec_millis()) / 1e3);
+    SINK.with(|x| {
+        let mut sink = x.borrow_mut();
+        if sink.is_none() {
+            *sink = Some(open());
+            crate::exit::push_hook(|| emit("disconnected", json!({})));
+        }
+        if let Err(err) = writeln!(sink.as_mut().unwrap(), "{}", serde_json::to_string(&fields).unwrap()) {
+            warn!("Failed to write an event: {}", err);
+        }
+    });
+}
+
+/// Notes a file transfer's progress, in thousandths, emitting an event each
+/// time it passes another tenth.
+crate fn transfer_progress(progress: u64) {
+    let tenths = progress / 100;
+    if tenths == TRANSFER_TENTHS.with(Cell::get) {
+        return;
+    }
+    TRANSFER_TENTHS.with(|x| x.set(tenths));
+    emit("transfer_progress", json!({ "percent": tenths * 10 }));
+}
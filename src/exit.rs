@@ -1,9 +1,131 @@
 #[allow(unused_imports)]
 use log::{debug, error, info, log, trace, warn};
-use std::cell::RefCell;
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+};
+
+/// The session completed normally.
+crate const EXIT_OK: i32 = 0;
+/// Something went wrong inside oxy itself.
+crate const EXIT_FAILURE: i32 = 1;
+/// The connection to the peer broke or timed out.
+crate const EXIT_CONNECTION_ERROR: i32 = 2;
+/// Key exchange failed: the peer's key was not accepted, or it did not accept
+/// ours.
+crate const EXIT_AUTH_REJECTED: i32 = 3;
+/// A server turned a client away for its credentials: an unknown key, or
+/// failed interactive or PAM auth. Only a reexec'd connection exits with this,
+/// for the listening server to count against --max-auth-tries, unlike
+/// connections that merely hung up during the handshake.
+crate const EXIT_LOGIN_REJECTED: i32 = 4;
+
+/// A remote command ran past --command-timeout. Matches timeout(1).
+crate const EXIT_COMMAND_TIMEOUT: i32 = 124;
+/// oxy itself failed, while a remote command's status was to be the client's.
+/// Matches ssh.
+crate const EXIT_REMOTE_SESSION_FAILED: i32 = 255;
+
+/// The status a shell gives a command killed by a signal.
+crate fn signal_status(signal: i32) -> i32 {
+    128 + signal
+}
+
+/// Notes that the client has started a remote command, whose exit status it
+/// will exit with. From then on, oxy's own failures exit with
+/// EXIT_REMOTE_SESSION_FAILED, which a command is unlikely to, rather than a
+/// status in the range commands use.
+crate fn expect_remote_status() {
+    REMOTE_STATUS_EXPECTED.with(|x| x.set(true));
+}
+
+/// The status to exit with when the connection is lost: EXIT_OK, unless the
+/// status of a remote command was still to come.
+crate fn connection_lost_status() -> i32 {
+    if REMOTE_STATUS_EXPECTED.with(|x| x.get()) {
+        EXIT_REMOTE_SESSION_FAILED
+    } else {
+        EXIT_OK
+    }
+}
+
+/// A failure worth telling the user about, classified so that scripts can
+/// match on it. Displayed as "E_CODE: context"; the codes are stable, and are
+/// listed in the guide.
+#[derive(Debug, Clone)]
+crate enum OxyError {
+    /// The server couldn't be resolved, reached, or stayed reachable.
+    Connection(String),
+    /// The peer rejected our key, or we rejected theirs as a client key.
+    Auth(String),
+    /// The server's key didn't match the one configured for it.
+    HostKey(String),
+    /// A port forward couldn't be set up.
+    Forward(String),
+    /// A config file exists but couldn't be used.
+    Config(String),
+    /// The peer sent something oxy doesn't understand.
+    Protocol(String),
+}
+
+impl OxyError {
+    crate fn code(&self) -> &'static str {
+        match self {
+            OxyError::Connection(_) => "E_CONNECT",
+            OxyError::Auth(_) => "E_AUTH",
+            OxyError::HostKey(_) => "E_HOSTKEY",
+            OxyError::Forward(_) => "E_FORWARD",
+            OxyError::Config(_) => "E_CONFIG",
+            OxyError::Protocol(_) => "E_PROTOCOL",
+        }
+    }
+
+    crate fn exit_status(&self) -> i32 {
+        match self {
+            OxyError::Connection(_) => EXIT_CONNECTION_ERROR,
+            OxyError::Auth(_) | OxyError::HostKey(_) => EXIT_AUTH_REJECTED,
+            OxyError::Forward(_) | OxyError::Config(_) | OxyError::Protocol(_) => EXIT_FAILURE,
+        }
+    }
+
+    fn context(&self) -> &str {
+        match self {
+            OxyError::Connection(x)
+            | OxyError::Auth(x)
+            | OxyError::HostKey(x)
+            | OxyError::Forward(x)
+            | OxyError::Config(x)
+            | OxyError::Protocol(x) => x,
+        }
+    }
+}
+
+impl fmt::Display for OxyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.code(), self.context())
+    }
+}
+
+/// Report a fatal error as "oxy: E_CODE: context" on stderr, whatever the log
+/// level, then exit with the status for its class.
+crate fn fail(error: OxyError) -> ! {
+    eprintln!("oxy: {}", error);
+    if REMOTE_STATUS_EXPECTED.with(|x| x.get()) {
+        exit(EXIT_REMOTE_SESSION_FAILED);
+    }
+    exit(error.exit_status());
+}
+
+/// Like fail, for a server rejecting a client's credentials: exits with
+/// EXIT_LOGIN_REJECTED.
+crate fn reject_login(error: OxyError) -> ! {
+    eprintln!("oxy: {}", error);
+    exit(EXIT_LOGIN_REJECTED);
+}
 
 thread_local! {
     static EXIT_HOOKS: RefCell<Vec<Box<dyn Fn() -> ()>>> = RefCell::new(Vec::new());
+    static REMOTE_STATUS_EXPECTED: Cell<bool> = Cell::new(false);
 }
 
 crate fn exit(status: i32) -> ! {
@@ -0,0 +1,148 @@
+// Fan-out: `oxy client --hosts a,b,c command` runs the command on each host
+// by running an ordinary `oxy client` for each of them, at most --parallel at
+// a time, and sums up how they went.
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use std::{
+    process::{Child, Command},
+    thread::sleep,
+    time::Duration,
+};
+
+crate fn run() -> ! {
+    let matches = crate::arg::matches();
+    if matches.value_of("destination").is_none() {
+        crate::exit::fail(crate::exit::OxyError::Config("--hosts needs a command to run".to_string()));
+    }
+    if matches.value_of("command").is_some() {
+        crate::exit::fail(crate::exit::OxyError::Config("With --hosts, give only the command, not a destination".to_string()));
+    }
+    let hosts = hosts(matches.value_of("hosts").unwrap());
+    if hosts.is_empty() {
+        crate::exit::fail(crate::exit::OxyError::Config("--hosts lists no hosts".to_string()));
+    }
+    let parallel: usize = matches.value_of("parallel").unwrap().parse().unwrap();
+    let identity = indirect_identity();
+    let args = child_args(identity.is_some());
+    let exe = ::std::env::current_exe().unwrap();
+
+    let mut queue = hosts.into_iter();
+    let mut running: Vec<(String, Child)> = Vec::new();
+    let mut failures: Vec<(String, i32)> = Vec::new();
+    let mut successes = 0;
+    loop {
+        while running.len() < parallel {
+            let host = match queue.next() {
+                Some(host) => host,
+                None => break,
+            };
+            debug!("Running on {}", host);
+            let mut command = Command::new(&exe);
+            command.arg("client").arg(&host).args(&args);
+            if !matches.is_present("output prefix") {
+                command.arg("--output-prefix=%h: ");
+            }
+            if let Some(identity) = identity.as_ref() {
+                command.env("OXY_IDENTITY", identity);
+            }
+            match command.spawn() {
+                Ok(child) => running.push((host, child)),
+                Err(err) => {
+                    warn!("Failed to start a client for {}: {}", host, err);
+                    failures.push((host, crate::exit::EXIT_FAILURE));
+                }
+            }
+        }
+        if running.is_empty() {
+            break;
+        }
+        let mut i = 0;
+        while i < running.len() {
+            match running[i].1.try_wait() {
+                Ok(None) => i += 1,
+                Ok(Some(status)) => {
+                    let (host, _) = running.remove(i);
+                    match status.code() {
+                        Some(0) => successes += 1,
+                        Some(code) => failures.push((host, code)),
+                        None => failures.push((host, crate::exit::EXIT_FAILURE)),
+                    }
+                }
+                Err(err) => {
+                    let (host, _) = running.remove(i);
+                    warn!("Failed to wait for the client for {}: {}", host, err);
+                    failures.push((host, crate::exit::EXIT_FAILURE));
+                }
+            }
+        }
+        sleep(Duration::from_millis(50));
+    }
+
+    if failures.is_empty() {
+        eprintln!("{} succeeded", successes);
+        crate::exit::exit(crate::exit::EXIT_OK);
+    }
+    let failed: Vec<String> = failures.iter().map(|(host, code)| format!("{} (exit {})", host, code)).collect();
+    eprintln!("{} succeeded, {} failed: {}", successes, failures.len(), failed.join(", "));
+    crate::exit::exit(failures[0].1);
+}
+
+/// The hosts from --hosts: a comma-separated list, or @path for a file with
+/// one host per line, where blank lines and # comments are skipped.
+fn hosts(value: &str) -> Vec<String> {
+    if value.starts_with('@') {
+        let path = crate::conf::expand_home(&value[1..]).unwrap_or_else(|| value[1..].to_string());
+        let contents = ::std::fs::read_to_string(&path);
+        if let Err(err) = contents {
+            crate::exit::fail(crate::exit::OxyError::Config(format!("Failed to read hosts from {}: {}", path, err)));
+        }
+        return contents
+            .unwrap()
+            .lines()
+            .map(|x| x.split('#').next().unwrap().trim().to_string())
+            .filter(|x| !x.is_empty())
+            .collect();
+    }
+    value.split(',').map(|x| x.trim().to_string()).filter(|x| !x.is_empty()).collect()
+}
+
+/// The identity, however it was given, read once here and handed to the
+/// clients through their environment. One from stdin or a file isn't read
+/// again by each of them, and none shows up in their command lines.
+fn indirect_identity() -> Option<String> {
+    let matches = crate::arg::matches();
+    if matches.is_present("identity") || matches.is_present("identity from stdin") {
+        Some(crate::keys::identity_string())
+    } else {
+        None
+    }
+}
+
+/// Our own arguments after `client`, less the fan-out options, and less the
+/// identity options if the identity is passed on by indirect_identity. With
+/// the host inserted in front of them, the command ends up in the command
+/// position.
+fn child_args(strip_identity: bool) -> Vec<String> {
+    let mut args = ::std::env::args().skip_while(|x| x != "client").skip(1);
+    let mut result = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "--hosts" || arg == "--parallel" {
+            args.next();
+            continue;
+        }
+        if arg.starts_with("--hosts=") || arg.starts_with("--parallel=") {
+            continue;
+        }
+        if strip_identity {
+            if arg == "--identity" || arg == "-i" {
+                args.next();
+                continue;
+            }
+            if arg.starts_with("--identity=") || arg == "--identity-from-stdin" {
+                continue;
+            }
+        }
+        result.push(arg);
+    }
+    result
+}
@@ -1,6 +1,146 @@
+use std::{
+    collections::BTreeMap,
+    fs::OpenOptions,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
 use textwrap::{fill, termwidth};
+use toml::Value::{self, Array, Table};
 
 crate fn print_guide() -> ! {
+    if crate::arg::matches().is_present("setup") {
+        setup();
+    }
     println!("{}", fill(include_str!("guide.txt"), termwidth()));
     ::std::process::exit(0);
 }
+
+/// guide --setup: generate a server identity and a client identity that trust
+/// each other, write them out as a server.conf and a client.conf, and offer to
+/// test the connection.
+fn setup() -> ! {
+    let config_dir = crate::arg::matches().value_of("config dir").unwrap_or("~/.config/oxy");
+    let dir = crate::conf::expand_home(config_dir);
+    if dir.is_none() {
+        crate::exit::fail(crate::exit::OxyError::Config("Can't find your home directory".to_string()));
+    }
+    let dir = PathBuf::from(dir.unwrap());
+    let flag = if crate::arg::matches().occurrences_of("config dir") > 0 {
+        format!(" --config-dir={}", config_dir)
+    } else {
+        String::new()
+    };
+    println!("{}", fill("This sets up a server config and a matching client config for connecting to it.", termwidth()));
+    println!();
+    let name = ask("Name to connect to the server by", "myserver");
+    let host = ask("Server hostname or address", "localhost");
+    let mut port = ask("Server port", "2600");
+    while port.parse::<u16>().is_err() {
+        port = ask("Server port (1-65535)", "2600");
+    }
+    let user = crate::util::current_user_pw().map(|x| x.name).unwrap_or_else(|_| "root".to_string());
+    let setuser = ask("User to log in as on the server", &user);
+
+    let server_keys = crate::keys::generate_keys();
+    let client_keys = crate::keys::generate_keys();
+    let mut client = BTreeMap::new();
+    client.insert("name".to_string(), Value::String(setuser.clone()));
+    client.insert("pubkey".to_string(), Value::String(client_keys.pubkey.clone()));
+    client.insert("setuser".to_string(), Value::String(setuser));
+    let mut server_conf = BTreeMap::new();
+    server_conf.insert("privkey".to_string(), Value::String(server_keys.privkey.clone()));
+    server_conf.insert("knock".to_string(), Value::String(server_keys.knock.clone()));
+    server_conf.insert("psk".to_string(), Value::String(server_keys.psk.clone()));
+    server_conf.insert("clients".to_string(), Array(vec![Table(client)]));
+    let mut server = BTreeMap::new();
+    server.insert("name".to_string(), Value::String(name.clone()));
+    server.insert("host".to_string(), Value::String(host.clone()));
+    server.insert("port".to_string(), Value::String(port));
+    server.insert("pubkey".to_string(), Value::String(server_keys.pubkey.clone()));
+    server.insert("privkey".to_string(), Value::String(client_keys.privkey.clone()));
+    server.insert("knock".to_string(), Value::String(server_keys.knock.clone()));
+    server.insert("psk".to_string(), Value::String(server_keys.psk.clone()));
+    let mut client_conf = BTreeMap::new();
+    client_conf.insert("servers".to_string(), Array(vec![Table(server)]));
+
+    println!();
+    if let Err(err) = ::std::fs::create_dir_all(&dir) {
+        crate::exit::fail(crate::exit::OxyError::Config(format!("Failed to create {}: {}", dir.display(), err)));
+    }
+    let server_path = dir.join("server.conf");
+    let client_path = dir.join("client.conf");
+    for path in &[&server_path, &client_path] {
+        if path.exists() && !confirm(&format!("{} already exists. Overwrite it?", path.display()), false) {
+            println!("Leaving your existing configs alone.");
+            ::std::process::exit(1);
+        }
+    }
+    write_config(&server_path, &Table(server_conf));
+    write_config(&client_path, &Table(client_conf));
+
+    println!();
+    if !["localhost", "127.0.0.1", "::1"].contains(&host.as_str()) {
+        let message = format!(
+            "Copy server.conf to {} on {}, then run `oxy server{}` there. This machine connects with `oxy {}{}`.",
+            config_dir, host, flag, name, flag
+        );
+        println!("{}", fill(&message, termwidth()));
+    } else {
+        println!("{}", fill(&format!("Run `oxy server{}`, then connect with `oxy {}{}`.", flag, name, flag), termwidth()));
+    }
+    if confirm("Test the connection now? The server needs to be running already.", false) {
+        let status = ::std::env::current_exe().and_then(|exe| {
+            ::std::process::Command::new(exe)
+                .args(&["client", &name, "--test-connection"])
+                .arg(format!("--config-dir={}", config_dir))
+                .status()
+        });
+        match status {
+            Ok(status) if status.success() => println!("Connected to {} successfully.", name),
+            Ok(_) => println!("The connection test failed. Is `oxy server` running on {}?", host),
+            Err(err) => println!("Failed to run the connection test: {}", err),
+        }
+    }
+    ::std::process::exit(0);
+}
+
+/// Writes a config file, readable only by its owner since it holds private
+/// keys.
+fn write_config(path: &Path, config: &Value) {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let result = options.open(path).and_then(|mut file| file.write_all(::toml::to_string(config).unwrap().as_bytes()));
+    if let Err(err) = result {
+        crate::exit::fail(crate::exit::OxyError::Config(format!("Failed to write {}: {}", path.display(), err)));
+    }
+    println!("Wrote {}", path.display());
+}
+
+fn ask(question: &str, default: &str) -> String {
+    print!("{} [{}]: ", question, default);
+    io::stdout().flush().ok();
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).unwrap_or(0) == 0 {
+        println!();
+        ::std::process::exit(1);
+    }
+    let answer = answer.trim();
+    if answer.is_empty() {
+        return default.to_string();
+    }
+    answer.to_string()
+}
+
+fn confirm(question: &str, default: bool) -> bool {
+    let answer = ask(question, if default { "Y/n" } else { "y/N" });
+    match answer.to_lowercase().as_str() {
+        "y" | "yes" => true,
+        "n" | "no" => false,
+        _ => default,
+    }
+}
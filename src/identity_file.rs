@@ -0,0 +1,148 @@
+// --identity @path: reading the identity from a file, which may be encrypted
+// with a passphrase so that it's never at rest in the clear, and keygen
+// --encrypt-identity, which writes one.
+//
+// An encrypted file holds a header line, then a line of base32: a salt, a
+// nonce, and the identity sealed with AES-256-GCM, under a key derived from
+// the passphrase and the salt with PBKDF2. A file without the header holds
+// the identity in the clear, as --identity would take it.
+//
+// The passphrase comes from the variable --identity-passphrase-env names, or
+// is asked for on the controlling terminal. Without either, oxy fails instead
+// of waiting for input that will never come.
+use crate::{
+    exit::OxyError,
+    secret::{zeroize, SecretBytes},
+};
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use std::{
+    fs::OpenOptions,
+    io::{Read, Write},
+    os::unix::io::AsRawFd,
+};
+use transportation::ring::{
+    self,
+    aead::{open_in_place, seal_in_place, OpeningKey, SealingKey, AES_256_GCM},
+    rand::SecureRandom,
+};
+
+const HEADER: &str = "oxy encrypted identity v1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+/// The identity in the file at path, decrypted if need be.
+crate fn read(path: &str) -> Vec<u8> {
+    let path = &crate::conf::expand_home(path).unwrap_or_else(|| path.to_string());
+    let text = match ::std::fs::read(path) {
+        Ok(text) => SecretBytes::from(text),
+        Err(err) => crate::exit::fail(OxyError::Config(format!("Failed to read identity file {}: {}", path, err))),
+    };
+    let text = ::std::str::from_utf8(&text).unwrap_or("").trim();
+    if !text.starts_with(HEADER) {
+        return match ::data_encoding::BASE32_NOPAD.decode(text.as_bytes()) {
+            Ok(identity) => identity,
+            Err(_) => crate::exit::fail(OxyError::Config(format!("{} doesn't hold an identity", path))),
+        };
+    }
+    let sealed = match ::data_encoding::BASE32_NOPAD.decode(text[HEADER.len()..].trim().as_bytes()) {
+        Ok(sealed) if sealed.len() > SALT_LEN + NONCE_LEN + AES_256_GCM.tag_len() => sealed,
+        _ => crate::exit::fail(OxyError::Config(format!("{} is damaged", path))),
+    };
+    let (salt, rest) = sealed.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let passphrase = passphrase(&format!("Passphrase for {}: ", path));
+    let key = derive_key(&passphrase, salt);
+    let key = OpeningKey::new(&AES_256_GCM, &key).unwrap();
+    let mut buf = SecretBytes::from(ciphertext.to_vec());
+    match open_in_place(&key, nonce, HEADER.as_bytes(), 0, &mut buf) {
+        Ok(identity) => identity.to_vec(),
+        Err(_) => crate::exit::fail(OxyError::Config(format!("Wrong passphrase for {}", path))),
+    }
+}
+
+/// keygen --encrypt-identity: reads an identity from the first line of stdin,
+/// and prints it encrypted under a new passphrase.
+crate fn encrypt_identity() {
+    let identity = SecretBytes::from(crate::keys::stdin_identity_bytes());
+    let passphrase = passphrase("New passphrase: ");
+    if crate::arg::matches().value_of("identity passphrase env").is_none() && passphrase[..] != passphrase_from_tty("Again: ")[..] {
+        crate::exit::fail(OxyError::Config("The passphrases don't match".to_string()));
+    }
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    ::transportation::RNG.fill(&mut salt).unwrap();
+    ::transportation::RNG.fill(&mut nonce).unwrap();
+    let key = derive_key(&passphrase, &salt);
+    let key = SealingKey::new(&AES_256_GCM, &key).unwrap();
+    let tag_len = AES_256_GCM.tag_len();
+    let mut buf = SecretBytes::concat(&[&identity[..], &vec![0u8; tag_len][..]]);
+    let len = seal_in_place(&key, &nonce, HEADER.as_bytes(), &mut buf, tag_len).unwrap();
+    let sealed = [&salt[..], &nonce[..], &buf[..len]].concat();
+    println!("{}", HEADER);
+    println!("{}", ::data_encoding::BASE32_NOPAD.encode(&sealed));
+}
+
+fn derive_key(passphrase: &[u8], salt: &[u8]) -> SecretBytes {
+    let mut key = SecretBytes::from(vec![0u8; 32]);
+    ring::pbkdf2::derive(&ring::digest::SHA512, PBKDF2_ITERATIONS, salt, passphrase, &mut key);
+    key
+}
+
+/// The passphrase, from --identity-passphrase-env, or else the terminal. The
+/// variable is removed once read, so that commands oxy runs don't inherit it.
+fn passphrase(prompt: &str) -> SecretBytes {
+    if let Some(var) = crate::arg::matches().value_of("identity passphrase env") {
+        let passphrase = match ::std::env::var(var) {
+            Ok(passphrase) => SecretBytes::from(passphrase.into_bytes()),
+            Err(_) => crate::exit::fail(OxyError::Config(format!("--identity-passphrase-env names {}, which isn't set", var))),
+        };
+        ::std::env::remove_var(var);
+        return passphrase;
+    }
+    passphrase_from_tty(prompt)
+}
+
+/// Asks for a passphrase on the controlling terminal, without echoing it.
+fn passphrase_from_tty(prompt: &str) -> SecretBytes {
+    let tty = OpenOptions::new().read(true).write(true).open("/dev/tty");
+    let mut tty = match tty {
+        Ok(tty) => tty,
+        Err(_) => crate::exit::fail(OxyError::Config(
+            "The identity is encrypted and there's no terminal to ask for its passphrase on. Use --identity-passphrase-env."
+                .to_string(),
+        )),
+    };
+    let fd = tty.as_raw_fd();
+    let saved = unsafe {
+        let mut saved: ::libc::termios = ::std::mem::zeroed();
+        if ::libc::tcgetattr(fd, &mut saved) == 0 {
+            let mut quiet = saved;
+            quiet.c_lflag &= !::libc::ECHO;
+            ::libc::tcsetattr(fd, ::libc::TCSANOW, &quiet);
+            Some(saved)
+        } else {
+            None
+        }
+    };
+    tty.write_all(prompt.as_bytes()).ok();
+    // Sized up front so that the buffer never reallocates, leaving copies of
+    // the passphrase behind.
+    let mut line = Vec::with_capacity(1024);
+    let mut byte = [0u8];
+    while line.len() < line.capacity() {
+        match tty.read(&mut byte) {
+            Ok(1) if byte[0] != b'\n' => line.push(byte[0]),
+            _ => break,
+        }
+    }
+    zeroize(&mut byte);
+    if let Some(saved) = saved {
+        unsafe {
+            ::libc::tcsetattr(fd, ::libc::TCSANOW, &saved);
+        }
+    }
+    tty.write_all(b"\n").ok();
+    SecretBytes::from(line)
+}
@@ -0,0 +1,99 @@
+// `oxy keyscan host...` prints each server's public key as a known_hosts
+// line, like ssh-keyscan. Each host is scanned by an ordinary `oxy client
+// --scan-key`, which stops once the server's key arrives.
+//
+// Oxy servers only show their key to a client they accept, so the scanning
+// identity has to be authorized (or the server has to fall back to
+// interactive auth). The known_hosts side is what's filled in.
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use std::{
+    process::{Child, Command},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+crate fn run() -> ! {
+    let matches = crate::arg::matches();
+    let hosts: Vec<String> = matches.values_of("hosts").unwrap().map(|x| x.to_string()).collect();
+    let timeout = crate::util::parse_duration(matches.value_of("timeout").unwrap()).unwrap();
+    let args = child_args();
+    // Resolved here, so that an identity from stdin or an encrypted file is
+    // only read once for all the clients, and handed to them through their
+    // environment, so that it doesn't show up in ps.
+    let identity = if matches.is_present("identity") { Some(crate::keys::identity_string()) } else { None };
+    let exe = ::std::env::current_exe().unwrap();
+
+    let mut running: Vec<(String, Child)> = Vec::new();
+    let mut failures: Vec<String> = Vec::new();
+    for host in hosts {
+        debug!("Scanning {}", host);
+        let mut command = Command::new(&exe);
+        command.arg("client").arg(&host).arg("--scan-key").args(&args);
+        if let Some(identity) = identity.as_ref() {
+            command.env("OXY_IDENTITY", identity);
+        }
+        match command.spawn() {
+            Ok(child) => running.push((host, child)),
+            Err(err) => {
+                warn!("Failed to start a client for {}: {}", host, err);
+                failures.push(host);
+            }
+        }
+    }
+    let deadline = Instant::now() + timeout;
+    while !running.is_empty() {
+        let mut i = 0;
+        while i < running.len() {
+            match running[i].1.try_wait() {
+                Ok(None) if Instant::now() < deadline => i += 1,
+                Ok(None) => {
+                    let (host, mut child) = running.remove(i);
+                    warn!("{} didn't answer within {}", host, crate::util::format_duration(timeout));
+                    child.kill().ok();
+                    child.wait().ok();
+                    failures.push(host);
+                }
+                Ok(Some(status)) => {
+                    let (host, _) = running.remove(i);
+                    if !status.success() {
+                        failures.push(host);
+                    }
+                }
+                Err(err) => {
+                    let (host, _) = running.remove(i);
+                    warn!("Failed to wait for the client for {}: {}", host, err);
+                    failures.push(host);
+                }
+            }
+        }
+        sleep(Duration::from_millis(50));
+    }
+
+    if failures.is_empty() {
+        crate::exit::exit(crate::exit::EXIT_OK);
+    }
+    eprintln!("Failed to scan: {}", failures.join(", "));
+    crate::exit::exit(crate::exit::EXIT_FAILURE);
+}
+
+/// The options given to keyscan that the clients need too.
+fn child_args() -> Vec<String> {
+    let matches = crate::arg::matches();
+    let mut args = vec![format!("--handshake-timeout={}", matches.value_of("timeout").unwrap())];
+    let forwarded = &[
+        ("config dir", "config-dir"),
+        ("client config", "client-config"),
+        ("port", "port"),
+        ("log format", "log-format"),
+    ];
+    for (name, long) in forwarded {
+        if matches.occurrences_of(name) > 0 {
+            args.push(format!("--{}={}", long, matches.value_of(name).unwrap()));
+        }
+    }
+    for _ in 0..matches.occurrences_of("verbose") {
+        args.push("-v".to_string());
+    }
+    args
+}
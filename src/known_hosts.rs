@@ -0,0 +1,159 @@
+// The client's known_hosts file, which remembers the host keys of servers
+// that have no pubkey in the client config. Each line is a destination, as
+// given to oxy client, and that server's base32 public key, optionally
+// followed by when it was recorded, in seconds since the epoch.
+//
+// --write-known-hosts-on, or --strict-host-key-checking as ssh spells it,
+// decides when new entries are written: on first contact (trust on first use),
+// only after confirming the key at a prompt, or never, for a curated file that
+// is only read. At the prompt, a key that replaces a remembered one can be
+// accepted too. With --known-hosts=none there's no file at all, so anything
+// trusted is only trusted for the one connection.
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    time::{Duration, UNIX_EPOCH},
+};
+
+fn path() -> Option<String> {
+    let path = crate::arg::config_file("known hosts", "known_hosts")?;
+    if path == "none" {
+        return None;
+    }
+    crate::conf::expand_home(&path)
+}
+
+struct Entry {
+    destination: String,
+    key:         String,
+    recorded:    Option<u64>,
+}
+
+fn entries() -> Vec<Entry> {
+    let contents = path().and_then(|x| ::std::fs::read_to_string(x).ok()).unwrap_or_default();
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            Some(Entry {
+                destination: parts.next()?.to_string(),
+                key:         parts.next()?.to_string(),
+                recorded:    parts.next().and_then(|x| x.parse().ok()),
+            })
+        })
+        .collect()
+}
+
+/// The recorded key for a destination: Some(true) if it matches `key`,
+/// Some(false) if a different key was recorded, or None if there's no entry.
+crate fn check(destination: &str, key: &[u8]) -> Option<bool> {
+    let key = ::data_encoding::BASE32_NOPAD.encode(key);
+    let recorded: Vec<String> = entries().into_iter().filter(|x| x.destination == destination).map(|x| x.key).collect();
+    if recorded.is_empty() {
+        return None;
+    }
+    Some(recorded.contains(&key))
+}
+
+/// Decides whether to trust a destination's key that nothing vouches for
+/// yet, following --write-known-hosts-on, and records it if so.
+crate fn consider_unknown(destination: &str, key: &[u8]) -> bool {
+    let encoded = ::data_encoding::BASE32_NOPAD.encode(key);
+    let accept = match crate::arg::write_known_hosts_on() {
+        "first" => {
+            warn!("Trusting {}'s host key {} on first use", destination, encoded);
+            true
+        }
+        "verify" => confirm(destination, &encoded),
+        _ => false,
+    };
+    if accept {
+        record(destination, &encoded);
+    }
+    accept
+}
+
+/// Under --write-known-hosts-on=verify, shows what changed about a
+/// destination's key and asks whether to replace the remembered key with it.
+/// Otherwise a changed key is refused.
+crate fn consider_changed(destination: &str, key: &[u8]) -> bool {
+    if crate::arg::write_known_hosts_on() != "verify" {
+        return false;
+    }
+    if !::termion::is_tty(&io::stdin()) {
+        warn!("Not prompting to replace {}'s host key, since stdin isn't a terminal", destination);
+        return false;
+    }
+    eprintln!("WARNING: {}'s host key has changed. Someone may be impersonating the server.", destination);
+    let now = UNIX_EPOCH.elapsed().unwrap_or_default().as_secs();
+    for entry in entries().into_iter().filter(|x| x.destination == destination) {
+        let fingerprint = ::data_encoding::BASE32_NOPAD
+            .decode(entry.key.as_bytes())
+            .map(|x| crate::keys::key_fingerprint(&x))
+            .unwrap_or_else(|_| entry.key.clone());
+        let age = match entry.recorded {
+            Some(recorded) => format!(" (recorded {} ago)", crate::util::format_duration(Duration::from_secs(now.saturating_sub(recorded)))),
+            None => String::new(),
+        };
+        eprintln!("  remembered: {}{}", fingerprint, age);
+    }
+    eprintln!("  presented:  {}", crate::keys::key_fingerprint(key));
+    eprint!("Replace the remembered key with the presented one? Type \"yes\" to replace it: ");
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).ok();
+    if answer.trim() != "yes" {
+        return false;
+    }
+    forget(destination);
+    record(destination, &::data_encoding::BASE32_NOPAD.encode(key));
+    true
+}
+
+/// Removes a destination's entries, leaving the rest of the file as it was.
+fn forget(destination: &str) {
+    let path = match path() {
+        Some(path) => path,
+        None => return,
+    };
+    let contents = ::std::fs::read_to_string(&path).unwrap_or_default();
+    let kept: String = contents
+        .lines()
+        .filter(|x| x.split_whitespace().next() != Some(destination))
+        .map(|x| format!("{}\n", x))
+        .collect();
+    if let Err(err) = ::std::fs::write(&path, kept) {
+        warn!("Failed to remove {}'s old host key from {}: {}", destination, path, err);
+    }
+}
+
+fn confirm(destination: &str, encoded: &str) -> bool {
+    if !::termion::is_tty(&io::stdin()) {
+        warn!("Not prompting to trust {}'s host key, since stdin isn't a terminal", destination);
+        return false;
+    }
+    eprint!("{}'s host key is {}, which isn't in your known_hosts. Trust it? [y/N]: ", destination, encoded);
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).ok();
+    ["y", "yes"].contains(&answer.trim().to_lowercase().as_str())
+}
+
+fn record(destination: &str, encoded: &str) {
+    let path = path();
+    if path.is_none() {
+        return;
+    }
+    let path = path.unwrap();
+    let result = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&path)
+        .and_then(|mut file| writeln!(file, "{} {} {}", destination, encoded, UNIX_EPOCH.elapsed().unwrap_or_default().as_secs()));
+    match result {
+        Ok(()) => info!("Added {} to {}", destination, path),
+        Err(err) => warn!("Failed to record {}'s host key in {}: {}", destination, path, err),
+    }
+}
@@ -0,0 +1,43 @@
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use std::{
+    fs::{create_dir_all, File},
+    io::{Read, Write},
+    path::PathBuf,
+    time::{Duration, UNIX_EPOCH},
+};
+
+fn lastlog_path() -> Option<PathBuf> {
+    crate::conf::expand_home(&crate::arg::config_dir_file("lastlog")?).map(PathBuf::from)
+}
+
+/// Records a login for the given key fingerprint, returning how long ago the
+/// previous login for that fingerprint was. The file holds one
+/// "<fingerprint> <unix timestamp>" line per key.
+crate fn update(fingerprint: &str) -> Option<Duration> {
+    let path = lastlog_path()?;
+    let now = UNIX_EPOCH.elapsed().unwrap().as_secs();
+    let mut text = String::new();
+    if let Ok(mut file) = File::open(&path) {
+        file.read_to_string(&mut text).ok();
+    }
+    let mut previous = None;
+    let mut lines: Vec<String> = Vec::new();
+    for line in text.lines() {
+        let mut parts = line.split_whitespace();
+        if parts.next() == Some(fingerprint) {
+            previous = parts.next().and_then(|x| x.parse::<u64>().ok());
+            continue;
+        }
+        lines.push(line.to_string());
+    }
+    lines.push(format!("{} {}", fingerprint, now));
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).ok();
+    }
+    let result = File::create(&path).and_then(|mut file| file.write_all((lines.join("\n") + "\n").as_bytes()));
+    if result.is_err() {
+        warn!("Failed to update {:?}: {:?}", path, result);
+    }
+    previous.map(|x| Duration::from_secs(now.saturating_sub(x)))
+}
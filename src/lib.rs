@@ -1,22 +1,49 @@
 #![feature(rust_2018_preview)]
 
 mod arg;
+mod audit;
+mod authorized;
+mod bench;
 mod client;
 mod conf;
+mod config_url;
 mod copy;
 mod core;
+mod events;
 mod exit;
+mod fanout;
 mod guide;
+mod identity_file;
 mod keys;
+mod keyscan;
+mod known_hosts;
+mod lastlog;
 mod message;
 #[cfg(unix)]
+mod obfuscate;
+mod output_file;
+mod output_prefix;
+#[cfg(all(unix, feature = "pam"))]
+mod pam;
+mod pattern;
+mod protocol_trace;
+mod proxy_protocol;
+#[cfg(unix)]
 mod pty;
+mod record;
 mod reexec;
+#[cfg(unix)]
+mod roam;
+mod secret;
 mod server;
+mod session_env;
+mod tempfile;
 #[cfg(unix)]
 mod tuntap;
 mod ui;
 mod util;
+#[cfg(unix)]
+mod xforward;
 
 #[allow(unused_imports)]
 use log::{debug, error, info, log, trace, warn};
@@ -29,11 +56,17 @@ pub fn run() {
             std::process::exit(1);
         }
     }
+    secret::disable_core_dumps();
     debug!("Oxy starting");
     arg::process();
     debug!("Args processed");
-    conf::init();
+    // A connection may still pick a --virtual-server's config, which
+    // reexec::serve_fd loads once it knows.
+    if !arg::has_virtual_servers() || !["reexec", "serve-one"].contains(&arg::mode().as_str()) {
+        conf::init();
+    }
     debug!("Conf processed");
+    protocol_trace::start();
     match arg::mode().as_str() {
         "client" => client::run(),
         "reexec" => reexec::run(),
@@ -43,7 +76,11 @@ pub fn run() {
         "reverse-client" => client::reverse_client(),
         "guide" => guide::print_guide(),
         "copy" => copy::run(),
+        "bench" => bench::run(),
         "keygen" => keys::keygen(),
+        "fingerprint" => keys::fingerprint(),
+        "keyscan" => keyscan::run(),
+        "playback" => record::playback(),
         _ => unreachable!(),
     }
 }
@@ -49,4 +49,41 @@ pub enum OxyMessage {
     CompressionRequest { compression_type: u64 },
     CompressionStart { compression_type: u64 },
     EnvironmentAdvertisement { key: String, value: String},
+    HostKeyRotation { pubkey: Vec<u8> },
+    RemoteBindAllocated { reference: u64, port: u16 },
+    PipeCommandTerminate { reference: u64 },
+    SessionSuspend { },
+    SessionResume { },
+    AuthPrompt { prompt: String, echo: bool },
+    AuthResponse { response: String },
+    AuthResult { accepted: bool },
+    NoShellAdvertisement { },
+    FileSignatureRequest { path: String, block_size: u64 },
+    FileSignatureData { reference: u64, len: u64, digests: Vec<Vec<u8>> },
+    WhoamiRequest { },
+    WhoamiResult { reference: u64, user: String, uid: u32, client_name: Option<String> },
+    FollowRequest { path: String, offset_start: Option<u64> },
+    CompressionStop { },
+    VersionAdvertisement { version: String },
+    PtyPixelSizeAdvertisement { xpixel: u16, ypixel: u16 },
+    XForwardRequest { cookie: String },
+    XDisplayAllocated { reference: u64, display: u64 },
+    LoginShellAdvertisement { },
+    TunnelMtu { reference: u64, mtu: u64 },
+    ClearEnvironmentAdvertisement { },
+    PtyFlowControl { paused: bool },
+    RemoteShellAdvertisement { shell: String },
+    PtyLocalEcho { enabled: bool },
+    PtyModeChanged {},
+    WorkingDirectoryAdvertisement { path: String },
+    BindConnectionSource { reference: u64, source: String, destination: String },
+    Hangup { keep_running: bool },
+    ReadLinkRequest { path: String },
+    ReadLinkResult { reference: u64, target: String },
+    SymlinkRequest { path: String, target: String },
+    SetPermissionsRequest { path: String, octal_permissions: u16 },
+    RealPathRequest { path: String },
+    RealPathResult { reference: u64, path: String },
+    PipeCommandStatus { reference: u64, status: i32 },
+    CreateDirectoryRequest { path: String },
 }
@@ -0,0 +1,161 @@
+// --obfuscate: disguising oxy's traffic from filters that block it by its
+// fingerprint. The connection is wrapped below the usual transport, so what
+// crosses the network is bytes that look random from the first one on.
+//
+// xor: the side that dialed the connection opens it with a random nonce. After
+// that, each direction is XORed with a keystream of SHA-256 blocks over the
+// --obfuscate-secret, the nonce, the direction and a counter. This is only a
+// disguise: it hides the handshake's fixed bytes and framing from a passive
+// observer, while secrecy and authentication still come from oxy's own
+// encryption. Message sizes and timing are left as they are.
+//
+// Both ends must agree on the method and secret. The session is given one end
+// of a socketpair, and two threads relay between the other end and the real
+// connection, so the process must not fork without execing afterwards.
+use byteorder::{BigEndian, ByteOrder};
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    os::unix::{
+        io::{AsRawFd, FromRawFd, IntoRawFd, RawFd},
+        net::UnixStream,
+    },
+};
+use transportation::{
+    ring::{digest, rand::SecureRandom},
+    BufferedTransport,
+};
+
+const NONCE_LEN: usize = 16;
+/// Which way keystream bytes flow: from the side that dialed, or towards it.
+const FROM_DIALER: u8 = 1;
+const TO_DIALER: u8 = 2;
+
+struct Keystream {
+    seed:     Vec<u8>,
+    counter:  u64,
+    block:    Vec<u8>,
+    position: usize,
+}
+
+impl Keystream {
+    fn create(secret: &[u8], nonce: &[u8], direction: u8) -> Keystream {
+        let mut seed = secret.to_vec();
+        seed.extend_from_slice(nonce);
+        seed.push(direction);
+        Keystream {
+            seed,
+            counter:  0,
+            block:    Vec::new(),
+            position: 0,
+        }
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            if self.position == self.block.len() {
+                let mut input = self.seed.clone();
+                let mut counter = [0u8; 8];
+                BigEndian::write_u64(&mut counter, self.counter);
+                input.extend_from_slice(&counter);
+                self.block = digest::digest(&digest::SHA256, &input).as_ref().to_vec();
+                self.counter += 1;
+                self.position = 0;
+            }
+            *byte ^= self.block[self.position];
+            self.position += 1;
+        }
+    }
+}
+
+/// The transport for a session on this connection, wrapped if --obfuscate
+/// asks for it. dialer is whether this side opened the connection.
+crate fn transport<T: IntoRawFd + Into<BufferedTransport>>(stream: T, dialer: bool) -> BufferedTransport {
+    if crate::arg::obfuscate_secret().is_none() {
+        return stream.into();
+    }
+    BufferedTransport::from(wrap(stream.into_raw_fd(), dialer))
+}
+
+/// Like transport, for a connection that's only an fd. Returns the fd the
+/// session should use instead.
+crate fn wrap(fd: RawFd, dialer: bool) -> RawFd {
+    let secret = match crate::arg::obfuscate_secret() {
+        Some(secret) => secret.as_bytes().to_vec(),
+        None => return fd,
+    };
+    unsafe {
+        // The relay threads block on the connection, whatever the session's
+        // transport would have made of it.
+        let flags = ::libc::fcntl(fd, ::libc::F_GETFL);
+        ::libc::fcntl(fd, ::libc::F_SETFL, flags & !::libc::O_NONBLOCK);
+        ::libc::fcntl(fd, ::libc::F_SETFD, ::libc::FD_CLOEXEC);
+    }
+    let (session, relay) = match UnixStream::pair() {
+        Ok(pair) => pair,
+        Err(err) => crate::exit::fail(crate::exit::OxyError::Connection(format!("Failed to set up --obfuscate: {}", err))),
+    };
+    let mut network = unsafe { File::from_raw_fd(fd) };
+    let nonce = if dialer {
+        let mut nonce = [0u8; NONCE_LEN];
+        ::transportation::RNG.fill(&mut nonce).unwrap();
+        if let Err(err) = network.write_all(&nonce) {
+            crate::exit::fail(crate::exit::OxyError::Connection(format!("Failed to send the --obfuscate nonce: {}", err)));
+        }
+        Some(nonce.to_vec())
+    } else {
+        None
+    };
+    debug!("Obfuscating the connection");
+    ::std::thread::spawn(move || {
+        // The other side's nonce is read here, so a peer that never sends one
+        // only holds up this thread.
+        let nonce = match nonce {
+            Some(nonce) => nonce,
+            None => {
+                let mut nonce = vec![0u8; NONCE_LEN];
+                if let Err(err) = network.read_exact(&mut nonce) {
+                    debug!("Failed to read the --obfuscate nonce: {}", err);
+                    return;
+                }
+                nonce
+            }
+        };
+        let (outbound, inbound) = if dialer { (FROM_DIALER, TO_DIALER) } else { (TO_DIALER, FROM_DIALER) };
+        let outbound = Keystream::create(&secret, &nonce, outbound);
+        let inbound = Keystream::create(&secret, &nonce, inbound);
+        let (network2, relay2) = match (network.try_clone(), relay.try_clone()) {
+            (Ok(network2), Ok(relay2)) => (network2, relay2),
+            _ => {
+                warn!("Failed to set up --obfuscate relaying");
+                return;
+            }
+        };
+        ::std::thread::spawn(move || pump(relay2, network2, outbound));
+        pump(network, relay, inbound);
+    });
+    session.into_raw_fd()
+}
+
+/// Copies from one side to the other through the keystream until either
+/// gives out, then passes the end of input along.
+fn pump<R: Read, W: Write + AsRawFd>(mut from: R, mut to: W, mut keystream: Keystream) {
+    let mut buf = [0u8; 16384];
+    loop {
+        let len = match from.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+        keystream.apply(&mut buf[..len]);
+        if to.write_all(&buf[..len]).is_err() {
+            break;
+        }
+    }
+    unsafe {
+        ::libc::shutdown(to.as_raw_fd(), ::libc::SHUT_WR);
+    }
+}
@@ -0,0 +1,82 @@
+// --output-file: a copy of what a remote command or the pty writes, saved to
+// a local file while it's still shown on the terminal. --output-file-stderr
+// keeps the command's stderr in a file of its own; otherwise it goes to
+// --output-file along with stdout. The files are flushed and synced to disk
+// when oxy exits.
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use std::{
+    cell::RefCell,
+    fs::{File, OpenOptions},
+    io::Write,
+};
+
+thread_local! {
+    static FILES: RefCell<Option<Files>> = RefCell::new(None);
+}
+
+struct Files {
+    stdout: Option<File>,
+    stderr: Option<File>,
+}
+
+fn start() -> Files {
+    let matches = crate::arg::matches();
+    let files = Files {
+        stdout: matches.value_of("output file").map(open),
+        stderr: matches.value_of("output file stderr").map(open),
+    };
+    crate::exit::push_hook(finish);
+    files
+}
+
+fn open(path: &str) -> File {
+    let mut options = OpenOptions::new();
+    if crate::arg::matches().is_present("output file append") {
+        options.append(true).create(true);
+    } else {
+        options.write(true).create(true).truncate(true);
+    }
+    match options.open(path) {
+        Ok(file) => file,
+        Err(err) => crate::exit::fail(crate::exit::OxyError::Config(format!("Failed to open output file {}: {}", path, err))),
+    }
+}
+
+/// Saves a chunk of output, if --output-file or --output-file-stderr asks
+/// for it.
+crate fn write(stderr: bool, data: &[u8]) {
+    let matches = crate::arg::matches();
+    if !matches.is_present("output file") && !matches.is_present("output file stderr") {
+        return;
+    }
+    FILES.with(|x| {
+        let mut files = x.borrow_mut();
+        if files.is_none() {
+            *files = Some(start());
+        }
+        let files = files.as_mut().unwrap();
+        let file = match (stderr, files.stderr.is_some()) {
+            (true, true) => files.stderr.as_mut(),
+            _ => files.stdout.as_mut(),
+        };
+        if let Some(file) = file {
+            if let Err(err) = file.write_all(data) {
+                warn!("Failed to write to the output file: {}", err);
+            }
+        }
+    });
+}
+
+fn finish() {
+    FILES.with(|x| {
+        if let Some(files) = x.borrow_mut().as_mut() {
+            for file in files.stdout.iter_mut().chain(files.stderr.iter_mut()) {
+                file.flush().ok();
+                if let Err(err) = file.sync_all() {
+                    warn!("Failed to sync the output file: {}", err);
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,105 @@
+// --output-prefix: tag each line a remote command writes with the host and
+// channel it came from, so output from several commands can be told apart.
+// Only whole lines are tagged and written. A partial line waits for the rest
+// of it, or for its command to exit.
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::{self, Write},
+};
+
+thread_local! {
+    static PENDING: RefCell<HashMap<(u64, bool), Vec<u8>>> = RefCell::new(HashMap::new());
+}
+
+/// Write a command's output to our stdout, or stderr, tagging each line if
+/// --output-prefix was given.
+crate fn write(host: &str, channel: u64, stderr: bool, data: &[u8]) -> io::Result<()> {
+    let template = crate::arg::matches().value_of("output prefix");
+    if template.is_none() {
+        return output(stderr, data);
+    }
+    let lines = PENDING.with(|x| {
+        let mut pending = x.borrow_mut();
+        let buffer = pending.entry((channel, stderr)).or_insert_with(Vec::new);
+        buffer.extend_from_slice(data);
+        let complete = buffer.iter().rposition(|x| *x == b'\n').map(|x| x + 1).unwrap_or(0);
+        let rest = buffer.split_off(complete);
+        ::std::mem::replace(buffer, rest)
+    });
+    if lines.is_empty() {
+        return Ok(());
+    }
+    output(stderr, &tag(&expand(template.unwrap(), host, channel), &lines))
+}
+
+/// Write out what's left of a channel's partial lines, once its command has
+/// exited. The last line is ended so the next prefix starts a line.
+crate fn finish(host: &str, channel: u64) {
+    let template = crate::arg::matches().value_of("output prefix");
+    if template.is_none() {
+        return;
+    }
+    for stderr in &[false, true] {
+        let rest = PENDING.with(|x| x.borrow_mut().remove(&(channel, *stderr)));
+        if let Some(mut rest) = rest {
+            if rest.is_empty() {
+                continue;
+            }
+            rest.push(b'\n');
+            if let Err(err) = output(*stderr, &tag(&expand(template.unwrap(), host, channel), &rest)) {
+                warn!("Error writing command output: {:?}", err);
+            }
+        }
+    }
+}
+
+/// Substitute %h with the host, %c with the channel and %% with a %.
+fn expand(template: &str, host: &str, channel: u64) -> String {
+    let mut result = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('h') => result.push_str(host),
+            Some('c') => result.push_str(&channel.to_string()),
+            Some('%') => result.push('%'),
+            Some(other) => {
+                result.push('%');
+                result.push(other);
+            }
+            None => result.push('%'),
+        }
+    }
+    result
+}
+
+/// Prefix each of some newline-terminated lines.
+fn tag(prefix: &str, lines: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(lines.len());
+    for line in lines[..lines.len() - 1].split(|x| *x == b'\n') {
+        result.extend_from_slice(prefix.as_bytes());
+        result.extend_from_slice(line);
+        result.push(b'\n');
+    }
+    result
+}
+
+fn output(stderr: bool, data: &[u8]) -> io::Result<()> {
+    if stderr {
+        let stderr = io::stderr();
+        let mut lock = stderr.lock();
+        lock.write_all(data)?;
+        lock.flush()
+    } else {
+        let stdout = io::stdout();
+        let mut lock = stdout.lock();
+        lock.write_all(data)?;
+        lock.flush()
+    }
+}
@@ -0,0 +1,207 @@
+// PAM authentication for --auth pam.
+//
+// pam_authenticate blocks and asks its questions through a conversation
+// callback, so it runs on a helper thread. Each question is handed to the main
+// thread, which relays it to the client as an AuthPrompt, and the thread waits
+// for the matching AuthResponse. A byte written to a socket pair wakes the main
+// thread's event loop whenever there is something to relay.
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use libc::{c_char, c_int, c_void};
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString},
+    io::Write,
+    os::unix::{io::IntoRawFd, net::UnixStream},
+    ptr,
+    rc::Rc,
+    sync::mpsc::{channel, Receiver, Sender},
+};
+use transportation::{BufferedTransport, Notifies};
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_BUF_ERR: c_int = 5;
+const PAM_CONV_ERR: c_int = 19;
+const PAM_PROMPT_ECHO_OFF: c_int = 1;
+const PAM_PROMPT_ECHO_ON: c_int = 2;
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg:       *const c_char,
+}
+
+#[repr(C)]
+struct PamResponse {
+    resp:         *mut c_char,
+    #[allow(dead_code)]
+    resp_retcode: c_int,
+}
+
+#[repr(C)]
+struct PamConv {
+    conv:        extern "C" fn(c_int, *mut *const PamMessage, *mut *mut PamResponse, *mut c_void) -> c_int,
+    appdata_ptr: *mut c_void,
+}
+
+enum PamHandle {}
+
+#[link(name = "pam")]
+extern "C" {
+    fn pam_start(service: *const c_char, user: *const c_char, conv: *const PamConv, handle: *mut *mut PamHandle) -> c_int;
+    fn pam_authenticate(handle: *mut PamHandle, flags: c_int) -> c_int;
+    fn pam_acct_mgmt(handle: *mut PamHandle, flags: c_int) -> c_int;
+    fn pam_end(handle: *mut PamHandle, status: c_int) -> c_int;
+}
+
+/// Something PAM needs relayed to the client, or the outcome of the attempt.
+crate enum Event {
+    Prompt { prompt: String, echo: bool },
+    Info(String),
+    Done(bool),
+}
+
+thread_local! {
+    static SESSION: RefCell<Option<Session>> = RefCell::new(None);
+}
+
+struct Session {
+    events:    Receiver<Event>,
+    responses: Sender<String>,
+    wake:      BufferedTransport,
+}
+
+/// The helper thread's end of an attempt.
+struct Conversation {
+    events:    Sender<Event>,
+    responses: Receiver<String>,
+    wake:      UnixStream,
+}
+
+impl Conversation {
+    fn send(&self, event: Event) {
+        self.events.send(event).ok();
+        (&self.wake).write_all(&[0]).ok();
+    }
+}
+
+/// Start authenticating `user` against the PAM `service`. `handler` is called
+/// on the main thread with each event, ending with a Done.
+crate fn start(service: &str, user: &str, handler: Rc<dyn Fn(Event)>) {
+    let (event_sender, event_receiver) = channel();
+    let (response_sender, response_receiver) = channel();
+    let (wake, wake_remote) = UnixStream::pair().unwrap();
+    let wake = BufferedTransport::from(wake.into_raw_fd());
+    wake.set_notify(Rc::new(move || notify(&handler)));
+    SESSION.with(|x| {
+        *x.borrow_mut() = Some(Session {
+            events:    event_receiver,
+            responses: response_sender,
+            wake,
+        })
+    });
+    let service = service.to_string();
+    let user = user.to_string();
+    ::std::thread::spawn(move || {
+        let conversation = Conversation {
+            events:    event_sender,
+            responses: response_receiver,
+            wake:      wake_remote,
+        };
+        let accepted = authenticate(&service, &user, &conversation);
+        conversation.send(Event::Done(accepted));
+    });
+}
+
+/// Answer the question from the last Prompt event.
+crate fn respond(response: String) {
+    SESSION.with(|x| {
+        if let Some(session) = x.borrow().as_ref() {
+            session.responses.send(response).ok();
+        }
+    });
+}
+
+fn notify(handler: &Rc<dyn Fn(Event)>) {
+    let events: Vec<Event> = SESSION.with(|x| {
+        let session = x.borrow();
+        match session.as_ref() {
+            Some(session) => {
+                session.wake.take();
+                session.events.try_iter().collect()
+            }
+            None => Vec::new(),
+        }
+    });
+    for event in events {
+        if let Event::Done(_) = event {
+            if let Some(session) = SESSION.with(|x| x.borrow_mut().take()) {
+                session.wake.close();
+            }
+        }
+        handler(event);
+    }
+}
+
+fn authenticate(service: &str, user: &str, conversation: &Conversation) -> bool {
+    let service = CString::new(service).unwrap();
+    let user = CString::new(user).unwrap();
+    let conv = PamConv {
+        conv:        converse,
+        appdata_ptr: conversation as *const Conversation as *mut c_void,
+    };
+    let mut handle = ptr::null_mut();
+    let status = unsafe { pam_start(service.as_ptr(), user.as_ptr(), &conv, &mut handle) };
+    if status != PAM_SUCCESS {
+        error!("Failed to start PAM for service {:?}: {}", service, status);
+        return false;
+    }
+    let mut status = unsafe { pam_authenticate(handle, 0) };
+    if status == PAM_SUCCESS {
+        status = unsafe { pam_acct_mgmt(handle, 0) };
+    }
+    debug!("PAM finished with status {}", status);
+    unsafe { pam_end(handle, status) };
+    status == PAM_SUCCESS
+}
+
+extern "C" fn converse(count: c_int, messages: *mut *const PamMessage, responses: *mut *mut PamResponse, appdata: *mut c_void) -> c_int {
+    let conversation = unsafe { &*(appdata as *const Conversation) };
+    let replies = unsafe { ::libc::calloc(count as usize, ::std::mem::size_of::<PamResponse>()) as *mut PamResponse };
+    if replies.is_null() {
+        return PAM_BUF_ERR;
+    }
+    for i in 0..count as isize {
+        let message = unsafe { &**messages.offset(i) };
+        let text = unsafe { CStr::from_ptr(message.msg) }.to_string_lossy().into_owned();
+        let echo = match message.msg_style {
+            PAM_PROMPT_ECHO_ON => true,
+            PAM_PROMPT_ECHO_OFF => false,
+            _ => {
+                conversation.send(Event::Info(text));
+                continue;
+            }
+        };
+        conversation.send(Event::Prompt { prompt: text, echo });
+        let response = conversation.responses.recv().ok().and_then(|x| CString::new(x).ok());
+        if response.is_none() {
+            free_replies(replies, i);
+            return PAM_CONV_ERR;
+        }
+        let mut response = response.unwrap().into_bytes_with_nul();
+        unsafe { (*replies.offset(i)).resp = ::libc::strdup(response.as_ptr() as *const c_char) };
+        crate::secret::zeroize(&mut response);
+    }
+    unsafe { *responses = replies };
+    PAM_SUCCESS
+}
+
+fn free_replies(replies: *mut PamResponse, count: isize) {
+    for i in 0..count {
+        let reply = unsafe { (*replies.offset(i)).resp };
+        if !reply.is_null() {
+            unsafe { ::libc::free(reply as *mut c_void) };
+        }
+    }
+    unsafe { ::libc::free(replies as *mut c_void) };
+}
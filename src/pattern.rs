@@ -0,0 +1,246 @@
+// Pattern matching shared by the allowlist options (--permit-open,
+// --accept-env, and friends), so that they all agree on what a pattern means.
+//
+// Names are matched with shell-style globs: `*` matches any run of
+// characters, including none, and `?` matches exactly one character.
+// Addresses may additionally be given in CIDR notation, such as `10.0.0.0/8`
+// or `fd00::/8`.
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
+
+crate fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|x| *x == '*')
+}
+
+crate fn glob_match_any<'a, T: IntoIterator<Item = &'a str>>(patterns: T, text: &str) -> bool {
+    patterns.into_iter().any(|x| glob_match(x, text))
+}
+
+/// Parses `addr/prefix`. Returns None for anything that isn't CIDR notation.
+fn parse_cidr(pattern: &str) -> Option<(IpAddr, u32)> {
+    let mut parts = pattern.splitn(2, '/');
+    let addr: IpAddr = parts.next()?.parse().ok()?;
+    let prefix: u32 = parts.next()?.parse().ok()?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    if prefix > max {
+        return None;
+    }
+    Some((addr, prefix))
+}
+
+/// An IPv4-mapped IPv6 address (::ffff:a.b.c.d) as the IPv4 address it
+/// stands for, as seen on dual-stack sockets. Other addresses are left alone.
+fn unmap(addr: IpAddr) -> IpAddr {
+    if let IpAddr::V6(v6) = addr {
+        let segments = v6.segments();
+        if segments[..6] == [0, 0, 0, 0, 0, 0xffff] {
+            let octets = v6.octets();
+            return IpAddr::V4(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]));
+        }
+    }
+    addr
+}
+
+fn cidr_contains(network: IpAddr, prefix: u32, addr: IpAddr) -> bool {
+    let addr = if network.is_ipv4() { unmap(addr) } else { addr };
+    match (network, addr) {
+        (IpAddr::V4(network), IpAddr::V4(addr)) => {
+            let mask = if prefix == 0 { 0 } else { !0u32 << (32 - prefix) };
+            (u32::from(network) & mask) == (u32::from(addr) & mask)
+        }
+        (IpAddr::V6(network), IpAddr::V6(addr)) => {
+            let mask = if prefix == 0 { 0 } else { !0u128 << (128 - prefix) };
+            (u128::from(network) & mask) == (u128::from(addr) & mask)
+        }
+        _ => false,
+    }
+}
+
+/// Matches an address against a CIDR network or a glob of its text form.
+crate fn address_match(pattern: &str, addr: IpAddr) -> bool {
+    match parse_cidr(pattern) {
+        Some((network, prefix)) => cidr_contains(network, prefix, addr),
+        None => glob_match(pattern, &addr.to_string()) || glob_match(pattern, &unmap(addr).to_string()),
+    }
+}
+
+/// Splits `host:port`, `[v6addr]:port` or a bare host. CIDR patterns keep
+/// their slash in the host part.
+fn split_host_port(spec: &str) -> (&str, &str) {
+    if spec.starts_with('[') {
+        if let Some(end) = spec.find(']') {
+            let host = &spec[1..end];
+            let port = spec[end + 1..].trim_left_matches(':');
+            return (host, if port.is_empty() { "*" } else { port });
+        }
+    }
+    match spec.rfind(':') {
+        Some(idx) if spec[..idx].find(':').is_none() => (&spec[..idx], &spec[idx + 1..]),
+        _ => (spec, "*"),
+    }
+}
+
+/// Matches a forwarding destination against a `host:port` pattern. Socket
+/// paths (destinations starting with `/`) are globbed against the whole
+/// pattern. A CIDR host only matches a destination host that is an address
+/// in that network, or a name that resolves entirely within it.
+crate fn destination_match(pattern: &str, destination: &str) -> bool {
+    if destination.starts_with('/') {
+        return glob_match(pattern, destination);
+    }
+    let (pattern_host, pattern_port) = split_host_port(pattern);
+    let (host, port) = split_host_port(destination);
+    if !glob_match(pattern_port, port) {
+        return false;
+    }
+    if let Some((network, prefix)) = parse_cidr(pattern_host) {
+        if let Ok(addr) = host.parse::<IpAddr>() {
+            return cidr_contains(network, prefix, addr);
+        }
+        let resolved: Vec<IpAddr> = match (host, 0).to_socket_addrs() {
+            Ok(addrs) => addrs.map(|x| x.ip()).collect(),
+            Err(_) => return false,
+        };
+        return !resolved.is_empty() && resolved.into_iter().all(|x| cidr_contains(network, prefix, x));
+    }
+    glob_match(pattern_host, host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("", ""));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("LC_*", "LC_ALL"));
+        assert!(!glob_match("LC_*", "LANG"));
+        assert!(glob_match("?", "a"));
+        assert!(!glob_match("?", ""));
+        assert!(!glob_match("?", "ab"));
+        assert!(glob_match("a?c", "abc"));
+        assert!(!glob_match("a?c", "ac"));
+        assert!(glob_match("exact", "exact"));
+        assert!(!glob_match("exact", "exactly"));
+        assert!(!glob_match("exactly", "exact"));
+    }
+
+    #[test]
+    fn glob_backtracking() {
+        // The first * has to give back what it swallowed for the rest to match.
+        assert!(glob_match("*.example.com", "a.b.example.com"));
+        assert!(glob_match("*a*b", "xaxxaxb"));
+        assert!(!glob_match("*a*b", "xaxxax"));
+        assert!(glob_match("*?*", "x"));
+        assert!(glob_match("a*b*c", "abbbc"));
+        assert!(glob_match("a*b*c", "abcbc"));
+        assert!(!glob_match("a*b*c", "abcb"));
+        assert!(glob_match("**", "x"));
+        assert!(glob_match("*ab", "aab"));
+        assert!(glob_match("*aab", "aaab"));
+    }
+
+    #[test]
+    fn cidr_parsing() {
+        assert_eq!(parse_cidr("10.0.0.0/8"), Some((addr("10.0.0.0"), 8)));
+        assert_eq!(parse_cidr("0.0.0.0/0"), Some((addr("0.0.0.0"), 0)));
+        assert_eq!(parse_cidr("10.1.2.3/32"), Some((addr("10.1.2.3"), 32)));
+        assert_eq!(parse_cidr("fd00::/8"), Some((addr("fd00::"), 8)));
+        assert_eq!(parse_cidr("::1/128"), Some((addr("::1"), 128)));
+        assert_eq!(parse_cidr("10.0.0.0/33"), None);
+        assert_eq!(parse_cidr("fd00::/129"), None);
+        assert_eq!(parse_cidr("10.0.0.0/"), None);
+        assert_eq!(parse_cidr("10.0.0.0/x"), None);
+        assert_eq!(parse_cidr("10.0.0.0/-1"), None);
+        assert_eq!(parse_cidr("10.0.0.0"), None);
+        assert_eq!(parse_cidr("example.com/8"), None);
+    }
+
+    #[test]
+    fn cidr_v4() {
+        assert!(cidr_contains(addr("10.0.0.0"), 8, addr("10.255.1.2")));
+        assert!(!cidr_contains(addr("10.0.0.0"), 8, addr("11.0.0.1")));
+        assert!(cidr_contains(addr("0.0.0.0"), 0, addr("203.0.113.9")));
+        assert!(cidr_contains(addr("192.0.2.7"), 32, addr("192.0.2.7")));
+        assert!(!cidr_contains(addr("192.0.2.7"), 32, addr("192.0.2.8")));
+        assert!(!cidr_contains(addr("0.0.0.0"), 0, addr("::1")));
+    }
+
+    #[test]
+    fn cidr_v6() {
+        assert!(cidr_contains(addr("fd00::"), 8, addr("fd12:3456::1")));
+        assert!(!cidr_contains(addr("fd00::"), 8, addr("fe80::1")));
+        assert!(cidr_contains(addr("::"), 0, addr("2001:db8::1")));
+        assert!(cidr_contains(addr("2001:db8::1"), 128, addr("2001:db8::1")));
+        assert!(!cidr_contains(addr("2001:db8::1"), 128, addr("2001:db8::2")));
+        assert!(!cidr_contains(addr("::"), 0, addr("10.0.0.1")));
+    }
+
+    #[test]
+    fn address_v4_mapped() {
+        assert!(address_match("10.0.0.0/8", addr("::ffff:10.1.2.3")));
+        assert!(!address_match("10.0.0.0/8", addr("::ffff:11.1.2.3")));
+        assert!(address_match("192.0.2.1/32", addr("::ffff:192.0.2.1")));
+        assert!(address_match("::ffff:0:0/96", addr("::ffff:10.1.2.3")));
+        assert!(address_match("10.*", addr("::ffff:10.1.2.3")));
+        assert!(address_match("10.*", addr("10.1.2.3")));
+        assert!(!address_match("10.0.0.0/8", addr("fd00::10:1:2:3")));
+    }
+
+    #[test]
+    fn host_port_splitting() {
+        assert_eq!(split_host_port("example.com:22"), ("example.com", "22"));
+        assert_eq!(split_host_port("example.com"), ("example.com", "*"));
+        assert_eq!(split_host_port("10.0.0.0/8:80"), ("10.0.0.0/8", "80"));
+        assert_eq!(split_host_port("[::1]:22"), ("::1", "22"));
+        assert_eq!(split_host_port("[::1]"), ("::1", "*"));
+        assert_eq!(split_host_port("[fd00::/8]:*"), ("fd00::/8", "*"));
+        assert_eq!(split_host_port("::1"), ("::1", "*"));
+    }
+
+    #[test]
+    fn destinations() {
+        assert!(destination_match("localhost:80", "localhost:80"));
+        assert!(!destination_match("localhost:80", "localhost:81"));
+        assert!(destination_match("*:80", "example.com:80"));
+        assert!(destination_match("localhost", "localhost:8080"));
+        assert!(destination_match("10.0.0.0/8:*", "10.1.2.3:443"));
+        assert!(!destination_match("10.0.0.0/8:*", "192.0.2.1:443"));
+        assert!(destination_match("[::1]:22", "[::1]:22"));
+        assert!(!destination_match("[::1]:22", "[::1]:23"));
+        assert!(destination_match("[fd00::/8]:*", "[fd00::1]:22"));
+        assert!(!destination_match("[fd00::/8]:*", "[fe80::1]:22"));
+    }
+
+    #[test]
+    fn socket_paths() {
+        assert!(destination_match("/run/user/*/bus", "/run/user/1000/bus"));
+        assert!(!destination_match("/run/user/*/bus", "/run/user/1000/other"));
+        assert!(!destination_match("localhost:*", "/run/socket"));
+        assert!(destination_match("/tmp/*", "/tmp/a:b"));
+    }
+}
@@ -0,0 +1,82 @@
+// --protocol-trace: a dump of every protocol message this end sends and
+// receives, after decryption and decompression, for attaching to protocol bug
+// reports. Each line is the time, the process, the direction (> sent, <
+// received), the message number, the message type, and the message's CBOR
+// encoding in hex.
+use crate::message::OxyMessage;
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use std::{
+    cell::RefCell,
+    fs::{File, OpenOptions},
+    io::Write,
+    time::UNIX_EPOCH,
+};
+
+thread_local! {
+    static TRACE: RefCell<Option<File>> = RefCell::new(None);
+}
+
+/// Open the trace file, if --protocol-trace was given. It's appended to, so
+/// that a server's sessions can share one.
+crate fn start() {
+    let path = crate::arg::matches().value_of("protocol trace");
+    if path.is_none() {
+        return;
+    }
+    let path = path.unwrap();
+    let mut options = OpenOptions::new();
+    options.append(true).create(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let file = options.open(path);
+    if let Err(err) = file {
+        error!("Failed to open protocol trace {:?}: {:?}", path, err);
+        crate::exit::exit(crate::exit::EXIT_FAILURE);
+    }
+    warn!(
+        "Tracing protocol messages to {:?}. The trace holds everything sent over the connection, including keystrokes, passwords \
+         typed at prompts, and file contents, so review it before sharing it.",
+        path
+    );
+    TRACE.with(|x| *x.borrow_mut() = Some(file.unwrap()));
+}
+
+crate fn observe_outbound(message_number: u64, message: &OxyMessage) {
+    record('>', message_number, message);
+}
+
+crate fn observe_inbound(message_number: u64, message: &OxyMessage) {
+    record('<', message_number, message);
+}
+
+/// Note a message that couldn't be decoded, which is usually a sign of a
+/// protocol mismatch.
+crate fn observe_undecodable(message_number: u64) {
+    write_line(&format!("< {} ?", message_number));
+}
+
+fn record(direction: char, message_number: u64, message: &OxyMessage) {
+    if !TRACE.with(|x| x.borrow().is_some()) {
+        return;
+    }
+    let debug = format!("{:?}", message);
+    let name = debug.split(|x: char| !x.is_alphanumeric()).next().unwrap_or("");
+    let encoded = ::serde_cbor::ser::to_vec_packed(message).unwrap_or_default();
+    write_line(&format!("{} {} {} {}", direction, message_number, name, ::data_encoding::HEXLOWER.encode(&encoded)));
+}
+
+fn write_line(line: &str) {
+    let elapsed = UNIX_EPOCH.elapsed().unwrap();
+    let line = format!("{}.{:06} {} {}\n", elapsed.as_secs(), elapsed.subsec_micros(), ::std::process::id(), line);
+    TRACE.with(|x| {
+        if let Some(file) = x.borrow_mut().as_mut() {
+            if let Err(err) = file.write_all(line.as_bytes()) {
+                warn!("Failed to write to the protocol trace: {:?}", err);
+            }
+        }
+    });
+}
@@ -0,0 +1,80 @@
+// PROXY protocol headers (haproxy's proxy-protocol.txt), which tell a backend
+// behind a forward where the connection really came from. Version 1 is a line
+// of text, version 2 a binary header. Connections without an IP address on
+// both ends, like those through unix sockets, get a header that says so.
+use std::net::SocketAddr;
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\x00\r\nQUIT\n";
+
+/// The header for a connection from `source` to `destination`.
+crate fn header(version: u8, source: Option<SocketAddr>, destination: Option<SocketAddr>) -> Vec<u8> {
+    match version {
+        1 => v1(source, destination),
+        _ => v2(source, destination),
+    }
+}
+
+fn v1(source: Option<SocketAddr>, destination: Option<SocketAddr>) -> Vec<u8> {
+    let line = match (source, destination) {
+        (Some(SocketAddr::V4(source)), Some(SocketAddr::V4(destination))) => format!(
+            "PROXY TCP4 {} {} {} {}\r\n",
+            source.ip(),
+            destination.ip(),
+            source.port(),
+            destination.port()
+        ),
+        (Some(SocketAddr::V6(source)), Some(SocketAddr::V6(destination))) => format!(
+            "PROXY TCP6 {} {} {} {}\r\n",
+            source.ip(),
+            destination.ip(),
+            source.port(),
+            destination.port()
+        ),
+        _ => "PROXY UNKNOWN\r\n".to_string(),
+    };
+    line.into_bytes()
+}
+
+fn v2(source: Option<SocketAddr>, destination: Option<SocketAddr>) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    let mut addresses = Vec::new();
+    // The version in the high nibble, then PROXY (1) or LOCAL (0). Then the
+    // address family and protocol: TCP over IPv4 (0x11) or IPv6 (0x21).
+    let (command, family) = match (source, destination) {
+        (Some(SocketAddr::V4(source)), Some(SocketAddr::V4(destination))) => {
+            addresses.extend_from_slice(&source.ip().octets());
+            addresses.extend_from_slice(&destination.ip().octets());
+            push_u16(&mut addresses, source.port());
+            push_u16(&mut addresses, destination.port());
+            (0x21, 0x11)
+        }
+        (Some(SocketAddr::V6(source)), Some(SocketAddr::V6(destination))) => {
+            addresses.extend_from_slice(&source.ip().octets());
+            addresses.extend_from_slice(&destination.ip().octets());
+            push_u16(&mut addresses, source.port());
+            push_u16(&mut addresses, destination.port());
+            (0x21, 0x21)
+        }
+        _ => (0x20, 0x00),
+    };
+    header.push(command);
+    header.push(family);
+    push_u16(&mut header, addresses.len() as u16);
+    header.extend(addresses);
+    header
+}
+
+/// Ports and the address length are big-endian.
+fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.push((value >> 8) as u8);
+    buf.push(value as u8);
+}
+
+/// The version a proxy-protocol option names.
+crate fn parse_version(value: &str) -> Option<u8> {
+    match value {
+        "v1" => Some(1),
+        "v2" => Some(2),
+        _ => None,
+    }
+}
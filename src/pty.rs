@@ -1,10 +1,10 @@
-use libc::{ioctl, winsize, TIOCSCTTY, TIOCSWINSZ};
+use libc::{ioctl, tcgetattr, tcsetattr, termios, winsize, ECHO, TCSANOW, TIOCGWINSZ, TIOCSCTTY, TIOCSWINSZ};
 #[allow(unused_imports)]
 use log::{debug, error, info, log, trace, warn};
 use nix::{
     pty::openpty,
     unistd::{
-        close, dup2, execv, fork, setsid,
+        close, dup2, execv, execvp, fork, setsid,
         ForkResult::{Child, Parent},
         Pid,
     },
@@ -16,31 +16,38 @@ crate struct Pty {
     crate underlying: BufferedTransport,
     crate fd:         RawFd,
     crate child_pid:  Pid,
+    /// While the client echoes input itself: the settings it left the
+    /// terminal with, and whether the terminal echoed before.
+    local_echo:       Option<(termios, bool)>,
 }
 
 impl Pty {
-    crate fn forkpty(command: Option<&str>) -> Result<Pty, ()> {
+    /// Run a program in a new pty: argv if given, looked up in PATH, or
+    /// otherwise a login shell, the given one or else the user's.
+    crate fn forkpty(command: Option<Vec<String>>, shell: Option<String>) -> Result<Pty, ()> {
         let result = openpty(None, None).map_err(|_| ())?;
         let parent_fd = result.master;
         let child_fd = result.slave;
         debug!("openpty results: {:?} {:?}", parent_fd, child_fd);
 
         let exe;
-        let argv;
-        if command.is_some() {
-            let sh = CString::new("/bin/sh").unwrap();
-            let sh2 = CString::new("/bin/sh").unwrap();
-            let minus_c = CString::new("-c").unwrap();
-            let command = CString::new(command.unwrap()).unwrap();
-            exe = sh;
-            argv = vec![sh2, minus_c, command];
+        let argv: Vec<CString>;
+        let search_path = command.is_some();
+        if let Some(command) = command {
+            argv = command.into_iter().map(CString::new).collect::<Result<_, _>>().map_err(|_| ())?;
+            exe = argv[0].clone();
         } else {
-            let shell = crate::util::current_user_pw();
-            if shell.is_err() {
-                error!("Failed to get user shell.");
-                ::std::process::exit(1);
-            }
-            let shell = shell.unwrap().shell;
+            let shell = match shell {
+                Some(shell) => shell,
+                None => {
+                    let shell = crate::util::current_user_pw();
+                    if shell.is_err() {
+                        error!("Failed to get user shell.");
+                        ::std::process::exit(1);
+                    }
+                    shell.unwrap().shell
+                }
+            };
             let shell_fname = PathBuf::from(&shell).file_name().unwrap().to_str().unwrap().to_string();
             exe = CString::new(shell).unwrap();
             argv = vec![CString::new(format!("-{}", shell_fname)).unwrap()]; // A leading - makes it a login shell. Seems like a strange convention to
@@ -75,6 +82,7 @@ impl Pty {
                     underlying: bt,
                     fd:         parent_fd,
                     child_pid:  child,
+                    local_echo: None,
                 })
             }
             Ok(Child) => {
@@ -84,12 +92,17 @@ impl Pty {
                 dup2(child_fd, 1).unwrap();
                 dup2(child_fd, 2).unwrap();
                 close(child_fd).unwrap();
+                let keep = crate::arg::keep_fds();
                 for i in &pids {
-                    if *i > 2 {
+                    if *i > 2 && !keep.contains(i) {
                         close(*i).ok();
                     }
                 }
-                execv(&exe, &argv[..]).expect("execv failed");
+                if search_path {
+                    execvp(&exe, &argv[..]).expect("execvp failed");
+                } else {
+                    execv(&exe, &argv[..]).expect("execv failed");
+                }
                 unreachable!();
             }
             Err(_) => panic!("Fork failed"),
@@ -109,6 +122,61 @@ impl Pty {
             .unwrap_or(cwd)
     }
 
+    /// Turns the terminal's echo off while the client echoes input itself.
+    crate fn start_local_echo(&mut self) {
+        if self.local_echo.is_some() {
+            return;
+        }
+        if let Some(mut settings) = self.settings() {
+            let echoed = settings.c_lflag & ECHO != 0;
+            settings.c_lflag &= !ECHO;
+            self.set_settings(&settings);
+            self.local_echo = Some((settings, echoed));
+        }
+    }
+
+    /// Gives echo back to the terminal, unless the program in it has changed
+    /// the settings since.
+    crate fn stop_local_echo(&mut self) {
+        if let Some((installed, echoed)) = self.local_echo.take() {
+            if let Some(mut settings) = self.settings() {
+                if echoed && same_mode(&settings, &installed) {
+                    settings.c_lflag |= ECHO;
+                    self.set_settings(&settings);
+                }
+            }
+        }
+    }
+
+    /// Whether the program in the terminal has changed its settings while the
+    /// client echoes locally, which ends local echo.
+    crate fn local_echo_overridden(&mut self) -> bool {
+        let changed = match (&self.local_echo, self.settings()) {
+            (Some((installed, _)), Some(settings)) => !same_mode(installed, &settings),
+            _ => false,
+        };
+        if changed {
+            self.local_echo = None;
+        }
+        changed
+    }
+
+    fn settings(&self) -> Option<termios> {
+        unsafe {
+            let mut settings: termios = ::std::mem::zeroed();
+            if tcgetattr(self.fd, &mut settings) != 0 {
+                return None;
+            }
+            Some(settings)
+        }
+    }
+
+    fn set_settings(&self, settings: &termios) {
+        unsafe {
+            tcsetattr(self.fd, TCSANOW, settings);
+        }
+    }
+
     crate fn set_size(&self, w: u16, h: u16) {
         let size = winsize {
             ws_row:    h,
@@ -121,4 +189,22 @@ impl Pty {
             ioctl(self.fd, TIOCSWINSZ, &size as *const _);
         }
     }
+
+    /// Set the size of the terminal in pixels, keeping its rows and columns.
+    crate fn set_pixel_size(&self, xpixel: u16, ypixel: u16) {
+        let mut size = winsize {
+            ws_row:    0,
+            ws_col:    0,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        unsafe {
+            if ioctl(self.fd, TIOCGWINSZ, &mut size as *mut _) != 0 {
+                return;
+            }
+            size.ws_xpixel = xpixel;
+            size.ws_ypixel = ypixel;
+            ioctl(self.fd, TIOCSWINSZ, &size as *const _);
+        }
+    }
 }
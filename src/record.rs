@@ -0,0 +1,133 @@
+// Session recording for --record, and the playback subcommand.
+//
+// Recordings use the asciicast v2 format, so they can also be played with
+// asciinema. The first line is a JSON header object, and every following line
+// is a JSON array of [seconds since the start, "o", output]:
+//
+//     {"version": 2, "width": 80, "height": 24, "timestamp": 1530000000}
+//     [0.25, "o", "user@host:~$ "]
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use serde_json::{self, json};
+use std::{
+    cell::RefCell,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+thread_local! {
+    static RECORDING: RefCell<Option<Recording>> = RefCell::new(None);
+}
+
+struct Recording {
+    file:    File,
+    start:   Instant,
+    pending: Vec<u8>,
+}
+
+fn start() -> Option<Recording> {
+    let path = crate::arg::matches().value_of("record")?;
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let file = options.open(path);
+    if file.is_err() {
+        error!("Failed to open recording {:?}: {:?}", path, file);
+        crate::exit::exit(crate::exit::EXIT_FAILURE);
+    }
+    let mut file = file.unwrap();
+    #[cfg(unix)]
+    let (width, height) = ::termion::terminal_size().unwrap_or((80, 24));
+    #[cfg(not(unix))]
+    let (width, height) = (80, 24);
+    let header = json!({
+        "version": 2,
+        "width": width,
+        "height": height,
+        "timestamp": UNIX_EPOCH.elapsed().unwrap().as_secs(),
+    });
+    writeln!(file, "{}", header).ok();
+    Some(Recording {
+        file,
+        start: Instant::now(),
+        pending: Vec::new(),
+    })
+}
+
+/// Record a chunk of pty output, if --record was given.
+crate fn pty_output(data: &[u8]) {
+    if !crate::arg::matches().is_present("record") {
+        return;
+    }
+    RECORDING.with(|x| {
+        let mut recording = x.borrow_mut();
+        if recording.is_none() {
+            *recording = start();
+        }
+        let recording = recording.as_mut().unwrap();
+        recording.pending.extend_from_slice(data);
+        // A multi-byte character may be split across chunks, so hold back an
+        // incomplete sequence at the end until the rest of it arrives.
+        let valid = match ::std::str::from_utf8(&recording.pending) {
+            Ok(_) => recording.pending.len(),
+            Err(err) => match err.error_len() {
+                None => err.valid_up_to(),
+                Some(_) => recording.pending.len(),
+            },
+        };
+        if valid == 0 {
+            return;
+        }
+        let text: Vec<u8> = recording.pending.drain(..valid).collect();
+        let text = String::from_utf8_lossy(&text);
+        let elapsed = recording.start.elapsed();
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1_000_000_000.0;
+        let event = json!([elapsed, "o", text]);
+        if let Err(err) = writeln!(recording.file, "{}", event) {
+            warn!("Failed writing to recording: {:?}", err);
+        }
+    });
+}
+
+crate fn playback() -> ! {
+    let path = crate::arg::matches().value_of("file").unwrap();
+    let speed: f64 = crate::arg::matches().value_of("speed").unwrap().parse().unwrap();
+    let file = File::open(path);
+    if file.is_err() {
+        error!("Failed to open recording {:?}: {:?}", path, file);
+        crate::exit::exit(crate::exit::EXIT_FAILURE);
+    }
+    let mut lines = BufReader::new(file.unwrap()).lines();
+    let header: Option<serde_json::Value> = lines.next().and_then(|x| x.ok()).and_then(|x| serde_json::from_str(&x).ok());
+    if header.as_ref().and_then(|x| x["version"].as_u64()) != Some(2) {
+        error!("{:?} is not an asciicast v2 recording", path);
+        crate::exit::exit(crate::exit::EXIT_FAILURE);
+    }
+    let stdout = ::std::io::stdout();
+    let mut stdout = stdout.lock();
+    let mut previous = 0.0;
+    for line in lines {
+        let line = line.unwrap_or_default();
+        let event: Result<(f64, String, String), _> = serde_json::from_str(&line);
+        if event.is_err() {
+            continue;
+        }
+        let (time, kind, data) = event.unwrap();
+        if kind != "o" {
+            continue;
+        }
+        if time > previous {
+            let delay = (time - previous) / speed;
+            ::std::thread::sleep(Duration::new(delay as u64, (delay.fract() * 1_000_000_000.0) as u32));
+            previous = time;
+        }
+        stdout.write_all(data.as_bytes()).ok();
+        stdout.flush().ok();
+    }
+    crate::exit::exit(crate::exit::EXIT_OK);
+}
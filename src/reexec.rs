@@ -12,16 +12,106 @@ lazy_static! {
 crate fn run() {
     #[cfg(unix)]
     {
-        use std::os::unix::io::RawFd;
         let fd = crate::arg::matches().value_of("fd").unwrap().parse().unwrap();
         debug!("Reexec using fd {}", fd);
-        let bt = <BufferedTransport as From<RawFd>>::from(fd);
-        Oxy::run(bt);
+        serve_fd(fd);
     }
     #[cfg(windows)]
     unimplemented!();
 }
 
+/// Service a single connection on an already-connected socket.
+#[cfg(unix)]
+crate fn serve_fd(fd: ::std::os::unix::io::RawFd) -> ! {
+    use std::os::unix::io::RawFd;
+    crate::util::check_keep_fds();
+    crate::util::apply_tcp_user_timeout(fd);
+    crate::util::apply_priority(fd);
+    if let Some(notify_fd) = auth_notify_fd() {
+        use nix::fcntl::{fcntl, FcntlArg::F_SETFD, FdFlag};
+        // Keep it away from anything run before authentication finishes.
+        fcntl(notify_fd, F_SETFD(FdFlag::FD_CLOEXEC)).ok();
+    }
+    // Past here, under --obfuscate, fd is the socketpair rather than the
+    // connection itself.
+    let source = ::nix::sys::socket::getpeername(fd).ok().map(|x| x.to_str());
+    let fd = crate::obfuscate::wrap(fd, false);
+    if crate::arg::has_virtual_servers() {
+        select_virtual_server(fd);
+    }
+    crate::conf::init();
+    crate::audit::start(source.clone());
+    crate::authorized::start(source.clone());
+    crate::session_env::start(source);
+    let bt = <BufferedTransport as From<RawFd>>::from(fd);
+    Oxy::run(bt);
+}
+
+/// Handshake version indicator of the message a client sends ahead of its key
+/// to pick a --virtual-server.
+crate const SERVER_NAME_VERSION: u8 = 3;
+
+/// Reads the --server-name a client sends ahead of its key, if it sent one,
+/// and switches to that virtual server's config. Anything else is left
+/// unread for the handshake.
+#[cfg(unix)]
+fn select_virtual_server(fd: ::std::os::unix::io::RawFd) {
+    use std::{
+        io::Read,
+        net::TcpStream,
+        os::unix::io::{FromRawFd, IntoRawFd},
+        time::{Duration, Instant},
+    };
+    use crate::exit::{fail, OxyError};
+    use byteorder::{ByteOrder, BE};
+    let mut stream = unsafe { TcpStream::from_raw_fd(fd) };
+    let deadline = Instant::now() + Duration::from_secs(30);
+    stream.set_read_timeout(Some(Duration::from_secs(30))).ok();
+    // The message length and the version indicator.
+    let mut header = [0u8; 3];
+    loop {
+        match stream.peek(&mut header) {
+            Ok(0) => fail(OxyError::Auth("The peer hung up during the handshake".to_string())),
+            Ok(3) => break,
+            Ok(_) if Instant::now() < deadline => ::std::thread::sleep(Duration::from_millis(10)),
+            Ok(_) | Err(_) => fail(OxyError::Auth("The peer didn't start the handshake".to_string())),
+        }
+    }
+    if header[2] == SERVER_NAME_VERSION {
+        // The length covers the version indicator, so it's at least 1.
+        let length = BE::read_u16(&header[..2]) as usize;
+        if length < 1 {
+            fail(OxyError::Protocol("The client sent an empty server name message".to_string()));
+        }
+        let mut message = vec![0u8; 2 + length];
+        if let Err(err) = stream.read_exact(&mut message) {
+            fail(OxyError::Auth(format!("Failed to read the client's server name: {}", err)));
+        }
+        let name = String::from_utf8_lossy(&message[3..]).to_string();
+        if let Err(err) = crate::conf::select_virtual_server(&name) {
+            warn!("{}", err);
+            fail(OxyError::Config(err));
+        }
+    }
+    stream.set_read_timeout(None).ok();
+    stream.into_raw_fd();
+}
+
+#[cfg(unix)]
+fn auth_notify_fd() -> Option<::std::os::unix::io::RawFd> {
+    crate::arg::matches().value_of("auth notify fd").and_then(|x| x.parse().ok())
+}
+
+/// Tells the listening server this connection has authenticated, so it no
+/// longer counts against --max-startups.
+#[cfg(unix)]
+crate fn notify_authenticated() {
+    if let Some(fd) = auth_notify_fd() {
+        ::nix::unistd::write(fd, &[1]).ok();
+        ::nix::unistd::close(fd).ok();
+    }
+}
+
 #[cfg(unix)]
 crate fn is_suid() -> bool {
     let uid = ::nix::unistd::getuid();
@@ -29,14 +119,50 @@ crate fn is_suid() -> bool {
     euid != uid
 }
 
+/// The binary that's run for each connection: --reexec-path, or else the
+/// running executable. The path is used as given, so if it's a symlink, each
+/// new connection runs whatever it points to at the time.
+fn reexec_path() -> ::std::path::PathBuf {
+    match crate::arg::matches().value_of("reexec path") {
+        Some(path) => ::std::path::PathBuf::from(path),
+        None => CURRENT_EXE.clone(),
+    }
+}
+
 crate fn safety_check() {
     if crate::arg::mode() == "server" {
+        check_reexec_path();
         safety_check_hard();
     }
 }
 
+/// Fail at startup, rather than on the first connection, if --reexec-path
+/// can't be run.
+fn check_reexec_path() {
+    let path = crate::arg::matches().value_of("reexec path");
+    if path.is_none() {
+        return;
+    }
+    let path = path.unwrap();
+    let metadata = ::std::fs::metadata(path);
+    if let Err(err) = metadata {
+        crate::exit::fail(crate::exit::OxyError::Config(format!("Can't use --reexec-path {:?}: {}", path, err)));
+    }
+    let metadata = metadata.unwrap();
+    #[cfg(unix)]
+    let executable = {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o111 != 0
+    };
+    #[cfg(not(unix))]
+    let executable = true;
+    if !metadata.is_file() || !executable {
+        crate::exit::fail(crate::exit::OxyError::Config(format!("--reexec-path {:?} isn't an executable file", path)));
+    }
+}
+
 crate fn safety_check_hard() {
-    let path = CURRENT_EXE.clone();
+    let path = reexec_path();
     if crate::arg::matches().is_present("unsafe reexec") {
         warn!("Using --unsafe-reexec");
         return;
@@ -47,7 +173,10 @@ crate fn safety_check_hard() {
     }
 }
 
-crate fn reexec(args: &[&str]) {
+/// Runs oxy again with the given arguments in a child process, returning the
+/// child's pid. env is added to the child's environment, for secrets that
+/// mustn't show up in its command line.
+crate fn reexec(args: &[&str], env: &[(&str, String)]) -> Option<::nix::unistd::Pid> {
     // SECURITYWATCH: We shouldn't reexec if another non-root user has write
     // permission on our binary or any parent folder. This is an out-and-out vuln
     // if somebody puts oxy in /tmp or something. It should be fine as long as
@@ -60,21 +189,29 @@ crate fn reexec(args: &[&str]) {
             panic!("Reexec when running suid is potentially unsafe - not implemented yet.");
         }
     }
-    let path = CURRENT_EXE.clone();
+    let path = reexec_path();
     #[cfg(unix)]
     {
-        use nix::unistd::{execv, fork, ForkResult::*};
-        use std::ffi::CString;
+        use nix::unistd::{execve, fork, ForkResult::*};
+        use std::{ffi::CString, os::unix::ffi::OsStrExt};
 
         let mut args2: Vec<CString> = args.iter().map(|x| CString::new(x.as_bytes()).unwrap()).collect();
+        // Built before forking, so that the child does nothing but exec.
+        let mut env2: Vec<CString> = ::std::env::vars_os()
+            .filter(|(key, _)| !env.iter().any(|(name, _)| key.as_bytes() == name.as_bytes()))
+            .filter_map(|(key, value)| CString::new([key.as_bytes(), &b"="[..], value.as_bytes()].concat()).ok())
+            .collect();
+        for (name, value) in env {
+            env2.push(CString::new(format!("{}={}", name, value)).unwrap());
+        }
         let path = CString::new(path.as_os_str().to_str().unwrap().as_bytes()).unwrap();
         args2.insert(0, path.clone());
         match fork() {
-            Ok(Parent { .. }) => {
-                return;
+            Ok(Parent { child }) => {
+                return Some(child);
             }
             Ok(Child) => {
-                execv(&path, &args2).unwrap();
+                execve(&path, &args2, &env2).unwrap();
                 unreachable!();
             }
             Err(_) => {
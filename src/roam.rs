@@ -0,0 +1,184 @@
+// --roam: reconnecting a forward-only client when the local network changes.
+//
+// The session runs in a child process, while the parent watches the address
+// the client reaches the server from. On Linux it wakes up on routing
+// notifications; elsewhere it checks every few seconds. When that address
+// changes, as when moving from Wi-Fi to LTE, the connection is dead even if
+// TCP hasn't noticed yet, so the parent ends the session and starts a new one
+// without waiting for a timeout. A session that ends on its own is started
+// again too. The new session sets the forwards up afresh; connections that
+// were open through them are lost.
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use nix::{
+    sys::{
+        signal::{kill, Signal},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::{fork, ForkResult, Pid},
+};
+use std::{
+    net::{IpAddr, SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+/// How often to check the address without routing notifications, and to look
+/// for the session having ended.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+#[cfg(not(target_os = "linux"))]
+const CHECKS_PER_POLL: u32 = 5;
+
+/// Returns in a child process that should run the session as usual. The
+/// parent process never returns.
+crate fn supervise() {
+    let destinations = crate::conf::locate_destination(&crate::arg::destination());
+    if destinations.is_empty() {
+        return;
+    }
+    let watcher = Watcher::create();
+    let mut delay = crate::arg::retry_delay();
+    loop {
+        let source = route_source(&destinations);
+        debug!("Roaming from {:?}", source);
+        let child = match fork() {
+            Ok(ForkResult::Child) => return,
+            Ok(ForkResult::Parent { child }) => child,
+            Err(err) => {
+                warn!("Failed to fork for --roam, continuing without it: {}", err);
+                return;
+            }
+        };
+        loop {
+            if session_ended(child) {
+                info!("Session ended. Reconnecting in {}.", crate::util::format_duration(delay));
+                ::std::thread::sleep(delay);
+                delay = (delay * 2).min(crate::arg::retry_max_delay());
+                break;
+            }
+            if !watcher.wait() {
+                continue;
+            }
+            let current = route_source(&destinations);
+            if current != source {
+                info!("The local address changed from {:?} to {:?}. Reconnecting.", source, current);
+                kill(child, Signal::SIGTERM).ok();
+                while !session_ended(child) {
+                    ::std::thread::sleep(Duration::from_millis(10));
+                }
+                delay = crate::arg::retry_delay();
+                break;
+            }
+        }
+    }
+}
+
+fn session_ended(child: Pid) -> bool {
+    match waitpid(child, Some(WaitPidFlag::WNOHANG)) {
+        Ok(WaitStatus::StillAlive) => false,
+        Ok(WaitStatus::Exited(..)) | Ok(WaitStatus::Signaled(..)) => true,
+        Ok(_) => false,
+        Err(::nix::Error::Sys(::nix::errno::Errno::EINTR)) => false,
+        Err(_) => true,
+    }
+}
+
+/// The local address the kernel would send to the server from, found by
+/// connecting a UDP socket, which sends nothing.
+fn route_source(destinations: &[SocketAddr]) -> Option<IpAddr> {
+    for destination in destinations {
+        let bind = if destination.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = match UdpSocket::bind(bind) {
+            Ok(socket) => socket,
+            Err(_) => continue,
+        };
+        if socket.connect(destination).is_ok() {
+            if let Ok(addr) = socket.local_addr() {
+                return Some(addr.ip());
+            }
+        }
+    }
+    None
+}
+
+/// Waits for something that might have changed the local address.
+#[cfg(target_os = "linux")]
+struct Watcher {
+    fd: Option<::std::os::unix::io::RawFd>,
+}
+
+#[cfg(target_os = "linux")]
+impl Watcher {
+    fn create() -> Watcher {
+        const RTMGRP_LINK: u32 = 0x1;
+        const RTMGRP_IPV4_IFADDR: u32 = 0x10;
+        const RTMGRP_IPV4_ROUTE: u32 = 0x40;
+        const RTMGRP_IPV6_IFADDR: u32 = 0x100;
+        const RTMGRP_IPV6_ROUTE: u32 = 0x400;
+        let fd = unsafe { ::libc::socket(::libc::AF_NETLINK, ::libc::SOCK_RAW | ::libc::SOCK_CLOEXEC, ::libc::NETLINK_ROUTE) };
+        if fd < 0 {
+            warn!("Failed to watch for routing changes: {}", ::std::io::Error::last_os_error());
+            return Watcher { fd: None };
+        }
+        let mut addr: ::libc::sockaddr_nl = unsafe { ::std::mem::zeroed() };
+        addr.nl_family = ::libc::AF_NETLINK as ::libc::sa_family_t;
+        addr.nl_groups = RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV4_ROUTE | RTMGRP_IPV6_IFADDR | RTMGRP_IPV6_ROUTE;
+        let result = unsafe {
+            ::libc::bind(
+                fd,
+                &addr as *const ::libc::sockaddr_nl as *const ::libc::sockaddr,
+                ::std::mem::size_of::<::libc::sockaddr_nl>() as ::libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            warn!("Failed to watch for routing changes: {}", ::std::io::Error::last_os_error());
+            unsafe { ::libc::close(fd) };
+            return Watcher { fd: None };
+        }
+        Watcher { fd: Some(fd) }
+    }
+
+    /// Whether a routing notification arrived within CHECK_INTERVAL. Without
+    /// a netlink socket, every interval counts.
+    fn wait(&self) -> bool {
+        let fd = match self.fd {
+            Some(fd) => fd,
+            None => {
+                ::std::thread::sleep(CHECK_INTERVAL);
+                return true;
+            }
+        };
+        let mut pollfd = ::libc::pollfd {
+            fd,
+            events:  ::libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = CHECK_INTERVAL.as_secs() as i32 * 1000;
+        if unsafe { ::libc::poll(&mut pollfd, 1, timeout) } <= 0 {
+            return false;
+        }
+        // The notifications themselves don't matter, only that there were some.
+        let mut buf = [0u8; 8192];
+        while unsafe { ::libc::recv(fd, buf.as_mut_ptr() as *mut ::libc::c_void, buf.len(), ::libc::MSG_DONTWAIT) } > 0 {}
+        true
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct Watcher {
+    checks: ::std::cell::Cell<u32>,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Watcher {
+    fn create() -> Watcher {
+        Watcher {
+            checks: ::std::cell::Cell::new(0),
+        }
+    }
+
+    fn wait(&self) -> bool {
+        ::std::thread::sleep(CHECK_INTERVAL);
+        self.checks.set((self.checks.get() + 1) % CHECKS_PER_POLL);
+        self.checks.get() == 0
+    }
+}
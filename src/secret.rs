@@ -0,0 +1,87 @@
+// Handling for key material: a byte buffer that scrubs itself when dropped,
+// and keeping secrets out of core dumps.
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+};
+
+/// Bytes that are overwritten with zeroes when dropped. The buffer never
+/// grows, since reallocating would leave a copy behind in freed memory.
+crate struct SecretBytes(Vec<u8>);
+
+impl SecretBytes {
+    crate fn concat(parts: &[&[u8]]) -> SecretBytes {
+        let mut bytes = Vec::with_capacity(parts.iter().map(|x| x.len()).sum());
+        for part in parts {
+            bytes.extend_from_slice(part);
+        }
+        SecretBytes(bytes)
+    }
+}
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(bytes: Vec<u8>) -> SecretBytes {
+        SecretBytes(bytes)
+    }
+}
+
+impl Deref for SecretBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl DerefMut for SecretBytes {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        zeroize(&mut self.0);
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretBytes(<{} bytes>)", self.0.len())
+    }
+}
+
+/// Overwrite sensitive bytes, in a way the optimizer won't elide.
+crate fn zeroize(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        unsafe { ::std::ptr::write_volatile(byte, 0) };
+    }
+}
+
+/// Keep this process's memory, which holds the identity and session keys, out
+/// of core dumps. On Linux this marks the process undumpable, which is reset by
+/// exec, so commands run from a session can still dump core.
+#[cfg(target_os = "linux")]
+crate fn disable_core_dumps() {
+    if unsafe { ::libc::prctl(::libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } != 0 {
+        warn!("Failed to disable core dumps: {:?}", ::std::io::Error::last_os_error());
+    }
+}
+
+/// Keep this process's memory, which holds the identity and session keys, out
+/// of core dumps. Elsewhere the core size limit is set to zero, which commands
+/// run from a session inherit.
+#[cfg(all(unix, not(target_os = "linux")))]
+crate fn disable_core_dumps() {
+    let mut limit = ::libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    unsafe { ::libc::getrlimit(::libc::RLIMIT_CORE, &mut limit) };
+    limit.rlim_cur = 0;
+    if unsafe { ::libc::setrlimit(::libc::RLIMIT_CORE, &limit) } != 0 {
+        warn!("Failed to disable core dumps: {:?}", ::std::io::Error::last_os_error());
+    }
+}
+
+#[cfg(not(unix))]
+crate fn disable_core_dumps() {}
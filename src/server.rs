@@ -1,14 +1,17 @@
-use crate::{core::Oxy, reexec::reexec};
+use crate::{core::Oxy, exit::EXIT_LOGIN_REJECTED, reexec::reexec, util::format_duration};
 #[allow(unused_imports)]
 use log::{debug, error, info, log, trace, warn};
 use nix::{
-    errno::Errno::ECHILD,
+    errno::Errno::{EAGAIN, ECHILD},
     sys::wait::{waitpid, WaitPidFlag, WaitStatus},
+    unistd::Pid,
     Error::Sys,
 };
 use std::{
     cell::RefCell,
-    net::IpAddr,
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    os::unix::io::RawFd,
     rc::Rc,
     time::{Duration, Instant},
 };
@@ -20,7 +23,12 @@ use transportation::{
     },
 };
 
+/// How long a source address is refused after --max-auth-tries failures. It's
+/// also how long failures are remembered for.
+const AUTH_LOCKOUT: Duration = Duration::from_secs(600);
+
 crate fn run() -> ! {
+    crate::util::check_keep_fds();
     Server::create();
     transportation::run();
 }
@@ -32,13 +40,17 @@ struct Server {
 
 #[derive(Default)]
 struct ServerInternal {
-    knock_listener:    RefCell<Option<UdpSocket>>,
-    knock_token:       RefCell<usize>,
+    knock_listeners:   RefCell<Vec<(UdpSocket, usize)>>,
+    knock_progress:    RefCell<HashMap<IpAddr, (usize, Instant)>>,
     tcp_listener:      RefCell<Option<TcpListener>>,
     tcp_token:         RefCell<usize>,
     open_knocks:       RefCell<Vec<(Instant, IpAddr)>>,
     sweeper_scheduled: RefCell<bool>,
     serve_one:         RefCell<bool>,
+    children:          RefCell<HashMap<Pid, IpAddr>>,
+    startups:          RefCell<HashMap<Pid, RawFd>>,
+    auth_failures:     RefCell<HashMap<IpAddr, (u32, Instant)>>,
+    seen_knocks:       RefCell<HashMap<(usize, Vec<u8>), Instant>>,
 }
 
 impl Server {
@@ -54,26 +66,42 @@ impl Server {
 
     fn init(&self) {
         crate::reexec::safety_check();
-        let knock_port = crate::keys::knock_port(None);
-        info!("Listening for knocks on port UDP {}", knock_port);
-        let bind_addr = format!("[::]:{}", knock_port).parse().unwrap();
-        let mut knock_listener = UdpSocket::bind(&bind_addr);
-        if knock_listener.is_err() {
-            let bind_addr = format!("0.0.0.0:{}", knock_port).parse().unwrap();
-            knock_listener = UdpSocket::bind(&bind_addr);
+        if crate::arg::matches().is_present("su mode") {
+            if !::nix::unistd::getuid().is_root() {
+                crate::exit::fail(crate::exit::OxyError::Config("--su-mode needs the server to run as root".to_string()));
+            }
+            if crate::arg::auth_method() != "key" {
+                // PAM would check whoever the server is running as, which is still root at that point.
+                crate::exit::fail(crate::exit::OxyError::Config("--su-mode only works with --auth key".to_string()));
+            }
+        }
+        if crate::arg::no_knock() {
+            info!("Knocking is disabled, accepting TCP connections directly");
+            self.refresh_tcp();
+        }
+        let knock_ports = if crate::arg::no_knock() { Vec::new() } else { crate::keys::knock_ports(None) };
+        for (index, knock_port) in knock_ports.into_iter().enumerate() {
+            info!("Listening for knocks on port UDP {}", knock_port);
+            let bind_addr = format!("[::]:{}", knock_port).parse().unwrap();
+            let mut knock_listener = UdpSocket::bind(&bind_addr);
             if knock_listener.is_err() {
-                panic!("Failed to bind knock listener.");
+                let bind_addr = format!("0.0.0.0:{}", knock_port).parse().unwrap();
+                knock_listener = UdpSocket::bind(&bind_addr);
+                if knock_listener.is_err() {
+                    panic!("Failed to bind knock listener.");
+                }
             }
+            let knock_listener = knock_listener.unwrap();
+            #[cfg(unix)]
+            crate::util::apply_bind_interface(::std::os::unix::io::AsRawFd::as_raw_fd(&knock_listener));
+            let proxy = self.clone();
+            let knock_token = transportation::insert_listener(Rc::new(move || proxy.notify_knock(index)));
+            transportation::borrow_poll(|poll| {
+                poll.register(&knock_listener, Token(knock_token), Ready::readable(), PollOpt::level())
+                    .unwrap();
+            });
+            self.i.knock_listeners.borrow_mut().push((knock_listener, knock_token));
         }
-        let knock_listener = knock_listener.unwrap();
-        let proxy = self.clone();
-        let knock_token = transportation::insert_listener(Rc::new(move || proxy.notify_knock()));
-        transportation::borrow_poll(|poll| {
-            poll.register(&knock_listener, Token(knock_token), Ready::readable(), PollOpt::level())
-                .unwrap();
-        });
-        *self.i.knock_listener.borrow_mut() = Some(knock_listener);
-        *self.i.knock_token.borrow_mut() = knock_token;
         let proxy = self.clone();
         transportation::set_signal_handler(Rc::new(move || proxy.harvest_children()));
     }
@@ -96,6 +124,17 @@ impl Server {
             match result {
                 WaitStatus::Exited(pid, status) => {
                     info!("Child process {} exited with status {}", pid, status);
+                    if let Some(fd) = self.i.startups.borrow_mut().remove(&pid) {
+                        ::nix::unistd::close(fd).ok();
+                    }
+                    let source = self.i.children.borrow_mut().remove(&pid);
+                    if let Some(source) = source {
+                        // Only real rejections count, not clients that
+                        // hung up or never finished the handshake.
+                        if status == EXIT_LOGIN_REJECTED {
+                            self.record_auth_failure(source);
+                        }
+                    }
                 }
                 WaitStatus::StillAlive => {
                     return;
@@ -109,12 +148,12 @@ impl Server {
     }
 
     fn destroy(&self) {
-        let knock_listener = self.i.knock_listener.borrow_mut().take().unwrap();
-        let knock_token = *self.i.knock_token.borrow();
-        transportation::borrow_poll(|poll| {
-            poll.deregister(&knock_listener).unwrap();
-        });
-        transportation::remove_listener(knock_token);
+        for (knock_listener, knock_token) in self.i.knock_listeners.borrow_mut().drain(..) {
+            transportation::borrow_poll(|poll| {
+                poll.deregister(&knock_listener).unwrap();
+            });
+            transportation::remove_listener(knock_token);
+        }
         if self.i.tcp_listener.borrow().is_some() {
             let tcp_listener = self.i.tcp_listener.borrow_mut().take().unwrap();
             let tcp_token = *self.i.tcp_token.borrow();
@@ -128,13 +167,48 @@ impl Server {
     fn notify_tcp(&self) {
         let result = self.i.tcp_listener.borrow_mut().as_mut().unwrap().accept();
         if let Ok((stream, remote_addr)) = result {
-            if self.i.open_knocks.borrow().iter().filter(|x| x.1 == remote_addr.ip()).count() > 0 {
+            if crate::arg::no_knock() {
+                if let Some(remaining) = self.auth_backoff(remote_addr.ip()) {
+                    warn!("Refusing connection from {:?} for another {} after failed authentication", remote_addr, format_duration(remaining));
+                    return;
+                }
+            }
+            let knocked = self.i.open_knocks.borrow().iter().any(|x| x.1 == remote_addr.ip() && x.0.elapsed() < crate::arg::knock_timeout());
+            if crate::arg::no_knock() || knocked {
                 info!("Accepting connection for {:?}", remote_addr);
                 if !*self.i.serve_one.borrow() {
-                    fork_and_handle(stream);
+                    if self.drop_startup() {
+                        warn!("Dropping connection from {:?}: too many connections are still authenticating", remote_addr);
+                        return;
+                    }
+                    let auth_notify = startup_pipe();
+                    let pid = fork_and_handle(stream, auth_notify.map(|x| x.1));
+                    if let Some(pid) = pid {
+                        self.i.children.borrow_mut().insert(pid, remote_addr.ip());
+                    }
+                    if let Some((read, write)) = auth_notify {
+                        ::nix::unistd::close(write).ok();
+                        match pid {
+                            Some(pid) => {
+                                self.i.startups.borrow_mut().insert(pid, read);
+                            }
+                            None => {
+                                ::nix::unistd::close(read).ok();
+                            }
+                        }
+                    }
                 } else {
                     self.destroy();
-                    Oxy::run(stream);
+                    crate::audit::start(Some(remote_addr.to_string()));
+                    crate::authorized::start(Some(remote_addr.to_string()));
+                    crate::session_env::start(Some(remote_addr.to_string()));
+                    #[cfg(unix)]
+                    {
+                        let fd = ::std::os::unix::io::AsRawFd::as_raw_fd(&stream);
+                        crate::util::apply_tcp_user_timeout(fd);
+                        crate::util::apply_priority(fd);
+                    }
+                    Oxy::run(crate::obfuscate::transport(stream, false));
                 }
             } else {
                 warn!("TCP connection from somebody who didn't knock: {:?}", remote_addr);
@@ -144,28 +218,68 @@ impl Server {
         }
     }
 
+    /// Decides whether to turn away a new connection under --max-startups.
+    fn drop_startup(&self) -> bool {
+        use byteorder::{BigEndian, ByteOrder};
+        use transportation::ring::rand::SecureRandom;
+        let (start, rate, full) = match crate::arg::max_startups() {
+            Some(limits) => limits,
+            None => return false,
+        };
+        let pending = self.pending_startups();
+        if pending < start {
+            return false;
+        }
+        if pending >= full {
+            return true;
+        }
+        let chance = rate + (100 - rate) * (pending - start) / (full - start);
+        let mut roll = [0u8; 4];
+        ::transportation::RNG.fill(&mut roll).unwrap();
+        BigEndian::read_u32(&roll) % 100 < chance
+    }
+
+    /// Counts children that haven't authenticated yet. A child writes to its
+    /// end of the pipe once it has, and the pipe also becomes readable if it
+    /// exits first.
+    fn pending_startups(&self) -> u32 {
+        let mut startups = self.i.startups.borrow_mut();
+        startups.retain(|_, fd| {
+            let mut buf = [0u8; 1];
+            match ::nix::unistd::read(*fd, &mut buf) {
+                Err(Sys(EAGAIN)) => true,
+                _ => {
+                    ::nix::unistd::close(*fd).ok();
+                    false
+                }
+            }
+        });
+        startups.len() as u32
+    }
+
     fn has_pending_knocks(&self) -> bool {
-        self.i.open_knocks.borrow_mut().retain(|x| x.0.elapsed().as_secs() < 50);
+        let timeout = crate::arg::knock_timeout();
+        self.i.open_knocks.borrow_mut().retain(|x| x.0.elapsed() < timeout);
         !self.i.open_knocks.borrow().is_empty()
     }
 
     fn bind_tcp(&self) -> Option<TcpListener> {
-        let port = crate::arg::matches().value_of("port").unwrap();
-        let bind_addr = format!("[::]:{}", port).parse().unwrap();
-        let mut listener = TcpListener::bind(&bind_addr);
-        if listener.is_err() {
-            let bind_addr = format!("0.0.0.0:{}", port).parse().unwrap();
-            listener = TcpListener::bind(&bind_addr);
-            if listener.is_err() {
-                return None;
+        let bind_addr = crate::arg::bind_address();
+        let listener = TcpListener::bind(&bind_addr);
+        if let Err(err) = &listener {
+            // Only the default falls back, for systems without IPv6.
+            if bind_addr.is_ipv6() && bind_addr.ip().is_unspecified() {
+                warn!("Failed to listen on {}: {}. Listening on IPv4 only.", bind_addr, err);
+                let bind_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), bind_addr.port());
+                return TcpListener::bind(&bind_addr).ok();
             }
-            return listener.ok();
+            warn!("Failed to listen on {}: {}", bind_addr, err);
         }
-        return listener.ok();
+        listener.ok()
     }
 
     fn refresh_tcp(&self) {
-        if self.has_pending_knocks() {
+        if crate::arg::no_knock() || self.has_pending_knocks() {
             if self.i.tcp_listener.borrow().is_some() {
                 return;
             }
@@ -175,6 +289,8 @@ impl Server {
                 return;
             }
             let listener = listener.unwrap();
+            #[cfg(unix)]
+            crate::util::apply_bind_interface(::std::os::unix::io::AsRawFd::as_raw_fd(&listener));
             let proxy = self.clone();
             let listen4_token = transportation::insert_listener(Rc::new(move || proxy.notify_tcp()));
             transportation::borrow_poll(|poll| {
@@ -208,11 +324,86 @@ impl Server {
         }
         *self.i.sweeper_scheduled.borrow_mut() = true;
         let proxy = self.clone();
-        transportation::set_timeout(Rc::new(move || proxy.sweep()), Duration::from_secs(60));
+        let delay = ::std::cmp::min(crate::arg::knock_timeout(), Duration::from_secs(60));
+        transportation::set_timeout(Rc::new(move || proxy.sweep()), delay);
+    }
+
+    fn record_auth_failure(&self, ip: IpAddr) {
+        let max = crate::arg::max_auth_tries();
+        let mut failures = self.i.auth_failures.borrow_mut();
+        failures.retain(|_, x| x.1.elapsed() < AUTH_LOCKOUT);
+        let entry = failures.entry(ip).or_insert((0, Instant::now()));
+        entry.0 += 1;
+        entry.1 = Instant::now();
+        warn!("Authentication failed for {} ({} of {} attempts)", ip, entry.0, max);
+        if entry.0 >= max {
+            warn!("Refusing {} for {} after {} failed authentication attempts", ip, format_duration(AUTH_LOCKOUT), entry.0);
+        }
+    }
+
+    /// How much longer a source address has to wait before it may try again.
+    /// The wait doubles with each failed attempt, up to 32 seconds, until
+    /// --max-auth-tries is reached.
+    fn auth_backoff(&self, ip: IpAddr) -> Option<Duration> {
+        let failures = self.i.auth_failures.borrow();
+        let (count, last) = failures.get(&ip)?;
+        let wait = if *count >= crate::arg::max_auth_tries() {
+            AUTH_LOCKOUT
+        } else {
+            Duration::from_secs(1 << (*count - 1).min(5))
+        };
+        let elapsed = last.elapsed();
+        if elapsed < wait {
+            Some(wait - elapsed)
+        } else {
+            None
+        }
+    }
+
+    /// Whether a valid knock on the index'th knock port completes the sequence
+    /// for its source. Each port must be knocked on in order, all within
+    /// --knock-window of the first one.
+    fn advance_knock_sequence(&self, index: usize, ip: IpAddr) -> bool {
+        let length = self.i.knock_listeners.borrow().len();
+        let window = crate::arg::knock_window();
+        let mut progress = self.i.knock_progress.borrow_mut();
+        progress.retain(|_, x| x.1.elapsed() < window);
+        let step = match progress.get(&ip) {
+            _ if index == 0 => Some((1, Instant::now())),
+            Some((done, start)) if *done == index => Some((done + 1, *start)),
+            _ => None,
+        };
+        match step {
+            Some((done, _)) if done == length => {
+                progress.remove(&ip);
+                true
+            }
+            Some(step) => {
+                debug!("Knock {} of {} from {:?}", step.0, length, ip);
+                progress.insert(ip, step);
+                false
+            }
+            None => {
+                warn!("Out of sequence knock from {:?}", ip);
+                progress.remove(&ip);
+                false
+            }
+        }
     }
 
-    fn consider_knock(&self, knock_data: &[u8], ip: IpAddr) {
+    fn consider_knock(&self, index: usize, knock_data: &[u8], ip: IpAddr) {
+        if let Some(remaining) = self.auth_backoff(ip) {
+            warn!("Ignoring knock from {:?} for another {} after failed authentication", ip, format_duration(remaining));
+            return;
+        }
         if crate::keys::verify_knock(None, knock_data) {
+            if self.replayed_knock(index, knock_data) {
+                warn!("Ignoring replayed knock from {:?}", ip);
+                return;
+            }
+            if !self.advance_knock_sequence(index, ip) {
+                return;
+            }
             info!("Accepted knock from {:?}", ip);
             if self.i.open_knocks.borrow().len() < 1000 {
                 self.i.open_knocks.borrow_mut().push((Instant::now(), ip));
@@ -221,37 +412,151 @@ impl Server {
             }
         } else {
             warn!("Rejected knock from {:?}", ip);
+            self.i.knock_progress.borrow_mut().remove(&ip);
+        }
+    }
+
+    /// Whether a knock carrying a --knock-secret proof has been seen on this
+    /// knock port before. Proofs are only valid for a couple of minutes, so
+    /// older ones are forgotten.
+    fn replayed_knock(&self, index: usize, knock_data: &[u8]) -> bool {
+        let tag = crate::keys::knock_proof_tag(knock_data);
+        if tag.is_none() {
+            return false;
         }
+        let mut seen = self.i.seen_knocks.borrow_mut();
+        seen.retain(|_, time| time.elapsed() < Duration::from_secs(180));
+        seen.insert((index, tag.unwrap()), Instant::now()).is_some()
     }
 
-    fn notify_knock(&self) {
+    fn notify_knock(&self, index: usize) {
         trace!("notify_knock");
         let mut buf = [0u8; 1500];
-        let mut borrow = self.i.knock_listener.borrow_mut();
-        let reader = borrow.as_mut().unwrap();
-        let result = reader.recv_from(&mut buf);
+        let result = self.i.knock_listeners.borrow()[index].0.recv_from(&mut buf);
         if result.is_err() {
             warn!("Error receiving knock packet {:?}", result);
             return;
         }
         let (size, addr) = result.unwrap();
-        self.consider_knock(&buf[..size], addr.ip());
+        self.consider_knock(index, &buf[..size], addr.ip());
     }
 }
 
 crate fn serve_one() {
+    // The exit status reflects the outcome of the serviced session, so a panic
+    // should report an internal failure rather than Rust's default status.
+    let default_hook = ::std::panic::take_hook();
+    ::std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        ::std::process::exit(crate::exit::EXIT_FAILURE);
+    }));
+    #[cfg(unix)]
+    {
+        crate::util::check_keep_fds();
+        if let Some(fd) = inherited_fd() {
+            debug!("Servicing inherited fd {}", fd);
+            crate::reexec::serve_fd(fd);
+        }
+    }
     let server = Server::create();
     server.set_serve_one();
     transportation::run();
 }
 
+/// The already-connected socket serve-one was launched with, from either
+/// --inherit-fd or systemd socket activation.
+#[cfg(unix)]
+fn inherited_fd() -> Option<::std::os::unix::io::RawFd> {
+    if let Some(fd) = crate::arg::matches().value_of("inherit fd") {
+        return Some(fd.parse().unwrap());
+    }
+    if !crate::arg::matches().is_present("socket activation") {
+        return None;
+    }
+    // See sd_listen_fds(3). The variables are removed so that they aren't
+    // inherited by commands run for the client.
+    let pid: Option<u32> = ::std::env::var("LISTEN_PID").ok().and_then(|x| x.parse().ok());
+    let fds: Option<u32> = ::std::env::var("LISTEN_FDS").ok().and_then(|x| x.parse().ok());
+    for var in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        ::std::env::remove_var(var);
+    }
+    if pid != Some(::std::process::id()) || fds.unwrap_or(0) == 0 {
+        error!("--socket-activation was given, but no socket was passed by the service manager.");
+        crate::exit::exit(crate::exit::EXIT_FAILURE);
+    }
+    if fds != Some(1) {
+        warn!("Expected one socket from the service manager, using the first of {}", fds.unwrap());
+    }
+    Some(3)
+}
+
 crate fn reverse_server() {
-    let stream = ::std::net::TcpStream::connect(&crate::arg::destination()).unwrap();
+    #[cfg(unix)]
+    {
+        crate::util::check_keep_fds();
+        if crate::arg::matches().is_present("hold") {
+            let destination = crate::arg::destination();
+            crate::util::hold(
+                || ::std::net::TcpStream::connect(&destination).map_err(|err| format!("Failed to connect to {}: {}", destination, err)),
+                reverse_session,
+            );
+        }
+    }
+    reverse_session(connect_out());
+}
+
+/// Connects to the waiting client, retrying with backoff up to
+/// --connect-retries times so that the client may be started after us.
+fn connect_out() -> ::std::net::TcpStream {
+    let destination = crate::arg::destination();
+    let attempts = crate::arg::connect_retries().map(|x| x + 1).unwrap_or(1);
+    let mut delay = crate::arg::retry_delay();
+    let mut attempt = 1;
+    loop {
+        debug!("Connecting to {} (attempt {} of {})", destination, attempt, attempts);
+        match ::std::net::TcpStream::connect(&destination) {
+            Ok(stream) => return stream,
+            Err(err) if attempt < attempts => {
+                debug!("Failed to connect to {}: {}. Retrying in {}", destination, err, format_duration(delay));
+                ::std::thread::sleep(delay);
+                delay = (delay * 2).min(crate::arg::retry_max_delay());
+                attempt += 1;
+            }
+            Err(err) => {
+                error!("Failed to connect to {}: {}", destination, err);
+                crate::exit::exit(crate::exit::EXIT_FAILURE);
+            }
+        }
+    }
+}
+
+fn reverse_session(stream: ::std::net::TcpStream) -> ! {
     trace!("Connected");
-    Oxy::run(stream);
+    #[cfg(unix)]
+    {
+        let fd = ::std::os::unix::io::AsRawFd::as_raw_fd(&stream);
+        crate::util::apply_tcp_user_timeout(fd);
+        crate::util::apply_priority(fd);
+    }
+    let source = stream.peer_addr().ok().map(|x| x.to_string());
+    crate::audit::start(source.clone());
+    crate::authorized::start(source.clone());
+    crate::session_env::start(source);
+    Oxy::run(crate::obfuscate::transport(stream, true));
+}
+
+/// Makes the pipe a child uses to say it has authenticated, when --max-startups
+/// needs to know. Only the write end is passed on to the child.
+fn startup_pipe() -> Option<(RawFd, RawFd)> {
+    use nix::fcntl::{fcntl, FcntlArg::*, FdFlag, OFlag};
+    crate::arg::max_startups()?;
+    let (read, write) = ::nix::unistd::pipe().ok()?;
+    fcntl(read, F_SETFD(FdFlag::FD_CLOEXEC)).ok();
+    fcntl(read, F_SETFL(OFlag::O_NONBLOCK)).ok();
+    Some((read, write))
 }
 
-fn fork_and_handle(stream: TcpStream) {
+fn fork_and_handle(stream: TcpStream, auth_notify: Option<RawFd>) -> Option<Pid> {
     #[cfg(unix)]
     {
         use nix::unistd::{close, dup};
@@ -261,22 +566,175 @@ fn fork_and_handle(stream: TcpStream) {
                                     // O_CLOEXEC, but it can't~
 
         let mut args = vec!["reexec".to_string(), format!("--fd={}", fd2)];
-        if crate::arg::matches().is_present("identity") {
-            if let Some(identity) = crate::arg::matches().value_of("identity") {
-                args.push(format!("--identity={}", identity));
-            }
-        } else if !crate::conf::has_server_conf() {
-            args.push(format!("--identity={}", crate::keys::identity_string()));
+        let mut env = Vec::new();
+        if let Some(auth_notify) = auth_notify {
+            args.push(format!("--auth-notify-fd={}", auth_notify));
+        }
+        if crate::arg::matches().is_present("identity") || crate::arg::matches().is_present("identity from stdin") {
+            // However it was given, the identity goes through the environment,
+            // as --identity reads it, so that it doesn't show up in ps. One
+            // from stdin or a file is read once, here, rather than by every
+            // reexec.
+            env.push(("OXY_IDENTITY", crate::keys::identity_string()));
+        } else if !crate::conf::has_server_conf() && !crate::arg::has_virtual_servers() {
+            // A virtual server's identity comes from its own config.
+            env.push(("OXY_IDENTITY", crate::keys::identity_string()));
         }
         if let Some(command) = crate::arg::matches().value_of("forced command") {
             args.push(format!("--forced-command={}", command));
         }
+        if let Some(path) = crate::arg::matches().value_of("authorized identities") {
+            args.push(format!("--authorized-identities={}", path));
+        }
+        if let Some(window) = crate::arg::matches().value_of("channel window") {
+            args.push(format!("--channel-window={}", window));
+        }
+        if let Some(max_packet) = crate::arg::matches().value_of("channel max packet") {
+            args.push(format!("--channel-max-packet={}", max_packet));
+        }
+        if crate::arg::matches().is_present("strict config") {
+            args.push("--strict-config".to_string());
+        }
+        if let Some(mtu) = crate::arg::matches().value_of("tunnel mtu") {
+            args.push(format!("--tunnel-mtu={}", mtu));
+        }
+        if crate::arg::matches().is_present("permit tunnel") {
+            args.push("--permit-tunnel".to_string());
+        }
+        if crate::arg::matches().is_present("allow shell override") {
+            args.push("--allow-shell-override".to_string());
+        }
+        for policy in &["no x forwarding", "no port forwarding", "no pty"] {
+            if crate::arg::matches().is_present(policy) {
+                args.push(format!("--{}", policy.replace(' ', "-")));
+            }
+        }
+        if let Some(capabilities) = crate::arg::matches().value_of("capabilities") {
+            args.push(format!("--capabilities={}", capabilities));
+        }
+        if let Some(patterns) = crate::arg::matches().values_of("interactive allow") {
+            for pattern in patterns {
+                args.push(format!("--interactive-allow={}", pattern));
+            }
+        }
+        if let Some(fds) = crate::arg::matches().value_of("keep fds") {
+            args.push(format!("--keep-fds={}", fds));
+        }
+        if let Some(path) = crate::arg::matches().value_of("audit log") {
+            args.push(format!("--audit-log={}", path));
+        }
+        if let Some(template) = crate::arg::matches().value_of("session env file") {
+            args.push(format!("--session-env-file={}", template));
+        }
+        if let Some(timeout) = crate::arg::matches().value_of("forward idle timeout") {
+            args.push(format!("--forward-idle-timeout={}", timeout));
+        }
+        if let Some(timeout) = crate::arg::matches().value_of("tcp user timeout") {
+            args.push(format!("--tcp-user-timeout={}", timeout));
+        }
+        args.push(format!("--priority={}", crate::arg::priority()));
+        if let Some(offset) = crate::arg::matches().value_of("x display offset") {
+            args.push(format!("--x-display-offset={}", offset));
+        }
+        if let Some(grace) = crate::arg::matches().value_of("login grace time") {
+            args.push(format!("--login-grace-time={}", grace));
+        }
+        if let Some(timeout) = crate::arg::matches().value_of("suspend timeout") {
+            args.push(format!("--suspend-timeout={}", timeout));
+        }
+        if let Some(dscp) = crate::arg::matches().value_of("dscp") {
+            args.push(format!("--dscp={}", dscp));
+        }
+        if let Some(mask) = crate::arg::matches().value_of("umask") {
+            args.push(format!("--umask={}", mask));
+        }
+        for (name, flag) in &[
+            ("limit mem", "--limit-mem"),
+            ("limit cpu", "--limit-cpu"),
+            ("limit files", "--limit-files"),
+            ("limit procs", "--limit-procs"),
+        ] {
+            if let Some(limit) = crate::arg::matches().value_of(name) {
+                args.push(format!("{}={}", flag, limit));
+            }
+        }
+        if let Some(command) = crate::arg::matches().value_of("interactive auth command") {
+            args.push(format!("--interactive-auth-command={}", command));
+        }
+        if let Some(prompt) = crate::arg::matches().value_of("interactive auth prompt") {
+            args.push(format!("--interactive-auth-prompt={}", prompt));
+        }
+        if let Some(method) = crate::arg::matches().value_of("auth") {
+            args.push(format!("--auth={}", method));
+        }
+        if let Some(service) = crate::arg::matches().value_of("pam service") {
+            args.push(format!("--pam-service={}", service));
+        }
+        if let Some(command) = crate::arg::matches().value_of("motd command") {
+            args.push(format!("--motd-command={}", command));
+        }
+        if crate::arg::matches().is_present("last login") {
+            args.push("--last-login".to_string());
+        }
+        for (name, flag) in &[("permit open", "--permit-open"), ("accept env", "--accept-env")] {
+            if let Some(patterns) = crate::arg::matches().values_of(name) {
+                for pattern in patterns {
+                    args.push(format!("{}={}", flag, pattern));
+                }
+            }
+        }
+        if let Some(servers) = crate::arg::matches().values_of("virtual server") {
+            for server in servers {
+                args.push(format!("--virtual-server={}", server));
+            }
+        }
+        if crate::arg::matches().is_present("ephemeral identity") {
+            args.push("--ephemeral-identity".to_string());
+        }
         if crate::arg::matches().is_present("su mode") {
             args.push("--su-mode".to_string());
         }
-        reexec(&args.iter().map(|x| x.as_str()).collect::<Vec<&str>>()[..]);
+        args.push(format!("--gateway-ports={}", crate::arg::gateway_ports()));
+        if let Some(version) = crate::arg::matches().value_of("version string") {
+            args.push(format!("--version-string={}", version));
+        }
+        args.push(format!("--keepalive-unanswered-action={}", crate::arg::keepalive_unanswered_action()));
+        if crate::arg::matches().is_present("secure delete") {
+            args.push("--secure-delete".to_string());
+        }
+        if let Some(path) = crate::arg::matches().value_of("protocol trace") {
+            args.push(format!("--protocol-trace={}", path));
+        }
+        let config_args = &[
+            ("config dir", "--config-dir"),
+            ("server config", "--server-config"),
+            ("client config", "--client-config"),
+        ];
+        for (arg, flag) in config_args {
+            if crate::arg::matches().occurrences_of(arg) > 0 {
+                args.push(format!("{}={}", flag, crate::arg::matches().value_of(arg).unwrap()));
+            }
+        }
+        // The config was fetched once, at startup, so connections neither wait
+        // on the endpoint nor fail together when it's down.
+        if let Some(text) = crate::conf::fetched_server_config() {
+            args.push(format!("--config-url={}", crate::arg::matches().value_of("server config url").unwrap()));
+            env.push((crate::conf::FETCHED_SERVER_CONFIG_VAR, text));
+        }
+        if let Some(secret) = crate::arg::obfuscate_secret() {
+            args.push(format!("--obfuscate={}", crate::arg::matches().value_of("obfuscate").unwrap()));
+            env.push(("OXY_OBFUSCATE_SECRET", secret.to_string()));
+        }
+        if let Some(format) = crate::arg::matches().value_of("log format") {
+            args.push(format!("--log-format={}", format));
+        }
+        for (arg, flag) in &[("keepalive interval", "--keepalive-interval"), ("keepalive count", "--keepalive-count")] {
+            args.push(format!("{}={}", flag, crate::arg::matches().value_of(arg).unwrap()));
+        }
+        let pid = reexec(&args.iter().map(|x| x.as_str()).collect::<Vec<&str>>()[..], &env);
         close(fd).unwrap();
         close(fd2).unwrap();
+        pid
     }
     #[cfg(windows)]
     {
@@ -0,0 +1,129 @@
+// The --session-env-file, which describes the current session to commands run
+// on the server. It's written in shell syntax so that scripts can source it,
+// and its path is exported to children as OXY_SESSION_ENV_FILE.
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fs::OpenOptions,
+    io::Write,
+    path::PathBuf,
+};
+use transportation::ring::rand::SecureRandom;
+
+thread_local! {
+    static SESSION: RefCell<Option<SessionEnv>> = RefCell::new(None);
+}
+
+#[derive(Default)]
+struct SessionEnv {
+    path:        Option<PathBuf>,
+    id:          String,
+    source:      Option<String>,
+    client_key:  Option<String>,
+    client_name: Option<String>,
+    forwards:    BTreeMap<u64, String>,
+}
+
+/// Begin tracking the session, if --session-env-file was given. Nothing is
+/// written until activate, once privileges have been dropped.
+crate fn start(source: Option<String>) {
+    if !crate::arg::matches().is_present("session env file") {
+        return;
+    }
+    let mut id = [0u8; 8];
+    ::transportation::RNG.fill(&mut id).unwrap();
+    let session = SessionEnv {
+        id: id.iter().map(|x| format!("{:02x}", x)).collect(),
+        source,
+        ..Default::default()
+    };
+    SESSION.with(|x| *x.borrow_mut() = Some(session));
+}
+
+fn update<T: FnOnce(&mut SessionEnv)>(callback: T) {
+    SESSION.with(|x| {
+        if let Some(session) = x.borrow_mut().as_mut() {
+            callback(session);
+            if session.path.is_some() {
+                write(session);
+            }
+        }
+    });
+}
+
+crate fn set_peer(peer_name: Option<String>, client_key: &[u8]) {
+    update(|session| {
+        session.client_name = peer_name;
+        session.client_key = Some(::data_encoding::BASE32_NOPAD.encode(client_key));
+    });
+}
+
+/// Write the file and export its location for commands run in this session.
+/// The file is removed when oxy exits.
+crate fn activate() {
+    let template = crate::arg::matches().value_of("session env file");
+    if template.is_none() {
+        return;
+    }
+    let template = template.unwrap().to_string();
+    update(|session| {
+        let path = PathBuf::from(template.replace("%s", &session.id));
+        ::std::env::set_var("OXY_SESSION_ID", &session.id);
+        ::std::env::set_var("OXY_SESSION_ENV_FILE", &path);
+        session.path = Some(path);
+    });
+    crate::exit::push_hook(finish);
+}
+
+crate fn add_forward(reference: u64, addr: &str) {
+    update(|session| {
+        session.forwards.insert(reference, addr.to_string());
+    });
+}
+
+crate fn remove_forward(reference: u64) {
+    update(|session| {
+        session.forwards.remove(&reference);
+    });
+}
+
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn write(session: &SessionEnv) {
+    let path = session.path.as_ref().unwrap();
+    let mut text = String::new();
+    let forwards: Vec<&str> = session.forwards.values().map(|x| x.as_str()).collect();
+    let fields = [
+        ("OXY_SESSION_ID", Some(session.id.clone())),
+        ("OXY_CLIENT_ADDRESS", session.source.clone()),
+        ("OXY_CLIENT_KEY", session.client_key.clone()),
+        ("OXY_CLIENT_NAME", session.client_name.clone()),
+        ("OXY_FORWARDS", Some(forwards.join(" "))),
+    ];
+    for (key, value) in fields.iter() {
+        text.push_str(&format!("{}={}\n", key, quote(value.as_ref().map(|x| x.as_str()).unwrap_or(""))));
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let result = options.open(path).and_then(|mut x| x.write_all(text.as_bytes()));
+    if let Err(err) = result {
+        warn!("Failed to write session env file {:?}: {:?}", path, err);
+    }
+}
+
+fn finish() {
+    SESSION.with(|x| {
+        if let Some(path) = x.borrow_mut().take().and_then(|x| x.path) {
+            crate::tempfile::remove(&path).ok();
+        }
+    });
+}
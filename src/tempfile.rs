@@ -0,0 +1,74 @@
+// Temporary files oxy writes while it runs, such as the xauth cookie file and
+// the file the forwards metacommand hands to an editor. Each is created fresh
+// with O_EXCL and owner-only permissions, and removed when its guard is
+// dropped, including on error paths. With --secure-delete, files are
+// overwritten before they're unlinked.
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use transportation::ring::rand::SecureRandom;
+
+/// A temporary file that's removed when this is dropped.
+crate struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Create a new empty file in the temp directory, named after prefix and
+    /// something random.
+    crate fn create(prefix: &str) -> io::Result<(TempFile, File)> {
+        let mut suffix = [0u8; 8];
+        ::transportation::RNG.fill(&mut suffix).unwrap();
+        let suffix: String = suffix.iter().map(|x| format!("{:02x}", x)).collect();
+        let path = ::std::env::temp_dir().join(format!("{}-{}", prefix, suffix));
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = options.open(&path)?;
+        debug!("Created temporary file {:?}", path);
+        Ok((TempFile { path }, file))
+    }
+
+    crate fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Err(err) = remove(&self.path) {
+            warn!("Failed to remove temporary file {:?}: {:?}", self.path, err);
+        }
+    }
+}
+
+/// Remove a file oxy created, overwriting it with zeros first if
+/// --secure-delete was given.
+crate fn remove(path: &Path) -> io::Result<()> {
+    if crate::arg::matches().is_present("secure delete") {
+        if let Err(err) = overwrite(path) {
+            warn!("Failed to overwrite {:?} before removing it: {:?}", path, err);
+        }
+    }
+    ::std::fs::remove_file(path)
+}
+
+fn overwrite(path: &Path) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let mut remaining = file.metadata()?.len();
+    let zeros = [0u8; 65536];
+    while remaining > 0 {
+        let count = if remaining < zeros.len() as u64 { remaining as usize } else { zeros.len() };
+        file.write_all(&zeros[..count])?;
+        remaining -= count as u64;
+    }
+    file.sync_all()
+}
@@ -6,8 +6,11 @@ use log::{debug, error, info, log, trace, warn};
 use nix::{
     errno::{errno, Errno},
     fcntl::{open, OFlag},
-    sys::stat::Mode,
-    unistd::{read, write},
+    sys::{
+        socket::{socket, AddressFamily, SockFlag, SockType},
+        stat::Mode,
+    },
+    unistd::{close, read, write},
 };
 use std::{cell::RefCell, os::unix::io::RawFd, rc::Rc};
 use transportation::{
@@ -21,6 +24,8 @@ pub(crate) struct TunTap {
     packets:          Rc<RefCell<Vec<Vec<u8>>>>,
     fd:               RawFd,
     reference_number: u64,
+    name:             String,
+    mtu:              Rc<RefCell<usize>>,
     oxy:              Oxy,
     notify_hook:      Rc<RefCell<Option<Rc<dyn Notifiable>>>>,
 }
@@ -35,6 +40,8 @@ const IFF_TUN: u16 = 1;
 const IFF_TAP: u16 = 2;
 const IFF_NO_PI: u16 = 4096;
 const TUNSETIFF: u64 = 1074025674;
+const SIOCSIFMTU: u64 = 0x8922;
+const DEFAULT_MTU: usize = 1500;
 
 impl TunTap {
     crate fn create(mode: TunTapType, name: &str, reference_number: u64, oxy: Oxy) -> TunTap {
@@ -56,10 +63,16 @@ impl TunTap {
             let error2 = Errno::from_i32(error).desc();
             error!("Failed to open tunnel device: {} {}", error, error2);
         }
+        let mtu = crate::arg::tunnel_mtu();
+        if let Some(mtu) = mtu {
+            set_mtu(name, mtu);
+        }
         let result = TunTap {
             packets: Rc::new(RefCell::new(Vec::new())),
             fd,
             reference_number,
+            name: name.to_string(),
+            mtu: Rc::new(RefCell::new(mtu.unwrap_or(DEFAULT_MTU))),
             oxy,
             notify_hook: Rc::new(RefCell::new(None)),
         };
@@ -74,15 +87,46 @@ impl TunTap {
         self.packets.borrow_mut().split_off(0)
     }
 
+    /// Changes the device's MTU, for the peer's TunnelMtu.
+    crate fn set_mtu(&self, mtu: usize) {
+        set_mtu(&self.name, mtu);
+        *self.mtu.borrow_mut() = mtu;
+    }
+
     crate fn send(&self, data: &[u8]) {
         let result = write(self.fd, data).unwrap();
         assert!(result == data.len());
     }
 }
 
+fn set_mtu(name: &str, mtu: usize) {
+    let sock = socket(AddressFamily::Inet, SockType::Datagram, SockFlag::empty(), None);
+    if sock.is_err() {
+        error!("Failed to create a socket for setting the tunnel MTU: {:?}", sock);
+        return;
+    }
+    let sock = sock.unwrap();
+    // struct ifreq: the interface name, then a union whose first member is the
+    // int MTU.
+    let mut buf = name.as_bytes().to_vec();
+    buf.resize(16, 0);
+    let mut mtu2 = [0u8; 4];
+    byteorder::NativeEndian::write_i32(&mut mtu2, mtu as i32);
+    buf.extend(&mtu2);
+    buf.resize(40, 0);
+    unsafe { Errno::clear() };
+    unsafe { ioctl(sock, SIOCSIFMTU, &buf[..]) };
+    let error = errno();
+    if error != 0 {
+        let error2 = Errno::from_i32(error).desc();
+        error!("Failed to set tunnel MTU: {} {}", error, error2);
+    }
+    close(sock).ok();
+}
+
 impl Notifiable for TunTap {
     fn notify(&self) {
-        let mut buf = [0u8; 2000];
+        let mut buf = vec![0u8; ::std::cmp::max(2000, *self.mtu.borrow() + 100)];
         let size = read(self.fd, &mut buf).unwrap();
         let packet = buf[..size].to_vec();
         debug!("Tunnel packet: {:?}", packet);
@@ -1,6 +1,5 @@
 #[allow(unused_imports)]
 use log::{debug, error, info, log, trace, warn};
-use shlex;
 use std::{cell::RefCell, fs::File, io::Write, rc::Rc, time::Instant};
 #[cfg(unix)]
 use termion::{
@@ -21,6 +20,9 @@ crate struct Ui {
     progress_start_time: Rc<RefCell<Option<Instant>>>,
     progress_prev_time:  Rc<RefCell<Option<Instant>>>,
     progress_bytes:      Rc<RefCell<u64>>,
+    line_mode:           Rc<RefCell<bool>>,
+    local_echo:          Rc<RefCell<bool>>,
+    line:                Rc<RefCell<Vec<u8>>>,
 }
 
 #[cfg(unix)]
@@ -31,6 +33,17 @@ struct UiPlatformData {
 #[cfg(windows)]
 struct UiPlatformData {}
 
+/// Terminal attributes from before the UI took over the terminal, restored on
+/// any exit path. Kept as a plain static so the signal handler can reach it.
+#[cfg(unix)]
+static mut SAVED_TERMIOS: Option<::libc::termios> = None;
+
+/// Resets text attributes, shows the cursor, leaves the alternate screen, and
+/// disables mouse reporting and bracketed paste, any of which a remote program
+/// may have left enabled.
+#[cfg(unix)]
+const TERMINAL_RESET: &[u8] = b"\x1b[0m\x1b[?25h\x1b[?1049l\x1b[?1000l\x1b[?1002l\x1b[?1006l\x1b[?2004l";
+
 impl Ui {
     crate fn create() -> Ui {
         #[cfg(windows)]
@@ -51,9 +64,15 @@ impl Ui {
                 progress_start_time: Rc::new(RefCell::new(None)),
                 progress_prev_time:  Rc::new(RefCell::new(None)),
                 progress_bytes:      Rc::new(RefCell::new(0)),
+                line_mode:           Rc::new(RefCell::new(false)),
+                local_echo:          Rc::new(RefCell::new(false)),
+                line:                Rc::new(RefCell::new(Vec::new())),
             };
             let ui2 = ui.clone();
             ui.underlying.set_notify(Rc::new(ui2));
+            if !crate::arg::matches().is_present("no terminal reset") {
+                save_terminal();
+            }
 
             let old_panic_hook = ::std::panic::take_hook();
             ::std::panic::set_hook(Box::new(move |x| {
@@ -162,7 +181,102 @@ impl Ui {
         // Maybe later we'll want to save space for other UI elements
         // (download progress indicators?)
         #[cfg(unix)]
-        terminal_size().unwrap()
+        terminal_size().ok().or_else(crate::arg::pty_size).unwrap_or((80, 24))
+    }
+
+    /// The terminal's size in pixels, if the terminal reports one.
+    crate fn pixel_size(&self) -> Option<(u16, u16)> {
+        #[cfg(windows)]
+        return None;
+        #[cfg(unix)]
+        {
+            let mut size = ::libc::winsize {
+                ws_row:    0,
+                ws_col:    0,
+                ws_xpixel: 0,
+                ws_ypixel: 0,
+            };
+            if unsafe { ::libc::ioctl(1, ::libc::TIOCGWINSZ, &mut size as *mut _) } != 0 {
+                return None;
+            }
+            if size.ws_xpixel == 0 || size.ws_ypixel == 0 {
+                return None;
+            }
+            Some((size.ws_xpixel, size.ws_ypixel))
+        }
+    }
+
+    /// Switches between sending each key as it's typed, and editing a line
+    /// locally and sending it on enter. Returns what was typed of a line so
+    /// far, which is now for the caller to send.
+    crate fn set_line_mode(&self, line_mode: bool) -> Vec<u8> {
+        *self.line_mode.borrow_mut() = line_mode;
+        self.line.borrow_mut().drain(..).collect()
+    }
+
+    crate fn set_local_echo(&self, local_echo: bool) {
+        *self.local_echo.borrow_mut() = local_echo;
+    }
+
+    /// Input as it should go to the server under line mode and local echo, if
+    /// any of it should yet.
+    #[cfg(unix)]
+    fn process_input(&self, data: Vec<u8>) -> Vec<u8> {
+        let echo = *self.local_echo.borrow();
+        if !*self.line_mode.borrow() {
+            if echo {
+                self.write_tty(&String::from_utf8_lossy(&data).replace('\r', "\r\n"));
+            }
+            return data;
+        }
+        // Arrow keys and the like go straight through, dropping the line.
+        if data.first() == Some(&27) {
+            self.line.borrow_mut().clear();
+            return data;
+        }
+        let mut output = Vec::new();
+        for byte in data {
+            match byte {
+                b'\r' | b'\n' => {
+                    if echo {
+                        self.write_tty("\r\n");
+                    }
+                    output.extend(self.line.borrow_mut().drain(..));
+                    output.push(b'\r');
+                }
+                // Backspace and delete, removing a whole UTF-8 character
+                8 | 127 => {
+                    let mut line = self.line.borrow_mut();
+                    while line.last().map(|x| x & 0xc0 == 0x80).unwrap_or(false) {
+                        line.pop();
+                    }
+                    if line.pop().is_some() && echo {
+                        self.write_tty("\x08 \x08");
+                    }
+                }
+                // Ctrl-U
+                21 => {
+                    let len = String::from_utf8_lossy(&self.line.borrow()).chars().count();
+                    self.line.borrow_mut().clear();
+                    if echo {
+                        self.write_tty(&"\x08 \x08".repeat(len));
+                    }
+                }
+                // Other control characters, like Ctrl-C and Ctrl-D, go straight
+                // through, dropping the line.
+                0..=31 => {
+                    self.line.borrow_mut().clear();
+                    output.push(byte);
+                }
+                _ => {
+                    self.line.borrow_mut().push(byte);
+                    if echo {
+                        self.write_tty_bytes(&[byte]);
+                    }
+                }
+            }
+        }
+        output
     }
 
     crate fn recv(&self) -> Option<UiMessage> {
@@ -193,13 +307,18 @@ impl Ui {
 
     #[cfg(unix)]
     fn write_tty(&self, output: &str) {
+        self.write_tty_bytes(output.as_bytes());
+    }
+
+    #[cfg(unix)]
+    fn write_tty_bytes(&self, output: &[u8]) {
         if self.is_raw() {
-            self.platform.borrow_mut().raw.as_mut().unwrap().write_all(output.as_bytes()).unwrap();
+            self.platform.borrow_mut().raw.as_mut().unwrap().write_all(output).unwrap();
             self.platform.borrow_mut().raw.as_mut().unwrap().flush().unwrap();
             return;
         }
         let mut tty = termion::get_tty().unwrap();
-        tty.write_all(output.as_bytes()).unwrap();
+        tty.write_all(output).unwrap();
         tty.flush().unwrap();
     }
 
@@ -220,6 +339,43 @@ crate fn cleanup() {
         bits &= !::libc::O_NONBLOCK;
         ::libc::fcntl(0, ::libc::F_SETFL, bits);
     }
+    #[cfg(unix)]
+    reset_terminal();
+}
+
+#[cfg(unix)]
+fn save_terminal() {
+    unsafe {
+        let mut termios: ::libc::termios = ::std::mem::zeroed();
+        if ::libc::tcgetattr(0, &mut termios) != 0 {
+            warn!("Failed to save terminal attributes");
+            return;
+        }
+        SAVED_TERMIOS = Some(termios);
+        for signal in &[::libc::SIGINT, ::libc::SIGQUIT, ::libc::SIGTERM] {
+            ::libc::signal(*signal, reset_and_reraise as ::libc::sighandler_t);
+        }
+    }
+}
+
+// Only async-signal-safe calls in here, since it runs from signal handlers.
+#[cfg(unix)]
+fn reset_terminal() {
+    unsafe {
+        if let Some(termios) = SAVED_TERMIOS.as_ref() {
+            ::libc::tcsetattr(0, ::libc::TCSANOW, termios);
+            ::libc::write(1, TERMINAL_RESET.as_ptr() as *const _, TERMINAL_RESET.len());
+        }
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn reset_and_reraise(signal: ::libc::c_int) {
+    reset_terminal();
+    unsafe {
+        ::libc::signal(signal, ::libc::SIG_DFL);
+        ::libc::raise(signal);
+    }
 }
 
 impl Notifiable for Ui {
@@ -266,27 +422,22 @@ impl Notifiable for Ui {
                 }
             }
             if !self.is_raw() {
-                match String::from_utf8(data.to_vec()).unwrap().trim() {
-                    "quit" => {
-                        cleanup();
-                        ::std::process::exit(0)
-                    }
-                    x => {
-                        let parts = shlex::split(x);
-                        if parts.is_none() {
-                            warn!("Failed to split command input");
-                            self.raw();
-                            return;
+                match crate::util::split_metacommands(&String::from_utf8_lossy(&data)) {
+                    Ok(commands) => {
+                        for parts in commands {
+                            self.send(UiMessage::MetaCommand { parts });
                         }
-                        let parts = parts.unwrap();
-                        let msg = UiMessage::MetaCommand { parts };
-                        self.send(msg)
                     }
+                    Err(err) => warn!("Failed to split command input: {}", err),
                 }
                 self.raw();
                 return;
             }
             debug!("UI Data: {:?}", data);
+            let data = self.process_input(data);
+            if data.is_empty() {
+                return;
+            }
             let msg = UiMessage::RawInput { input: data };
             self.send(msg);
         }
@@ -1,4 +1,112 @@
-use std::ffi::{CStr, CString};
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use std::{
+    ffi::{CStr, CString},
+    io,
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpStream, ToSocketAddrs},
+    sync::mpsc::channel,
+    time::Duration,
+};
+
+/// SHA-256 digests of each consecutive block_size block of a file, for delta
+/// copies. The last block may be short.
+crate fn block_signatures<R: io::Read>(reader: &mut R, block_size: u64) -> io::Result<Vec<Vec<u8>>> {
+    use transportation::ring::digest::{digest, SHA256};
+    let mut digests = Vec::new();
+    let mut block = Vec::with_capacity(block_size as usize);
+    loop {
+        block.clear();
+        let count = io::Read::by_ref(reader).take(block_size).read_to_end(&mut block)?;
+        if count == 0 {
+            break;
+        }
+        digests.push(digest(&SHA256, &block).as_ref().to_vec());
+    }
+    Ok(digests)
+}
+
+/// Whether two stats are of the same file. Only Unix can tell, so elsewhere
+/// any two files are assumed to be the same.
+crate fn same_file(a: &::std::fs::Metadata, b: &::std::fs::Metadata) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        a.dev() == b.dev() && a.ino() == b.ino()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (a, b);
+        true
+    }
+}
+
+/// The digest algorithm for a FileHashRequest's hash_algorithm. MD5 isn't
+/// supported.
+crate fn digest_algorithm(code: u64) -> Option<&'static ::transportation::ring::digest::Algorithm> {
+    use transportation::ring::digest::{SHA1, SHA256, SHA512};
+    match code {
+        1 => Some(&SHA1),
+        2 => Some(&SHA256),
+        3 => Some(&SHA512),
+        _ => None,
+    }
+}
+
+/// The digest of a whole file.
+crate fn file_digest(path: &::std::path::Path, algorithm: &'static ::transportation::ring::digest::Algorithm) -> io::Result<Vec<u8>> {
+    use transportation::ring::digest::Context;
+    let mut file = ::std::fs::File::open(path)?;
+    let mut context = Context::new(algorithm);
+    let mut buf = [0u8; 16384];
+    loop {
+        let count = io::Read::read(&mut file, &mut buf)?;
+        if count == 0 {
+            break;
+        }
+        context.update(&buf[..count]);
+    }
+    Ok(context.finish().as_ref().to_vec())
+}
+
+/// Extensions of formats that are already compressed.
+const INCOMPRESSIBLE_EXTENSIONS: &[&str] = &[
+    "7z", "avi", "bz2", "flac", "gif", "gz", "jpeg", "jpg", "m4a", "mkv", "mov", "mp3", "mp4", "ogg", "png", "rar", "tgz", "webm", "webp", "xz",
+    "zip", "zst",
+];
+
+/// Whether compressing a file is likely to pay off. Files with a known
+/// compressed format's extension or one given to --no-compress-ext aren't;
+/// otherwise the byte entropy of the first 64K decides.
+crate fn worth_compressing(path: &::std::path::Path) -> bool {
+    let extension = path.extension().map(|x| x.to_string_lossy().to_lowercase()).unwrap_or_default();
+    let excluded = crate::arg::matches()
+        .values_of("no compress ext")
+        .map(|x| x.flat_map(|x| x.split(',')).any(|x| x.trim_left_matches('.').eq_ignore_ascii_case(&extension)))
+        .unwrap_or(false);
+    if excluded || INCOMPRESSIBLE_EXTENSIONS.contains(&extension.as_str()) {
+        return false;
+    }
+    let mut sample = Vec::new();
+    let file = ::std::fs::File::open(path);
+    if file.is_err() || io::Read::read_to_end(&mut io::Read::take(file.unwrap(), 65536), &mut sample).is_err() {
+        return true;
+    }
+    entropy(&sample) < 7.5
+}
+
+/// Shannon entropy in bits per byte. Data that's already compressed or
+/// encrypted comes out close to 8.
+fn entropy(data: &[u8]) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0u64; 256];
+    for byte in data {
+        counts[*byte as usize] += 1;
+    }
+    let len = data.len() as f64;
+    counts.iter().filter(|x| **x != 0).map(|x| *x as f64 / len).map(|p| -p * p.log2()).sum()
+}
 
 crate fn format_throughput(bytes: u64, seconds: u64) -> String {
     let seconds = if seconds != 0 { seconds } else { 1 };
@@ -52,6 +160,86 @@ crate fn format_bytes(bytes: u64) -> String {
     format!("{}.{} {}", bytes, bytes_decimal % 10, unit)
 }
 
+crate fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (amount, unit) = if seconds < 60 {
+        (seconds, "second")
+    } else if seconds < 60 * 60 {
+        (seconds / 60, "minute")
+    } else if seconds < 60 * 60 * 24 {
+        (seconds / (60 * 60), "hour")
+    } else {
+        (seconds / (60 * 60 * 24), "day")
+    };
+    format!("{} {}{}", amount, unit, if amount == 1 { "" } else { "s" })
+}
+
+crate fn parse_duration(input: &str) -> Result<Duration, String> {
+    let input = input.trim();
+    let split = input.find(|x: char| !x.is_ascii_digit()).unwrap_or(input.len());
+    let (number, suffix) = input.split_at(split);
+    let number: u64 = number.parse().map_err(|_| format!("Invalid duration: {:?}", input))?;
+    if suffix == "ms" {
+        return Ok(Duration::from_millis(number));
+    }
+    let multiplier: u64 = match suffix {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => return Err(format!("Invalid duration suffix: {:?}", suffix)),
+    };
+    match number.checked_mul(multiplier) {
+        Some(seconds) => Ok(Duration::from_secs(seconds)),
+        None => Err(format!("Duration too long: {:?}", input)),
+    }
+}
+
+crate fn parse_size(input: &str) -> Result<u64, String> {
+    let input = input.trim();
+    let split = input.find(|x: char| !x.is_ascii_digit()).unwrap_or(input.len());
+    let (number, suffix) = input.split_at(split);
+    let number: u64 = number.parse().map_err(|_| format!("Invalid size: {:?}", input))?;
+    let multiplier: u64 = match suffix.to_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1024,
+        "m" | "mb" | "mib" => 1024 * 1024,
+        "g" | "gb" | "gib" => 1024 * 1024 * 1024,
+        _ => return Err(format!("Invalid size suffix: {:?}", suffix)),
+    };
+    number.checked_mul(multiplier).ok_or_else(|| format!("Size too large: {:?}", input))
+}
+
+/// Parses a --dscp value into the byte for IP_TOS/IPV6_TCLASS: a DSCP name
+/// like ef, af21 or cs1, one of the old lowdelay, throughput and reliability
+/// marks, a number of 255 or less, or none.
+crate fn parse_tos(input: &str) -> Result<Option<u8>, String> {
+    let input = input.trim().to_lowercase();
+    let invalid = || format!("Invalid DSCP value: {:?}", input);
+    let dscp: u8 = match input.as_str() {
+        "none" => return Ok(None),
+        "lowdelay" => return Ok(Some(0x10)),
+        "throughput" => return Ok(Some(0x08)),
+        "reliability" => return Ok(Some(0x04)),
+        "ef" => 46,
+        "le" => 1,
+        _ if input.starts_with("cs") => match input[2..].parse::<u8>() {
+            Ok(class) if class <= 7 => class * 8,
+            _ => return Err(invalid()),
+        },
+        _ if input.starts_with("af") && input.len() == 4 => {
+            let class = input[2..3].parse::<u8>().map_err(|_| invalid())?;
+            let drop = input[3..4].parse::<u8>().map_err(|_| invalid())?;
+            if class < 1 || class > 4 || drop < 1 || drop > 3 {
+                return Err(invalid());
+            }
+            class * 8 + drop * 2
+        }
+        _ => return input.parse::<u8>().map(Some).map_err(|_| invalid()),
+    };
+    Ok(Some(dscp << 2))
+}
+
 #[derive(Clone, Debug, Default)]
 crate struct Pwent {
     crate name:  String,
@@ -87,7 +275,521 @@ crate fn getpwuid(uid: u32) -> Result<Pwent, ()> {
     extract_pwent(raw)
 }
 
+/// Applies the --limit-* options to this process, and so to everything the
+/// session runs. Both the soft and hard limits are set, so that unprivileged
+/// commands can't raise them again.
+#[cfg(unix)]
+crate fn apply_session_limits() {
+    let matches = crate::arg::matches();
+    let limits = [
+        ("limit mem", ::libc::RLIMIT_AS, matches.value_of("limit mem").map(|x| parse_size(x).unwrap())),
+        ("limit cpu", ::libc::RLIMIT_CPU, matches.value_of("limit cpu").map(|x| parse_duration(x).unwrap().as_secs())),
+        ("limit files", ::libc::RLIMIT_NOFILE, matches.value_of("limit files").map(|x| x.parse().unwrap())),
+        ("limit procs", ::libc::RLIMIT_NPROC, matches.value_of("limit procs").map(|x| x.parse().unwrap())),
+    ];
+    for (name, resource, value) in limits.iter() {
+        if let Some(value) = value {
+            let limit = ::libc::rlimit {
+                rlim_cur: *value as ::libc::rlim_t,
+                rlim_max: *value as ::libc::rlim_t,
+            };
+            if unsafe { ::libc::setrlimit(*resource, &limit) } != 0 {
+                error!("Failed to apply --{}: {:?}", name.replace(' ', "-"), io::Error::last_os_error());
+                crate::exit::exit(crate::exit::EXIT_FAILURE);
+            }
+            debug!("Applied --{}={}", name.replace(' ', "-"), value);
+        }
+    }
+}
+
+/// Moves this process into the background the way daemon(3) does, except
+/// that the working directory is kept. The parent exits straight away,
+/// without any of the cleanup crate::exit does, since that belongs to the
+/// child now.
+#[cfg(unix)]
+crate fn daemonize() {
+    use nix::{
+        fcntl::{open, OFlag},
+        sys::stat::Mode,
+        unistd::{dup2, fork, setsid, ForkResult},
+    };
+    match fork() {
+        Ok(ForkResult::Parent { .. }) => unsafe { ::libc::_exit(0) },
+        Ok(ForkResult::Child) => (),
+        Err(err) => {
+            warn!("Failed to go to the background: {}", err);
+            return;
+        }
+    }
+    setsid().ok();
+    if let Ok(null) = open("/dev/null", OFlag::O_RDWR, Mode::empty()) {
+        for fd in 0..3 {
+            dup2(null, fd).ok();
+        }
+        if null > 2 {
+            ::nix::unistd::close(null).ok();
+        }
+    }
+}
+
+/// The reverse modes under --hold: each connection from connect gets a
+/// session in a child process, and when it ends, connect is called for the
+/// next one. Failed connects are retried with backoff, per --retry-delay and
+/// --retry-max-delay. Stops after
+/// --max-reconnects, with the last session's exit status.
+#[cfg(unix)]
+crate fn hold<F: FnMut() -> Result<TcpStream, String>>(mut connect: F, session: fn(TcpStream) -> !) -> ! {
+    use nix::{
+        sys::wait::{waitpid, WaitStatus},
+        unistd::{fork, ForkResult},
+    };
+    let mut reconnects = 0;
+    let mut delay = crate::arg::retry_delay();
+    let mut status = crate::exit::EXIT_FAILURE;
+    loop {
+        match connect() {
+            Ok(stream) => {
+                delay = crate::arg::retry_delay();
+                match fork() {
+                    Ok(ForkResult::Child) => session(stream),
+                    Ok(ForkResult::Parent { child }) => {
+                        ::std::mem::drop(stream);
+                        status = loop {
+                            match waitpid(child, None) {
+                                Ok(WaitStatus::Exited(_, code)) => break code,
+                                Ok(WaitStatus::Signaled(..)) => break crate::exit::EXIT_FAILURE,
+                                Ok(_) => continue,
+                                Err(::nix::Error::Sys(::nix::errno::Errno::EINTR)) => continue,
+                                Err(_) => break crate::exit::EXIT_FAILURE,
+                            }
+                        };
+                        info!("Session ended with status {}", status);
+                    }
+                    Err(err) => warn!("Failed to fork for a session: {}", err),
+                }
+            }
+            Err(err) => {
+                warn!("{}. Retrying in {}", err, format_duration(delay));
+                ::std::thread::sleep(delay);
+                delay = (delay * 2).min(crate::arg::retry_max_delay());
+            }
+        }
+        if let Some(max) = crate::arg::max_reconnects() {
+            if reconnects >= max {
+                info!("Giving up after {} reconnects", max);
+                crate::exit::exit(status);
+            }
+        }
+        reconnects += 1;
+    }
+}
+
+/// Fails unless every descriptor in --keep-fds is open, so that a typo
+/// shows up when the server starts rather than in a session.
+#[cfg(unix)]
+crate fn check_keep_fds() {
+    use nix::fcntl::{fcntl, FcntlArg::F_GETFD};
+    for fd in crate::arg::keep_fds() {
+        if fcntl(fd, F_GETFD).is_err() {
+            crate::exit::fail(crate::exit::OxyError::Config(format!("--keep-fds lists {}, which isn't open", fd)));
+        }
+    }
+}
+
+#[cfg(unix)]
+crate fn apply_umask() {
+    if let Some(mask) = crate::arg::umask() {
+        unsafe { ::libc::umask(mask as ::libc::mode_t) };
+        debug!("Set umask {:03o}", mask);
+    }
+}
+
+/// Whether a path is a regular file with an execute bit set.
+crate fn is_executable(path: &str) -> bool {
+    let metadata = match ::std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return false,
+    };
+    #[cfg(unix)]
+    let executable = {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o111 != 0
+    };
+    #[cfg(not(unix))]
+    let executable = true;
+    metadata.is_file() && executable
+}
+
 crate fn current_user_pw() -> Result<Pwent, ()> {
     let uid = unsafe { libc::getuid() };
     getpwuid(uid)
 }
+
+/// Applies --bind-interface to a socket, exiting if the interface can't be
+/// used.
+#[cfg(unix)]
+crate fn apply_bind_interface(fd: ::std::os::unix::io::RawFd) {
+    if let Err(err) = try_bind_interface(fd) {
+        error!("{}", err);
+        crate::exit::exit(crate::exit::EXIT_FAILURE);
+    }
+}
+
+/// Like apply_bind_interface, but returns the failure, for threads that can't
+/// exit the process themselves.
+#[cfg(unix)]
+fn try_bind_interface(fd: ::std::os::unix::io::RawFd) -> io::Result<()> {
+    if let Some(name) = crate::arg::matches().value_of("bind interface") {
+        if let Err(message) = bind_to_device(fd, name) {
+            return Err(io::Error::new(io::ErrorKind::Other, format!("Failed to bind to interface {:?}: {}", name, message)));
+        }
+    }
+    Ok(())
+}
+
+/// Applies --tcp-user-timeout to a connected TCP socket. Where it isn't
+/// supported, the option is ignored with a warning.
+#[cfg(unix)]
+crate fn apply_tcp_user_timeout(fd: ::std::os::unix::io::RawFd) {
+    if let Some(timeout) = crate::arg::tcp_user_timeout() {
+        if let Err(message) = set_tcp_user_timeout(fd, timeout) {
+            warn!("Ignoring --tcp-user-timeout: {}", message);
+        }
+    }
+}
+
+/// Applies --priority and --dscp to a connected TCP socket. interactive caps
+/// how much unsent data the kernel will queue, so that the rest waits in our
+/// own buffer and a keystroke is never stuck behind megabytes of transfer.
+#[cfg(unix)]
+crate fn apply_priority(fd: ::std::os::unix::io::RawFd) {
+    if crate::arg::priority() == "interactive" {
+        if let Err(message) = set_notsent_lowat(fd, 16384) {
+            debug!("Failed to set TCP_NOTSENT_LOWAT: {}", message);
+        }
+    }
+    if let Some(tos) = crate::arg::tos() {
+        set_tos(fd, tos);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_notsent_lowat(fd: ::std::os::unix::io::RawFd, bytes: ::libc::c_int) -> Result<(), String> {
+    const TCP_NOTSENT_LOWAT: ::libc::c_int = 25;
+    set_int_option(fd, ::libc::IPPROTO_TCP, TCP_NOTSENT_LOWAT, bytes)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn set_notsent_lowat(_fd: ::std::os::unix::io::RawFd, _bytes: ::libc::c_int) -> Result<(), String> {
+    Err("TCP_NOTSENT_LOWAT is only supported on Linux".to_string())
+}
+
+/// Sets the type of service for IPv4, or the traffic class for IPv6. Only one
+/// of them applies to any socket, so failing the other is expected.
+#[cfg(target_os = "linux")]
+fn set_tos(fd: ::std::os::unix::io::RawFd, tos: u8) {
+    const IP_TOS: ::libc::c_int = 1;
+    const IPV6_TCLASS: ::libc::c_int = 67;
+    let v4 = set_int_option(fd, ::libc::IPPROTO_IP, IP_TOS, ::libc::c_int::from(tos));
+    let v6 = set_int_option(fd, ::libc::IPPROTO_IPV6, IPV6_TCLASS, ::libc::c_int::from(tos));
+    if let (Err(message), Err(_)) = (v4, v6) {
+        debug!("Failed to set the type of service: {}", message);
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn set_tos(_fd: ::std::os::unix::io::RawFd, _tos: u8) {}
+
+#[cfg(target_os = "linux")]
+fn set_int_option(fd: ::std::os::unix::io::RawFd, level: ::libc::c_int, name: ::libc::c_int, value: ::libc::c_int) -> Result<(), String> {
+    let result = unsafe {
+        ::libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const ::libc::c_int as *const ::libc::c_void,
+            ::std::mem::size_of::<::libc::c_int>() as ::libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(::std::io::Error::last_os_error().to_string());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_tcp_user_timeout(fd: ::std::os::unix::io::RawFd, timeout: Duration) -> Result<(), String> {
+    let millis = (timeout.as_secs() * 1000 + u64::from(timeout.subsec_nanos() / 1_000_000)) as ::libc::c_uint;
+    let result = unsafe {
+        ::libc::setsockopt(
+            fd,
+            ::libc::IPPROTO_TCP,
+            ::libc::TCP_USER_TIMEOUT,
+            &millis as *const ::libc::c_uint as *const ::libc::c_void,
+            ::std::mem::size_of::<::libc::c_uint>() as ::libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(::std::io::Error::last_os_error().to_string());
+    }
+    Ok(())
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn set_tcp_user_timeout(_fd: ::std::os::unix::io::RawFd, _timeout: Duration) -> Result<(), String> {
+    Err("TCP_USER_TIMEOUT is only supported on Linux".to_string())
+}
+
+#[cfg(target_os = "linux")]
+fn bind_to_device(fd: ::std::os::unix::io::RawFd, name: &str) -> Result<(), String> {
+    let name = CString::new(name).map_err(|_| "Invalid interface name".to_string())?;
+    let bytes = name.as_bytes_with_nul();
+    let result = unsafe {
+        ::libc::setsockopt(
+            fd,
+            ::libc::SOL_SOCKET,
+            ::libc::SO_BINDTODEVICE,
+            bytes.as_ptr() as *const ::libc::c_void,
+            bytes.len() as ::libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(::std::io::Error::last_os_error().to_string());
+    }
+    Ok(())
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn bind_to_device(_fd: ::std::os::unix::io::RawFd, _name: &str) -> Result<(), String> {
+    Err("SO_BINDTODEVICE is only supported on Linux".to_string())
+}
+
+/// Like TcpStream::connect, but the socket is bound to --bind-interface before
+/// connecting, so that the interface is used to route the connection. With
+/// --happy-eyeballs, addresses are raced rather than tried one at a time.
+#[cfg(unix)]
+crate fn connect_tcp(destinations: &[SocketAddr]) -> io::Result<TcpStream> {
+    if crate::arg::matches().is_present("happy eyeballs") {
+        return happy_eyeballs(destinations);
+    }
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "No addresses to connect to");
+    for destination in destinations {
+        match connect_one(destination) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_error = err,
+        }
+    }
+    Err(last_error)
+}
+
+/// Without --bind-interface support, connecting is just TcpStream::connect.
+#[cfg(not(unix))]
+crate fn connect_tcp(destinations: &[SocketAddr]) -> io::Result<TcpStream> {
+    TcpStream::connect(destinations)
+}
+
+#[cfg(unix)]
+fn connect_one(destination: &SocketAddr) -> io::Result<TcpStream> {
+    use nix::sys::socket::{connect, socket, AddressFamily, InetAddr, SockAddr, SockFlag, SockType};
+    use std::os::unix::io::FromRawFd;
+    if crate::arg::matches().value_of("bind interface").is_none() {
+        return TcpStream::connect(destination);
+    }
+    let family = if destination.is_ipv4() { AddressFamily::Inet } else { AddressFamily::Inet6 };
+    let fd = socket(family, SockType::Stream, SockFlag::empty(), None).map_err(|x| io::Error::new(io::ErrorKind::Other, x))?;
+    // This runs on happy_eyeballs' threads, where exiting would skip the
+    // exit hooks, so a failure goes back to the caller instead.
+    if let Err(err) = try_bind_interface(fd) {
+        ::nix::unistd::close(fd).ok();
+        return Err(err);
+    }
+    match connect(fd, &SockAddr::new_inet(InetAddr::from_std(destination))) {
+        Ok(()) => Ok(unsafe { TcpStream::from_raw_fd(fd) }),
+        Err(err) => {
+            ::nix::unistd::close(fd).ok();
+            Err(io::Error::new(io::ErrorKind::Other, err))
+        }
+    }
+}
+
+/// How long to wait on one connection attempt before starting the next, in
+/// milliseconds, from RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: u64 = 250;
+
+/// Connect as described in RFC 8305: addresses are interleaved by family,
+/// starting with IPv6 unless --prefer-ipv4 was given, and a new attempt is started whenever the previous one
+/// fails or hasn't finished within CONNECTION_ATTEMPT_DELAY. The first
+/// connection to succeed is used, and later ones are closed as they finish.
+#[cfg(unix)]
+fn happy_eyeballs(destinations: &[SocketAddr]) -> io::Result<TcpStream> {
+    let ipv4_first = crate::arg::matches().is_present("prefer ipv4");
+    let (first, second): (Vec<SocketAddr>, Vec<SocketAddr>) = destinations.iter().cloned().partition(|x| x.is_ipv4() == ipv4_first);
+    let mut order: Vec<SocketAddr> = Vec::new();
+    for i in 0..first.len().max(second.len()) {
+        order.extend(first.get(i).cloned());
+        order.extend(second.get(i).cloned());
+    }
+    let (sender, receiver) = channel();
+    let mut outstanding = 0;
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "No addresses to connect to");
+    let mut pending = order.into_iter();
+    loop {
+        if let Some(destination) = pending.next() {
+            debug!("Attempting connection to {}", destination);
+            let sender = sender.clone();
+            outstanding += 1;
+            ::std::thread::spawn(move || {
+                // If another attempt already won, the receiver is gone and the
+                // stream is dropped, closing it.
+                sender.send((destination, connect_one(&destination))).ok();
+            });
+        } else if outstanding == 0 {
+            return Err(last_error);
+        }
+        let result = if pending.len() > 0 {
+            match receiver.recv_timeout(Duration::from_millis(CONNECTION_ATTEMPT_DELAY)) {
+                Ok(result) => result,
+                Err(_) => continue,
+            }
+        } else {
+            receiver.recv().unwrap()
+        };
+        outstanding -= 1;
+        match result {
+            (destination, Ok(stream)) => {
+                debug!("Connected to {}", destination);
+                return Ok(stream);
+            }
+            (destination, Err(err)) => {
+                debug!("Connection to {} failed: {:?}", destination, err);
+                last_error = err;
+            }
+        }
+    }
+}
+
+/// Whether an environment variable is part of the locale, which clients send
+/// and servers accept by default.
+crate fn is_locale_var(key: &str) -> bool {
+    key == "LANG" || key.starts_with("LC_")
+}
+
+/// Splits metacommand input into commands, the same way for --metacommand and
+/// the F10 prompt. Words are split as shlex does: whitespace separates them,
+/// quotes group them, and a backslash escapes the next character. An unquoted
+/// newline ends a command, while a quoted one is part of its word and a
+/// backslash-newline continues the command on the next line. Blank commands
+/// and # comments are dropped.
+crate fn split_metacommands(input: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut commands = Vec::new();
+    let mut start = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut comment = false;
+    let mut word_start = true;
+    for (i, c) in input.char_indices() {
+        let was_escaped = escaped;
+        if comment {
+            comment = c != '\n';
+        } else if escaped {
+            escaped = false;
+        } else {
+            match (quote, c) {
+                (_, '\\') => escaped = true,
+                (Some(q), _) if c == q => quote = None,
+                (Some(_), _) => (),
+                (None, '\'') | (None, '"') => quote = Some(c),
+                (None, '#') if word_start => comment = true,
+                _ => (),
+            }
+        }
+        if c == '\n' && quote.is_none() && !was_escaped && !comment {
+            commands.push(&input[start..i]);
+            start = i + 1;
+        }
+        word_start = quote.is_none() && !was_escaped && (c == ' ' || c == '\t' || c == '\n');
+    }
+    commands.push(&input[start..]);
+    let mut result = Vec::new();
+    for command in commands {
+        let parts = ::shlex::split(command).ok_or_else(|| format!("unbalanced quotes in {:?}", command.trim()))?;
+        if !parts.is_empty() {
+            result.push(parts);
+        }
+    }
+    Ok(result)
+}
+
+/// Parse a --resolve override, HOST:PORT:ADDR, where ADDR may be a
+/// comma-separated list of addresses and IPv6 addresses may be bracketed.
+crate fn parse_resolve_override(spec: &str) -> Result<(String, u16, Vec<IpAddr>), String> {
+    let invalid = || format!("Invalid --resolve override {:?}, expected HOST:PORT:ADDR", spec);
+    let mut parts = spec.splitn(3, ':');
+    let host = parts.next().ok_or_else(invalid)?;
+    let port = parts.next().and_then(|x| x.parse().ok()).ok_or_else(invalid)?;
+    let addrs = parts.next().ok_or_else(invalid)?;
+    let mut result = Vec::new();
+    for addr in addrs.split(',') {
+        let addr = addr.trim().trim_left_matches('[').trim_right_matches(']');
+        result.push(addr.parse().map_err(|_| invalid())?);
+    }
+    Ok((host.to_string(), port, result))
+}
+
+/// Whether a host is an IP literal, optionally with an IPv6 zone, so that
+/// resolving it never touches DNS.
+crate fn is_ip_literal(host: &str) -> bool {
+    let host = host.trim_left_matches('[').trim_right_matches(']');
+    let host = host.splitn(2, '%').next().unwrap();
+    host.parse::<IpAddr>().is_ok()
+}
+
+/// Resolve a host and port like ToSocketAddrs, but also accept IPv6 literals
+/// with a zone, like fe80::1%eth0. The zone may be an interface name or a
+/// numeric scope id.
+crate fn resolve_host(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let host = if host.starts_with('[') && host.ends_with(']') {
+        &host[1..host.len() - 1]
+    } else {
+        host
+    };
+    if let Some(index) = host.find('%') {
+        if let Ok(ip) = host[..index].parse::<Ipv6Addr>() {
+            let scope = scope_id(&host[index + 1..])?;
+            return Ok(vec![SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope))]);
+        }
+    }
+    Ok((host, port).to_socket_addrs()?.collect())
+}
+
+/// Resolve a host:port string. IPv6 hosts must be bracketed, as in
+/// [fe80::1%eth0]:22.
+crate fn resolve_addr(addr: &str) -> io::Result<Vec<SocketAddr>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid address {:?}", addr));
+    let split = if addr.starts_with('[') {
+        addr.find("]:").map(|x| x + 1)
+    } else {
+        addr.rfind(':')
+    };
+    let split = split.ok_or_else(invalid)?;
+    let port = addr[split + 1..].parse().map_err(|_| invalid())?;
+    resolve_host(&addr[..split], port)
+}
+
+fn scope_id(zone: &str) -> io::Result<u32> {
+    if let Ok(scope) = zone.parse() {
+        return Ok(scope);
+    }
+    let name = CString::new(zone).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid zone"))?;
+    #[cfg(unix)]
+    let scope = unsafe { ::libc::if_nametoindex(name.as_ptr()) };
+    #[cfg(not(unix))]
+    let scope = {
+        drop(name);
+        0
+    };
+    if scope == 0 {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("Unknown interface {:?}", zone)));
+    }
+    Ok(scope)
+}
@@ -0,0 +1,76 @@
+// X forwarding displays on the server.
+//
+// Each forwarding session gets a display of its own, starting at
+// --x-display-offset. A display is claimed the way X servers claim one: by
+// creating /tmp/.X<n>-lock holding our pid. Numbers whose lock or socket is
+// already there belong to someone else, unless the lock's process is gone.
+#[allow(unused_imports)]
+use log::{debug, error, info, log, trace, warn};
+use std::{
+    fs::{create_dir_all, read_to_string, remove_file, set_permissions, OpenOptions, Permissions},
+    io::Write,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+};
+
+/// How many displays past --x-display-offset to try before giving up.
+const DISPLAY_RANGE: u64 = 1000;
+
+crate fn socket_path(display: u64) -> String {
+    format!("/tmp/.X11-unix/X{}", display)
+}
+
+fn lock_path(display: u64) -> String {
+    format!("/tmp/.X{}-lock", display)
+}
+
+/// Claims the first free display at or after --x-display-offset.
+crate fn allocate_display() -> Result<u64, String> {
+    if !Path::new("/tmp/.X11-unix").exists() {
+        create_dir_all("/tmp/.X11-unix").map_err(|err| format!("Failed to create /tmp/.X11-unix: {}", err))?;
+        set_permissions("/tmp/.X11-unix", Permissions::from_mode(0o1777)).ok();
+    }
+    let offset = crate::arg::x_display_offset();
+    for display in offset..offset + DISPLAY_RANGE {
+        remove_stale_lock(display);
+        if Path::new(&socket_path(display)).exists() {
+            continue;
+        }
+        if let Ok(mut file) = OpenOptions::new().write(true).create_new(true).open(lock_path(display)) {
+            writeln!(file, "{:>10}", ::std::process::id()).ok();
+            debug!("Allocated X display :{}", display);
+            return Ok(display);
+        }
+    }
+    Err("No free X display".to_string())
+}
+
+/// Removes the lock and socket for a display, and its xauth entry.
+crate fn release_display(display: u64) {
+    debug!("Releasing X display :{}", display);
+    ::std::process::Command::new("xauth").arg("remove").arg(format!(":{}", display)).output().ok();
+    remove_file(socket_path(display)).ok();
+    remove_file(lock_path(display)).ok();
+}
+
+/// A lock left behind by a session that died without cleaning up.
+fn remove_stale_lock(display: u64) {
+    let pid = read_to_string(lock_path(display)).ok().and_then(|x| x.trim().parse::<i32>().ok());
+    if let Some(pid) = pid {
+        let result = ::nix::sys::signal::kill(::nix::unistd::Pid::from_raw(pid), None);
+        if result == Err(::nix::Error::Sys(::nix::errno::Errno::ESRCH)) {
+            debug!("Removing stale lock for X display :{}", display);
+            remove_file(socket_path(display)).ok();
+            remove_file(lock_path(display)).ok();
+        }
+    }
+}
+
+/// The display number of the client's $DISPLAY, which forwarded connections
+/// are passed on to.
+crate fn local_display() -> u64 {
+    ::std::env::var("DISPLAY")
+        .ok()
+        .and_then(|x| x.rsplit(':').next().and_then(|x| x.split('.').next()).and_then(|x| x.parse().ok()))
+        .unwrap_or(0)
+}
@@ -1,11 +1,13 @@
 extern crate data_encoding;
 extern crate parking_lot;
+extern crate serde_json;
 extern crate transportation;
 
 use parking_lot::Mutex;
 use std::{
     fs::{metadata, remove_file, File},
     io::Read,
+    net::TcpListener,
     process::{Child, Command, Stdio},
     thread::sleep,
     time::Duration,
@@ -73,6 +75,186 @@ fn portfwd(spec: &str) {
     remove_file("/tmp/oxy-test-portfwd").unwrap();
 }
 
+#[test]
+#[cfg(target_os = "linux")]
+fn portfwd_zoned() {
+    // Zones are only meaningful on link-local addresses, which the test
+    // environment may not have, but the kernel accepts (and ignores) them on
+    // loopback, which is enough to exercise the parsing.
+    let _guard = SERIAL_TESTS.lock();
+    let identity = mk_identity();
+    let mut server = Command::new(&binpath()).args(&["serve-one", &identity]).spawn().unwrap();
+    hold();
+    let mut client = Command::new(&binpath())
+        .args(&["client", "[::1%lo]:2600", &identity, "-m", "L [::1%lo]:34615 127.0.0.1:44615"])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    hold();
+    let mut ncat_listener = system("ncat --ssl -l 44615 >/tmp/oxy-test-portfwd-zoned");
+    hold();
+    let mut ncat_sender = system("ncat --ssl ::1 34615 < <(echo -n abcdef)");
+    hold();
+    server.kill().ok();
+    client.kill().ok();
+    ncat_listener.kill().ok();
+    ncat_sender.kill().ok();
+    let mut read_buf = Vec::new();
+    File::open("/tmp/oxy-test-portfwd-zoned").unwrap().read_to_end(&mut read_buf).unwrap();
+    assert_eq!(&read_buf, b"abcdef");
+    remove_file("/tmp/oxy-test-portfwd-zoned").unwrap();
+}
+
+#[test]
+fn test_connection() {
+    let _guard = SERIAL_TESTS.lock();
+    let identity = mk_identity();
+    let mut server = Command::new(&binpath()).args(&["server", &identity]).spawn().unwrap();
+    hold();
+    let good = Command::new(&binpath())
+        .args(&["client", "127.0.0.1:2600", &identity, "--test-connection"])
+        .status()
+        .unwrap();
+    let bad = Command::new(&binpath())
+        .args(&["client", "127.0.0.1:2600", &mk_identity(), "--test-connection"])
+        .status()
+        .unwrap();
+    server.kill().unwrap();
+    assert!(good.success());
+    assert!(!bad.success());
+}
+
+#[test]
+fn handshake_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let destination = listener.local_addr().unwrap().to_string();
+    let status = Command::new(&binpath())
+        .args(&["client", &destination, &mk_identity(), "--handshake-timeout=1s", "--test-connection"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(2));
+}
+
+#[test]
+fn print_connection_string() {
+    let output = Command::new(&binpath())
+        .args(&[
+            "client",
+            "127.0.0.1:2600",
+            &mk_identity(),
+            "-L",
+            "1234:localhost:22",
+            "-C",
+            "--print-connection-string",
+            "echo hi",
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        &output.stdout[..],
+        &b"oxy client --identity <redacted> -L 1234:localhost:22 --compress 127.0.0.1:2600 \"echo hi\"\n"[..]
+    );
+}
+
+#[test]
+fn no_shell() {
+    let _guard = SERIAL_TESTS.lock();
+    let identity = mk_identity();
+    let mut server = Command::new(&binpath()).args(&["server", &identity]).spawn().unwrap();
+    hold();
+    let output = Command::new(&binpath())
+        .args(&["client", "127.0.0.1:2600", &identity, "--no-shell", r#"echo '$HOME' "a  b" c\;"#])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    server.kill().unwrap();
+    assert_eq!(&output.stdout[..], b"$HOME a  b c;\n");
+}
+
+#[test]
+fn stdin_eof_action() {
+    let _guard = SERIAL_TESTS.lock();
+    let identity = mk_identity();
+    let mut server = Command::new(&binpath()).args(&["server", &identity]).spawn().unwrap();
+    hold();
+    // With close, cat sees the end of its input. With keep it never would, so
+    // the command stops reading by itself.
+    for (action, command) in &[("close", "cat; echo done"), ("keep", "head -c 3; echo done")] {
+        let output = system_output(&["client", "127.0.0.1:2600", &identity, "--stdin-eof-action", action, command], b"abc");
+        assert_eq!(&output[..], b"abcdone\n");
+    }
+    server.kill().unwrap();
+}
+
+fn system_output(args: &[&str], input: &[u8]) -> Vec<u8> {
+    use std::io::Write;
+    let mut child = Command::new(&binpath()).args(args).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap().stdout
+}
+
+#[test]
+fn output_prefix() {
+    let _guard = SERIAL_TESTS.lock();
+    let identity = mk_identity();
+    let mut server = Command::new(&binpath()).args(&["server", &identity]).spawn().unwrap();
+    hold();
+    // The unterminated last line still gets its prefix, and is ended when the
+    // command exits.
+    let output = Command::new(&binpath())
+        .args(&["client", "127.0.0.1:2600", &identity, "--output-prefix", "[100%%] ", r#"printf 'a\n\nb'"#])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    server.kill().unwrap();
+    assert_eq!(&output.stdout[..], b"[100%] a\n[100%] \n[100%] b\n");
+}
+
+#[test]
+fn remote_exit_status() {
+    let _guard = SERIAL_TESTS.lock();
+    let identity = mk_identity();
+    let mut server = Command::new(&binpath()).args(&["server", &identity]).spawn().unwrap();
+    hold();
+    let status = |command| {
+        Command::new(&binpath())
+            .args(&["client", "127.0.0.1:2600", &identity, command])
+            .stdin(Stdio::null())
+            .status()
+            .unwrap()
+            .code()
+    };
+    let exited = status("exit 3");
+    let killed = status("kill -TERM $$");
+    server.kill().unwrap();
+    assert_eq!(exited, Some(3));
+    assert_eq!(killed, Some(128 + 15));
+}
+
+#[test]
+fn metacommand_quoting() {
+    let _guard = SERIAL_TESTS.lock();
+    let identity = mk_identity();
+    let mut server = Command::new(&binpath()).args(&["server", &identity]).spawn().unwrap();
+    hold();
+    // Quoted spaces and newlines stay inside their word, and an unquoted
+    // newline separates commands, just as when typed at the F10 prompt.
+    let meta = "sh 'printf \"[%s]\" \"a  b\" > /tmp/oxy-test-metacommand'\nsh 'printf \"[%s]\" \"c\nd\" >> /tmp/oxy-test-metacommand'";
+    let mut client = Command::new(&binpath())
+        .args(&["client", "127.0.0.1:2600", &identity, "-m", meta])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    hold();
+    server.kill().ok();
+    client.kill().ok();
+    let mut read_buf = Vec::new();
+    File::open("/tmp/oxy-test-metacommand").unwrap().read_to_end(&mut read_buf).unwrap();
+    assert_eq!(&read_buf, b"[a  b][c\nd]");
+    remove_file("/tmp/oxy-test-metacommand").unwrap();
+}
+
 #[test]
 fn catpty() {
     let _guard = SERIAL_TESTS.lock();
@@ -107,6 +289,23 @@ fn catpty_rev() {
     assert_eq!(&output.stdout[..], b"\x00\x10\n\xff\rB");
 }
 
+#[test]
+fn reverse_server_retries() {
+    let _guard = SERIAL_TESTS.lock();
+    let identity = mk_identity();
+    let mut server = Command::new(&binpath())
+        .args(&["reverse-server", "127.0.0.1:2600", &identity, "--connect-retries=5", "--retry-delay=1s"])
+        .spawn()
+        .unwrap();
+    hold();
+    let output = Command::new(&binpath())
+        .args(&["reverse-client", "127.0.0.1:2600", &identity, "-m", "pty \"echo -n retried\""])
+        .output()
+        .unwrap();
+    server.kill().ok();
+    assert_eq!(&output.stdout[..], b"retried");
+}
+
 #[test]
 #[cfg(unix)]
 fn copy_single_file() {
@@ -129,3 +328,314 @@ fn copy_single_file() {
     assert_eq!(metadata("/etc/hosts").unwrap().len(), metadata("/tmp/hosts").unwrap().len());
     remove_file("/tmp/hosts").unwrap();
 }
+
+#[test]
+#[cfg(unix)]
+fn copy_to_stdout() {
+    let _guard = SERIAL_TESTS.lock();
+    let identity = mk_identity();
+    let mut server = Command::new("./target/debug/oxy").arg("serve-one").arg(&identity).spawn().unwrap();
+    hold();
+    let client = Command::new("./target/debug/oxy")
+        .arg("copy")
+        .arg("localhost:/etc/hosts")
+        .arg("-")
+        .arg(&identity)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let output = client.wait_with_output().unwrap();
+    server.kill().ok();
+    let mut hosts = Vec::new();
+    File::open("/etc/hosts").unwrap().read_to_end(&mut hosts).unwrap();
+    assert_eq!(output.stdout, hosts);
+}
+
+#[test]
+#[cfg(unix)]
+fn copy_archive() {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    let _guard = SERIAL_TESTS.lock();
+    let source = "/tmp/oxy-archive-source";
+    let dest = "/tmp/oxy-archive-test";
+    ::std::fs::remove_dir_all(source).ok();
+    ::std::fs::remove_dir_all(dest).ok();
+    ::std::fs::create_dir_all(format!("{}/sub", source)).unwrap();
+    ::std::fs::write(format!("{}/top", source), "top\n").unwrap();
+    ::std::fs::write(format!("{}/sub/file", source), "nested\n").unwrap();
+    ::std::fs::set_permissions(format!("{}/sub/file", source), ::std::fs::Permissions::from_mode(0o604)).unwrap();
+    ::std::fs::set_permissions(format!("{}/sub", source), ::std::fs::Permissions::from_mode(0o750)).unwrap();
+    let touched = Command::new("touch").args(&["-d", "@1000000000", &format!("{}/sub/file", source)]).status().unwrap();
+    assert!(touched.success());
+    let identity = mk_identity();
+    let mut server = Command::new(&binpath()).arg("serve-one").arg(&identity).spawn().unwrap();
+    hold();
+    let status = Command::new(&binpath())
+        .args(&["copy", "--archive", &format!("localhost:{}", source), &format!("{}/", dest), &identity])
+        .status()
+        .unwrap();
+    server.kill().ok();
+    assert!(status.success());
+    let copied = format!("{}/oxy-archive-source", dest);
+    assert_eq!(::std::fs::read_to_string(format!("{}/top", copied)).unwrap(), "top\n");
+    assert_eq!(::std::fs::read_to_string(format!("{}/sub/file", copied)).unwrap(), "nested\n");
+    let file = metadata(format!("{}/sub/file", copied)).unwrap();
+    assert_eq!(file.permissions().mode() & 0o7777, 0o604);
+    assert_eq!(file.mtime(), 1000000000);
+    let sub = metadata(format!("{}/sub", copied)).unwrap();
+    assert!(sub.is_dir());
+    assert_eq!(sub.permissions().mode() & 0o7777, 0o750);
+    ::std::fs::remove_dir_all(source).unwrap();
+    ::std::fs::remove_dir_all(dest).unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn copy_verify() {
+    let _guard = SERIAL_TESTS.lock();
+    let identity = mk_identity();
+    let mut server = Command::new("./target/debug/oxy").arg("serve-one").arg(&identity).spawn().unwrap();
+    hold();
+    let status = Command::new("./target/debug/oxy")
+        .arg("copy")
+        .arg("--verify")
+        .arg("localhost:/etc/hosts")
+        .arg("/tmp/oxy-verify-test/")
+        .arg(&identity)
+        .status()
+        .unwrap();
+    server.kill().ok();
+    assert!(status.success());
+    assert_eq!(
+        metadata("/etc/hosts").unwrap().len(),
+        metadata("/tmp/oxy-verify-test/hosts").unwrap().len()
+    );
+    ::std::fs::remove_dir_all("/tmp/oxy-verify-test").unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn copy_json() {
+    let _guard = SERIAL_TESTS.lock();
+    let identity = mk_identity();
+    let mut server = Command::new("./target/debug/oxy").arg("serve-one").arg(&identity).spawn().unwrap();
+    hold();
+    let output = Command::new("./target/debug/oxy")
+        .arg("copy")
+        .arg("--json")
+        .arg("localhost:/etc/hosts")
+        .arg("/tmp/oxy-json-test/")
+        .arg(&identity)
+        .output()
+        .unwrap();
+    server.kill().ok();
+    assert!(output.status.success());
+    let summary: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let len = metadata("/etc/hosts").unwrap().len();
+    assert_eq!(summary["files"][0]["status"], "transferred");
+    assert_eq!(summary["files"][0]["bytes"], len);
+    assert_eq!(summary["totals"]["transferred"], 1);
+    ::std::fs::remove_dir_all("/tmp/oxy-json-test").unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn copy_direct_verify() {
+    let _guard = SERIAL_TESTS.lock();
+    let identity = mk_identity();
+    let home = "/tmp/oxy-direct-home";
+    let dest = "/tmp/oxy-direct-test";
+    ::std::fs::remove_dir_all(home).ok();
+    ::std::fs::remove_dir_all(dest).ok();
+    ::std::fs::create_dir_all(format!("{}/.config/oxy", home)).unwrap();
+    let config_path = format!("{}/.config/oxy/client.conf", home);
+    let config = format!(
+        "identity = \"{}\"\n[[servers]]\nname = \"direct-source\"\nhost = \"127.0.0.1\"\nport = 2613\n\
+         [[servers]]\nname = \"direct-dest\"\nhost = \"127.0.0.1\"\nport = 2614\n",
+        identity.trim_left_matches("--identity=")
+    );
+    ::std::fs::write(&config_path, config).unwrap();
+    // The source server finds the oxy under test in its PATH, and the
+    // destination in client.conf under its HOME.
+    let bin = ::std::fs::canonicalize(binpath()).unwrap();
+    let path = format!("{}:{}", bin.parent().unwrap().display(), ::std::env::var("PATH").unwrap_or_default());
+    let mut source = Command::new(&binpath())
+        .args(&["serve-one", &identity, "--port=2613"])
+        .env("HOME", home)
+        .env("PATH", path)
+        .spawn()
+        .unwrap();
+    let mut destination = Command::new(&binpath()).args(&["server", &identity, "--port=2614"]).spawn().unwrap();
+    hold();
+    let output = Command::new(&binpath())
+        .args(&[
+            "copy",
+            "--remote-to-remote-direct",
+            "--verify",
+            &format!("--client-config={}", config_path),
+            "direct-source:/etc/hosts",
+            &format!("direct-dest:{}/", dest),
+            &identity,
+        ])
+        .output()
+        .unwrap();
+    source.kill().ok();
+    destination.kill().ok();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success());
+    assert!(stderr.contains("Copied direct-source:/etc/hosts directly"));
+    assert!(stderr.contains("Verified"));
+    assert_eq!(
+        metadata("/etc/hosts").unwrap().len(),
+        metadata(format!("{}/hosts", dest)).unwrap().len()
+    );
+    ::std::fs::remove_dir_all(home).unwrap();
+    ::std::fs::remove_dir_all(dest).unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn copy_recursive() {
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    let _guard = SERIAL_TESTS.lock();
+    let source = "/tmp/oxy-recursive-source";
+    let dest = "/tmp/oxy-recursive-test";
+    ::std::fs::remove_dir_all(source).ok();
+    ::std::fs::create_dir_all(format!("{}/sub", source)).unwrap();
+    ::std::fs::create_dir_all(format!("{}/empty", source)).unwrap();
+    ::std::fs::write(format!("{}/sub/script", source), "#!/bin/sh\n").unwrap();
+    ::std::fs::set_permissions(format!("{}/sub/script", source), ::std::fs::Permissions::from_mode(0o750)).unwrap();
+    ::std::fs::set_permissions(format!("{}/sub", source), ::std::fs::Permissions::from_mode(0o750)).unwrap();
+    symlink("sub/script", format!("{}/link", source)).unwrap();
+    let identity = mk_identity();
+    let mut server = Command::new(&binpath()).arg("serve-one").arg(&identity).spawn().unwrap();
+    hold();
+    let status = Command::new(&binpath())
+        .args(&["copy", "-r", &format!("localhost:{}", source), &format!("{}/", dest), &identity])
+        .status()
+        .unwrap();
+    server.kill().ok();
+    assert!(status.success());
+    let script = metadata(format!("{}/oxy-recursive-source/sub/script", dest)).unwrap();
+    assert_eq!(script.permissions().mode() & 0o7777, 0o750);
+    let sub = metadata(format!("{}/oxy-recursive-source/sub", dest)).unwrap();
+    assert_eq!(sub.permissions().mode() & 0o7777, 0o750);
+    assert!(metadata(format!("{}/oxy-recursive-source/empty", dest)).unwrap().is_dir());
+    let link = ::std::fs::read_link(format!("{}/oxy-recursive-source/link", dest)).unwrap();
+    assert_eq!(link, ::std::path::PathBuf::from("sub/script"));
+    ::std::fs::remove_dir_all(source).unwrap();
+    ::std::fs::remove_dir_all(dest).unwrap();
+}
+
+#[test]
+fn strict_config() {
+    let path = "/tmp/oxy-strict-config-test.conf";
+    ::std::fs::write(path, "[[servers]]\nname = \"typo\"\nhost = \"localhost\"\ncomplression = true\n").unwrap();
+    let output = Command::new(&binpath())
+        .args(&["client", "--strict-config", &format!("--client-config={}", path), "typo"])
+        .output()
+        .unwrap();
+    remove_file(path).unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(&format!("{}:4: unknown key \"complression\"", path)));
+}
+
+#[cfg(unix)]
+fn socks_exchange(port: u16, messages: &[&[u8]]) -> Vec<Vec<u8>> {
+    use std::{io::Write, net::TcpStream};
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut replies = Vec::new();
+    for message in messages {
+        stream.write_all(message).unwrap();
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).unwrap();
+        replies.push(reply.to_vec());
+    }
+    replies
+}
+
+#[test]
+#[cfg(unix)]
+fn socks_auth() {
+    let _guard = SERIAL_TESTS.lock();
+    let identity = mk_identity();
+    let mut server = Command::new(&binpath()).args(&["serve-one", &identity]).spawn().unwrap();
+    hold();
+    let mut client = Command::new(&binpath())
+        .args(&["client", "127.0.0.1:2600", &identity, "-D", "127.0.0.1:34616,auth=user:secret", "-D", "34617"])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    hold();
+    let no_auth = socks_exchange(34616, &[b"\x05\x01\x00"]);
+    let accepted = socks_exchange(34616, &[b"\x05\x01\x02", b"\x01\x04user\x06secret"]);
+    let refused = socks_exchange(34616, &[b"\x05\x01\x02", b"\x01\x04user\x05wrong"]);
+    let open = socks_exchange(34617, &[b"\x05\x01\x00"]);
+    server.kill().ok();
+    client.kill().ok();
+    assert_eq!(no_auth, vec![b"\x05\xff".to_vec()]);
+    assert_eq!(accepted, vec![b"\x05\x02".to_vec(), b"\x01\x00".to_vec()]);
+    assert_eq!(refused, vec![b"\x05\x02".to_vec(), b"\x01\x01".to_vec()]);
+    assert_eq!(open, vec![b"\x05\x00".to_vec()]);
+}
+
+#[test]
+fn socks_auth_validated() {
+    let identity = mk_identity();
+    for args in &[&["-D", "34618", "--socks-auth=nocolon"][..], &["-D", "34618,auth=nocolon"][..]] {
+        let output = Command::new(&binpath()).args(&["client", "127.0.0.1:2600", &identity]).args(*args).output().unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("SOCKS credentials must be given as user:pass"));
+    }
+}
+
+#[test]
+fn bind_address() {
+    let _guard = SERIAL_TESTS.lock();
+    for (bind, destination) in &[("::0", "127.0.0.1:2600"), ("0.0.0.0", "127.0.0.1:2600"), ("[::1]", "[::1]:2600")] {
+        let identity = mk_identity();
+        let mut server = Command::new(&binpath()).args(&["serve-one", &identity, bind]).spawn().unwrap();
+        hold();
+        let status = Command::new(&binpath())
+            .args(&["client", destination, &identity, "--test-connection"])
+            .status()
+            .unwrap();
+        server.kill().ok();
+        server.wait().ok();
+        assert!(status.success(), "connecting to {} with the server bound to {}", destination, bind);
+    }
+    let output = Command::new(&binpath()).args(&["serve-one", "300.1.2.3"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not an IP address to listen on"));
+}
+
+#[test]
+fn config_port() {
+    let _guard = SERIAL_TESTS.lock();
+    let path = "/tmp/oxy-config-port-test.conf";
+    ::std::fs::write(path, "[[servers]]\nname = \"prod-db\"\nhost = \"127.0.0.1\"\nport = 2611\n").unwrap();
+    let identity = mk_identity();
+    let config = format!("--client-config={}", path);
+    let mut server = Command::new(&binpath()).args(&["serve-one", &identity, "--port=2611"]).spawn().unwrap();
+    hold();
+    let from_config = Command::new(&binpath())
+        .args(&["client", &config, &identity, "prod-db", "--test-connection"])
+        .status()
+        .unwrap();
+    server.kill().ok();
+    server.wait().ok();
+    let mut server = Command::new(&binpath()).args(&["serve-one", &identity, "--port=2612"]).spawn().unwrap();
+    hold();
+    let from_flag = Command::new(&binpath())
+        .args(&["client", &config, &identity, "-p", "2612", "prod-db", "--test-connection"])
+        .status()
+        .unwrap();
+    server.kill().ok();
+    server.wait().ok();
+    remove_file(path).unwrap();
+    assert!(from_config.success());
+    assert!(from_flag.success());
+}